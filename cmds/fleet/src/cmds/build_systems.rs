@@ -1,23 +1,249 @@
-use std::{env::current_dir, os::unix::fs::symlink, path::PathBuf, time::Duration};
+use std::{
+	env::current_dir,
+	fs,
+	future::Future,
+	num::NonZeroUsize,
+	os::unix::fs::symlink,
+	path::{Path, PathBuf},
+	sync::Arc,
+	thread::available_parallelism,
+	time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use clap::{Parser, ValueEnum};
 use fleet_base::{
+	fleetdata::GoodGeneration,
 	host::{Config, ConfigHost},
 	opts::FleetOpts,
 };
 use itertools::Itertools as _;
 use nix_eval::{nix_go, NixBuildBatch};
-use tokio::{task::LocalSet, time::sleep};
-use tracing::{error, field, info, info_span, warn, Instrument};
+use serde::Serialize;
+use tokio::{
+	sync::{Semaphore, SemaphorePermit},
+	task::{JoinHandle, LocalSet},
+	time::sleep,
+};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+
+use crate::{
+	failure::Failure,
+	host_select::{pick_hosts, should_pick, TerminalPicker},
+};
+
+/// Signing of the uploaded system closures, hosts only accept paths signed by the trusted keys.
+#[derive(Parser, Clone)]
+pub struct SignOpts {
+	/// Key used to sign system closure before upload, by default /etc/nix/private-key is used if exists.
+	#[clap(long)]
+	sign_key: Option<PathBuf>,
+	/// Do not sign system closure before upload
+	#[clap(long, conflicts_with = "sign_key")]
+	no_sign: bool,
+}
+impl SignOpts {
+	pub(super) fn key(&self, exists: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+		if self.no_sign {
+			return None;
+		}
+		if let Some(key) = &self.sign_key {
+			if !exists(key) {
+				warn!("signing key {key:?} not found, closure will not be signed");
+				return None;
+			}
+			return Some(key.clone());
+		}
+		let key = PathBuf::from(DEFAULT_SIGN_KEY);
+		if !exists(&key) {
+			debug!("{DEFAULT_SIGN_KEY} not found, skipping closure signing");
+			return None;
+		}
+		Some(key)
+	}
+}
+
+/// Failure is only reported, host might trust the deployer store anyway.
+pub(super) async fn sign_closure(config: &Config, sign_key: &Path, built: &Path) -> Result<()> {
+	// Alternatively, nix store make-content-addressed can be used,
+	// at least for the first deployment, to provide trusted store key.
+	//
+	// It is much slower, yet doesn't require root on the deployer machine.
+	let mut sign = config
+		.local_host()
+		.cmd("nix")
+		.await
+		.context("failed to setup local")?;
+	sign.arg("store")
+		.arg("sign")
+		.comparg("--key-file", sign_key)
+		.arg("-r")
+		.arg(built);
+	if let Err(e) = sign.sudo().run_nix().await {
+		warn!("failed to sign store paths: {e}");
+	};
+	Ok(())
+}
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 pub struct Deploy {
 	/// Disable automatic rollback
 	#[clap(long)]
 	disable_rollback: bool,
+	#[clap(flatten)]
+	sign: SignOpts,
+	/// Pick hosts to deploy from the list, enabled by default when running in terminal
+	/// without --only/--skip
+	#[clap(long)]
+	interactive: bool,
+	/// Deploy to all selected hosts without asking
+	#[clap(long, conflicts_with = "interactive")]
+	all: bool,
+	/// Skip hosts, for which built system is the same as the last deployed one
+	#[clap(long)]
+	skip_unchanged: bool,
+	/// Skip upload and activation for hosts, which are already running the built system,
+	/// as reported by the host itself
+	#[clap(long)]
+	only_changed: bool,
+	/// Upload the full system closure from the deployer, instead of letting hosts download
+	/// paths from their binary caches, for hosts without internet access.
+	///
+	/// Upload size grows to the whole closure on the first deployment.
+	#[clap(long)]
+	no_substitute_on_destination: bool,
+	/// Only build and upload closures to all selected hosts, without asking, to activate them later
+	#[clap(long, conflicts_with_all = ["action", "interactive"])]
+	upload_all: bool,
+	/// Command used to activate the system instead of bin/switch-to-configuration, relative to
+	/// the system closure unless absolute. Overrides `activationCommand` host option.
+	#[clap(long)]
+	activation_script: Option<String>,
+	/// Write JSON report with per-host deployment outcome to the file
+	#[clap(long)]
+	report: Option<PathBuf>,
+	/// Deploy this already built system closure instead of building it from the config, i.e to
+	/// roll back to a known-good build. Closure is host-specific, so exactly one host should be
+	/// selected.
+	#[clap(long, value_name = "STORE_PATH")]
+	prebuilt: Option<PathBuf>,
+	/// Maximum number of hosts evaluated, uploaded and activated at once, number of CPUs by default
+	#[clap(long)]
+	parallel: Option<NonZeroUsize>,
+	/// Only build systems and print what would be deployed, hosts are not contacted at all
+	#[clap(long)]
+	dry_run: bool,
+	/// Shell command to run locally once all hosts are processed, even if some of them have
+	/// failed. Outcome is passed in `FLEET_DEPLOY_STATUS` (`success`, `partial` or `failed`),
+	/// `FLEET_DEPLOY_HOSTS_OK`, `FLEET_DEPLOY_HOSTS_FAILED` (comma-separated host names) and
+	/// `FLEET_DEPLOY_DURATION` (seconds) environment variables.
+	#[clap(long, value_name = "COMMAND")]
+	notify: Option<String>,
 	/// Action to execute after system is built
+	#[clap(required_unless_present = "upload_all")]
+	action: Option<DeployAction>,
+}
+
+/// Host symlink to the system, which is replaced by the action, and the path it should point to
+/// after the deployment.
+fn deployed_link(
+	action: DeployAction,
+	built: &Path,
+	specialisation: Option<&str>,
+) -> (&'static str, PathBuf) {
+	if matches!(action, DeployAction::Boot) {
+		// Specialisation is only picked on activation, profile points to the toplevel.
+		return (SYSTEM_PROFILE, built.to_owned());
+	}
+	let built = match specialisation {
+		Some(specialisation) => built.join("specialisation").join(specialisation),
+		None => built.to_owned(),
+	};
+	("/run/current-system", built)
+}
+/// Specialisation link of the built system points to the specialisation's own store path, which
+/// is what `readlink -f` of the running system reports, and the built system is present locally.
+fn resolve_expected(expected: &Path) -> Result<PathBuf> {
+	expected
+		.canonicalize()
+		.with_context(|| format!("failed to resolve {}", expected.display()))
+}
+fn is_unchanged(expected: &Path, deployed: &str) -> bool {
+	Path::new(deployed.trim()) == expected
+}
+async fn is_deployed(
+	host: &ConfigHost,
 	action: DeployAction,
+	built: &Path,
+	specialisation: Option<&str>,
+) -> Result<bool> {
+	let (link, expected) = deployed_link(action, built, specialisation);
+	let expected = resolve_expected(&expected)?;
+	let mut cmd = host.cmd("readlink").await?;
+	cmd.arg("-f").arg(link);
+	Ok(is_unchanged(&expected, &cmd.run_string().await?))
+}
+
+fn dry_run_line(host: &str, action: DeployAction, built: &Path) -> String {
+	let action = action
+		.to_possible_value()
+		.expect("no skipped variants")
+		.get_name()
+		.to_owned();
+	format!("{host}: would {action} {}", built.display())
+}
+
+/// Resolves `result` symlinks, so that the closure can be uploaded by its store path.
+fn prebuilt_closure(path: &Path) -> Result<PathBuf> {
+	let resolved = path
+		.canonicalize()
+		.with_context(|| format!("prebuilt closure {} not found", path.display()))?;
+	ensure!(
+		resolved.starts_with("/nix/store"),
+		"prebuilt closure {} is not a store path",
+		resolved.display()
+	);
+	ensure!(
+		resolved.join("nixos-version").exists(),
+		"{} is not a system closure, nixos-version is missing",
+		resolved.display()
+	);
+	Ok(resolved)
+}
+/// Prebuilt closure is used as-is, build is only performed if there is none.
+async fn system_closure(
+	prebuilt: Option<PathBuf>,
+	build: impl Future<Output = Result<PathBuf>>,
+) -> Result<PathBuf> {
+	match prebuilt {
+		Some(path) => Ok(path),
+		None => build.await,
+	}
+}
+
+const DEFAULT_ACTIVATION_SCRIPT: &str = "bin/switch-to-configuration";
+
+/// Activation command of the system, called with action name as an argument.
+pub(crate) fn activation_script(specialised: &Path, command: Option<&str>) -> PathBuf {
+	// Absolute command replaces the system path.
+	specialised.join(command.unwrap_or(DEFAULT_ACTIVATION_SCRIPT))
+}
+
+/// Private key for host machine is registered in nix-sign.nix
+const DEFAULT_SIGN_KEY: &str = "/etc/nix/private-key";
+
+impl Deploy {
+	/// Deploy to every selected host, without asking which ones.
+	pub(crate) fn without_prompt(self) -> Self {
+		Self {
+			interactive: false,
+			all: true,
+			..self
+		}
+	}
+	fn action(&self) -> DeployAction {
+		self.action.unwrap_or(DeployAction::Upload)
+	}
 }
 
 #[derive(ValueEnum, Clone, Copy)]
@@ -63,21 +289,45 @@ pub struct BuildSystems {
 	/// are "sdImage"/"isoImage", and your configuration may include any other build attributes.
 	#[clap(long, default_value = "toplevel")]
 	build_attr: String,
+	/// Maximum number of hosts evaluated at once, number of CPUs by default
+	#[clap(long)]
+	parallel: Option<NonZeroUsize>,
 }
 
-struct Generation {
-	id: u32,
-	current: bool,
-	datetime: String,
+/// Limit of concurrently processed hosts.
+///
+/// Build itself is not limited, builds of all hosts are coalesced into a single batch, which is
+/// only started once every host has submitted its derivation, thus permits should be released
+/// before waiting for the build result.
+fn host_limit(parallel: Option<NonZeroUsize>) -> Arc<Semaphore> {
+	let parallel = parallel
+		.or_else(|| available_parallelism().ok())
+		.map_or(1, NonZeroUsize::get);
+	Arc::new(Semaphore::new(parallel))
 }
-async fn get_current_generation(host: &ConfigHost) -> Result<Generation> {
-	let mut cmd = host.cmd("nix-env").await?;
-	cmd.comparg("--profile", "/nix/var/nix/profiles/system")
-		.arg("--list-generations");
-	// Sudo is required due to --list-generations acquiring lock on the profile.
-	let data = cmd.sudo().run_string().await?;
-	let generations = data
-		.split('\n')
+
+/// Wait for the host processing slot, see [`host_limit`].
+async fn host_permit(limit: Option<&Semaphore>) -> Result<Option<SemaphorePermit<'_>>> {
+	Ok(match limit {
+		Some(limit) => Some(limit.acquire().await?),
+		None => None,
+	})
+}
+
+/// System profile, generations are linked as `{SYSTEM_PROFILE}-{id}-link`
+pub(crate) const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+pub(crate) fn generation_link(id: u32) -> String {
+	format!("{SYSTEM_PROFILE}-{id}-link")
+}
+
+pub(crate) struct Generation {
+	pub(crate) id: u32,
+	pub(crate) current: bool,
+	pub(crate) datetime: String,
+}
+/// Parse `nix-env --list-generations` output.
+pub(crate) fn parse_generations(data: &str) -> Vec<Generation> {
+	data.split('\n')
 		.map(|e| e.trim())
 		.filter(|&l| !l.is_empty())
 		.filter_map(|g| {
@@ -111,14 +361,26 @@ async fn get_current_generation(host: &ConfigHost) -> Result<Generation> {
 			}
 			gen
 		})
-		.collect::<Vec<_>>();
-	let current = generations
+		.collect()
+}
+pub(crate) async fn list_generations(host: &ConfigHost) -> Result<Vec<Generation>> {
+	let mut cmd = host.cmd("nix-env").await?;
+	cmd.comparg("--profile", SYSTEM_PROFILE)
+		.arg("--list-generations");
+	// Sudo is required due to --list-generations acquiring lock on the profile.
+	let data = cmd.sudo().run_string().await?;
+	Ok(parse_generations(&data))
+}
+pub(crate) fn current_generation(generations: Vec<Generation>) -> Result<Generation> {
+	generations
 		.into_iter()
 		.filter(|g| g.current)
 		.at_most_one()
 		.map_err(|_e| anyhow!("bad list-generations output"))?
-		.ok_or_else(|| anyhow!("failed to find generation"))?;
-	Ok(current)
+		.ok_or_else(|| anyhow!("failed to find generation"))
+}
+async fn get_current_generation(host: &ConfigHost) -> Result<Generation> {
+	current_generation(list_generations(host).await?)
 }
 
 async fn deploy_task(
@@ -126,6 +388,7 @@ async fn deploy_task(
 	host: &ConfigHost,
 	built: PathBuf,
 	specialisation: Option<String>,
+	activation_command: Option<String>,
 	disable_rollback: bool,
 ) -> Result<()> {
 	let mut failed = false;
@@ -200,7 +463,7 @@ async fn deploy_task(
 		} else {
 			built.clone()
 		};
-		let switch_script = specialised.join("bin/switch-to-configuration");
+		let switch_script = activation_script(&specialised, activation_command.as_deref());
 		let mut cmd = host.cmd(switch_script).in_current_span().await?;
 		cmd.arg(action.name().expect("upload.should_activate == false"));
 		if let Err(e) = cmd.sudo().run().in_current_span().await {
@@ -248,20 +511,26 @@ async fn deploy_task(
 			// Marker might not exist, yet better try to remove it.
 		}
 	}
+	ensure!(!failed, "deployment failed, see errors above");
 	Ok(())
 }
 
-async fn build_task(
+pub(crate) async fn build_task(
 	config: Config,
 	hostname: String,
 	build_attr: &str,
 	batch: Option<NixBuildBatch>,
+	limit: Option<Arc<Semaphore>>,
 ) -> Result<PathBuf> {
-	info!("building");
-	let host = config.host(&hostname).await?;
-	// let action = Action::from(self.subcommand.clone());
-	let nixos = host.nixos_config().await?;
-	let drv = nix_go!(nixos.system.build[{ build_attr }]);
+	// Permit is released once evaluation is done, other hosts may proceed, see `host_limit`.
+	let drv = {
+		let _permit = host_permit(limit.as_deref()).await?;
+		info!("building");
+		let host = config.host(&hostname).await?;
+		// let action = Action::from(self.subcommand.clone());
+		let nixos = host.nixos_config().await?;
+		nix_go!(nixos.system.build[{ build_attr }])
+	};
 	let outputs = drv.build_maybe_batch(batch).await?;
 	let out_output = outputs
 		.get("out")
@@ -285,11 +554,118 @@ async fn build_task(
 	Ok(out_output.clone())
 }
 
+/// Per-host failures are only logged, this error summarizes them once all hosts are processed.
+async fn failed_hosts(tasks: Vec<(String, JoinHandle<bool>)>) -> Result<()> {
+	let mut failed = vec![];
+	for (name, task) in tasks {
+		if !task.await? {
+			failed.push(name);
+		}
+	}
+	partial_failure(failed)
+}
+fn partial_failure(failed: Vec<String>) -> Result<()> {
+	if failed.is_empty() {
+		return Ok(());
+	}
+	Err(anyhow!("{}", failed.join(", ")).context(Failure::PartialHosts))
+}
+
+/// Outcome of the host deployment, successful ones are named by the last executed stage.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+enum HostStatus {
+	/// Closure is already deployed, nothing was done
+	Skipped,
+	/// Closure was built, but not uploaded, see `--dry-run`
+	Built,
+	/// Closure was uploaded (or is local), but not activated
+	Uploaded,
+	Activated,
+	Failed,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HostReport {
+	host: String,
+	status: HostStatus,
+	/// Built system closure, missing if the build has failed
+	built: Option<PathBuf>,
+	duration_secs: f64,
+	error: Option<String>,
+}
+
+#[derive(Serialize, Default, PartialEq, Debug)]
+struct DeploySummary {
+	total: usize,
+	succeeded: usize,
+	skipped: usize,
+	failed: usize,
+}
+
+/// Environment of the `--notify` command, skipped hosts are counted as successful.
+fn notify_env(hosts: &[HostReport], duration: Duration) -> Vec<(&'static str, String)> {
+	let (failed, ok): (Vec<_>, Vec<_>) = hosts.iter().partition(|h| h.status == HostStatus::Failed);
+	let status = if failed.is_empty() {
+		"success"
+	} else if ok.is_empty() {
+		"failed"
+	} else {
+		"partial"
+	};
+	let names = |hosts: Vec<&HostReport>| hosts.iter().map(|h| &h.host).join(",");
+	vec![
+		("FLEET_DEPLOY_STATUS", status.to_owned()),
+		("FLEET_DEPLOY_HOSTS_OK", names(ok)),
+		("FLEET_DEPLOY_HOSTS_FAILED", names(failed)),
+		("FLEET_DEPLOY_DURATION", duration.as_secs().to_string()),
+	]
+}
+async fn notify(command: &str, env: Vec<(&'static str, String)>) -> Result<()> {
+	let status = tokio::process::Command::new("sh")
+		.arg("-c")
+		.arg(command)
+		.envs(env)
+		.status()
+		.await
+		.context("failed to run notify command")?;
+	ensure!(status.success(), "notify command failed: {status}");
+	Ok(())
+}
+
+/// Machine-readable outcome of the deployment, see `--report`.
+#[derive(Serialize, Debug)]
+struct DeployReport {
+	hosts: Vec<HostReport>,
+	summary: DeploySummary,
+}
+impl DeployReport {
+	fn new(hosts: Vec<HostReport>) -> Self {
+		let mut summary = DeploySummary {
+			total: hosts.len(),
+			..Default::default()
+		};
+		for host in &hosts {
+			match host.status {
+				HostStatus::Skipped => summary.skipped += 1,
+				HostStatus::Failed => summary.failed += 1,
+				HostStatus::Built | HostStatus::Uploaded | HostStatus::Activated => {
+					summary.succeeded += 1
+				}
+			}
+		}
+		Self { hosts, summary }
+	}
+}
+
 impl BuildSystems {
 	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
 		let hosts = opts.filter_skipped(config.list_hosts().await?).await?;
 		let set = LocalSet::new();
+		let mut tasks = vec![];
 		let build_attr = self.build_attr.clone();
+		let limit = host_limit(self.parallel);
 		let batch = (hosts.len() > 1).then(|| {
 			config
 				.nix_session
@@ -299,127 +675,540 @@ impl BuildSystems {
 			let config = config.clone();
 			let span = info_span!("build", host = field::display(&host.name));
 			let hostname = host.name;
+			let name = hostname.clone();
 			let build_attr = build_attr.clone();
 			let batch = batch.clone();
-			set.spawn_local(
+			let limit = limit.clone();
+			let task = set.spawn_local(
 				(async move {
-					let built = match build_task(config, hostname.clone(), &build_attr, batch).await
-					{
-						Ok(path) => path,
-						Err(e) => {
-							error!("failed to deploy host: {}", e);
-							return;
-						}
-					};
+					let built =
+						match build_task(config, hostname.clone(), &build_attr, batch, Some(limit))
+							.await
+						{
+							Ok(path) => path,
+							Err(e) => {
+								error!("failed to deploy host: {}", e);
+								return false;
+							}
+						};
 					// TODO: Handle error
 					let mut out = current_dir().expect("cwd exists");
 					out.push(format!("built-{}", hostname));
 
 					info!("linking iso image to {:?}", out);
 					if let Err(e) = symlink(built, out) {
-						error!("failed to symlink: {e}")
+						error!("failed to symlink: {e}");
+						return false;
 					}
+					true
 				})
 				.instrument(span),
 			);
+			tasks.push((name, task));
 		}
 		drop(batch);
 		set.await;
-		Ok(())
+		failed_hosts(tasks).await
 	}
 }
 
 impl Deploy {
 	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
-		let hosts = opts.filter_skipped(config.list_hosts().await?).await?;
+		let started = Instant::now();
+		let mut hosts = opts.filter_skipped(config.list_hosts().await?).await?;
+		if should_pick(opts, self.interactive, self.all || self.upload_all) {
+			hosts = pick_hosts(hosts, "Select hosts to deploy", &TerminalPicker).await?;
+		}
+		let action = self.action();
+		let prebuilt = match &self.prebuilt {
+			Some(path) => {
+				ensure!(
+					hosts.len() == 1,
+					"--prebuilt closure is host-specific, exactly one host should be selected, got {}",
+					hosts.len()
+				);
+				Some(prebuilt_closure(path)?)
+			}
+			None => None,
+		};
 		let set = LocalSet::new();
+		let mut tasks = vec![];
 		let batch = (hosts.len() > 1).then(|| {
 			config
 				.nix_session
 				.new_build_batch("deploy-hosts".to_string())
 		});
+		let sign_key = self.sign.key(Path::exists);
+		let limit = host_limit(self.parallel);
+		let report_path = self.report.clone();
+		let notify_command = self.notify.clone();
 		for host in hosts.into_iter() {
 			let config = config.clone();
 			let span = info_span!("deploy", host = field::display(&host.name));
 			let hostname = host.name.clone();
-			let local_host = config.local_host();
 			let opts = opts.clone();
 			let batch = batch.clone();
+			let sign_key = sign_key.clone();
+			let activation_script = self.activation_script.clone();
+			let prebuilt = prebuilt.clone();
+			let limit = limit.clone();
 
-			set.spawn_local(
+			let task = set.spawn_local(
 				(async move {
-					let built =
-						match build_task(config.clone(), hostname.clone(), "toplevel", batch).await
-						{
-							Ok(path) => path,
+					let started = Instant::now();
+					let mut built = None;
+					let result = async {
+						let build = build_task(
+							config.clone(),
+							hostname.clone(),
+							"toplevel",
+							batch,
+							Some(limit.clone()),
+						);
+						let path = system_closure(prebuilt, build)
+							.await
+							.context("failed to deploy host")?;
+						let _permit = host_permit(Some(&limit)).await?;
+						let built = built.insert(path).clone();
+						if self.dry_run {
+							println!("{}", dry_run_line(&hostname, action, &built));
+							return Ok(HostStatus::Built);
+						}
+						let hash = match config.nix_session.path_hash(&built).await {
+							Ok(hash) => Some(hash),
 							Err(e) => {
-								error!("failed to deploy host: {}", e);
-								return;
+								warn!("failed to hash system closure: {e}");
+								None
 							}
 						};
-					if !opts.is_local(&hostname) {
-						info!("uploading system closure");
-						{
-							// TODO: Move to remote_derivation method.
-							// Alternatively, nix store make-content-addressed can be used,
-							// at least for the first deployment, to provide trusted store key.
-							//
-							// It is much slower, yet doesn't require root on the deployer machine.
-							let Ok(mut sign) = local_host.cmd("nix").await else {
-								error!("failed to setup local");
-								return;
-							};
-							// Private key for host machine is registered in nix-sign.nix
-							sign.arg("store")
-								.arg("sign")
-								.comparg("--key-file", "/etc/nix/private-key")
-								.arg("-r")
-								.arg(&built);
-							if let Err(e) = sign.sudo().run_nix().await {
-								warn!("failed to sign store paths: {e}");
-							};
+						if self.skip_unchanged && hash.is_some() {
+							let data = config.data();
+							let deployed = data
+								.hosts
+								.get(&hostname)
+								.and_then(|h| h.deployed_system_hash.as_ref());
+							if deployed == hash.as_ref() {
+								info!("system is unchanged since the last deployment, skipping");
+								return Ok(HostStatus::Skipped);
+							}
 						}
-						let mut tries = 0;
-						loop {
-							match host.remote_derivation(&built).await {
-								Ok(remote) => {
-									assert!(remote == built, "CA derivations aren't implemented");
-									break;
+						let specialisation = opts
+							.action_attr(&host, "specialisation")
+							.await
+							.context("failed to get specialisation")?;
+						if self.only_changed {
+							match is_deployed(&host, action, &built, specialisation.as_deref())
+								.await
+							{
+								Ok(true) => {
+									info!("host is already running the built system, skipping");
+									return Ok(HostStatus::Skipped);
 								}
-								Err(e) if tries < 3 => {
-									tries += 1;
-									warn!("copy failure ({}/3): {}", tries, e);
-									sleep(Duration::from_millis(5000)).await;
+								Ok(false) => {}
+								Err(e) => warn!("failed to query deployed system: {e}"),
+							}
+						}
+						if !opts.is_local(&hostname) {
+							info!("uploading system closure");
+							if let Some(sign_key) = &sign_key {
+								// TODO: Move to remote_derivation method.
+								sign_closure(&config, sign_key, &built).await?;
+							}
+							let mut tries = 0;
+							loop {
+								match host
+									.remote_derivation(&built, !self.no_substitute_on_destination)
+									.await
+								{
+									Ok(remote) => {
+										assert!(
+											remote == built,
+											"CA derivations aren't implemented"
+										);
+										break;
+									}
+									Err(e) if tries < 3 => {
+										tries += 1;
+										warn!("copy failure ({}/3): {}", tries, e);
+										sleep(Duration::from_millis(5000)).await;
+									}
+									Err(e) => return Err(e.context("upload failed")),
 								}
-								Err(e) => {
-									error!("upload failed: {e}");
-									return;
+							}
+						}
+						let activation_command = match activation_script {
+							Some(script) => Some(script),
+							None if action.should_activate() => host
+								.activation_command()
+								.await
+								.context("failed to get activation command")?,
+							None => None,
+						};
+						deploy_task(
+							action,
+							&host,
+							built.clone(),
+							specialisation,
+							activation_command,
+							self.disable_rollback,
+						)
+						.await
+						.context("activation failed")?;
+						if matches!(action, DeployAction::Switch) {
+							// Profile is switched, the new generation is current.
+							match get_current_generation(&host).await {
+								Ok(generation) => {
+									config
+										.data_mut()
+										.hosts
+										.entry(hostname.clone())
+										.or_default()
+										.last_good_generation = Some(GoodGeneration {
+										id: generation.id,
+										system: built.clone(),
+									})
 								}
+								Err(e) => warn!("failed to record deployed generation: {e}"),
 							}
 						}
+						// Uploaded system isn't deployed yet.
+						if let Some(hash) = hash.filter(|_| !matches!(action, DeployAction::Upload))
+						{
+							config
+								.data_mut()
+								.hosts
+								.entry(hostname.clone())
+								.or_default()
+								.deployed_system_hash = Some(hash);
+						}
+						Ok(if action.should_activate() {
+							HostStatus::Activated
+						} else {
+							HostStatus::Uploaded
+						})
 					}
-					if let Err(e) = deploy_task(
-						self.action,
-						&host,
+					.await;
+					let (status, error) = match result {
+						Ok(status) => (status, None),
+						Err(e) => {
+							error!("{e:#}");
+							(HostStatus::Failed, Some(format!("{e:#}")))
+						}
+					};
+					HostReport {
+						host: hostname,
+						status,
 						built,
-						if let Ok(v) = opts.action_attr(&host, "specialisation").await {
-							v
-						} else {
-							error!("unreachable? failed to get specialization");
-							return;
-						},
-						self.disable_rollback,
-					)
-					.await
-					{
-						error!("activation failed: {e}");
+						duration_secs: started.elapsed().as_secs_f64(),
+						error,
 					}
 				})
 				.instrument(span),
 			);
+			tasks.push(task);
 		}
 		drop(batch);
 		set.await;
-		Ok(())
+		let mut hosts = vec![];
+		for task in tasks {
+			hosts.push(task.await?);
+		}
+		let skipped = hosts
+			.iter()
+			.filter(|h| h.status == HostStatus::Skipped)
+			.map(|h| &h.host)
+			.join(", ");
+		if !skipped.is_empty() {
+			info!("skipped unchanged hosts: {skipped}");
+		}
+		let failed = hosts
+			.iter()
+			.filter(|h| h.status == HostStatus::Failed)
+			.map(|h| h.host.clone())
+			.collect();
+		if let Some(command) = notify_command {
+			// Deployment outcome is already known, notification failure shouldn't change it.
+			if let Err(e) = notify(&command, notify_env(&hosts, started.elapsed())).await {
+				error!("{e:#}");
+			}
+		}
+		if let Some(path) = report_path {
+			let report = DeployReport::new(hosts);
+			fs::write(&path, serde_json::to_string_pretty(&report)?)
+				.with_context(|| format!("failed to write report to {}", path.display()))?;
+		}
+		partial_failure(failed)
+	}
+}
+
+#[test]
+fn upload_only() {
+	let upload = DeployAction::Upload;
+	assert_eq!(upload.name(), None);
+	assert!(!upload.should_switch_profile());
+	assert!(!upload.should_activate());
+	assert!(!upload.should_create_rollback_marker());
+	assert!(!upload.should_schedule_rollback_run());
+
+	let deploy = Deploy::parse_from(["deploy", "--upload-all"]);
+	assert!(matches!(deploy.action(), DeployAction::Upload));
+	assert!(matches!(
+		Deploy::parse_from(["deploy", "upload"]).action(),
+		DeployAction::Upload
+	));
+	assert!(Deploy::try_parse_from(["deploy"]).is_err());
+	assert!(Deploy::try_parse_from(["deploy", "--upload-all", "switch"]).is_err());
+}
+
+#[test]
+fn deploy_sign_key() {
+	let deploy = |args: &[&str]| Deploy::parse_from(["deploy", "switch"].iter().chain(args));
+	let default = Path::new(DEFAULT_SIGN_KEY);
+
+	assert_eq!(
+		deploy(&[]).sign.key(|p| p == default),
+		Some(default.to_owned())
+	);
+	assert_eq!(deploy(&[]).sign.key(|_| false), None);
+	assert_eq!(deploy(&["--no-sign"]).sign.key(|_| true), None);
+
+	let custom = deploy(&["--sign-key", "/root/key"]);
+	assert_eq!(
+		custom.sign.key(|p| p == Path::new("/root/key")),
+		Some(PathBuf::from("/root/key"))
+	);
+	assert_eq!(custom.sign.key(|p| p == default), None);
+	assert!(Deploy::try_parse_from(["deploy", "switch", "--no-sign", "--sign-key", "/k"]).is_err());
+}
+
+#[test]
+fn unchanged_system() {
+	let built = Path::new("/nix/store/aaa-nixos-system");
+	let (link, expected) = deployed_link(DeployAction::Switch, built, None);
+	assert_eq!(link, "/run/current-system");
+	assert!(is_unchanged(&expected, "/nix/store/aaa-nixos-system\n"));
+	assert!(!is_unchanged(&expected, "/nix/store/bbb-nixos-system\n"));
+
+	// Running specialisation is reported by its own path, not by the toplevel link.
+	let store = tempfile::tempdir().unwrap();
+	let (toplevel, gpu) = (store.path().join("system"), store.path().join("gpu"));
+	std::fs::create_dir_all(toplevel.join("specialisation")).unwrap();
+	std::fs::create_dir(&gpu).unwrap();
+	std::os::unix::fs::symlink(&gpu, toplevel.join("specialisation/gpu")).unwrap();
+	let (_, expected) = deployed_link(DeployAction::Test, &toplevel, Some("gpu"));
+	let expected = resolve_expected(&expected).unwrap();
+	assert!(is_unchanged(
+		&expected,
+		&format!("{}\n", gpu.canonicalize().unwrap().display())
+	));
+	assert!(!is_unchanged(&expected, &toplevel.display().to_string()));
+	let (_, missing) = deployed_link(DeployAction::Test, &toplevel, Some("cpu"));
+	assert!(resolve_expected(&missing).is_err());
+
+	// Boot only replaces the profile, running system is irrelevant.
+	let (link, expected) = deployed_link(DeployAction::Boot, built, Some("gpu"));
+	assert_eq!(link, SYSTEM_PROFILE);
+	assert!(is_unchanged(&expected, "/nix/store/aaa-nixos-system"));
+}
+
+#[test]
+fn activation_command() {
+	let system = Path::new("/nix/store/aaa-nixos-system");
+	assert_eq!(
+		activation_script(system, None),
+		Path::new("/nix/store/aaa-nixos-system/bin/switch-to-configuration")
+	);
+	assert_eq!(
+		activation_script(&system.join("specialisation/gpu"), Some("bin/activate")),
+		Path::new("/nix/store/aaa-nixos-system/specialisation/gpu/bin/activate")
+	);
+	assert_eq!(
+		activation_script(system, Some("/run/current-system/sw/bin/install-system")),
+		Path::new("/run/current-system/sw/bin/install-system")
+	);
+	let deploy = Deploy::parse_from(["deploy", "--activation-script", "bin/activate", "switch"]);
+	assert_eq!(deploy.activation_script.as_deref(), Some("bin/activate"));
+}
+
+#[test]
+fn deploy_report() {
+	let report = DeployReport::new(vec![
+		HostReport {
+			host: "a".to_owned(),
+			status: HostStatus::Activated,
+			built: Some(PathBuf::from("/nix/store/aaa-nixos-system")),
+			duration_secs: 10.0,
+			error: None,
+		},
+		HostReport {
+			host: "b".to_owned(),
+			status: HostStatus::Skipped,
+			built: Some(PathBuf::from("/nix/store/bbb-nixos-system")),
+			duration_secs: 1.0,
+			error: None,
+		},
+		HostReport {
+			host: "c".to_owned(),
+			status: HostStatus::Failed,
+			built: None,
+			duration_secs: 2.5,
+			error: Some("failed to deploy host: build failed".to_owned()),
+		},
+	]);
+	assert_eq!(
+		report.summary,
+		DeploySummary {
+			total: 3,
+			succeeded: 1,
+			skipped: 1,
+			failed: 1,
+		}
+	);
+	let json = serde_json::to_value(&report).unwrap();
+	assert_eq!(json["hosts"][0]["status"], "activated");
+	assert_eq!(json["hosts"][0]["built"], "/nix/store/aaa-nixos-system");
+	assert_eq!(json["hosts"][2]["status"], "failed");
+	assert_eq!(json["hosts"][2]["built"], serde_json::Value::Null);
+	assert_eq!(
+		json["hosts"][2]["error"],
+		"failed to deploy host: build failed"
+	);
+	assert_eq!(json["hosts"][2]["durationSecs"], 2.5);
+}
+
+#[tokio::test]
+async fn prebuilt_system() {
+	let prebuilt = PathBuf::from("/nix/store/aaa-nixos-system");
+	let unreachable = async { panic!("prebuilt system shouldn't be built") };
+	assert_eq!(
+		system_closure(Some(prebuilt.clone()), unreachable)
+			.await
+			.unwrap(),
+		prebuilt
+	);
+	let built = async { Ok(PathBuf::from("/nix/store/bbb-nixos-system")) };
+	assert_eq!(
+		system_closure(None, built).await.unwrap(),
+		Path::new("/nix/store/bbb-nixos-system")
+	);
+
+	let not_store = tempfile::tempdir().unwrap();
+	assert!(prebuilt_closure(not_store.path()).is_err());
+	assert!(prebuilt_closure(Path::new("/nonexistent/system")).is_err());
+	assert!(
+		Deploy::parse_from(["deploy", "--prebuilt", "./result", "switch"])
+			.prebuilt
+			.is_some()
+	);
+}
+
+#[test]
+fn dry_run() {
+	let deploy = Deploy::parse_from(["deploy", "--dry-run", "switch"]);
+	assert!(deploy.dry_run);
+	assert_eq!(
+		dry_run_line(
+			"a",
+			deploy.action(),
+			Path::new("/nix/store/aaa-nixos-system")
+		),
+		"a: would switch /nix/store/aaa-nixos-system"
+	);
+	let report = DeployReport::new(vec![HostReport {
+		host: "a".to_owned(),
+		status: HostStatus::Built,
+		built: Some(PathBuf::from("/nix/store/aaa-nixos-system")),
+		duration_secs: 1.0,
+		error: None,
+	}]);
+	assert_eq!(report.summary.succeeded, 1);
+}
+
+#[tokio::test]
+async fn notify_command() {
+	let host = |host: &str, status| HostReport {
+		host: host.to_owned(),
+		status,
+		built: None,
+		duration_secs: 1.0,
+		error: None,
+	};
+	let hosts = [
+		host("a", HostStatus::Activated),
+		host("b", HostStatus::Failed),
+		host("c", HostStatus::Skipped),
+	];
+	let dir = tempfile::tempdir().unwrap();
+	let out = dir.path().join("out");
+	let command = format!(
+		"echo \"$FLEET_DEPLOY_STATUS;$FLEET_DEPLOY_HOSTS_OK;$FLEET_DEPLOY_HOSTS_FAILED;$FLEET_DEPLOY_DURATION\" > {}",
+		out.display()
+	);
+	notify(&command, notify_env(&hosts, Duration::from_secs(75)))
+		.await
+		.unwrap();
+	assert_eq!(fs::read_to_string(&out).unwrap(), "partial;a,c;b;75\n");
+
+	let env = notify_env(&hosts[..1], Duration::ZERO);
+	assert_eq!(env[0], ("FLEET_DEPLOY_STATUS", "success".to_owned()));
+	let env = notify_env(&hosts[1..2], Duration::ZERO);
+	assert_eq!(env[0], ("FLEET_DEPLOY_STATUS", "failed".to_owned()));
+	assert!(notify("exit 1", vec![]).await.is_err());
+}
+
+#[test]
+fn parallel_hosts() {
+	let deploy = Deploy::parse_from(["deploy", "--parallel", "3", "switch"]);
+	assert_eq!(host_limit(deploy.parallel).available_permits(), 3);
+	assert!(Deploy::try_parse_from(["deploy", "--parallel", "0", "switch"]).is_err());
+	let build = BuildSystems::parse_from(["build-systems"]);
+	assert!(host_limit(build.parallel).available_permits() >= 1);
+}
+
+#[tokio::test]
+async fn limited_hosts() {
+	use std::{cell::Cell, rc::Rc};
+
+	use tokio::sync::Barrier;
+
+	let limit = host_limit(NonZeroUsize::new(1));
+	// Build batch is only started once every host has submitted its derivation.
+	let batch = Rc::new(Barrier::new(3));
+	let (active, max_active) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+	let set = LocalSet::new();
+	let mut hosts = vec![];
+	for _ in 0..3 {
+		let (limit, batch, active, max_active) = (
+			limit.clone(),
+			batch.clone(),
+			active.clone(),
+			max_active.clone(),
+		);
+		hosts.push(set.spawn_local(async move {
+			let step = || async {
+				active.set(active.get() + 1);
+				max_active.set(max_active.get().max(active.get()));
+				sleep(Duration::from_millis(20)).await;
+				active.set(active.get() - 1);
+				Ok::<_, anyhow::Error>(())
+			};
+			{
+				// Evaluation, as in `build_task`.
+				let _permit = host_permit(Some(&limit)).await?;
+				step().await?;
+			}
+			batch.wait().await;
+			// Upload and activation, as in `Deploy`.
+			let _permit = host_permit(Some(&limit)).await?;
+			step().await
+		}));
+	}
+	// Hosts holding their permits while waiting for the batch would never finish.
+	tokio::time::timeout(Duration::from_secs(10), set)
+		.await
+		.expect("permits are released before the build");
+	for host in hosts {
+		host.await.expect("not panicked").expect("processed");
 	}
+	assert_eq!(max_active.get(), 1);
 }
@@ -0,0 +1,351 @@
+use std::{
+	future::Future,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::Parser;
+use fleet_base::{
+	host::{Config, ConfigHost},
+	opts::FleetOpts,
+};
+use futures::future::join_all;
+use nix_eval::{nix_go, parse_diff_closures, ClosureDiffEntry};
+use tracing::{error, field, info_span, Instrument};
+
+use super::build_systems::{
+	build_task, current_generation, generation_link, list_generations, sign_closure, Generation,
+	SignOpts, SYSTEM_PROFILE,
+};
+use crate::failure::Failure;
+
+/// Show packages changed between two system generations of the host
+///
+/// Without `--host`, configuration of every selected host is built, and compared with its
+/// current system, to review changes before the deployment, or with the configuration of
+/// other flake ref with `--against`.
+#[derive(Parser)]
+pub struct Diff {
+	/// Compare generations of this host
+	#[clap(long, requires = "from_generation")]
+	host: Option<String>,
+	/// Older generation id, as listed by `nix-env --list-generations`
+	#[clap(long, requires = "host")]
+	from_generation: Option<u32>,
+	/// Newer generation id, current generation by default
+	#[clap(long, requires = "host")]
+	to_generation: Option<u32>,
+	/// Compare with the configuration of this flake ref (i.e `git+file:.?ref=main`) instead of
+	/// the current systems, both configurations are built locally
+	#[clap(long, conflicts_with = "host")]
+	against: Option<String>,
+	/// Output changes as JSON
+	#[clap(long)]
+	json: bool,
+	#[clap(flatten)]
+	sign: SignOpts,
+}
+
+/// Validate that both generations exist, returns their profile links.
+fn resolve_generations(
+	generations: Vec<Generation>,
+	from: u32,
+	to: Option<u32>,
+) -> Result<(String, String)> {
+	for id in [Some(from), to].into_iter().flatten() {
+		ensure!(
+			generations.iter().any(|g| g.id == id),
+			"generation {id} not found, it might have been garbage collected"
+		);
+	}
+	let to = match to {
+		Some(to) => to,
+		None => current_generation(generations)?.id,
+	};
+	Ok((generation_link(from), generation_link(to)))
+}
+
+/// Versions as printed by nix: ∅ for no versions, ε for the empty version string.
+fn format_versions(versions: &[String]) -> String {
+	if versions.is_empty() {
+		return "∅".to_owned();
+	}
+	versions
+		.iter()
+		.map(|v| if v.is_empty() { "ε" } else { v })
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+/// Line of `nix store diff-closures` text output, None if the change is insignificant.
+fn format_change(change: &ClosureDiffEntry) -> Option<String> {
+	let mut parts = vec![];
+	if change.versions_before != change.versions_after {
+		parts.push(format!(
+			"{} → {}",
+			format_versions(&change.versions_before),
+			format_versions(&change.versions_after)
+		));
+	}
+	// Same threshold as used by nix itself.
+	if change.size_delta.abs() >= 8 * 1024 {
+		parts.push(format!("{:+.1} KiB", change.size_delta as f64 / 1024.0));
+	}
+	if parts.is_empty() {
+		return None;
+	}
+	Some(format!("{}: {}", change.name, parts.join(", ")))
+}
+
+impl Diff {
+	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		if let Some(against) = &self.against {
+			return self.run_against(config, opts, against).await;
+		}
+		let (Some(host), Some(from_generation)) = (&self.host, self.from_generation) else {
+			return self.run_pending(config, opts).await;
+		};
+		let host = config.host(host).await?;
+		let generations = list_generations(&host).await?;
+		let (from, to) = resolve_generations(generations, from_generation, self.to_generation)?;
+		let changes = diff_closures(&host, Path::new(&from), Path::new(&to)).await?;
+		self.print(&changes)
+	}
+	/// Compare current systems of the selected hosts with the built configuration.
+	async fn run_pending(&self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let hosts = opts.filter_skipped(config.list_hosts().await?).await?;
+		let hosts = build_hosts(config, hosts).await;
+		let sign_key = self.sign.key(Path::exists);
+		diff_hosts(
+			hosts,
+			|(host, built)| {
+				let sign_key = sign_key.clone();
+				async move {
+					let built = built?;
+					if !opts.is_local(&host.name) {
+						// Host only accepts the closure signed by the trusted key, same as deploy.
+						if let Some(sign_key) = &sign_key {
+							sign_closure(config, sign_key, &built).await?;
+						}
+						// Only the closure is copied, the system is not activated.
+						host.remote_derivation(&built, true)
+							.await
+							.context("failed to upload system closure")?;
+					}
+					diff_closures(&host, Path::new(SYSTEM_PROFILE), &built).await
+				}
+			},
+			|host, changes| {
+				println!("{host}:");
+				self.print(changes)
+			},
+		)
+		.await
+	}
+	/// Compare built configurations of the selected hosts with the other flake ref.
+	async fn run_against(&self, config: &Config, opts: &FleetOpts, against: &str) -> Result<()> {
+		let other = config.other_flake_config(against).await?;
+		let hosts = opts.filter_skipped(config.list_hosts().await?).await?;
+		let hosts = build_hosts(config, hosts).await;
+		let local = config.local_host();
+		diff_hosts(
+			hosts,
+			|(host, built)| {
+				let other = other.clone();
+				let local = &local;
+				async move {
+					let built = built?;
+					let name = host.name;
+					let other_built =
+						nix_go!(other.hosts[{ name }].nixos.config.system.build.toplevel)
+							.build()
+							.await?;
+					let other_built = other_built
+						.get("out")
+						.ok_or_else(|| anyhow!("system build should produce \"out\" output"))?;
+					diff_closures(local, other_built, &built).await
+				}
+			},
+			|host, changes| {
+				println!("{host}:");
+				self.print(changes)
+			},
+		)
+		.await
+	}
+	fn print(&self, changes: &[ClosureDiffEntry]) -> Result<()> {
+		if self.json {
+			println!("{}", serde_json::to_string_pretty(changes)?);
+		} else {
+			for line in changes.iter().filter_map(format_change) {
+				println!("{line}");
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Build systems of every host before they are diffed, so that their builds are batched.
+async fn build_hosts(
+	config: &Config,
+	hosts: Vec<ConfigHost>,
+) -> Vec<(String, (ConfigHost, Result<PathBuf>))> {
+	let batch =
+		(hosts.len() > 1).then(|| config.nix_session.new_build_batch("diff-hosts".to_string()));
+	let builds = join_all(hosts.iter().map(|host| {
+		let span = info_span!("build", host = field::display(&host.name));
+		build_task(
+			config.clone(),
+			host.name.clone(),
+			"toplevel",
+			batch.clone(),
+			None,
+		)
+		.instrument(span)
+	}));
+	drop(batch);
+	let built = builds.await;
+	hosts
+		.into_iter()
+		.zip(built)
+		.map(|(h, built)| (h.name.clone(), (h, built)))
+		.collect()
+}
+
+/// Diff every host in order, printing changes as soon as they are known, failed hosts are reported
+/// at the end.
+async fn diff_hosts<H, F, Fut>(
+	hosts: Vec<(String, H)>,
+	diff: F,
+	mut report: impl FnMut(&str, &[ClosureDiffEntry]) -> Result<()>,
+) -> Result<()>
+where
+	F: Fn(H) -> Fut,
+	Fut: Future<Output = Result<Vec<ClosureDiffEntry>>>,
+{
+	let mut failed = vec![];
+	for (name, host) in hosts {
+		let span = info_span!("diff", host = field::display(&name));
+		match diff(host).instrument(span).await {
+			Ok(changes) => report(&name, &changes)?,
+			Err(e) => {
+				error!("failed to diff {name}: {e:#}");
+				failed.push(name);
+			}
+		}
+	}
+	if !failed.is_empty() {
+		return Err(anyhow!("{}", failed.join(", ")).context(Failure::PartialHosts));
+	}
+	Ok(())
+}
+
+/// Closures are compared on the host itself, where both of them are present.
+async fn diff_closures(host: &ConfigHost, from: &Path, to: &Path) -> Result<Vec<ClosureDiffEntry>> {
+	let mut cmd = host.cmd("nix").await?;
+	cmd.args(["--extra-experimental-features", "nix-command"])
+		.args(["store", "diff-closures", "--json"])
+		.arg(from)
+		.arg(to);
+	let output = cmd.run_string().await?;
+	Ok(parse_diff_closures(output.as_bytes())?)
+}
+
+#[test]
+fn generation_resolution() {
+	let generations = super::build_systems::parse_generations(
+		"  41   2024-05-01 10:00:00\n  42   2024-05-02 10:00:00   (current)\n",
+	);
+	assert_eq!(
+		resolve_generations(generations, 41, None).unwrap(),
+		(
+			"/nix/var/nix/profiles/system-41-link".to_owned(),
+			"/nix/var/nix/profiles/system-42-link".to_owned()
+		)
+	);
+	let generations = super::build_systems::parse_generations("  41   2024-05-01 10:00:00\n");
+	assert!(resolve_generations(generations, 40, Some(41)).is_err());
+	let generations = super::build_systems::parse_generations("  41   2024-05-01 10:00:00\n");
+	// No current generation to compare with.
+	assert!(resolve_generations(generations, 41, None).is_err());
+}
+
+#[test]
+fn diff_modes() {
+	let pending = Diff::parse_from(["diff"]);
+	assert!(pending.host.is_none() && pending.from_generation.is_none());
+	let generations = Diff::parse_from(["diff", "--host", "a", "--from-generation", "41"]);
+	assert_eq!(generations.host.as_deref(), Some("a"));
+	let against = Diff::parse_from(["diff", "--against", "git+file:.?ref=main"]);
+	assert_eq!(against.against.as_deref(), Some("git+file:.?ref=main"));
+	assert!(Diff::try_parse_from([
+		"diff",
+		"--against",
+		"git+file:.",
+		"--host",
+		"a",
+		"--from-generation",
+		"41"
+	])
+	.is_err());
+	// Closures uploaded for comparison are signed the same way as deployed ones.
+	assert!(Diff::parse_from(["diff", "--no-sign"])
+		.sign
+		.key(|_| true)
+		.is_none());
+	// Generations are only known for the single host.
+	assert!(Diff::try_parse_from(["diff", "--host", "a"]).is_err());
+	assert!(Diff::try_parse_from(["diff", "--from-generation", "41"]).is_err());
+}
+
+#[test]
+fn closure_changes() {
+	let changes = parse_diff_closures(br#"{"packages":{"firefox":{"sizeDelta":1049088,"versionsAfter":["126.0"],"versionsBefore":["125.0"]},"hello":{"sizeDelta":0,"versionsAfter":["2.12"],"versionsBefore":[]},"libfoo":{"sizeDelta":-20480,"versionsAfter":[],"versionsBefore":["1.0",""]},"linux":{"sizeDelta":16384,"versionsAfter":["6.6"],"versionsBefore":["6.6"]},"tzdata":{"sizeDelta":12,"versionsAfter":["2024a"],"versionsBefore":["2024a"]}}}"#).unwrap();
+	let lines = changes.iter().filter_map(format_change).collect::<Vec<_>>();
+	assert_eq!(
+		lines,
+		[
+			"firefox: 125.0 → 126.0, +1024.5 KiB",
+			"hello: ∅ → 2.12",
+			"libfoo: 1.0, ε → ∅, -20.0 KiB",
+			"linux: +16.0 KiB",
+		]
+	);
+}
+
+#[tokio::test]
+async fn pending_hosts() {
+	let changed = || {
+		parse_diff_closures(br#"{"packages":{"hello":{"sizeDelta":0,"versionsAfter":["2.12"],"versionsBefore":["2.10"]}}}"#)
+	};
+	let mut reported = vec![];
+	let result = diff_hosts(
+		vec![
+			("a".to_owned(), true),
+			("b".to_owned(), false),
+			("c".to_owned(), true),
+		],
+		|ok| async move {
+			if ok {
+				Ok(changed()?)
+			} else {
+				Err(anyhow!("build failed"))
+			}
+		},
+		|host, changes| {
+			let lines = changes.iter().filter_map(format_change).collect::<Vec<_>>();
+			reported.push((host.to_owned(), lines));
+			Ok(())
+		},
+	)
+	.await;
+	// Failure of one host doesn't stop the rest from being diffed.
+	let line = vec!["hello: 2.10 → 2.12".to_owned()];
+	assert_eq!(
+		reported,
+		[("a".to_owned(), line.clone()), ("c".to_owned(), line)]
+	);
+	let err = result.unwrap_err();
+	assert_eq!(err.downcast_ref::<Failure>(), Some(&Failure::PartialHosts));
+	assert_eq!(err.root_cause().to_string(), "b");
+}
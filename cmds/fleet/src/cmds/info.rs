@@ -4,6 +4,7 @@ use anyhow::{ensure, Result};
 use clap::Parser;
 use fleet_base::host::Config;
 use nix_eval::nix_go_json;
+use tracing::warn;
 
 #[derive(Parser)]
 pub struct Info {
@@ -28,6 +29,8 @@ pub enum InfoCmd {
 		#[clap(long)]
 		internal: bool,
 	},
+	/// List system out paths of all hosts, without building them
+	Toplevels,
 }
 
 impl Info {
@@ -71,6 +74,17 @@ impl Info {
 					data.push(ip);
 				}
 			}
+			InfoCmd::Toplevels => {
+				let toplevels = config.hosts_toplevel_paths().await?;
+				for host in toplevels.failed {
+					warn!("failed to evaluate system of {host}");
+				}
+				let mut paths = toplevels.paths.into_iter().collect::<Vec<_>>();
+				paths.sort();
+				for (host, path) in paths {
+					data.push(format!("{host} {}", path.display()));
+				}
+			}
 		}
 
 		if self.json {
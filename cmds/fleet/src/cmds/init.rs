@@ -0,0 +1,104 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use fleet_base::{
+	fleetdata::FleetData,
+	opts::{read_fleet_data, write_fleet_data, StateFormat},
+};
+use tracing::{info, warn};
+
+/// Create fleet.nix and a starter flake.nix for a new fleet project in the current directory
+#[derive(Parser)]
+pub struct Init {
+	/// Overwrite existing fleet.nix and flake.nix
+	#[clap(long)]
+	force: bool,
+}
+
+const FLAKE_TEMPLATE: &str = r#"{
+  description = "Fleet cluster configuration";
+  inputs = {
+    nixpkgs.url = "github:nixos/nixpkgs";
+    fleet = {
+      url = "github:CertainLach/fleet";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+    flake-parts.url = "github:hercules-ci/flake-parts";
+  };
+  outputs = inputs:
+    inputs.flake-parts.lib.mkFlake {inherit inputs;} {
+      imports = [inputs.fleet.flakeModules.default];
+      systems = ["x86_64-linux" "aarch64-linux"];
+
+      fleetConfigurations.default = {
+        nixos = {
+          # Configuration shared by all the hosts.
+        };
+        # hosts.example = {
+        #   system = "x86_64-linux";
+        #   nixos.imports = [./example/configuration.nix];
+        # };
+      };
+    };
+}
+"#;
+
+impl Init {
+	/// Runs without evaluating the project, as there is no project yet.
+	pub fn run(&self, directory: &Path, format: StateFormat, canonical: bool) -> Result<()> {
+		let fleet_nix = directory.join(format.file_name());
+		if fleet_nix.exists() && !self.force {
+			bail!(
+				"{} already exists, use --force to overwrite it",
+				fleet_nix.display()
+			);
+		}
+		write_fleet_data(directory, format, &FleetData::new_project(), canonical)?;
+		// Written file should be readable by fleet itself.
+		read_fleet_data(directory, format).context("created fleet state is invalid")?;
+		info!("created {}", fleet_nix.display());
+
+		let flake_nix = directory.join("flake.nix");
+		if flake_nix.exists() && !self.force {
+			warn!(
+				"keeping existing {}, make sure it exposes `fleetConfigurations.default`",
+				flake_nix.display()
+			);
+		} else {
+			fs::write(&flake_nix, FLAKE_TEMPLATE)?;
+			info!("created {}", flake_nix.display());
+		}
+		Ok(())
+	}
+}
+
+#[test]
+fn scaffold() {
+	let dir = tempfile::tempdir().expect("tempdir");
+	let init = Init { force: false };
+	init.run(dir.path(), StateFormat::Nix, false)
+		.expect("initialized");
+
+	let data = fs::read_to_string(dir.path().join("fleet.nix")).unwrap();
+	let value: serde_json::Value = nixlike::parse_str(&data).unwrap();
+	assert_eq!(value["version"], "0.1.0");
+	let data: FleetData = nixlike::parse_str(&data).expect("valid fleet.nix");
+	assert!(data.gc_root_prefix.starts_with("fleet-gc-"));
+	let flake = fs::read_to_string(dir.path().join("flake.nix")).unwrap();
+	assert!(flake.contains("fleetConfigurations.default"));
+
+	// Existing project is not touched.
+	let before = fs::read_to_string(dir.path().join("fleet.nix")).unwrap();
+	assert!(init.run(dir.path(), StateFormat::Nix, false).is_err());
+	assert_eq!(
+		fs::read_to_string(dir.path().join("fleet.nix")).unwrap(),
+		before
+	);
+	fs::write(dir.path().join("flake.nix"), "{ }").unwrap();
+	Init { force: true }
+		.run(dir.path(), StateFormat::Nix, false)
+		.expect("overwritten");
+	let flake = fs::read_to_string(dir.path().join("flake.nix")).unwrap();
+	assert_eq!(flake, FLAKE_TEMPLATE);
+}
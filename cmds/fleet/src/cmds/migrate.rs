@@ -0,0 +1,55 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use fleet_base::{
+	fleetdata::FleetData,
+	migrate::{data_version, migrate},
+	opts::{write_fleet_data, StateFormat},
+};
+use serde_json::Value;
+use tracing::info;
+
+/// Upgrade fleet.nix written by an older fleet version to the current format
+#[derive(Parser)]
+pub struct ConfigMigrate {
+	/// Only show which migrations would be applied
+	#[clap(long)]
+	dry_run: bool,
+}
+
+impl ConfigMigrate {
+	/// Runs without evaluating the project, as its data can't be loaded before the migration.
+	pub fn run(&self, directory: &Path, format: StateFormat, canonical: bool) -> Result<()> {
+		let path = directory.join(format.file_name());
+		let text = fs::read_to_string(&path)?;
+		let mut data: Value = match format {
+			StateFormat::Nix => nixlike::parse_str(&text)?,
+			StateFormat::Json => serde_json::from_str(&text)?,
+		};
+		let version = data_version(&data)?.unwrap_or("unversioned").to_owned();
+		let applied = migrate(&mut data)?;
+		if applied.is_empty() {
+			info!("fleet.nix is already at version {version}");
+			return Ok(());
+		}
+		info!(
+			"migrating fleet.nix from {version} through {}",
+			applied.join(", ")
+		);
+		let data: FleetData = serde_json::from_value(data)?;
+		if self.dry_run {
+			return Ok(());
+		}
+		let backup = directory.join(format!("{}.{version}.bak", format.file_name()));
+		if backup.exists() {
+			bail!(
+				"backup {} already exists, refusing to overwrite it",
+				backup.display()
+			);
+		}
+		fs::copy(&path, &backup)?;
+		info!("original is saved to {}", backup.display());
+		write_fleet_data(directory, format, &data, canonical)
+	}
+}
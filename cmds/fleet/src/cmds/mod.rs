@@ -1,5 +1,10 @@
 pub mod build_systems;
 pub mod complete;
+pub mod diff;
 pub mod info;
+pub mod init;
+pub mod migrate;
+pub mod rollback;
 pub mod secrets;
 pub mod tf;
+pub mod watch;
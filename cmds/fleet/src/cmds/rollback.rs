@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::Parser;
+use fleet_base::{
+	fleetdata::{FleetData, GoodGeneration},
+	host::{Config, ConfigHost},
+	opts::FleetOpts,
+};
+use tracing::{error, field, info, info_span, Instrument};
+
+use super::build_systems::{
+	activation_script, current_generation, generation_link, list_generations, Generation,
+	SYSTEM_PROFILE,
+};
+use crate::{
+	failure::Failure,
+	host_select::{pick_hosts, should_pick, TerminalPicker},
+};
+
+/// Switch selected hosts back to the older system generation
+#[derive(Parser)]
+pub struct Rollback {
+	/// Generation id to switch to, as listed by `nix-env --list-generations`, previous
+	/// generation by default
+	#[clap(long)]
+	to_generation: Option<u32>,
+	/// Switch to the generation activated by the last successful `deploy switch`
+	#[clap(long, conflicts_with = "to_generation")]
+	to_last_good: bool,
+	/// Pick hosts to roll back from the list, enabled by default when running in terminal
+	/// without --only/--skip
+	#[clap(long)]
+	interactive: bool,
+	/// Roll back all selected hosts without asking
+	#[clap(long, conflicts_with = "interactive")]
+	all: bool,
+}
+
+fn last_good_generation(data: &FleetData, host: &str) -> Result<GoodGeneration> {
+	data.hosts
+		.get(host)
+		.and_then(|h| h.last_good_generation.clone())
+		.ok_or_else(|| anyhow!("no successful deployment of {host} is recorded"))
+}
+
+/// Explicit generation should exist, otherwise the one preceding the current is used.
+fn rollback_target(generations: Vec<Generation>, to: Option<u32>) -> Result<Generation> {
+	let current = generations
+		.iter()
+		.find(|g| g.current)
+		.map(|g| g.id)
+		.ok_or_else(|| anyhow!("failed to find current generation"))?;
+	if let Some(to) = to {
+		ensure!(to != current, "generation {to} is already current");
+		return generations.into_iter().find(|g| g.id == to).ok_or_else(|| {
+			anyhow!("generation {to} not found, it might have been garbage collected")
+		});
+	}
+	generations
+		.into_iter()
+		.filter(|g| g.id < current)
+		.max_by_key(|g| g.id)
+		.ok_or_else(|| anyhow!("no generation older than the current {current}"))
+}
+
+impl Rollback {
+	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
+		let mut hosts = opts.filter_skipped(config.list_hosts().await?).await?;
+		if should_pick(opts, self.interactive, self.all) {
+			hosts = pick_hosts(hosts, "Select hosts to roll back", &TerminalPicker).await?;
+		}
+		let mut failed = vec![];
+		for host in hosts {
+			let span = info_span!("rollback", host = field::display(&host.name));
+			if let Err(e) = self.rollback(config, &host).instrument(span).await {
+				error!("failed to roll back {}: {e:#}", host.name);
+				failed.push(host.name);
+			}
+		}
+		if !failed.is_empty() {
+			return Err(anyhow!("{}", failed.join(", ")).context(Failure::PartialHosts));
+		}
+		Ok(())
+	}
+	async fn rollback(&self, config: &Config, host: &ConfigHost) -> Result<()> {
+		let last_good = if self.to_last_good {
+			Some(last_good_generation(&config.data(), &host.name)?)
+		} else {
+			None
+		};
+		let to = last_good.as_ref().map(|g| g.id).or(self.to_generation);
+		let generations = list_generations(host).await?;
+		let target = rollback_target(generations, to)?;
+		if let Some(last_good) = &last_good {
+			let mut cmd = host.cmd("readlink").await?;
+			cmd.arg("-f").arg(generation_link(target.id));
+			let system = cmd.run_string().await?;
+			ensure!(
+				Path::new(system.trim()) == last_good.system,
+				"generation {} points to {}, but {} was deployed, profile might have been recreated",
+				target.id,
+				system.trim(),
+				last_good.system.display(),
+			);
+		}
+		info!("rolling back to {} {}", target.id, target.datetime);
+
+		let mut cmd = host.cmd("nix-env").await?;
+		cmd.comparg("--profile", SYSTEM_PROFILE);
+		match to {
+			Some(id) => cmd.comparg("--switch-generation", id.to_string()),
+			None => cmd.arg("--rollback"),
+		};
+		cmd.sudo()
+			.run()
+			.await
+			.context("failed to switch system profile generation")?;
+		if current_generation(list_generations(host).await?)?.id != target.id {
+			bail!("system profile wasn't switched to generation {}", target.id);
+		}
+
+		// Otherwise watchdog of the interrupted deployment may switch the system once again.
+		if let Err(_e) = host.rm_file("/etc/fleet_rollback_marker", true).await {
+			// Marker only exists during the deployment.
+		}
+
+		info!("executing activation script");
+		let activation_command = host
+			.activation_command()
+			.await
+			.context("failed to get activation command")?;
+		let link = generation_link(target.id);
+		let switch_script = activation_script(Path::new(&link), activation_command.as_deref());
+		let mut cmd = host.cmd(switch_script).await?;
+		cmd.arg("switch");
+		cmd.sudo().run().await.context("failed to activate")?;
+		Ok(())
+	}
+}
+
+#[test]
+fn rollback_targets() {
+	let generations = || {
+		super::build_systems::parse_generations(
+			"  40   2024-04-30 10:00:00\n  41   2024-05-01 10:00:00\n  42   2024-05-02 10:00:00   (current)\n  43   2024-05-03 10:00:00\n",
+		)
+	};
+	assert_eq!(rollback_target(generations(), None).unwrap().id, 41);
+	assert_eq!(rollback_target(generations(), Some(40)).unwrap().id, 40);
+	// Rolling forward is allowed, i.e after the previous rollback.
+	assert_eq!(rollback_target(generations(), Some(43)).unwrap().id, 43);
+	assert!(rollback_target(generations(), Some(42)).is_err());
+	assert!(rollback_target(generations(), Some(39)).is_err());
+	let oldest =
+		super::build_systems::parse_generations("  40   2024-04-30 10:00:00   (current)\n");
+	assert!(rollback_target(oldest, None).is_err());
+}
+
+#[test]
+fn last_good() {
+	let mut data = FleetData::new_project();
+	assert!(last_good_generation(&data, "a").is_err());
+	let good = GoodGeneration {
+		id: 42,
+		system: "/nix/store/aaa-nixos-system".into(),
+	};
+	data.hosts
+		.entry("a".to_owned())
+		.or_default()
+		.last_good_generation = Some(good.clone());
+	let data: FleetData =
+		nixlike::parse_str(&nixlike::serialize(&data).unwrap()).expect("roundtrip");
+	assert_eq!(last_good_generation(&data, "a").unwrap(), good);
+	assert!(last_good_generation(&data, "b").is_err());
+	assert!(
+		Rollback::try_parse_from(["rollback", "--to-last-good", "--to-generation", "1"]).is_err()
+	);
+}
@@ -1,26 +1,36 @@
 use std::{
-	collections::{BTreeMap, BTreeSet, HashSet},
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+	future::Future,
 	io::{self, stdin, stdout, Read, Write},
-	path::PathBuf,
+	num::NonZeroUsize,
+	path::{Path, PathBuf},
+	process::Stdio,
 };
 
 use age::Recipient;
 use anyhow::{anyhow, bail, ensure, Context, Result};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use clap::Parser;
 use fleet_base::{
-	fleetdata::{encrypt_secret_data, FleetSecret, FleetSecretPart, FleetSharedSecret},
-	host::Config,
+	age_header::{recipient_stanzas, ssh_key_tag},
+	fleetdata::{
+		encrypt_secret_data, validate_part_name, FleetData, FleetSecret, FleetSecretPart,
+		FleetSharedSecret, GENERATOR_METADATA_FILES,
+	},
+	host::{Config, ConfigHost},
 	opts::FleetOpts,
 };
 use fleet_shared::SecretData;
+use futures::{stream, stream::FuturesUnordered, StreamExt as _, TryStreamExt as _};
 use nix_eval::{nix_go, nix_go_json, NixBuildBatch, Value};
 use owo_colors::OwoColorize;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tabled::{Table, Tabled};
-use tokio::fs::read;
+use tokio::{fs::read, io::AsyncWriteExt as _};
 use tracing::{error, info, info_span, warn, Instrument};
 
+use crate::failure::Failure;
+
 #[derive(Parser)]
 pub enum Secret {
 	/// Force load host keys for all defined hosts
@@ -94,6 +104,20 @@ pub enum Secret {
 		#[clap(short = 'p', long, default_value = "secret")]
 		part: String,
 	},
+	/// Print public secret part directly from fleet.nix, without evaluating config or
+	/// connecting to any host
+	CatPublic {
+		name: String,
+		/// Read shared secret
+		#[clap(long, conflicts_with = "machine", required_unless_present = "machine")]
+		shared: bool,
+		/// Read secret of this host
+		#[clap(short = 'm', long)]
+		machine: Option<String>,
+		/// Which public secret part to read
+		#[clap(short = 'p', long, default_value = "public")]
+		part: String,
+	},
 	/// Read secret from remote host, requires sudo on said host
 	ReadShared {
 		name: String,
@@ -120,6 +144,20 @@ pub enum Secret {
 		#[clap(long)]
 		prefer_identities: Vec<String>,
 	},
+	/// Replace the owner list of shared secret, reencrypting it for the new owners
+	///
+	/// Unlike `update-shared`, this command never regenerates the secret, regardless of
+	/// `regenerateOnOwnerAdded`/`regenerateOnOwnerRemoved`, the stored data is only reencrypted.
+	SetOwners {
+		name: String,
+		/// Final list of secret owners, tags (`@tag`) are expanded
+		#[clap(required = true)]
+		owners: Vec<String>,
+
+		/// Which host should we use to decrypt
+		#[clap(long)]
+		prefer_identities: Vec<String>,
+	},
 	Regenerate {
 		/// Which host should we use to decrypt, in case if reencryption is required, without
 		/// regeneration
@@ -128,8 +166,136 @@ pub enum Secret {
 		/// Only regenerate shared secrets
 		#[clap(long)]
 		skip_hosts: bool,
+		/// Only process secrets with specified names
+		#[clap(long, number_of_values = 1)]
+		only_secret: Vec<String>,
+		/// Regenerate selected secrets even if their generation data is up to date,
+		/// e.g to rotate possibly compromised secret
+		#[clap(long)]
+		force: bool,
+		/// Regenerate secrets which expire within this duration (`30s`, `15m`, `12h`, `7d`),
+		/// to compensate for the host clock skew, or to rotate secrets ahead of time
+		#[clap(long, default_value = "0s", value_parser = parse_leeway)]
+		expiry_leeway: Duration,
+		/// Number of host secrets regenerated at once, generators of different secrets are
+		/// independent, yet they might ask for input, thus secrets are processed one by one by default
+		#[clap(long, default_value = "1")]
+		parallel: NonZeroUsize,
+	},
+	/// Reencrypt all stored secrets for the current keys of their owners, without regeneration
+	///
+	/// Useful after rotating host keys or changing the encryption scheme. Shared secrets are
+	/// decrypted by one of their owners, host secrets are decrypted by the host itself.
+	RekeyAll {
+		/// Which host should we use to decrypt shared secrets
+		#[clap(long)]
+		prefer_identities: Vec<String>,
+		/// Only list secrets, which would be reencrypted
+		#[clap(long)]
+		dry_run: bool,
+	},
+	/// Transfer host secret to a different host, i.e when the service is migrated
+	///
+	/// Secret is reencrypted for the new owner, creation time and generation data are preserved.
+	Move {
+		name: String,
+		/// Current owner of the secret
+		#[clap(long)]
+		from: String,
+		/// New owner of the secret
+		#[clap(long)]
+		to: String,
+		/// Which host should we use to decrypt, if the secret was encrypted not only for `from`
+		#[clap(long)]
+		prefer_identities: Vec<String>,
+	},
+	/// Check that every encrypted part of stored secrets is decryptable by its owners
+	///
+	/// Shared secrets are decrypted by one of their owners, host secrets are decrypted by the
+	/// host itself.
+	Verify {
+		/// Which host should we use to decrypt shared secrets
+		#[clap(long)]
+		prefer_identities: Vec<String>,
+	},
+	/// Rename stored secret, i.e after it was renamed in the configuration
+	///
+	/// Secret data is moved as-is, without reencryption.
+	Rename {
+		old: String,
+		new: String,
+		/// Rename host secret of this machine, shared secret is renamed otherwise
+		#[clap(long)]
+		machine: Option<String>,
+		/// Replace the existing secret with the new name
+		#[clap(long)]
+		force: bool,
+	},
+	List {
+		/// Only list secrets, which are already expired and need regeneration
+		#[clap(long)]
+		expired_only: bool,
+	},
+	/// Report expired and soon expiring secrets from fleet.nix, for monitoring
+	///
+	/// Exits with a distinct code if any secret is expired, or expires within the threshold,
+	/// see README for exit codes.
+	CheckExpiry {
+		/// Also report secrets which expire within this duration (`30s`, `15m`, `12h`, `7d`)
+		#[clap(long, default_value = "0s", value_parser = parse_leeway)]
+		within: Duration,
+		/// Output found secrets as JSON
+		#[clap(long)]
+		json: bool,
+	},
+	/// Show which hosts are able to decrypt secret parts, by inspecting age headers of
+	/// the stored data
+	Recipients {
+		name: String,
+		/// Inspect secret of this host, instead of shared secret
+		#[clap(short = 'm', long)]
+		machine: Option<String>,
+	},
+	/// Compare decrypted secret part with the file contents, i.e before replacing the secret
+	///
+	/// Text is compared with `diff -u`, binary data is only reported as identical or different.
+	Diff {
+		name: String,
+		/// Compare secret of this host, instead of shared secret
+		#[clap(short = 'm', long)]
+		machine: Option<String>,
+		/// Which secret part to compare
+		#[clap(short = 'p', long, default_value = "secret")]
+		part: String,
+		/// File with the new secret contents
+		#[clap(long)]
+		against: PathBuf,
+		/// Which host should we use to decrypt shared secret
+		#[clap(long)]
+		prefer_identities: Vec<String>,
+	},
+	/// Show stored and expected generation data of the secret, to find out why it is regenerated
+	ShowGenerationData {
+		name: String,
+		/// Inspect secret of this host, instead of shared secret
+		#[clap(short = 'm', long)]
+		machine: Option<String>,
+	},
+	/// Print age recipients of hosts, one per line, so that external tools are able to encrypt
+	/// data for the same hosts as fleet
+	ExportRecipients {
+		/// Hosts, `@tag` selects all hosts with the tag
+		#[clap(
+			short = 'm',
+			long,
+			conflicts_with = "shared",
+			required_unless_present = "shared"
+		)]
+		machines: Vec<String>,
+		/// Use owners of this shared secret
+		#[clap(long)]
+		shared: Option<String>,
 	},
-	List {},
 	Edit {
 		name: String,
 		#[clap(short = 'm', long)]
@@ -144,19 +310,564 @@ pub enum Secret {
 	},
 }
 
+fn parse_leeway(s: &str) -> Result<Duration, String> {
+	let unit = s
+		.find(|c: char| !c.is_ascii_digit())
+		.ok_or("duration unit is missing")?;
+	let (amount, unit) = s.split_at(unit);
+	let amount: i64 = amount
+		.parse()
+		.map_err(|e| format!("invalid duration: {e}"))?;
+	let duration = match unit {
+		"s" => Duration::try_seconds(amount),
+		"m" => Duration::try_minutes(amount),
+		"h" => Duration::try_hours(amount),
+		"d" => Duration::try_days(amount),
+		_ => return Err(format!("unknown duration unit: {unit:?}")),
+	};
+	duration.ok_or_else(|| format!("duration is out of range: {s}"))
+}
+
+/// Skew, which is too small to affect expiration, `date +%s` has second precision, and the
+/// command roundtrip is not accounted precisely.
+const MIN_REPORTED_CLOCK_SKEW: Duration = Duration::minutes(1);
+
+fn is_expired(expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>, leeway: Duration) -> bool {
+	expires_at.is_some_and(|expiration| {
+		// Leeway reaching past the representable dates covers any expiration.
+		expiration
+			.checked_sub_signed(leeway)
+			.map_or(leeway > Duration::zero(), |at| at < now)
+	})
+}
+
+fn expiry_date(at: DateTime<Utc>) -> String {
+	at.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+/// Rough age for tables, i.e `3 months ago`, months are counted as 30 days.
+fn relative_age(at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+	let age = now - at;
+	let (n, unit) = if age < Duration::minutes(1) {
+		return "just now".to_owned();
+	} else if age < Duration::hours(1) {
+		(age.num_minutes(), "minute")
+	} else if age < Duration::days(1) {
+		(age.num_hours(), "hour")
+	} else if age < Duration::days(30) {
+		(age.num_days(), "day")
+	} else if age < Duration::days(365) {
+		(age.num_days() / 30, "month")
+	} else {
+		(age.num_days() / 365, "year")
+	};
+	let plural = if n == 1 { "" } else { "s" };
+	format!("{n} {unit}{plural} ago")
+}
+
 fn secret_needs_regeneration(
 	secret: &FleetSecret,
 	expected_generation_data: &serde_json::Value,
+	force: bool,
+	expiry_leeway: Duration,
 ) -> bool {
+	if force {
+		return true;
+	}
 	let data_is_expected = secret.generation_data == *expected_generation_data;
-	// TODO: Leeway?
-	let expired = secret
-		.expires_at
-		.map(|expiration| expiration < Utc::now())
-		.unwrap_or(false);
+	let expired = is_expired(secret.expires_at, Utc::now(), expiry_leeway);
 	expired || !data_is_expected
 }
 
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ExpiringSecret {
+	name: String,
+	/// None for shared secrets
+	host: Option<String>,
+	expires_at: DateTime<Utc>,
+	expired: bool,
+}
+
+/// Stored secrets, which are expired or expire within the threshold.
+fn expiring_secrets(data: &FleetData, now: DateTime<Utc>, within: Duration) -> Vec<ExpiringSecret> {
+	let shared = data
+		.shared_secrets
+		.iter()
+		.map(|(name, shared)| (name, None, &shared.secret));
+	let hosts = data.host_secrets.iter().flat_map(|(host, secrets)| {
+		secrets
+			.iter()
+			.map(move |(name, secret)| (name, Some(host), secret))
+	});
+	shared
+		.chain(hosts)
+		.filter(|(_, _, secret)| is_expired(secret.expires_at, now, within))
+		.filter_map(|(name, host, secret)| {
+			Some(ExpiringSecret {
+				name: name.clone(),
+				host: host.cloned(),
+				expires_at: secret.expires_at?,
+				expired: is_expired(secret.expires_at, now, Duration::zero()),
+			})
+		})
+		.collect()
+}
+
+fn expiry_failure(secrets: &[ExpiringSecret]) -> Option<Failure> {
+	if secrets.iter().any(|s| s.expired) {
+		Some(Failure::SecretsExpired)
+	} else if !secrets.is_empty() {
+		Some(Failure::SecretsExpiring)
+	} else {
+		None
+	}
+}
+
+/// Expiration is checked against local time, yet impure generators fill it based on the host clock.
+async fn warn_clock_skew(host: &ConfigHost, expiry_leeway: Duration) {
+	match host.clock_skew().await {
+		Ok(skew) if skew.abs() > expiry_leeway.max(MIN_REPORTED_CLOCK_SKEW) => {
+			warn!(
+				"host clock differs from local by {}s, secret expiration checks might be off, consider setting --expiry-leeway",
+				skew.num_seconds()
+			);
+		}
+		Ok(_) => {}
+		Err(e) => warn!("failed to check host clock: {e}"),
+	}
+}
+
+/// Differences between stored and expected generation data, one line per changed value,
+/// prefixed by its JSON pointer.
+fn generation_data_diff(stored: &serde_json::Value, expected: &serde_json::Value) -> Vec<String> {
+	fn walk(
+		path: &mut String,
+		stored: Option<&serde_json::Value>,
+		expected: Option<&serde_json::Value>,
+		out: &mut Vec<String>,
+	) {
+		if let (
+			Some(serde_json::Value::Object(stored)),
+			Some(serde_json::Value::Object(expected)),
+		) = (stored, expected)
+		{
+			let keys = stored
+				.keys()
+				.chain(expected.keys())
+				.collect::<BTreeSet<_>>();
+			for key in keys {
+				let len = path.len();
+				path.push('/');
+				path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+				walk(path, stored.get(key), expected.get(key), out);
+				path.truncate(len);
+			}
+			return;
+		}
+		if stored == expected {
+			return;
+		}
+		let path = if path.is_empty() { "/" } else { path };
+		if let Some(stored) = stored {
+			out.push(format!("- {path}: {stored}"));
+		}
+		if let Some(expected) = expected {
+			out.push(format!("+ {path}: {expected}"));
+		}
+	}
+	let mut out = vec![];
+	walk(&mut String::new(), Some(stored), Some(expected), &mut out);
+	out
+}
+
+/// Host secret generation failures are only logged, other errors stop the regeneration.
+async fn regenerate_host_secret(
+	config: &Config,
+	host: &ConfigHost,
+	name: String,
+	stored: bool,
+	force: bool,
+	expiry_leeway: Duration,
+) -> Result<()> {
+	let secret = host.secret_field(&name).await?;
+	let expected_generation_data = nix_go_json!(secret.expectedGenerationData);
+	if stored {
+		info!("updating secret: {name}");
+		let data = config.host_secret(&host.name, &name)?;
+		if !secret_needs_regeneration(&data, &expected_generation_data, force, expiry_leeway) {
+			return Ok(());
+		}
+		if !can_force_regenerate(&name, &secret, force).await {
+			return Ok(());
+		}
+	} else {
+		info!("generating secret: {name}");
+	}
+	// Every generator has its own output directory, created with mktemp, thus concurrent
+	// generators don't interfere.
+	match generate(
+		config,
+		&name,
+		secret,
+		&[host.name.clone()],
+		expected_generation_data,
+		None,
+	)
+	.await
+	{
+		Ok(generated) => config.insert_secret(&host.name, name, generated),
+		Err(e) => error!("{e:?}"),
+	}
+	Ok(())
+}
+
+/// Run up to `parallel` jobs at once, fleet data is only accessed through [`Config`] locks,
+/// thus jobs can share it.
+async fn regenerate_concurrently<J, F, Fut>(
+	jobs: Vec<J>,
+	parallel: NonZeroUsize,
+	regenerate: F,
+) -> Result<()>
+where
+	F: Fn(J) -> Fut,
+	Fut: Future<Output = Result<()>>,
+{
+	stream::iter(jobs)
+		.map(Ok)
+		.try_for_each_concurrent(parallel.get(), regenerate)
+		.await
+}
+
+fn secret_selected(only_secret: &[String], name: &str) -> bool {
+	only_secret.is_empty() || only_secret.iter().any(|s| s == name)
+}
+
+fn public_part(
+	data: &FleetData,
+	name: &str,
+	shared: bool,
+	machine: Option<&str>,
+	part_name: &str,
+) -> Result<Vec<u8>> {
+	let secret = if shared {
+		let Some(secret) = data.shared_secrets.get(name) else {
+			bail!("no shared secret {name}");
+		};
+		&secret.secret
+	} else {
+		let machine = machine.expect("either shared or machine is set");
+		let Some(secret) = data
+			.host_secrets
+			.get(machine)
+			.and_then(|secrets| secrets.get(name))
+		else {
+			bail!("machine {machine} has no secret {name}");
+		};
+		secret
+	};
+	let Some(part) = secret.parts.get(part_name) else {
+		bail!("no part {part_name} in secret {name}");
+	};
+	ensure!(
+		!part.raw.encrypted,
+		"part {part_name} of secret {name} is encrypted, use `fleet secret read` instead"
+	);
+	Ok(part.raw.data.clone())
+}
+
+/// Pick the owner, which will be used to decrypt the secret
+fn identity_holder<'a>(
+	owners: &'a [String],
+	prefer_identities: &'a [String],
+) -> Option<&'a String> {
+	if !prefer_identities.is_empty() {
+		prefer_identities
+			.iter()
+			.find(|i| owners.iter().any(|s| s == *i))
+	} else {
+		owners.first()
+	}
+}
+
+/// Owners to export recipients for, tags are not yet expanded.
+fn export_owners(
+	data: &FleetData,
+	machines: Vec<String>,
+	shared: Option<&str>,
+) -> Result<Vec<String>> {
+	let Some(shared) = shared else {
+		return Ok(machines);
+	};
+	let secret = data
+		.shared_secrets
+		.get(shared)
+		.ok_or_else(|| anyhow!("no shared secret {shared}"))?;
+	Ok(secret.owners.clone())
+}
+
+/// Recipient file contents, keys are validated to be usable as age recipients.
+fn recipient_lines(keys: impl IntoIterator<Item = (String, String)>) -> Result<String> {
+	let mut lines = BTreeSet::new();
+	for (host, key) in keys {
+		let key = key.trim();
+		key.parse::<age::ssh::Recipient>()
+			.map_err(|e| anyhow!("host {host} key is not a valid recipient: {e:?}"))?;
+		lines.insert(key.to_owned());
+	}
+	let mut out = String::new();
+	for line in lines {
+		out.push_str(&line);
+		out.push('\n');
+	}
+	Ok(out)
+}
+
+/// Reencrypt shared secret for the new owners, using `reencrypt(holder, data, owners)`,
+/// owners are only updated once every part is reencrypted.
+async fn set_shared_owners<F, Fut>(
+	secret: &mut FleetSharedSecret,
+	owners: Vec<String>,
+	prefer_identities: &[String],
+	mut reencrypt: F,
+) -> Result<usize>
+where
+	F: FnMut(String, SecretData, Vec<String>) -> Fut,
+	Fut: Future<Output = Result<SecretData>>,
+{
+	ensure!(!owners.is_empty(), "owner list expanded to no hosts");
+	let Some(holder) = identity_holder(&secret.owners, prefer_identities) else {
+		bail!("no available holder found");
+	};
+	let holder = holder.clone();
+	let parts = rekey_secret(&mut secret.secret, |data| {
+		reencrypt(holder.clone(), data, owners.clone())
+	})
+	.await?;
+	secret.owners = owners;
+	Ok(parts)
+}
+
+/// Hosts of `candidates` (name and key tag), for which every encrypted part of the secret is encrypted.
+fn header_recipients(
+	secret: &FleetSecret,
+	mut candidates: Vec<(String, Option<String>)>,
+) -> Result<Vec<String>> {
+	for (part, data) in &secret.parts {
+		if !data.raw.encrypted {
+			continue;
+		}
+		let stanzas = recipient_stanzas(&data.raw.data)
+			.with_context(|| format!("failed to read part {part} header"))?;
+		candidates.retain(|(_, tag)| tag.is_some() && stanzas.iter().any(|s| s.tag == *tag));
+	}
+	Ok(candidates.into_iter().map(|(host, _)| host).collect())
+}
+
+/// Reencrypt host secret for `to`, using `reencrypt(holder, data, owners)`, holder is picked
+/// from `holders`, which are able to decrypt the secret.
+async fn reencrypt_moved_secret<F, Fut>(
+	secret: &mut FleetSecret,
+	holders: &[String],
+	to: &str,
+	prefer_identities: &[String],
+	mut reencrypt: F,
+) -> Result<usize>
+where
+	F: FnMut(String, SecretData, Vec<String>) -> Fut,
+	Fut: Future<Output = Result<SecretData>>,
+{
+	let Some(holder) = identity_holder(holders, prefer_identities) else {
+		bail!("no available holder found, secret can only be decrypted by {holders:?}");
+	};
+	rekey_secret(secret, |data| {
+		reencrypt(holder.clone(), data, vec![to.to_owned()])
+	})
+	.await
+}
+
+fn transfer_host_secret(
+	data: &mut FleetData,
+	name: &str,
+	from: &str,
+	to: &str,
+	secret: FleetSecret,
+) -> Result<()> {
+	ensure!(
+		!data
+			.host_secrets
+			.get(to)
+			.is_some_and(|secrets| secrets.contains_key(name)),
+		"machine {to} already has secret {name}"
+	);
+	let Some(from_secrets) = data.host_secrets.get_mut(from) else {
+		bail!("no secrets for machine {from}");
+	};
+	ensure!(
+		from_secrets.remove(name).is_some(),
+		"machine {from} has no secret {name}"
+	);
+	if from_secrets.is_empty() {
+		data.host_secrets.remove(from);
+	}
+	data.host_secrets
+		.entry(to.to_owned())
+		.or_default()
+		.insert(name.to_owned(), secret);
+	Ok(())
+}
+
+fn rename_secret<V>(
+	secrets: &mut BTreeMap<String, V>,
+	old: &str,
+	new: &str,
+	force: bool,
+) -> Result<()> {
+	ensure!(old != new, "secret is already named {new}");
+	ensure!(
+		force || !secrets.contains_key(new),
+		"secret {new} already exists, use --force to replace it"
+	);
+	let secret = secrets
+		.remove(old)
+		.ok_or_else(|| anyhow!("secret {old} not found"))?;
+	secrets.insert(new.to_owned(), secret);
+	Ok(())
+}
+
+fn is_text(data: &[u8]) -> bool {
+	!data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Unified diff of the stored data against the file, None if they are identical.
+///
+/// Stored data is passed through stdin, so that decrypted secret is never written to disk.
+async fn diff_secret(stored: &[u8], against: &Path) -> Result<Option<String>> {
+	let new = read(against)
+		.await
+		.with_context(|| format!("failed to read {}", against.display()))?;
+	if stored == new {
+		return Ok(None);
+	}
+	if !is_text(stored) || !is_text(&new) {
+		return Ok(Some(format!(
+			"binary data differs: stored {} bytes, {} has {} bytes\n",
+			stored.len(),
+			against.display(),
+			new.len()
+		)));
+	}
+	let mut diff = tokio::process::Command::new("diff")
+		.args(["-u", "--label", "stored", "--label"])
+		.arg(against)
+		.arg("-")
+		.arg(against)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.spawn()
+		.context("failed to run diff")?;
+	let mut stdin = diff.stdin.take().expect("stdin is piped");
+	let stored = stored.to_vec();
+	let write = tokio::spawn(async move { stdin.write_all(&stored).await });
+	let output = diff.wait_with_output().await?;
+	write.await??;
+	// Exit code 1 means differences were found.
+	ensure!(
+		output.status.code() == Some(1),
+		"diff failed: {}",
+		output.status
+	);
+	Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Try to decrypt every encrypted part of the secret, returns decryption error of every part.
+async fn verify_secret<F, Fut>(
+	secret: &FleetSecret,
+	mut decrypt: F,
+) -> Vec<(String, Option<anyhow::Error>)>
+where
+	F: FnMut(SecretData) -> Fut,
+	Fut: Future<Output = Result<Vec<u8>>>,
+{
+	let mut out = vec![];
+	for (part_name, part) in &secret.parts {
+		if !part.raw.encrypted {
+			continue;
+		}
+		let error = decrypt(part.raw.clone()).await.err();
+		out.push((part_name.clone(), error));
+	}
+	out
+}
+
+/// Reencrypt every encrypted part of the secret, returns number of reencrypted parts.
+///
+/// Secret is left untouched if any of its parts has failed to reencrypt.
+async fn rekey_secret<F, Fut>(secret: &mut FleetSecret, mut reencrypt: F) -> Result<usize>
+where
+	F: FnMut(SecretData) -> Fut,
+	Fut: Future<Output = Result<SecretData>>,
+{
+	let mut parts = secret.parts.clone();
+	let mut count = 0;
+	for (part_name, part) in parts.iter_mut() {
+		if !part.raw.encrypted {
+			continue;
+		}
+		part.raw = reencrypt(part.raw.clone())
+			.await
+			.with_context(|| format!("failed to reencrypt part {part_name}"))?;
+		count += 1;
+	}
+	secret.parts = parts;
+	Ok(count)
+}
+
+/// Keys of the current and expected owners (with tags expanded), `None` if some owner was removed.
+///
+/// Removed owners might be decommissioned already, their keys are not fetched, as the secret
+/// should be reencrypted to revoke their access anyway.
+async fn owner_keys<F, Fut>(
+	current: &BTreeSet<String>,
+	expected: &BTreeSet<String>,
+	mut key: F,
+) -> Result<Option<(BTreeSet<String>, BTreeSet<String>)>>
+where
+	F: FnMut(String) -> Fut,
+	Fut: Future<Output = Result<String>>,
+{
+	if !current.is_subset(expected) {
+		return Ok(None);
+	}
+	let (mut current_keys, mut expected_keys) = (BTreeSet::new(), BTreeSet::new());
+	for owner in expected {
+		let owner_key = key(owner.clone()).await?.trim().to_owned();
+		if current.contains(owner) {
+			current_keys.insert(owner_key.clone());
+		}
+		expected_keys.insert(owner_key);
+	}
+	Ok(Some((current_keys, expected_keys)))
+}
+
+/// Reencrypt secret for the changed owners, unless the owner change is only textual,
+/// e.g a tag is replaced with the same hosts it resolves to.
+async fn reencrypt_for_owners<F, Fut>(
+	secret: &mut FleetSecret,
+	current_keys: &BTreeSet<String>,
+	expected_keys: &BTreeSet<String>,
+	reencrypt: F,
+) -> Result<usize>
+where
+	F: FnMut(SecretData) -> Fut,
+	Fut: Future<Output = Result<SecretData>>,
+{
+	if current_keys == expected_keys {
+		return Ok(0);
+	}
+	rekey_secret(secret, reencrypt).await
+}
+
 #[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip(config, secret, field, prefer_identities, batch))]
 async fn maybe_regenerate_shared_secret(
@@ -168,21 +879,30 @@ async fn maybe_regenerate_shared_secret(
 	expected_generation_data: serde_json::Value,
 	prefer_identities: &[String],
 	batch: Option<NixBuildBatch>,
+	force: bool,
+	expiry_leeway: Duration,
 ) -> Result<FleetSharedSecret> {
 	let original_set = secret.owners.clone();
 
 	let set = original_set.iter().collect::<BTreeSet<_>>();
 	let expected_set = expected_owners.iter().collect::<BTreeSet<_>>();
 
-	let regeneration_required =
-		secret_needs_regeneration(&secret.secret, &expected_generation_data);
+	let regeneration_required = secret_needs_regeneration(
+		&secret.secret,
+		&expected_generation_data,
+		force,
+		expiry_leeway,
+	);
 
 	if set == expected_set && !regeneration_required {
 		info!("no need to update owner list, it is already correct");
 		return Ok(secret);
 	}
 
-	let should_regenerate = if regeneration_required {
+	let should_regenerate = if force {
+		info!("forced regeneration");
+		true
+	} else if regeneration_required {
 		info!("secret has its generation data changed, regeneration is required");
 		true
 	} else if set.difference(&expected_set).next().is_some() {
@@ -209,27 +929,28 @@ async fn maybe_regenerate_shared_secret(
 		Ok(generated)
 	} else {
 		drop(batch);
-		let identity_holder = if !prefer_identities.is_empty() {
-			prefer_identities
-				.iter()
-				.find(|i| original_set.iter().any(|s| s == *i))
-		} else {
-			secret.owners.first()
-		};
-		let Some(identity_holder) = identity_holder else {
+		let Some(identity_holder) = identity_holder(&original_set, prefer_identities) else {
 			bail!("no available holder found");
 		};
 
-		for (part_name, part) in secret.secret.parts.iter_mut() {
-			let _span = info_span!("part reencryption", part_name);
-			if !part.raw.encrypted {
-				continue;
+		let keys = owner_keys(
+			&config.expand_owner_set(original_set.clone()).await?,
+			&config.expand_owner_set(expected_owners.to_vec()).await?,
+			|owner| async move { config.key(&owner).await },
+		)
+		.await?;
+		let host = config.host(identity_holder).await?;
+		let reencrypt = |data| host.reencrypt(data, expected_owners.to_vec());
+		let reencrypted = match keys {
+			Some((current_keys, expected_keys)) => {
+				reencrypt_for_owners(&mut secret.secret, &current_keys, &expected_keys, reencrypt)
+					.await
 			}
-			let host = config.host(identity_holder).await?;
-			let encrypted = host
-				.reencrypt(part.raw.clone(), expected_owners.to_vec())
-				.await?;
-			part.raw = encrypted;
+			None => rekey_secret(&mut secret.secret, reencrypt).await,
+		}
+		.context(Failure::Secret)?;
+		if reencrypted == 0 {
+			info!("owners are resolved to the same keys, reencryption is not needed");
 		}
 
 		secret.owners = expected_owners.to_vec();
@@ -283,15 +1004,14 @@ async fn generate_impure(
 	let generators = nix_go!(mk_secret_generators(Obj { recipients }));
 	let pkgs_and_generators = nix_go!(on_pkgs + generators);
 
-	let call_package = nix_go!(nixpkgs.lib.callPackageWith(pkgs_and_generators));
-
-	let generator = nix_go!(call_package(generator)(Obj {}));
+	let lib = nix_go!(nixpkgs.lib);
+	let generator = call_generator(&lib, &pkgs_and_generators, &generator).await?;
 
 	let generator = generator.build_maybe_batch(batch).await?;
 	let generator = generator
 		.get("out")
 		.ok_or_else(|| anyhow!("missing generateImpure out"))?;
-	let generator = host.remote_derivation(generator).await?;
+	let generator = host.remote_derivation(generator, true).await?;
 
 	let out_parent = host.mktemp_dir().await?;
 	let out = format!("{out_parent}/out");
@@ -317,9 +1037,10 @@ async fn generate_impure(
 
 	let mut parts = BTreeMap::new();
 	for part in host.read_dir(&out).await? {
-		if part == "created_at" || part == "expired_at" || part == "marker" {
+		if GENERATOR_METADATA_FILES.contains(&part.as_str()) {
 			continue;
 		}
+		validate_part_name(&part).context("impure generator produced invalid part")?;
 		let contents: SecretData = host
 			.read_file_text(format!("{out}/{part}"))
 			.await?
@@ -338,6 +1059,35 @@ async fn generate_impure(
 		generation_data: expected_generation_data,
 	})
 }
+async fn ensure_has_generator(name: &str, secret: &Value) -> Result<()> {
+	let generator = nix_go!(secret.generator);
+	ensure!(
+		generator.type_of().await? != "null",
+		"secret {name} has no generator, it was added manually and can't be force-regenerated, use add/add-shared to replace it"
+	);
+	Ok(())
+}
+/// Call generator, which is either a lambda or an attrset with `__functor`, with the arguments
+/// it requests from `pkgs`, the same way `callPackage` would.
+async fn call_generator(lib: &Value, pkgs: &Value, generator: &Value) -> Result<Value> {
+	let requested = nix_go!(lib.functionArgs(generator));
+	let args = nix_go!(lib.intersectAttrs(requested)(pkgs));
+	Ok(generator.call_functor(&args).await?)
+}
+/// Secrets without generator are reported and skipped by forced regeneration,
+/// without failing the regeneration of other secrets.
+async fn can_force_regenerate(name: &str, secret: &Value, force: bool) -> bool {
+	if !force {
+		return true;
+	}
+	match ensure_has_generator(name, secret).await {
+		Ok(()) => true,
+		Err(e) => {
+			error!("{e:?}");
+			false
+		}
+	}
+}
 async fn generate(
 	config: &Config,
 	display_name: &str,
@@ -353,8 +1103,9 @@ async fn generate(
 		if gen_ty == "null" {
 			bail!("secret has no generator defined, can't automatically generate it.");
 		}
-		if gen_ty != "lambda" {
-			bail!("generator should be lambda, got {gen_ty}");
+		let is_functor = gen_ty == "set" && generator.has_field("__functor").await?;
+		if gen_ty != "lambda" && !is_functor {
+			bail!("generator should be lambda or attrset with __functor, got {gen_ty}");
 		}
 	}
 	let nixpkgs = &config.nixpkgs;
@@ -364,8 +1115,6 @@ async fn generate(
 	// passthru we should call generator, but information about where this generator is supposed to build
 	// is located in passthru... Thus evaluating generator on host.
 	//
-	// Maybe it is also possible to do some magic with __functor?
-	//
 	// I don't want to make modules always responsible for additional secret data anyway,
 	// so it should be in derivation, and not in the secret data itself.
 	let generators = nix_go!(default_mk_secret_generators(Obj {
@@ -373,8 +1122,8 @@ async fn generate(
 	}));
 	let pkgs_and_generators = nix_go!(default_pkgs + generators);
 
-	let call_package = nix_go!(nixpkgs.lib.callPackageWith(pkgs_and_generators));
-	let default_generator = nix_go!(call_package(generator)(Obj {}));
+	let lib = nix_go!(nixpkgs.lib);
+	let default_generator = call_generator(&lib, &pkgs_and_generators, &generator).await?;
 
 	let kind: GeneratorKind = nix_go_json!(default_generator.generatorKind);
 
@@ -511,14 +1260,64 @@ fn parse_machines(
 	Ok(target_machines)
 }
 impl Secret {
+	/// Handles [`Secret::CatPublic`], which only needs fleet data.
+	pub fn cat_public(&self, data: &FleetData) -> Result<()> {
+		let Secret::CatPublic {
+			name,
+			shared,
+			machine,
+			part,
+		} = self
+		else {
+			unreachable!("only CatPublic can be ran without config");
+		};
+		let data = public_part(data, name, *shared, machine.as_deref(), part)?;
+		stdout().write_all(&data)?;
+		Ok(())
+	}
+	/// Handles [`Secret::CheckExpiry`], which only needs fleet data.
+	pub fn check_expiry(&self, data: &FleetData) -> Result<()> {
+		let Secret::CheckExpiry { within, json } = self else {
+			unreachable!("only CheckExpiry can be ran without config");
+		};
+		let secrets = expiring_secrets(data, Utc::now(), *within);
+		if *json {
+			println!("{}", serde_json::to_string_pretty(&secrets)?);
+		} else {
+			for secret in &secrets {
+				let name = match &secret.host {
+					Some(host) => format!("{host}/{}", secret.name),
+					None => secret.name.clone(),
+				};
+				let state = if secret.expired { "expired" } else { "expires" };
+				println!("{name}: {state} at {}", secret.expires_at);
+			}
+		}
+		match expiry_failure(&secrets) {
+			Some(failure) => {
+				Err(anyhow!("{} secrets need regeneration", secrets.len()).context(failure))
+			}
+			None => Ok(()),
+		}
+	}
 	pub async fn run(self, config: &Config, opts: &FleetOpts) -> Result<()> {
 		match self {
 			Secret::ForceKeys => {
-				for host in config.list_hosts().await? {
-					if opts.should_skip(&host).await? {
-						continue;
+				let hosts = opts.filter_skipped(config.list_hosts().await?).await?;
+				let mut keys = hosts
+					.iter()
+					.map(|host| async move { (&host.name, config.key(&host.name).await) })
+					.collect::<FuturesUnordered<_>>();
+				let mut failed = vec![];
+				while let Some((name, key)) = keys.next().await {
+					if let Err(e) = key {
+						error!("failed to load key for {name}: {e:#}");
+						failed.push(name.clone());
 					}
-					config.key(&host.name).await?;
+				}
+				if !failed.is_empty() {
+					failed.sort();
+					return Err(anyhow!("{}", failed.join(", ")).context(Failure::PartialHosts));
 				}
 			}
 			Secret::AddShared {
@@ -533,6 +1332,8 @@ impl Secret {
 				part: part_name,
 			} => {
 				// TODO: Forbid updating secrets with set expectedOwners (= not user-managed).
+				validate_part_name(&part_name)?;
+				validate_part_name(&public_name)?;
 
 				let exists = config.has_shared(&name);
 				if exists && !force && !re_add {
@@ -591,6 +1392,8 @@ impl Secret {
 				public_file,
 				part: part_name,
 			} => {
+				validate_part_name(&part_name)?;
+				validate_part_name(&public_name)?;
 				if config.has_secret(&machine, &name) && !replace && !merge {
 					bail!("secret already defined.\nUse --replace to override, or --merge to add new parts to existing secret");
 				}
@@ -645,13 +1448,17 @@ impl Secret {
 				};
 				let data = if secret.raw.encrypted {
 					let host = config.host(&machine).await?;
-					host.decrypt(secret.raw.clone()).await?
+					host.decrypt(secret.raw.clone())
+						.await
+						.context(Failure::Secret)?
 				} else {
 					secret.raw.data.clone()
 				};
 
 				stdout().write_all(&data)?;
 			}
+			s @ Secret::CatPublic { .. } => s.cat_public(&config.data())?,
+			s @ Secret::CheckExpiry { .. } => s.check_expiry(&config.data())?,
 			Secret::ReadShared {
 				name,
 				part: part_name,
@@ -662,18 +1469,14 @@ impl Secret {
 					bail!("no part {part_name} in secret {name}");
 				};
 				let data = if part.raw.encrypted {
-					let identity_holder = if !prefer_identities.is_empty() {
-						prefer_identities
-							.iter()
-							.find(|i| secret.owners.iter().any(|s| s == *i))
-					} else {
-						secret.owners.first()
-					};
-					let Some(identity_holder) = identity_holder else {
+					let Some(identity_holder) = identity_holder(&secret.owners, &prefer_identities)
+					else {
 						bail!("no available holder found");
 					};
 					let host = config.host(identity_holder).await?;
-					host.decrypt(part.raw.clone()).await?
+					host.decrypt(part.raw.clone())
+						.await
+						.context(Failure::Secret)?
 				} else {
 					part.raw.data.clone()
 				};
@@ -720,13 +1523,43 @@ impl Secret {
 					expected_generation_data,
 					&prefer_identities,
 					None,
+					false,
+					Duration::zero(),
 				)
 				.await?;
 				config.replace_shared(name, updated);
 			}
+			Secret::SetOwners {
+				name,
+				owners,
+				prefer_identities,
+			} => {
+				let mut secret = config.shared_secret(&name)?;
+				let owners = config
+					.expand_owner_set(owners)
+					.await?
+					.into_iter()
+					.collect::<Vec<_>>();
+				set_shared_owners(
+					&mut secret,
+					owners,
+					&prefer_identities,
+					|holder, data, owners| async move {
+						config.host(&holder).await?.reencrypt(data, owners).await
+					},
+				)
+				.await
+				.context(Failure::Secret)?;
+				info!("secret is now encrypted for {:?}", secret.owners);
+				config.replace_shared(name, secret);
+			}
 			Secret::Regenerate {
 				prefer_identities,
 				skip_hosts,
+				only_secret,
+				force,
+				expiry_leeway,
+				parallel,
 			} => {
 				info!("checking for secrets to regenerate");
 				let stored_shared_set = config.list_shared().into_iter().collect::<HashSet<_>>();
@@ -740,6 +1573,9 @@ impl Secret {
 						.into_iter()
 						.collect::<HashSet<_>>();
 					for missing in expected_shared_set.difference(&stored_shared_set) {
+						if !secret_selected(&only_secret, missing) {
+							continue;
+						}
 						let config_field = &config.config_field;
 						let secret = nix_go!(config_field.sharedSecrets[{ missing }]);
 						let expected_generation_data: serde_json::Value =
@@ -765,16 +1601,17 @@ impl Secret {
 					}
 				}
 				if !skip_hosts {
-					let hosts_batch = None;
-					for host in config.list_hosts().await? {
-						if opts.should_skip(&host).await? {
+					let hosts = config.list_hosts().await?;
+					let mut jobs = vec![];
+					for host in &hosts {
+						if opts.should_skip(host).await? {
 							continue;
 						}
 
-						let _span = info_span!("host", host = host.name).entered();
+						let span = info_span!("host", host = host.name);
 						let expected_set = host
 							.list_configured_secrets()
-							.in_current_span()
+							.instrument(span.clone())
 							.await?
 							.into_iter()
 							.collect::<HashSet<_>>();
@@ -782,61 +1619,39 @@ impl Secret {
 							.list_secrets(&host.name)
 							.into_iter()
 							.collect::<HashSet<_>>();
+						let has_expiring = stored_set.iter().any(|name| {
+							config
+								.host_secret(&host.name, name)
+								.is_ok_and(|s| s.expires_at.is_some())
+						});
+						if has_expiring {
+							warn_clock_skew(host, expiry_leeway)
+								.instrument(span.clone())
+								.await;
+						}
 						for missing in expected_set.difference(&stored_set) {
-							info!("generating secret: {missing}");
-							let secret = host.secret_field(missing).in_current_span().await?;
-							let expected_generation_data =
-								nix_go_json!(secret.expectedGenerationData);
-							let generated = match generate(
-								config,
-								missing,
-								secret,
-								&[host.name.clone()],
-								expected_generation_data,
-								hosts_batch.clone(),
-							)
-							.in_current_span()
-							.await
-							{
-								Ok(v) => v,
-								Err(e) => {
-									error!("{e:?}");
-									continue;
-								}
-							};
-							config.insert_secret(&host.name, missing.to_string(), generated)
+							if secret_selected(&only_secret, missing) {
+								jobs.push((host, missing.clone(), false));
+							}
 						}
 						for name in stored_set {
-							info!("updating secret: {name}");
-							let data = config.host_secret(&host.name, &name)?;
-							let secret = host.secret_field(&name).in_current_span().await?;
-							let expected_generation_data =
-								nix_go_json!(secret.expectedGenerationData);
-							if secret_needs_regeneration(&data, &expected_generation_data) {
-								let generated = match generate(
-									config,
-									&name,
-									secret,
-									&[host.name.clone()],
-									expected_generation_data,
-									hosts_batch.clone(),
-								)
-								.in_current_span()
-								.await
-								{
-									Ok(v) => v,
-									Err(e) => {
-										error!("{e:?}");
-										continue;
-									}
-								};
-								config.insert_secret(&host.name, name.to_string(), generated)
+							if secret_selected(&only_secret, &name) {
+								jobs.push((host, name, true));
 							}
 						}
 					}
+					regenerate_concurrently(jobs, parallel, |(host, name, stored)| {
+						let span = info_span!("host", host = host.name);
+						regenerate_host_secret(config, host, name, stored, force, expiry_leeway)
+							.instrument(span)
+					})
+					.await?;
 				}
 				let mut to_remove = Vec::new();
 				for name in &stored_shared_set {
+					if !secret_selected(&only_secret, name) {
+						continue;
+					}
 					info!("updating secret: {name}");
 					let data = config.shared_secret(name)?;
 					let config_field = &config.config_field;
@@ -849,6 +1664,9 @@ impl Secret {
 					}
 
 					let secret = nix_go!(config_field.sharedSecrets[{ name }]);
+					if !can_force_regenerate(name, &secret, force).await {
+						continue;
+					}
 					let expected_generation_data = nix_go_json!(secret.expectedGenerationData);
 					config.replace_shared(
 						name.to_owned(),
@@ -861,6 +1679,8 @@ impl Secret {
 							expected_generation_data,
 							&prefer_identities,
 							None,
+							force,
+							expiry_leeway,
 						)
 						.await?,
 					);
@@ -869,7 +1689,211 @@ impl Secret {
 					config.remove_shared(&k);
 				}
 			}
-			Secret::List {} => {
+			Secret::RekeyAll {
+				prefer_identities,
+				dry_run,
+			} => {
+				let mut secrets = 0;
+				let mut parts = 0;
+				let mut failed = 0;
+				for name in config.list_shared() {
+					let _span = info_span!("shared", name).entered();
+					let mut shared = config.shared_secret(&name)?;
+					let owners = shared.owners.clone();
+					let Some(identity_holder) = identity_holder(&owners, &prefer_identities) else {
+						error!("no available holder found");
+						failed += 1;
+						continue;
+					};
+					if dry_run {
+						info!("would reencrypt for {owners:?} using {identity_holder}");
+						secrets += 1;
+						continue;
+					}
+					let host = config.host(identity_holder).await?;
+					match rekey_secret(&mut shared.secret, |data| {
+						host.reencrypt(data, owners.clone())
+					})
+					.in_current_span()
+					.await
+					{
+						Ok(count) => {
+							secrets += 1;
+							parts += count;
+							config.replace_shared(name, shared);
+						}
+						Err(e) => {
+							error!("{e:?}");
+							failed += 1;
+						}
+					}
+				}
+				for host in config.list_hosts().await? {
+					if opts.should_skip(&host).await? {
+						continue;
+					}
+					for name in config.list_secrets(&host.name) {
+						let _span = info_span!("host", host = host.name, name).entered();
+						if dry_run {
+							info!("would reencrypt");
+							secrets += 1;
+							continue;
+						}
+						let mut secret = config.host_secret(&host.name, &name)?;
+						match rekey_secret(&mut secret, |data| {
+							host.reencrypt(data, vec![host.name.clone()])
+						})
+						.in_current_span()
+						.await
+						{
+							Ok(count) => {
+								secrets += 1;
+								parts += count;
+								config.insert_secret(&host.name, name, secret);
+							}
+							Err(e) => {
+								error!("{e:?}");
+								failed += 1;
+							}
+						}
+					}
+				}
+				if dry_run {
+					info!("{secrets} secrets would be reencrypted");
+				} else {
+					info!("reencrypted {parts} parts of {secrets} secrets");
+				}
+				if failed != 0 {
+					return Err(
+						anyhow!("failed to reencrypt {failed} secrets").context(Failure::Secret)
+					);
+				}
+			}
+			Secret::Verify { prefer_identities } => {
+				#[derive(Tabled)]
+				struct PartDisplay {
+					#[tabled(rename = "Secret")]
+					secret: String,
+					#[tabled(rename = "Part")]
+					part: String,
+					#[tabled(rename = "Decrypted by")]
+					holder: String,
+					#[tabled(rename = "Status")]
+					status: String,
+				}
+				let mut table = vec![];
+				let mut failed = 0;
+				let mut push = |secret: &str, holder: &str, part, error: Option<anyhow::Error>| {
+					let status = match error {
+						None => "ok".green().to_string(),
+						Some(e) => {
+							error!("failed to decrypt {secret} part {part}: {e:#}");
+							failed += 1;
+							"failed".red().to_string()
+						}
+					};
+					table.push(PartDisplay {
+						secret: secret.to_owned(),
+						part,
+						holder: holder.to_owned(),
+						status,
+					});
+				};
+				for name in config.list_shared() {
+					let _span = info_span!("shared", name).entered();
+					let shared = config.shared_secret(&name)?;
+					let Some(holder) = identity_holder(&shared.owners, &prefer_identities) else {
+						push(
+							&name,
+							"-",
+							"*".to_owned(),
+							Some(anyhow!("no available holder found")),
+						);
+						continue;
+					};
+					let host = config.host(holder).await?;
+					let parts = verify_secret(&shared.secret, |data| host.decrypt(data))
+						.in_current_span()
+						.await;
+					for (part, error) in parts {
+						push(&name, holder, part, error);
+					}
+				}
+				for host in opts.filter_skipped(config.list_hosts().await?).await? {
+					for name in config.list_secrets(&host.name) {
+						let _span = info_span!("host", host = host.name, name).entered();
+						let secret = config.host_secret(&host.name, &name)?;
+						let parts = verify_secret(&secret, |data| host.decrypt(data))
+							.in_current_span()
+							.await;
+						for (part, error) in parts {
+							push(&format!("{}/{name}", host.name), &host.name, part, error);
+						}
+					}
+				}
+				info!("verified\n{}", Table::new(table).to_string());
+				if failed != 0 {
+					return Err(
+						anyhow!("failed to decrypt {failed} secret parts").context(Failure::Secret)
+					);
+				}
+			}
+			Secret::Rename {
+				old,
+				new,
+				machine,
+				force,
+			} => {
+				let mut data = config.data_mut();
+				if let Some(machine) = &machine {
+					let Some(secrets) = data.host_secrets.get_mut(machine) else {
+						bail!("no secrets for machine {machine}");
+					};
+					rename_secret(secrets, &old, &new, force)?;
+				} else {
+					rename_secret(&mut data.shared_secrets, &old, &new, force)?;
+				}
+				info!("renamed secret {old} to {new}");
+			}
+			Secret::Move {
+				name,
+				from,
+				to,
+				prefer_identities,
+			} => {
+				ensure!(from != to, "secret is already owned by {to}");
+				let hosts = config
+					.list_hosts()
+					.await?
+					.into_iter()
+					.map(|h| h.name)
+					.collect::<BTreeSet<_>>();
+				for host in [&from, &to] {
+					ensure!(hosts.contains(host), "unknown host {host}");
+				}
+				let mut secret = config.host_secret(&from, &name)?;
+				// Secret might be encrypted not only for its owner, header tells for whom.
+				let mut candidates = vec![];
+				for host in prefer_identities.iter().filter(|h| **h != from) {
+					candidates.push((host.clone(), ssh_key_tag(&config.key(host).await?)));
+				}
+				let mut holders = vec![from.clone()];
+				holders.extend(header_recipients(&secret, candidates)?);
+				let parts = reencrypt_moved_secret(
+					&mut secret,
+					&holders,
+					&to,
+					&prefer_identities,
+					|holder, data, owners| async move {
+						config.host(&holder).await?.reencrypt(data, owners).await
+					},
+				)
+				.await
+				.context(Failure::Secret)?;
+				transfer_host_secret(&mut config.data_mut(), &name, &from, &to, secret)?;
+				info!("moved secret {name} from {from} to {to}, reencrypted {parts} parts");
+			}
+			Secret::List { expired_only } => {
 				let _span = info_span!("loading secrets").entered();
 				let configured = config.list_configured_shared().await?;
 				#[derive(Tabled)]
@@ -878,12 +1902,25 @@ impl Secret {
 					name: String,
 					#[tabled(rename = "Owners")]
 					owners: String,
+					#[tabled(rename = "Created")]
+					created: String,
+					#[tabled(rename = "Expires")]
+					expires: String,
 				}
+				let config_field = &config.config_field;
+				let mut expected_owners: HashMap<String, Vec<String>> =
+					nix_go!(config_field.sharedSecrets)
+						.select_all_json(&configured, &["expectedOwners"])
+						.await?;
 				let mut table = vec![];
+				let now = Utc::now();
 				for name in configured.iter().cloned() {
-					let config = config.clone();
-					let expected_owners = config.shared_secret_expected_owners(&name).await?;
+					let expected_owners = expected_owners.remove(&name).unwrap_or_default();
 					let data = config.shared_secret(&name)?;
+					let expires_at = data.secret.expires_at;
+					if expired_only && !is_expired(expires_at, now, Duration::zero()) {
+						continue;
+					}
 					let owners = data
 						.owners
 						.iter()
@@ -895,13 +1932,163 @@ impl Secret {
 							}
 						})
 						.collect::<Vec<_>>();
+					let expires = match expires_at {
+						Some(at) if is_expired(expires_at, now, Duration::zero()) => {
+							expiry_date(at).red().to_string()
+						}
+						Some(at) => expiry_date(at),
+						None => "never".to_owned(),
+					};
 					table.push(SecretDisplay {
 						owners: owners.join(", "),
 						name,
+						created: relative_age(data.secret.created_at, now),
+						expires,
 					})
 				}
 				info!("loaded\n{}", Table::new(table).to_string())
 			}
+			Secret::Diff {
+				name,
+				machine,
+				part: part_name,
+				against,
+				prefer_identities,
+			} => {
+				let (owners, secret) = if let Some(machine) = machine {
+					(vec![machine.clone()], config.host_secret(&machine, &name)?)
+				} else {
+					let shared = config.shared_secret(&name)?;
+					(shared.owners, shared.secret)
+				};
+				let Some(part) = secret.parts.get(&part_name) else {
+					bail!("no part {part_name} in secret {name}");
+				};
+				let stored = if part.raw.encrypted {
+					let Some(identity_holder) = identity_holder(&owners, &prefer_identities) else {
+						bail!("no available holder found");
+					};
+					let host = config.host(identity_holder).await?;
+					host.decrypt(part.raw.clone())
+						.await
+						.context(Failure::Secret)?
+				} else {
+					part.raw.data.clone()
+				};
+				match diff_secret(&stored, &against).await? {
+					None => println!("identical"),
+					Some(diff) => print!("{diff}"),
+				}
+			}
+			Secret::Recipients { name, machine } => {
+				let (owners, secret) = if let Some(machine) = machine {
+					let secret = config.host_secret(&machine, &name)?;
+					(BTreeSet::from([machine]), secret)
+				} else {
+					let shared = config.shared_secret(&name)?;
+					(config.expand_owner_set(shared.owners).await?, shared.secret)
+				};
+				let mut owner_tags = vec![];
+				for owner in owners {
+					let tag = ssh_key_tag(&config.key(&owner).await?);
+					owner_tags.push((owner, tag));
+				}
+				#[derive(Tabled)]
+				struct RecipientsDisplay {
+					#[tabled(rename = "Part")]
+					part: String,
+					#[tabled(rename = "Recipients")]
+					recipients: String,
+				}
+				let mut table = vec![];
+				let mut consistent = true;
+				for (part, data) in &secret.parts {
+					if !data.raw.encrypted {
+						continue;
+					}
+					let stanzas = recipient_stanzas(&data.raw.data)
+						.with_context(|| format!("failed to read part {part} header"))?;
+					let mut recipients = vec![];
+					// Random stanzas are added by age to prevent ossification.
+					for stanza in stanzas.iter().filter(|s| !s.kind.ends_with("-grease")) {
+						let owner = owner_tags
+							.iter()
+							.find(|(_, tag)| tag.is_some() && *tag == stanza.tag);
+						if let Some((owner, _)) = owner {
+							recipients.push(owner.green().to_string());
+						} else {
+							consistent = false;
+							let unknown = match &stanza.tag {
+								Some(tag) => format!("{} {tag}", stanza.kind),
+								None => stanza.kind.clone(),
+							};
+							recipients.push(unknown.red().to_string());
+						}
+					}
+					for (owner, tag) in &owner_tags {
+						if !stanzas.iter().any(|s| s.tag.is_some() && s.tag == *tag) {
+							consistent = false;
+							warn!("part {part} is not encrypted for owner {owner}");
+						}
+					}
+					table.push(RecipientsDisplay {
+						part: part.clone(),
+						recipients: recipients.join(", "),
+					});
+				}
+				info!("loaded\n{}", Table::new(table).to_string());
+				if !consistent {
+					warn!("secret recipients don't match its owners, it should be reencrypted");
+				}
+			}
+			Secret::ShowGenerationData { name, machine } => {
+				let (secret, field) = if let Some(machine) = &machine {
+					let host = config.host(machine).await?;
+					(
+						config.host_secret(machine, &name)?,
+						host.secret_field(&name).await?,
+					)
+				} else {
+					let config_field = &config.config_field;
+					(
+						config.shared_secret(&name)?.secret,
+						nix_go!(config_field.sharedSecrets[{ name }]),
+					)
+				};
+				let expected: serde_json::Value = nix_go_json!(field.expectedGenerationData);
+				println!(
+					"stored: {}",
+					serde_json::to_string_pretty(&secret.generation_data)?
+				);
+				println!("expected: {}", serde_json::to_string_pretty(&expected)?);
+				let diff = generation_data_diff(&secret.generation_data, &expected);
+				if diff.is_empty() {
+					info!("generation data is up to date");
+				} else {
+					warn!("generation data differs, secret will be regenerated:");
+					for line in diff {
+						if line.starts_with('-') {
+							println!("{}", line.red());
+						} else {
+							println!("{}", line.green());
+						}
+					}
+				}
+				if is_expired(secret.expires_at, Utc::now(), Duration::zero()) {
+					warn!(
+						"secret is expired, it will be regenerated regardless of generation data"
+					);
+				}
+			}
+			Secret::ExportRecipients { machines, shared } => {
+				let owners = export_owners(&config.data(), machines, shared.as_deref())?;
+				let mut keys = vec![];
+				for owner in config.expand_owner_set(owners).await? {
+					let key = config.key(&owner).await?;
+					keys.push((owner, key));
+				}
+				print!("{}", recipient_lines(keys)?);
+			}
 			Secret::Edit {
 				name,
 				machine,
@@ -911,7 +2098,10 @@ impl Secret {
 				let secret = config.host_secret(&machine, &name)?;
 				if let Some(data) = secret.parts.get(&part) {
 					let host = config.host(&machine).await?;
-					let secret = host.decrypt(data.raw.clone()).await?;
+					let secret = host
+						.decrypt(data.raw.clone())
+						.await
+						.context(Failure::Secret)?;
 					String::from_utf8(secret).context("secret is not utf8")?
 				} else if add {
 					String::new()
@@ -924,6 +2114,747 @@ impl Secret {
 	}
 }
 
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeSet;
+
+	use chrono::{Duration, Utc};
+	use fleet_base::fleetdata::{FleetData, FleetSecret, FleetSharedSecret};
+	use fleet_shared::SecretData;
+	use nix_eval::{nix_go, NixSessionPool, PoolOptions, Value};
+	use serde_json::json;
+
+	use super::{
+		call_generator, can_force_regenerate, diff_secret, expiring_secrets, expiry_failure,
+		export_owners, generation_data_diff, header_recipients, identity_holder, is_expired,
+		owner_keys, parse_leeway, public_part, recipient_lines, reencrypt_for_owners,
+		reencrypt_moved_secret, regenerate_concurrently, rekey_secret, relative_age, rename_secret,
+		secret_needs_regeneration, secret_selected, set_shared_owners, transfer_host_secret,
+		verify_secret,
+	};
+
+	/// Attribute of the flake with given `outputs` expression, tests using it need nix.
+	async fn flake_value(outputs: &str, name: &str) -> anyhow::Result<Value> {
+		nix_eval::init_tokio();
+		let dir = tempfile::tempdir()?.into_path();
+		std::fs::write(
+			dir.join("flake.nix"),
+			format!("{{ outputs = _: {outputs}; }}"),
+		)?;
+		let pool = NixSessionPool::new(
+			format!("path:{}", dir.display()).into(),
+			vec![
+				"--extra-experimental-features".into(),
+				"nix-command flakes".into(),
+			],
+			format!("{}-linux", std::env::consts::ARCH),
+			PoolOptions::default(),
+		)
+		.await?;
+		Ok(Value::binding(pool.get().await?, name).await?)
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	#[ignore = "needs nix"]
+	async fn forced_regeneration_without_generator() -> anyhow::Result<()> {
+		let secrets = flake_value(
+			"{ secrets = { manual = { generator = null; }; generated = { generator = _: null; }; }; }",
+			"secrets",
+		)
+		.await?;
+		let manual = nix_go!(secrets.manual);
+		let generated = nix_go!(secrets.generated);
+		// Manually added secret is skipped, instead of aborting the regeneration...
+		assert!(!can_force_regenerate("manual", &manual, true).await);
+		// ...and is still updated, when regeneration is not forced.
+		assert!(can_force_regenerate("manual", &manual, false).await);
+		assert!(can_force_regenerate("generated", &generated, true).await);
+		Ok(())
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	#[ignore = "needs nix"]
+	async fn called_generators() -> anyhow::Result<()> {
+		let test = flake_value(
+			r#"{ test = {
+				lib = rec {
+					functionArgs = f: if f ? __functor
+						then f.__functionArgs or (functionArgs (f.__functor f))
+						else builtins.functionArgs f;
+					intersectAttrs = builtins.intersectAttrs;
+				};
+				pkgs = { a = 1; b = 2; };
+				plain = { a, ... }: a;
+				functor = { offset = 10; __functor = self: { b }: b + self.offset; };
+			}; }"#,
+			"test",
+		)
+		.await?;
+		let (lib, pkgs) = (nix_go!(test.lib), nix_go!(test.pkgs));
+		for (generator, expected) in [(nix_go!(test.plain), 1), (nix_go!(test.functor), 12)] {
+			let called = call_generator(&lib, &pkgs, &generator).await?;
+			assert_eq!(called.as_json::<u32>().await?, expected);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn identity_holder_selection() {
+		let owners = vec!["a".to_owned(), "b".to_owned()];
+		assert_eq!(identity_holder(&owners, &[]).map(String::as_str), Some("a"));
+		assert_eq!(
+			identity_holder(&owners, &["c".to_owned(), "b".to_owned()]).map(String::as_str),
+			Some("b")
+		);
+		assert_eq!(identity_holder(&owners, &["c".to_owned()]), None);
+	}
+
+	#[test]
+	fn expiry_leeway() {
+		let now = Utc::now();
+		let expires_at = Some(now + Duration::hours(1));
+		assert!(!is_expired(None, now, Duration::days(1)));
+		assert!(!is_expired(expires_at, now, Duration::zero()));
+		assert!(!is_expired(expires_at, now, Duration::minutes(59)));
+		assert!(is_expired(expires_at, now, Duration::minutes(61)));
+		assert!(is_expired(
+			Some(now - Duration::seconds(1)),
+			now,
+			Duration::zero()
+		));
+
+		assert_eq!(parse_leeway("0s"), Ok(Duration::zero()));
+		assert_eq!(parse_leeway("15m"), Ok(Duration::minutes(15)));
+		assert_eq!(parse_leeway("7d"), Ok(Duration::days(7)));
+		assert!(parse_leeway("7").is_err());
+		assert!(parse_leeway("7w").is_err());
+		assert!(parse_leeway("m").is_err());
+		assert!(parse_leeway("1000000000000000d").is_err());
+		let far = parse_leeway("100000000d").unwrap();
+		assert!(is_expired(Some(now), now, far));
+		assert!(!is_expired(Some(now), now, -far));
+	}
+
+	#[test]
+	fn expiry_check() {
+		use crate::failure::Failure;
+
+		let now = Utc::now();
+		let expires = |offset: Option<Duration>| FleetSecret {
+			created_at: now,
+			expires_at: offset.map(|offset| now + offset),
+			parts: Default::default(),
+			generation_data: json!(null),
+		};
+		let mut data: FleetData = nixlike::parse_str(r#"{ version = "0.1.0"; }"#).unwrap();
+		data.shared_secrets.insert(
+			"old".to_owned(),
+			FleetSharedSecret {
+				owners: vec!["a".to_owned()],
+				secret: expires(Some(Duration::seconds(-1))),
+			},
+		);
+		let host_a = data.host_secrets.entry("a".to_owned()).or_default();
+		host_a.insert("soon".to_owned(), expires(Some(Duration::hours(12))));
+		host_a.insert("later".to_owned(), expires(Some(Duration::days(3))));
+		data.host_secrets
+			.entry("b".to_owned())
+			.or_default()
+			.insert("forever".to_owned(), expires(None));
+
+		let expired = expiring_secrets(&data, now, Duration::zero());
+		assert_eq!(expired.len(), 1);
+		assert!(expired[0].expired && expired[0].host.is_none());
+		assert_eq!(expiry_failure(&expired), Some(Failure::SecretsExpired));
+
+		// Boundary is exclusive, the same way as for regeneration.
+		let soon = expiring_secrets(&data, now, Duration::hours(12));
+		assert_eq!(soon.len(), 1);
+		let soon = expiring_secrets(&data, now, Duration::hours(12) + Duration::seconds(1));
+		assert_eq!(soon.len(), 2);
+		assert_eq!(soon[1].host.as_deref(), Some("a"));
+		assert!(!soon[1].expired);
+		assert_eq!(expiry_failure(&soon[1..]), Some(Failure::SecretsExpiring));
+		assert_eq!(expiring_secrets(&data, now, Duration::days(7)).len(), 3);
+
+		let future = now - Duration::days(1);
+		assert!(expiring_secrets(&data, future, Duration::zero()).is_empty());
+		assert_eq!(expiry_failure(&[]), None);
+	}
+
+	#[test]
+	fn forced_regeneration() {
+		let mut secret = FleetSecret {
+			created_at: Utc::now(),
+			expires_at: None,
+			parts: Default::default(),
+			generation_data: json!({"size": 32}),
+		};
+		let expected = json!({"size": 32});
+		let no_leeway = Duration::zero();
+		assert!(!secret_needs_regeneration(
+			&secret, &expected, false, no_leeway
+		));
+		assert!(secret_needs_regeneration(
+			&secret, &expected, true, no_leeway
+		));
+		assert!(secret_needs_regeneration(
+			&secret,
+			&json!({"size": 64}),
+			false,
+			no_leeway
+		));
+
+		secret.expires_at = Some(Utc::now() - Duration::hours(1));
+		assert!(secret_needs_regeneration(
+			&secret, &expected, false, no_leeway
+		));
+
+		assert!(secret_selected(&[], "a"));
+		assert!(secret_selected(&["a".to_owned()], "a"));
+		assert!(!secret_selected(&["a".to_owned()], "b"));
+	}
+
+	#[tokio::test]
+	async fn rekeyed_content() -> anyhow::Result<()> {
+		use std::{io::Read, iter};
+
+		use age::{x25519::Identity, Decryptor, Recipient};
+		use fleet_base::fleetdata::{encrypt_secret_data, FleetSecretPart};
+
+		fn decrypt(data: &SecretData, identity: &Identity) -> anyhow::Result<Vec<u8>> {
+			let mut out = vec![];
+			Decryptor::new(&data.data[..])?
+				.decrypt(iter::once(identity as &dyn age::Identity))?
+				.read_to_end(&mut out)?;
+			Ok(out)
+		}
+
+		let old = Identity::generate();
+		let new = Identity::generate();
+		let encrypted =
+			encrypt_secret_data([&old.to_public() as &dyn Recipient], b"secret".to_vec())
+				.expect("has recipient");
+		let public = SecretData {
+			data: b"public".to_vec(),
+			encrypted: false,
+		};
+		let mut secret = FleetSecret {
+			created_at: Utc::now(),
+			expires_at: None,
+			parts: [
+				(
+					"secret".to_owned(),
+					FleetSecretPart {
+						raw: encrypted.clone(),
+					},
+				),
+				(
+					"public".to_owned(),
+					FleetSecretPart {
+						raw: public.clone(),
+					},
+				),
+			]
+			.into(),
+			generation_data: json!(null),
+		};
+
+		let count = rekey_secret(&mut secret, |data| {
+			let (old, new) = (&old, new.to_public());
+			async move {
+				let plain = decrypt(&data, old)?;
+				Ok(encrypt_secret_data([&new as &dyn Recipient], plain).expect("has recipient"))
+			}
+		})
+		.await?;
+		assert_eq!(count, 1);
+		let rekeyed = &secret.parts["secret"].raw;
+		assert_ne!(rekeyed.data, encrypted.data);
+		assert!(decrypt(rekeyed, &old).is_err());
+		assert_eq!(decrypt(rekeyed, &new)?, b"secret");
+		assert_eq!(secret.parts["public"].raw.data, public.data);
+
+		let unchanged = secret.parts["secret"].raw.data.clone();
+		let failed = rekey_secret(&mut secret, |_| async { anyhow::bail!("offline") }).await;
+		assert!(failed.is_err());
+		assert_eq!(secret.parts["secret"].raw.data, unchanged);
+		Ok(())
+	}
+
+	#[test]
+	fn moved_host_secret() {
+		let mut data: FleetData = nixlike::parse_str(
+			r#"{
+				version = "0.1.0";
+				hostSecrets.a.key.createdAt = "2024-01-01T00:00:00Z";
+				hostSecrets.a.other.createdAt = "2024-01-01T00:00:00Z";
+				hostSecrets.c.key.createdAt = "2024-01-01T00:00:00Z";
+			}"#,
+		)
+		.expect("fleet data");
+		let moved = data.host_secrets["a"]["key"].clone();
+		let created_at = moved.created_at;
+
+		assert!(transfer_host_secret(&mut data, "key", "a", "c", moved.clone()).is_err());
+		assert!(transfer_host_secret(&mut data, "missing", "a", "b", moved.clone()).is_err());
+		transfer_host_secret(&mut data, "key", "a", "b", moved.clone()).expect("moved");
+		assert!(!data.host_secrets["a"].contains_key("key"));
+		assert!(data.host_secrets["a"].contains_key("other"));
+		assert_eq!(data.host_secrets["b"]["key"].created_at, created_at);
+
+		transfer_host_secret(&mut data, "other", "a", "b", moved).expect("moved");
+		assert!(!data.host_secrets.contains_key("a"));
+	}
+
+	#[tokio::test]
+	async fn replaced_owners() {
+		let mut secret: FleetSharedSecret = nixlike::parse_str(
+			r#"{
+				owners = [ "a" "b" ];
+				createdAt = "2024-01-01T00:00:00Z";
+				secret.raw = "<ENCRYPTED><PLAINTEXT>old";
+				public.raw = "<PLAINTEXT>public";
+			}"#,
+		)
+		.expect("secret");
+		let reencrypt = |holder: String, data: SecretData, owners: Vec<String>| async move {
+			let mut tagged = format!("{holder}->{}:", owners.join(",")).into_bytes();
+			tagged.extend(data.data);
+			Ok(SecretData {
+				data: tagged,
+				encrypted: true,
+			})
+		};
+
+		assert!(set_shared_owners(&mut secret, vec![], &[], reencrypt)
+			.await
+			.is_err());
+		let offline = |_, _, _| async { anyhow::bail!("offline") };
+		assert!(
+			set_shared_owners(&mut secret, vec!["c".to_owned()], &[], offline)
+				.await
+				.is_err()
+		);
+		assert_eq!(secret.owners, ["a", "b"]);
+
+		let parts = set_shared_owners(
+			&mut secret,
+			vec!["b".to_owned(), "c".to_owned()],
+			&["b".to_owned()],
+			reencrypt,
+		)
+		.await
+		.unwrap();
+		assert_eq!(parts, 1);
+		assert_eq!(secret.owners, ["b", "c"]);
+		assert_eq!(secret.secret.parts["secret"].raw.data, b"b->b,c:old");
+		assert_eq!(secret.secret.parts["public"].raw.data, b"public");
+	}
+
+	#[tokio::test]
+	async fn moved_secret_reencryption() {
+		let mut secret: FleetSecret = nixlike::parse_str(
+			r#"{
+				createdAt = "2024-01-01T00:00:00Z";
+				secret.raw = "<ENCRYPTED><PLAINTEXT>old";
+				public.raw = "<PLAINTEXT>public";
+			}"#,
+		)
+		.expect("secret");
+		let reencrypt = |holder: String, data: SecretData, owners: Vec<String>| async move {
+			let mut tagged = format!("{holder}->{}:", owners.join(",")).into_bytes();
+			tagged.extend(data.data);
+			Ok(SecretData {
+				data: tagged,
+				encrypted: true,
+			})
+		};
+
+		// Preferred host is not able to decrypt the secret.
+		assert!(reencrypt_moved_secret(
+			&mut secret.clone(),
+			&["a".to_owned()],
+			"b",
+			&["c".to_owned()],
+			reencrypt
+		)
+		.await
+		.is_err());
+		let parts =
+			reencrypt_moved_secret(&mut secret.clone(), &["a".to_owned()], "b", &[], reencrypt)
+				.await
+				.unwrap();
+		assert_eq!(parts, 1);
+		let parts = reencrypt_moved_secret(
+			&mut secret,
+			&["a".to_owned(), "c".to_owned()],
+			"b",
+			&["c".to_owned(), "a".to_owned()],
+			reencrypt,
+		)
+		.await
+		.unwrap();
+		assert_eq!(parts, 1);
+		assert_eq!(secret.parts["secret"].raw.data, b"c->b:old");
+		assert_eq!(secret.parts["public"].raw.data, b"public");
+	}
+
+	#[test]
+	fn encrypted_for_hosts() {
+		use std::str::FromStr;
+
+		use age::Recipient;
+		use fleet_base::{
+			age_header::ssh_key_tag,
+			fleetdata::{encrypt_secret_data, FleetSecretPart},
+		};
+
+		let keys = [
+			"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFS43dPiWf0FyZjbcyosLuPpn8nQVsqydRX6vz2PzyCu",
+			"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOIzpzAQp9bIIs4gDu3wv/NTPS+bjdPpVgLnQBiKTPtd",
+		];
+		let ssh = keys.map(|k| age::ssh::Recipient::from_str(k).expect("valid key"));
+		let encrypted = |recipients: &[&dyn Recipient]| FleetSecretPart {
+			raw: encrypt_secret_data(recipients.iter().copied(), b"data".to_vec())
+				.expect("encrypted"),
+		};
+		let mut secret: FleetSecret =
+			nixlike::parse_str(r#"{ public.raw = "<PLAINTEXT>public"; }"#).expect("secret");
+		secret
+			.parts
+			.insert("both".to_owned(), encrypted(&[&ssh[0], &ssh[1]]));
+		let candidates = vec![
+			("a".to_owned(), ssh_key_tag(keys[0])),
+			("b".to_owned(), ssh_key_tag(keys[1])),
+			("unknown".to_owned(), None),
+		];
+		assert_eq!(
+			header_recipients(&secret, candidates.clone()).unwrap(),
+			["a", "b"]
+		);
+		// Every part should be decryptable by the holder.
+		secret
+			.parts
+			.insert("only_b".to_owned(), encrypted(&[&ssh[1]]));
+		assert_eq!(header_recipients(&secret, candidates).unwrap(), ["b"]);
+	}
+
+	#[tokio::test]
+	async fn secret_diff() {
+		let dir = tempfile::tempdir().unwrap();
+		let file = dir.path().join("new");
+		std::fs::write(&file, "user=admin\npassword=new\n").unwrap();
+		assert_eq!(
+			diff_secret(b"user=admin\npassword=new\n", &file)
+				.await
+				.unwrap(),
+			None
+		);
+		let diff = diff_secret(b"user=admin\npassword=old\n", &file)
+			.await
+			.unwrap()
+			.expect("differs");
+		assert!(diff.starts_with("--- stored\n+++ "), "{diff}");
+		assert!(diff.contains("\n-password=old\n+password=new\n"), "{diff}");
+
+		let diff = diff_secret(b"\0\x01", &file)
+			.await
+			.unwrap()
+			.expect("differs");
+		assert!(
+			diff.starts_with("binary data differs: stored 2 bytes"),
+			"{diff}"
+		);
+		assert!(diff_secret(b"", &dir.path().join("missing")).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn verified_parts() {
+		let secret: FleetSecret = nixlike::parse_str(
+			r#"{
+				createdAt = "2024-01-01T00:00:00Z";
+				public.raw = "<PLAINTEXT>key";
+				secret.raw = "<ENCRYPTED><PLAINTEXT>ok";
+				stale.raw = "<ENCRYPTED><PLAINTEXT>stale";
+			}"#,
+		)
+		.expect("secret");
+		let parts = verify_secret(&secret, |data| async move {
+			anyhow::ensure!(data.data == b"ok", "no identity matched");
+			Ok(data.data)
+		})
+		.await;
+		let parts = parts
+			.into_iter()
+			.map(|(part, error)| (part, error.map(|e| e.to_string())))
+			.collect::<Vec<_>>();
+		// Plaintext parts are not checked.
+		assert_eq!(
+			parts,
+			[
+				("secret".to_owned(), None),
+				("stale".to_owned(), Some("no identity matched".to_owned())),
+			]
+		);
+	}
+
+	#[test]
+	fn renamed_secret() {
+		let mut data: FleetData = nixlike::parse_str(
+			r#"{
+				version = "0.1.0";
+				sharedSecrets.key = {
+					owners = [ "a" ];
+					createdAt = "2024-01-01T00:00:00Z";
+					expiresAt = "2030-01-01T00:00:00Z";
+					secret.raw = "<ENCRYPTED><PLAINTEXT>data";
+					generationData.length = 32;
+				};
+				sharedSecrets.other.owners = [ "a" ];
+				sharedSecrets.other.createdAt = "2024-01-01T00:00:00Z";
+			}"#,
+		)
+		.expect("fleet data");
+		let secrets = &mut data.shared_secrets;
+		let renamed = serde_json::to_value(&secrets["key"]).unwrap();
+
+		assert!(rename_secret(secrets, "missing", "new", false).is_err());
+		assert!(rename_secret(secrets, "key", "other", false).is_err());
+		assert!(rename_secret(secrets, "key", "key", true).is_err());
+		rename_secret(secrets, "key", "new", false).expect("renamed");
+		assert!(!secrets.contains_key("key"));
+		assert_eq!(serde_json::to_value(&secrets["new"]).unwrap(), renamed);
+
+		rename_secret(secrets, "new", "other", true).expect("replaced");
+		assert_eq!(secrets.len(), 1);
+		assert_eq!(serde_json::to_value(&secrets["other"]).unwrap(), renamed);
+	}
+
+	#[tokio::test]
+	async fn unchanged_owner_keys() {
+		let data = |data: &[u8]| SecretData {
+			data: data.to_vec(),
+			encrypted: true,
+		};
+		let mut secret: FleetSecret = nixlike::parse_str(
+			r#"{ createdAt = "2024-01-01T00:00:00Z"; secret.raw = "<ENCRYPTED><PLAINTEXT>old"; }"#,
+		)
+		.expect("secret");
+		let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<BTreeSet<_>>();
+		let reencrypt = |_| async { Ok(data(b"new")) };
+
+		// Owners `[ "a" "b" ]` changed to `[ "@servers" ]`, which is expanded to the same hosts.
+		let reencrypted = reencrypt_for_owners(
+			&mut secret,
+			&keys(&["ssh-ed25519 A", "ssh-ed25519 B"]),
+			&keys(&["ssh-ed25519 B", "ssh-ed25519 A"]),
+			reencrypt,
+		)
+		.await
+		.unwrap();
+		assert_eq!(reencrypted, 0);
+		assert_eq!(secret.parts["secret"].raw, data(b"old"));
+
+		let reencrypted = reencrypt_for_owners(
+			&mut secret,
+			&keys(&["ssh-ed25519 A", "ssh-ed25519 B"]),
+			&keys(&["ssh-ed25519 A"]),
+			reencrypt,
+		)
+		.await
+		.unwrap();
+		assert_eq!(reencrypted, 1);
+		assert_eq!(secret.parts["secret"].raw, data(b"new"));
+	}
+
+	#[tokio::test]
+	async fn remaining_owner_keys() {
+		let set = |owners: &[&str]| {
+			owners
+				.iter()
+				.map(|o| o.to_string())
+				.collect::<BTreeSet<_>>()
+		};
+		let key = |owner: String| async move {
+			anyhow::ensure!(owner != "decommissioned", "host is unreachable");
+			Ok(format!("ssh-ed25519 {owner}\n"))
+		};
+
+		// Removed owner is not asked for its key.
+		let keys = owner_keys(&set(&["a", "decommissioned"]), &set(&["a", "b"]), key)
+			.await
+			.unwrap();
+		assert_eq!(keys, None);
+
+		let keys = owner_keys(&set(&["a"]), &set(&["a", "b"]), key)
+			.await
+			.unwrap();
+		assert_eq!(
+			keys,
+			Some((
+				set(&["ssh-ed25519 a"]),
+				set(&["ssh-ed25519 a", "ssh-ed25519 b"])
+			))
+		);
+	}
+
+	#[tokio::test]
+	async fn concurrent_regeneration() {
+		use std::{num::NonZeroUsize, sync::Mutex, time::Duration as StdDuration};
+
+		use tokio::{sync::Barrier, time::timeout};
+
+		let data = Mutex::new(FleetData::new_project());
+		// Both secrets should be in progress at once to pass the barrier.
+		let barrier = Barrier::new(2);
+		let regenerate = |(host, name): (&'static str, &'static str)| {
+			let (data, barrier) = (&data, &barrier);
+			async move {
+				barrier.wait().await;
+				let secret: FleetSecret =
+					nixlike::parse_str(r#"{ createdAt = "2024-01-01T00:00:00Z"; }"#)?;
+				data.lock()
+					.unwrap()
+					.host_secrets
+					.entry(host.to_owned())
+					.or_default()
+					.insert(name.to_owned(), secret);
+				Ok(())
+			}
+		};
+		let jobs = vec![("a", "password"), ("b", "token")];
+		timeout(
+			StdDuration::from_secs(5),
+			regenerate_concurrently(jobs, NonZeroUsize::new(2).unwrap(), regenerate),
+		)
+		.await
+		.expect("secrets are regenerated concurrently")
+		.unwrap();
+		let data = data.into_inner().unwrap();
+		assert!(data.host_secrets["a"].contains_key("password"));
+		assert!(data.host_secrets["b"].contains_key("token"));
+
+		let failing = |_: ()| async { anyhow::bail!("host is unreachable") };
+		assert!(
+			regenerate_concurrently(vec![()], NonZeroUsize::MIN, failing)
+				.await
+				.is_err()
+		);
+	}
+
+	#[test]
+	fn secret_age() {
+		let now = Utc::now();
+		assert_eq!(relative_age(now, now), "just now");
+		// Clock skew between the generating machine and the current one.
+		assert_eq!(relative_age(now + Duration::hours(1), now), "just now");
+		assert_eq!(
+			relative_age(now - Duration::minutes(1), now),
+			"1 minute ago"
+		);
+		assert_eq!(relative_age(now - Duration::hours(5), now), "5 hours ago");
+		assert_eq!(relative_age(now - Duration::days(95), now), "3 months ago");
+		assert_eq!(relative_age(now - Duration::days(800), now), "2 years ago");
+	}
+
+	#[test]
+	fn generation_data_changes() {
+		let stored = json!({ "kind": "password", "length": 32, "opts": { "a/b": 1 } });
+		assert!(generation_data_diff(&stored, &stored.clone()).is_empty());
+
+		let expected = json!({ "kind": "password", "length": 64, "opts": {}, "new": true });
+		assert_eq!(
+			generation_data_diff(&stored, &expected),
+			[
+				"- /length: 32",
+				"+ /length: 64",
+				"+ /new: true",
+				"- /opts/a~1b: 1"
+			]
+		);
+		// Secrets generated before generation data was recorded.
+		assert_eq!(
+			generation_data_diff(&serde_json::Value::Null, &json!(1)),
+			["- /: null", "+ /: 1"]
+		);
+	}
+
+	#[test]
+	fn exported_recipients() {
+		let data: FleetData = nixlike::parse_str(
+			r#"{
+				version = "0.1.0";
+				sharedSecrets.key = {
+					owners = [ "a" "@servers" ];
+					createdAt = "2024-01-01T00:00:00Z";
+				};
+			}"#,
+		)
+		.expect("fleet data");
+		assert_eq!(
+			export_owners(&data, vec!["b".to_owned()], None).unwrap(),
+			["b"]
+		);
+		assert_eq!(
+			export_owners(&data, vec![], Some("key")).unwrap(),
+			["a", "@servers"]
+		);
+		assert!(export_owners(&data, vec![], Some("missing")).is_err());
+
+		let a = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA4IrUghbj6eTVOuuj31b+PIfnnqG5hyP/Lg5egqIKhl";
+		let b = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJkmStkJVp/Wc7mJiY9pGYOMf7Plte/ieb6FGfYibAIU";
+		let lines = recipient_lines([
+			("b".to_owned(), format!("{b}\n")),
+			("a".to_owned(), a.to_owned()),
+			// Same key might be used by multiple hosts.
+			("c".to_owned(), a.to_owned()),
+		])
+		.unwrap();
+		assert_eq!(lines, format!("{a}\n{b}\n"));
+		assert!(recipient_lines([("a".to_owned(), "garbage".to_owned())]).is_err());
+	}
+
+	#[test]
+	fn public_part_reading() {
+		let public = SecretData {
+			data: b"ssh-ed25519 AAAA".to_vec(),
+			encrypted: false,
+		};
+		let private = SecretData {
+			data: b"age-encrypted".to_vec(),
+			encrypted: true,
+		};
+		let data: FleetData = nixlike::parse_str(&format!(
+			r#"{{
+				version = "0.1.0";
+				sharedSecrets.key = {{
+					owners = [ "a" ];
+					public.raw = "{public}";
+					secret.raw = "{private}";
+				}};
+				hostSecrets.a.key = {{
+					public.raw = "{public}";
+				}};
+			}}"#
+		))
+		.expect("fleet data");
+
+		assert_eq!(
+			public_part(&data, "key", true, None, "public").unwrap(),
+			b"ssh-ed25519 AAAA"
+		);
+		assert_eq!(
+			public_part(&data, "key", false, Some("a"), "public").unwrap(),
+			b"ssh-ed25519 AAAA"
+		);
+		let err = public_part(&data, "key", true, None, "secret").unwrap_err();
+		assert!(err.to_string().contains("fleet secret read"), "{err}");
+		assert!(public_part(&data, "key", false, Some("b"), "public").is_err());
+		assert!(public_part(&data, "key", true, None, "missing").is_err());
+	}
+}
+
 /*
 async fn edit_temp_file(
 	builder: tempfile::Builder<'_, '_>,
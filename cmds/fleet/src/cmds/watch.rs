@@ -0,0 +1,314 @@
+use std::{
+	env::current_dir,
+	ffi::{OsStr, OsString},
+	hash::{DefaultHasher, Hash as _, Hasher as _},
+	path::{Path, PathBuf},
+	pin::pin,
+	time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use fleet_base::opts::FleetOpts;
+use nix_eval::NixSessionPool;
+use notify::{Event, EventKind, RecursiveMode, Watcher as _};
+use tokio::{select, signal::ctrl_c, sync::mpsc, time::sleep};
+use tracing::{error, info, warn};
+
+use super::build_systems::Deploy;
+
+/// Redeploy hosts every time fleet project files are changed.
+///
+/// Project is fully reevaluated on every change, as flake inputs and fleet.nix may
+/// have changed, evaluated values can't be reused between deployments. Nix sessions are
+/// reused, and only reload the flake, keeping their evaluation caches warm.
+#[derive(Parser)]
+pub struct Watch {
+	#[clap(flatten)]
+	deploy: Deploy,
+	/// Wait for this many milliseconds after the last change before redeploying
+	#[clap(long, default_value = "500")]
+	debounce_ms: u64,
+}
+
+/// VCS metadata, editor swap files and build results are not part of the configuration.
+fn is_ignored(name: &OsStr) -> bool {
+	let name = name.to_string_lossy();
+	name.starts_with('.')
+		|| name.starts_with("result")
+		|| name.starts_with("built-")
+		|| name.ends_with('~')
+		|| name == "target"
+}
+
+/// Fleet state file is rewritten by the deployment itself, but other fleet commands (i.e
+/// `fleet secret add`) may change it too.
+struct StateFile {
+	path: PathBuf,
+	/// Hash of the content last read or written by the watch
+	known: Option<u64>,
+}
+impl StateFile {
+	fn new(path: PathBuf) -> Self {
+		let mut state = Self { path, known: None };
+		state.remember();
+		state
+	}
+	fn current(&self) -> Option<u64> {
+		let data = std::fs::read(&self.path).ok()?;
+		let mut hasher = DefaultHasher::new();
+		data.hash(&mut hasher);
+		Some(hasher.finish())
+	}
+	fn remember(&mut self) {
+		self.known = self.current();
+	}
+	/// Whether the file was changed by somebody else since it was last remembered.
+	fn is_changed(&self) -> bool {
+		self.current() != self.known
+	}
+}
+
+/// Whether the filesystem event is a change of the project configuration.
+///
+/// Writes of the state file by the watch itself aren't a reason to redeploy.
+fn is_project_change(event: &Event, directory: &Path, state: &StateFile) -> bool {
+	if matches!(event.kind, EventKind::Access(_)) {
+		return false;
+	}
+	event.paths.iter().any(|path| {
+		if path == &state.path {
+			return state.is_changed();
+		}
+		path.strip_prefix(directory)
+			.is_ok_and(|p| !p.components().any(|c| is_ignored(c.as_os_str())))
+	})
+}
+
+/// Collapses bursts of changes (editor saves, git checkouts) into a single redeploy.
+struct Debouncer {
+	delay: Duration,
+	last_change: Option<Instant>,
+}
+impl Debouncer {
+	fn new(delay: Duration) -> Self {
+		Self {
+			delay,
+			last_change: None,
+		}
+	}
+	fn change(&mut self, now: Instant) {
+		self.last_change = Some(now);
+	}
+	/// Returns true once, when there were no changes for the debounce delay.
+	fn fire(&mut self, now: Instant) -> bool {
+		match self.last_change {
+			Some(last) if now.duration_since(last) >= self.delay => {
+				self.last_change = None;
+				true
+			}
+			_ => false,
+		}
+	}
+}
+
+impl Watch {
+	async fn deploy_once(
+		&self,
+		opts: &FleetOpts,
+		pool: &NixSessionPool,
+		nix_args: Vec<OsString>,
+		state: &mut StateFile,
+	) -> Result<()> {
+		// Values of the previous deployment are dropped, sessions may be reloaded.
+		pool.reload();
+		state.remember();
+		let config = opts.build_in(pool.clone(), nix_args, true).await?;
+		let result = self.deploy.clone().run(&config, opts).await;
+		if state.is_changed() {
+			// Saving would overwrite the change, it is deployed next instead.
+			warn!(
+				"{} was changed during deployment, not saving deployment state",
+				state.path.display()
+			);
+		} else {
+			config.save()?;
+			state.remember();
+		}
+		result
+	}
+
+	pub async fn run(self, opts: FleetOpts, nix_args: Vec<OsString>) -> Result<()> {
+		let this = Self {
+			// There is nobody to answer the host selection prompt on every change.
+			deploy: self.deploy.without_prompt(),
+			..self
+		};
+		let debounce = Duration::from_millis(this.debounce_ms);
+		let mut debouncer = Debouncer::new(debounce);
+		let directory = current_dir()?;
+		let mut state = StateFile::new(directory.join(opts.state_format(&directory).file_name()));
+		let pool = opts.session_pool(&nix_args).await?;
+
+		let (tx, mut events) = mpsc::unbounded_channel();
+		let mut watcher = notify::recommended_watcher(move |event| {
+			// Receiver is only dropped when the command is finished.
+			let _ = tx.send(event);
+		})?;
+		watcher.watch(&directory, RecursiveMode::Recursive)?;
+
+		let mut interrupt = pin!(ctrl_c());
+		let mut stopping = false;
+		// Initial deployment happens right away, no need to wait for the first change.
+		let mut should_deploy = true;
+		loop {
+			if should_deploy {
+				info!("deploying");
+				let result = {
+					let mut deploy =
+						pin!(this.deploy_once(&opts, &pool, nix_args.clone(), &mut state));
+					select! {
+						r = &mut deploy => r,
+						_ = &mut interrupt => {
+							stopping = true;
+							info!("interrupted, waiting for the current deployment to finish");
+							deploy.await
+						}
+					}
+				};
+				match result {
+					Ok(()) => info!("deployed, waiting for changes"),
+					Err(e) => error!("deployment failed: {e:#}"),
+				}
+				if stopping {
+					return Ok(());
+				}
+				let mut changed = 0;
+				while let Ok(event) = events.try_recv() {
+					if event.is_ok_and(|e| is_project_change(&e, &directory, &state)) {
+						changed += 1;
+					}
+				}
+				if changed != 0 {
+					warn!("project was changed during deployment");
+					debouncer.change(Instant::now());
+				}
+			}
+
+			select! {
+				_ = &mut interrupt => {
+					info!("interrupted");
+					return Ok(());
+				}
+				event = events.recv() => match event {
+					Some(Ok(event)) => {
+						if is_project_change(&event, &directory, &state) {
+							debouncer.change(Instant::now());
+						}
+					}
+					Some(Err(e)) => warn!("file watcher error: {e}"),
+					None => bail!("file watcher stopped"),
+				},
+				_ = sleep(debounce) => {}
+			}
+			should_deploy = debouncer.fire(Instant::now());
+		}
+	}
+}
+
+#[test]
+fn debounced_changes() {
+	let start = Instant::now();
+	let mut debouncer = Debouncer::new(Duration::from_millis(500));
+	assert!(!debouncer.fire(start));
+	debouncer.change(start);
+	assert!(!debouncer.fire(start + Duration::from_millis(300)));
+	debouncer.change(start + Duration::from_millis(300));
+	assert!(!debouncer.fire(start + Duration::from_millis(600)));
+	assert!(debouncer.fire(start + Duration::from_millis(800)));
+	assert!(!debouncer.fire(start + Duration::from_millis(2000)));
+}
+
+#[test]
+fn project_changes() {
+	use notify::event::{AccessKind, ModifyKind, RemoveKind};
+
+	let directory = Path::new("/src/project");
+	let state_file = StateFile::new(directory.join("fleet.nix"));
+	let event = |kind, path: &str| Event::new(kind).add_path(directory.join(path));
+	let modified = EventKind::Modify(ModifyKind::Any);
+
+	assert!(is_project_change(
+		&event(modified, "hosts/a.nix"),
+		directory,
+		&state_file
+	));
+	// Removal of a watched file is a change too.
+	assert!(is_project_change(
+		&event(EventKind::Remove(RemoveKind::File), "flake.nix"),
+		directory,
+		&state_file
+	));
+	for ignored in [".git/index", "result", "hosts/.a.nix.swp", "hosts/a.nix~"] {
+		assert!(
+			!is_project_change(&event(modified, ignored), directory, &state_file),
+			"{ignored}"
+		);
+	}
+	assert!(!is_project_change(
+		&event(EventKind::Access(AccessKind::Any), "flake.nix"),
+		directory,
+		&state_file
+	));
+}
+
+#[tokio::test]
+async fn watched_directory() -> Result<()> {
+	let dir = tempfile::tempdir()?;
+	let directory = dir.path().canonicalize()?;
+	std::fs::create_dir(directory.join("hosts"))?;
+	let (tx, mut events) = mpsc::unbounded_channel();
+	let mut watcher = notify::recommended_watcher(move |event| {
+		let _ = tx.send(event);
+	})?;
+	watcher.watch(&directory, RecursiveMode::Recursive)?;
+
+	std::fs::write(directory.join("hosts/a.nix"), "{ }")?;
+	let state_file = StateFile::new(directory.join("fleet.nix"));
+	let changed = tokio::time::timeout(Duration::from_secs(10), async {
+		while let Some(event) = events.recv().await {
+			if is_project_change(&event?, &directory, &state_file) {
+				return Ok(true);
+			}
+		}
+		Ok::<_, notify::Error>(false)
+	})
+	.await??;
+	assert!(changed);
+	Ok(())
+}
+
+#[test]
+fn state_file_changes() -> Result<()> {
+	use notify::event::ModifyKind;
+
+	let dir = tempfile::tempdir()?;
+	let directory = dir.path();
+	let path = directory.join("fleet.nix");
+	std::fs::write(&path, "{ version = 1; }")?;
+	let mut state = StateFile::new(path.clone());
+	let modified = Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.clone());
+	assert!(!is_project_change(&modified, directory, &state));
+
+	// Own write is remembered, and isn't a reason to redeploy.
+	std::fs::write(&path, "{ version = 2; }")?;
+	state.remember();
+	assert!(!state.is_changed());
+	assert!(!is_project_change(&modified, directory, &state));
+
+	// i.e `fleet secret add` while watching.
+	std::fs::write(&path, "{ version = 2; sharedSecrets = { }; }")?;
+	assert!(state.is_changed());
+	assert!(is_project_change(&modified, directory, &state));
+	Ok(())
+}
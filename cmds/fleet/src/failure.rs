@@ -0,0 +1,95 @@
+//! Process exit codes, so that automation can tell failure categories apart.
+//!
+//! Categories are attached to errors as [`anyhow::Context`] at the command layer, errors of
+//! known types are categorized without it.
+
+use std::{fmt, process::ExitCode};
+
+use fleet_base::host::ConnectionError;
+
+/// Failure category, see README for the list of exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+	/// Fleet configuration evaluation failed
+	Eval,
+	/// Host was not reachable over ssh
+	Connectivity,
+	/// Secret couldn't be decrypted or reencrypted
+	Secret,
+	/// Command was executed, but failed for some of the hosts
+	PartialHosts,
+	/// Some of the stored secrets are expired
+	SecretsExpired,
+	/// Some of the stored secrets expire soon
+	SecretsExpiring,
+}
+impl Failure {
+	/// 2 is used by clap for usage errors.
+	fn code(self) -> u8 {
+		match self {
+			Failure::Eval => 3,
+			Failure::Connectivity => 4,
+			Failure::Secret => 5,
+			Failure::PartialHosts => 6,
+			Failure::SecretsExpired => 7,
+			Failure::SecretsExpiring => 8,
+		}
+	}
+	fn of(e: &anyhow::Error) -> Option<Self> {
+		// Connectivity problem is the root cause of whatever operation has failed on the host.
+		if e.downcast_ref::<ConnectionError>().is_some() {
+			return Some(Failure::Connectivity);
+		}
+		if let Some(failure) = e.downcast_ref::<Failure>() {
+			return Some(*failure);
+		}
+		// Nix error might be the source of other error, i.e io error of the fleet.nix is not.
+		if e.chain()
+			.any(|e| e.downcast_ref::<nix_eval::Error>().is_some())
+		{
+			return Some(Failure::Eval);
+		}
+		None
+	}
+}
+impl fmt::Display for Failure {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Failure::Eval => "evaluation failed",
+			Failure::Connectivity => "host is unreachable",
+			Failure::Secret => "secret operation failed",
+			Failure::PartialHosts => "failed for some hosts",
+			Failure::SecretsExpired => "secrets are expired",
+			Failure::SecretsExpiring => "secrets are expiring",
+		})
+	}
+}
+
+pub fn exit_code(e: &anyhow::Error) -> ExitCode {
+	Failure::of(e).map_or(ExitCode::FAILURE, |f| ExitCode::from(f.code()))
+}
+
+#[test]
+fn failure_categories() {
+	use anyhow::anyhow;
+
+	assert_eq!(Failure::of(&anyhow!("generic")), None);
+	let eval = anyhow::Error::from(nix_eval::Error::ExpectedOutput).context("building config");
+	assert_eq!(Failure::of(&eval), Some(Failure::Eval));
+	// Config is built from more than nix evaluation.
+	let state = anyhow::Error::from(std::io::Error::other("permission denied"))
+		.context("failed to read fleet.nix");
+	assert_eq!(Failure::of(&state), None);
+	let tagged = anyhow!("failed to decrypt")
+		.context(Failure::Secret)
+		.context("reading secret");
+	assert_eq!(Failure::of(&tagged), Some(Failure::Secret));
+	// Connection failure is more specific than the operation category.
+	let connection = anyhow::Error::from(ConnectionError::new(
+		"a".to_owned(),
+		openssh::Error::Disconnected,
+	))
+	.context(Failure::Secret);
+	assert_eq!(Failure::of(&connection), Some(Failure::Connectivity));
+	assert_eq!(Failure::PartialHosts.code(), 6);
+}
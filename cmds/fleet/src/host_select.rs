@@ -0,0 +1,98 @@
+//! Interactive selection of hosts to operate on.
+
+use std::io::{stdin, IsTerminal as _};
+
+use anyhow::{bail, ensure, Result};
+use dialoguer::MultiSelect;
+use fleet_base::{host::ConfigHost, opts::FleetOpts};
+
+/// Operator prompt, abstracted to be replaceable in tests.
+pub(crate) trait HostPicker {
+	/// Returns indexes of the picked items.
+	fn pick(&self, prompt: &str, items: &[String]) -> Result<Vec<usize>>;
+}
+
+pub(crate) struct TerminalPicker;
+impl HostPicker for TerminalPicker {
+	fn pick(&self, prompt: &str, items: &[String]) -> Result<Vec<usize>> {
+		Ok(MultiSelect::new()
+			.with_prompt(prompt)
+			.items(items)
+			.interact()?)
+	}
+}
+
+/// Interactive selection is performed when explicitly requested, or by default when running in terminal
+/// without any host selectors.
+pub(crate) fn should_pick(opts: &FleetOpts, interactive: bool, all: bool) -> bool {
+	if all {
+		return false;
+	}
+	interactive || (opts.only.is_empty() && opts.skip.is_empty() && stdin().is_terminal())
+}
+
+fn host_label(name: &str, tags: &[String]) -> String {
+	if tags.is_empty() {
+		name.to_owned()
+	} else {
+		format!("{name} [{}]", tags.join(", "))
+	}
+}
+
+fn pick_items<T>(
+	items: Vec<T>,
+	labels: &[String],
+	prompt: &str,
+	picker: &impl HostPicker,
+) -> Result<Vec<T>> {
+	let mut picked = picker.pick(prompt, labels)?;
+	picked.sort_unstable();
+	picked.dedup();
+	if let Some(&last) = picked.last() {
+		ensure!(last < items.len(), "picked item is out of range");
+	} else {
+		bail!("no hosts selected");
+	}
+	Ok(items
+		.into_iter()
+		.enumerate()
+		.filter(|(i, _)| picked.binary_search(i).is_ok())
+		.map(|(_, v)| v)
+		.collect())
+}
+
+pub(crate) async fn pick_hosts(
+	hosts: Vec<ConfigHost>,
+	prompt: &str,
+	picker: &impl HostPicker,
+) -> Result<Vec<ConfigHost>> {
+	let mut labels = Vec::with_capacity(hosts.len());
+	for host in &hosts {
+		labels.push(host_label(&host.name, &host.tags().await?));
+	}
+	tokio::task::block_in_place(|| pick_items(hosts, &labels, prompt, picker))
+}
+
+#[test]
+fn picked_hosts() {
+	struct Fixed(Vec<usize>);
+	impl HostPicker for Fixed {
+		fn pick(&self, _prompt: &str, _items: &[String]) -> Result<Vec<usize>> {
+			Ok(self.0.clone())
+		}
+	}
+	let hosts = vec!["a", "b", "c"];
+	let labels = vec![
+		host_label("a", &[]),
+		host_label("b", &["web".to_owned(), "eu".to_owned()]),
+		host_label("c", &[]),
+	];
+	assert_eq!(labels[1], "b [web, eu]");
+
+	assert_eq!(
+		pick_items(hosts.clone(), &labels, "", &Fixed(vec![2, 0, 2])).unwrap(),
+		vec!["a", "c"]
+	);
+	assert!(pick_items(hosts.clone(), &labels, "", &Fixed(vec![])).is_err());
+	assert!(pick_items(hosts, &labels, "", &Fixed(vec![3])).is_err());
+}
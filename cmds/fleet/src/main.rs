@@ -4,19 +4,29 @@
 pub(crate) mod cmds;
 // pub(crate) mod command;
 pub(crate) mod extra_args;
+pub(crate) mod failure;
+pub(crate) mod host_select;
 
-use std::{ffi::OsString, process::ExitCode};
+use std::{env::current_dir, ffi::OsString, process::ExitCode};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::{CommandFactory, Parser};
 use cmds::{
 	build_systems::{BuildSystems, Deploy},
 	complete::Complete,
+	diff::Diff,
 	info::Info,
+	init::Init,
+	migrate::ConfigMigrate,
+	rollback::Rollback,
 	secrets::Secret,
 	tf::Tf,
+	watch::Watch,
+};
+use fleet_base::{
+	host::Config,
+	opts::{read_fleet_data, FleetOpts},
 };
-use fleet_base::{host::Config, opts::FleetOpts};
 use futures::{future::LocalBoxFuture, stream::FuturesUnordered, TryStreamExt};
 // use host::Config;
 #[cfg(feature = "indicatif")]
@@ -83,6 +93,12 @@ enum Opts {
 	Complete(Complete),
 	/// Compile and evaluate terranix configuration
 	Tf(Tf),
+	/// Redeploy on every change of the fleet project
+	Watch(Watch),
+	Diff(Diff),
+	Rollback(Rollback),
+	ConfigMigrate(ConfigMigrate),
+	Init(Init),
 }
 
 #[derive(Parser)]
@@ -102,6 +118,11 @@ async fn run_command(config: &Config, opts: FleetOpts, command: Opts) -> Result<
 		Opts::Info(i) => i.run(config).await?,
 		Opts::Prefetch(p) => p.run(config).await?,
 		Opts::Tf(t) => t.run(config).await?,
+		Opts::Diff(d) => d.run(config, &opts).await?,
+		Opts::Rollback(r) => r.run(config, &opts).await?,
+		Opts::Watch(_) => unreachable!("watch evaluates config by itself"),
+		Opts::ConfigMigrate(_) => unreachable!("migration doesn't evaluate config"),
+		Opts::Init(_) => unreachable!("init creates the project to evaluate"),
 		// TODO: actually parse commands before starting the async runtime
 		Opts::Complete(c) => {
 			tokio::task::spawn_blocking(move || c.run(RootOpts::command())).await?
@@ -191,18 +212,38 @@ async fn async_main(opts: RootOpts) -> ExitCode {
 		#[cfg(feature = "indicatif")]
 		info!("fixme: this line gets eaten by tracing-indicatif on levels info+");
 		error!("{e:#}");
-		return ExitCode::FAILURE;
+		return failure::exit_code(&e);
 	}
 	ExitCode::SUCCESS
 }
 
-async fn main_real(opts: RootOpts) -> Result<()> {
+async fn main_real(mut opts: RootOpts) -> Result<()> {
 	nix_eval::init_tokio();
 
+	let directory = current_dir()?;
+	let state_format = opts.fleet_opts.state_format(&directory);
+	// Public parts are stored in fleet.nix as-is, there is no need to start nix for them.
+	if let Opts::Secret(secret @ Secret::CatPublic { .. }) = &opts.command {
+		return secret.cat_public(&read_fleet_data(&directory, state_format)?);
+	}
+	if let Opts::Secret(secret @ Secret::CheckExpiry { .. }) = &opts.command {
+		return secret.check_expiry(&read_fleet_data(&directory, state_format)?);
+	}
+	if let Opts::ConfigMigrate(m) = &opts.command {
+		return m.run(&directory, state_format, opts.fleet_opts.canonical_data);
+	}
+	if let Opts::Init(i) = &opts.command {
+		return i.run(&directory, state_format, opts.fleet_opts.canonical_data);
+	}
+
+	opts.fleet_opts.load_hosts_from()?;
 	let nix_args = std::env::var_os("NIX_ARGS")
 		.map(|a| extra_args::parse_os(&a))
 		.transpose()?
 		.unwrap_or_default();
+	if let Opts::Watch(w) = opts.command {
+		return w.run(opts.fleet_opts, nix_args).await;
+	}
 	let config = opts
 		.fleet_opts
 		.build(
@@ -211,7 +252,15 @@ async fn main_real(opts: RootOpts) -> Result<()> {
 		)
 		.await?;
 
-	match run_command(&config, opts.fleet_opts, opts.command).await {
+	let result = tokio::select! {
+		result = run_command(&config, opts.fleet_opts, opts.command) => result,
+		_ = tokio::signal::ctrl_c() => {
+			// Running nix builds are killed with their batch, instead of being left behind.
+			config.nix_session.abort_builds();
+			Err(anyhow!("interrupted"))
+		}
+	};
+	match result {
 		Ok(()) => {
 			config.save()?;
 			Ok(())
@@ -99,6 +99,10 @@ fn process_message(m: &str) -> String {
 	// and not just be replaced with the constant number of spaces, but it's ok for now, as statuses are single-line.
 	DETABBER.replace_all(m.as_ref(), "  ").to_string()
 }
+/// `dumping very large path` is reported for both build (105) and unknown (111) activities.
+fn is_large_path_dump(typ: u32, text: &str) -> bool {
+	(typ == 105 || typ == 111) && text.starts_with("dumping very large path")
+}
 impl Handler for NixHandler {
 	fn handle_line(&mut self, e: &str) {
 		if let Some(e) = e.strip_prefix("@nix ") {
@@ -126,6 +130,10 @@ impl Handler for NixHandler {
 						}
 					}
 				}
+				NixLog::Start { text, typ, .. } if is_large_path_dump(typ, &text) => {
+					// Informational, nix only notices the size of the path it is adding to the store
+					info!(target: "nix", "{}", text.trim_end());
+				}
 				NixLog::Start {
 					ref fields,
 					typ,
@@ -321,3 +329,19 @@ impl Handler for NixHandler {
 		}
 	}
 }
+
+#[test]
+fn large_path_dump() {
+	let line = r#"@nix {"action":"start","id":42,"level":1,"parent":0,"text":"dumping very large path (> 256 MiB); this may run out of memory","type":111}"#;
+	let log: NixLog = serde_json::from_str(line.strip_prefix("@nix ").unwrap()).unwrap();
+	let NixLog::Start { text, typ, .. } = log else {
+		panic!("start message expected");
+	};
+	assert!(is_large_path_dump(typ, &text));
+	assert!(!is_large_path_dump(0, &text));
+
+	let mut handler = NixHandler::default();
+	handler.handle_line(line);
+	// Not a progress activity, nothing to track until the stop message.
+	assert!(handler.spans.is_empty());
+}
@@ -0,0 +1,94 @@
+//! Age file header inspection, to find out who is able to decrypt the secret without decrypting it.
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::engine::{
+	general_purpose::{STANDARD, STANDARD_NO_PAD},
+	Engine,
+};
+use sha2::{Digest, Sha256};
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+/// Stanza body is wrapped at 64 columns, shorter line terminates the stanza.
+const BODY_COLUMNS: usize = 64;
+
+/// Recipient stanza of the age header.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Stanza {
+	/// Recipient type, i.e `ssh-ed25519` or `X25519`
+	pub kind: String,
+	/// Key tag, only ssh recipients have it, X25519 stanzas can't be attributed to the recipient.
+	pub tag: Option<String>,
+}
+
+pub fn recipient_stanzas(data: &[u8]) -> Result<Vec<Stanza>> {
+	let mut lines = data.split(|&c| c == b'\n').map(|line| {
+		std::str::from_utf8(line).context("age header is not utf-8, is the data armored?")
+	});
+	ensure!(
+		lines.next().transpose()? == Some(VERSION_LINE),
+		"not an age v1 file"
+	);
+	let mut out = vec![];
+	loop {
+		let Some(line) = lines.next().transpose()? else {
+			bail!("unexpected end of age header");
+		};
+		if line.starts_with("---") {
+			return Ok(out);
+		}
+		let Some(stanza) = line.strip_prefix("-> ") else {
+			bail!("malformed age header line: {line:?}");
+		};
+		let mut args = stanza.split(' ');
+		let kind = args.next().unwrap_or_default().to_owned();
+		let tag = kind
+			.starts_with("ssh-")
+			.then(|| args.next().map(str::to_owned))
+			.flatten();
+		out.push(Stanza { kind, tag });
+		loop {
+			let Some(body) = lines.next().transpose()? else {
+				bail!("unexpected end of age stanza");
+			};
+			if body.len() < BODY_COLUMNS {
+				break;
+			}
+		}
+	}
+}
+
+/// Tag of the ssh public key, as written to the age recipient stanza.
+pub fn ssh_key_tag(key: &str) -> Option<String> {
+	let wire = STANDARD.decode(key.split_whitespace().nth(1)?).ok()?;
+	let hash = Sha256::digest(wire);
+	Some(STANDARD_NO_PAD.encode(&hash[..4]))
+}
+
+#[test]
+fn multiple_recipients() {
+	use std::str::FromStr;
+
+	use age::Recipient;
+
+	use crate::fleetdata::encrypt_secret_data;
+
+	let keys = [
+		"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFS43dPiWf0FyZjbcyosLuPpn8nQVsqydRX6vz2PzyCu",
+		"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOIzpzAQp9bIIs4gDu3wv/NTPS+bjdPpVgLnQBiKTPtd host",
+	];
+	let ssh = keys.map(|k| age::ssh::Recipient::from_str(k).expect("valid key"));
+	let x25519 = age::x25519::Identity::generate().to_public();
+	let recipients: [&dyn Recipient; 3] = [&ssh[0], &x25519, &ssh[1]];
+	let secret = encrypt_secret_data(recipients, b"hello".to_vec()).expect("encrypted");
+
+	let stanzas = recipient_stanzas(&secret.data).expect("parsed");
+	let tags = keys.map(|k| ssh_key_tag(k).expect("tag"));
+	assert_ne!(tags[0], tags[1]);
+	assert_eq!(stanzas.iter().filter(|s| s.kind == "X25519").count(), 1);
+	for tag in &tags {
+		assert!(stanzas
+			.iter()
+			.any(|s| s.kind == "ssh-ed25519" && s.tag.as_ref() == Some(tag)));
+	}
+	assert!(recipient_stanzas(b"hello").is_err());
+}
@@ -5,7 +5,12 @@ use better_command::{Handler, NixHandler, PlainHandler};
 use futures::StreamExt;
 use itertools::Either;
 use openssh::{OverSsh, OwningCommand, Session};
-use tokio::{io::AsyncRead, process::Command, select};
+use tokio::{
+	io::AsyncRead,
+	process::Command,
+	select,
+	sync::{OwnedSemaphorePermit, Semaphore},
+};
 use tokio_util::codec::{BytesCodec, FramedRead, LinesCodec};
 use tracing::debug;
 
@@ -38,6 +43,8 @@ pub struct MyCommand {
 	ssh_session: Option<Arc<Session>>,
 	escalation: EscalationStrategy,
 	escalate: bool,
+	/// Permit is held for the whole command execution
+	limit: Option<Arc<Semaphore>>,
 }
 impl MyCommand {
 	pub fn new_on(
@@ -53,6 +60,7 @@ impl MyCommand {
 			ssh_session: Some(session),
 			escalation,
 			escalate: false,
+			limit: None,
 		}
 	}
 	pub fn new(escalation: EscalationStrategy, cmd: impl AsRef<OsStr>) -> Self {
@@ -64,15 +72,36 @@ impl MyCommand {
 			ssh_session: None,
 			escalation,
 			escalate: false,
+			limit: None,
 		}
 	}
 	fn new_here(&self, cmd: impl AsRef<OsStr>) -> Self {
-		if let Some(ssh_session) = self.ssh_session.clone() {
-			Self::new_on(self.escalation, cmd, ssh_session)
+		let out = if let Some(ssh_session) = self.ssh_session.clone() {
+			Self::new_on(self.escalation.clone(), cmd, ssh_session)
 		} else {
-			Self::new(self.escalation, cmd)
+			Self::new(self.escalation.clone(), cmd)
+		};
+		Self {
+			limit: self.limit.clone(),
+			..out
+		}
+	}
+	/// Share concurrency limit with other commands.
+	pub fn limited(self, limit: Arc<Semaphore>) -> Self {
+		Self {
+			limit: Some(limit),
+			..self
 		}
 	}
+	async fn permit(&self) -> Option<OwnedSemaphorePermit> {
+		let limit = self.limit.clone()?;
+		Some(
+			limit
+				.acquire_owned()
+				.await
+				.expect("semaphore is never closed"),
+		)
+	}
 
 	fn into_args(self) -> Vec<String> {
 		let mut out = Vec::new();
@@ -187,7 +216,7 @@ impl MyCommand {
 		if !self.escalate {
 			return self;
 		}
-		match self.escalation {
+		match self.escalation.clone() {
 			EscalationStrategy::Su => {
 				let mut out = self.new_here("su");
 				out.arg("-c").arg(self.into_string());
@@ -198,6 +227,11 @@ impl MyCommand {
 				out.args(self.into_args());
 				out
 			}
+			EscalationStrategy::Custom(wrapper) => {
+				let mut out = self.new_here(wrapper);
+				out.args(self.into_args());
+				out
+			}
 			EscalationStrategy::Run0 => {
 				// run0 wants interactive authentication by default.
 				let mut run0 = self.new_here("run0");
@@ -218,6 +252,7 @@ impl MyCommand {
 	}
 
 	pub async fn run(self) -> Result<()> {
+		let _permit = self.permit().await;
 		let str = self.clone().into_string();
 		let cmd = self.wrap_sudo_if_needed().into_command()?;
 		match cmd {
@@ -231,6 +266,7 @@ impl MyCommand {
 		Ok(String::from_utf8(bytes)?)
 	}
 	pub async fn run_bytes(self) -> Result<Vec<u8>> {
+		let _permit = self.permit().await;
 		let str = self.clone().into_string();
 		let cmd = self.wrap_sudo_if_needed().into_command()?;
 		let v = match cmd {
@@ -241,6 +277,7 @@ impl MyCommand {
 	}
 
 	pub async fn run_nix_string(mut self) -> Result<String> {
+		let _permit = self.permit().await;
 		let str = self.clone().into_string();
 		self.arg("--log-format").arg("internal-json");
 		let cmd = self.wrap_sudo_if_needed().into_command()?;
@@ -253,6 +290,7 @@ impl MyCommand {
 		Ok(String::from_utf8(bytes)?)
 	}
 	pub async fn run_nix(mut self) -> Result<()> {
+		let _permit = self.permit().await;
 		let str = self.clone().into_string();
 		self.arg("--log-format").arg("internal-json");
 		let cmd = self.wrap_sudo_if_needed().into_command()?;
@@ -441,3 +479,48 @@ async fn run_nix_inner_raw_ssh(
 
 	Ok(out_buf)
 }
+
+#[test]
+fn custom_escalation() {
+	let wrapper = "/run/wrappers/bin/site-escalate";
+	let mut cmd = MyCommand::new(EscalationStrategy::Custom(wrapper.to_owned()), "systemctl");
+	cmd.arg("restart").arg("nginx.service").env("LANG", "C");
+	assert_eq!(
+		cmd.clone().wrap_sudo_if_needed().into_args(),
+		["env", "LANG=C", "systemctl", "restart", "nginx.service"]
+	);
+	assert_eq!(
+		cmd.sudo().wrap_sudo_if_needed().into_args(),
+		[
+			wrapper,
+			"env",
+			"LANG=C",
+			"systemctl",
+			"restart",
+			"nginx.service"
+		]
+	);
+}
+
+#[tokio::test]
+async fn limited_concurrency() -> Result<()> {
+	use std::time::{Duration, Instant};
+
+	use crate::host::HostSessions;
+
+	let sessions = HostSessions::new(2);
+	let start = Instant::now();
+	let tasks = (0..6).map(|_| {
+		let mut cmd =
+			MyCommand::new(EscalationStrategy::Sudo, "sleep").limited(sessions.limit("a"));
+		cmd.arg("0.2");
+		cmd.run()
+	});
+	futures::future::try_join_all(tasks).await?;
+	// 6 commands, 2 at a time
+	assert!(start.elapsed() >= Duration::from_millis(600));
+
+	assert_eq!(sessions.limit("a").available_permits(), 2);
+	assert!(!Arc::ptr_eq(&sessions.limit("a"), &sessions.limit("b")));
+	Ok(())
+}
@@ -1,6 +1,7 @@
 use std::{
 	collections::BTreeMap,
 	io::{self, Cursor},
+	path::PathBuf,
 };
 
 use age::Recipient;
@@ -19,9 +20,26 @@ pub struct HostData {
 	#[serde(default)]
 	#[serde(skip_serializing_if = "String::is_empty")]
 	pub encryption_key: String,
+	/// Hash of the last successfully deployed system closure
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub deployed_system_hash: Option<String>,
+	/// Generation activated by the last successful `deploy switch`, see `fleet rollback --to-last-good`
+	#[serde(default)]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub last_good_generation: Option<GoodGeneration>,
 }
 
-const VERSION: &str = "0.1.0";
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GoodGeneration {
+	/// Generation id of the system profile
+	pub id: u32,
+	/// System closure the generation points to, generation ids are only unique until the
+	/// profile is recreated
+	pub system: PathBuf,
+}
+
+pub(crate) const VERSION: &str = "0.1.0";
 pub struct FleetDataVersion;
 impl Serialize for FleetDataVersion {
 	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -39,7 +57,7 @@ impl<'de> Deserialize<'de> for FleetDataVersion {
 		let version = String::deserialize(deserializer)?;
 		if version != VERSION {
 			return Err(D::Error::custom(format!(
-				"fleet.nix data version mismatch, expected {VERSION}, got {version}.\nFollow the docs for migration instruction"
+				"fleet.nix data version mismatch, expected {VERSION}, got {version}.\nRun `fleet config-migrate` to upgrade it"
 			)));
 		}
 		Ok(Self)
@@ -73,6 +91,20 @@ pub struct FleetData {
 	pub extra: BTreeMap<String, Value>,
 }
 
+impl FleetData {
+	/// Data for a newly created fleet project, with unique gc root prefix.
+	pub fn new_project() -> Self {
+		Self {
+			version: FleetDataVersion,
+			gc_root_prefix: generate_gc_prefix(),
+			hosts: BTreeMap::new(),
+			shared_secrets: BTreeMap::new(),
+			host_secrets: BTreeMap::new(),
+			extra: BTreeMap::new(),
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 #[must_use]
@@ -122,3 +154,85 @@ pub struct FleetSecret {
 	#[serde(skip_serializing_if = "Value::is_null")]
 	pub generation_data: Value,
 }
+
+/// Metadata files written to the impure generator output along with secret parts.
+pub const GENERATOR_METADATA_FILES: &[&str] = &["created_at", "expires_at", "marker"];
+/// Part names, which collide with secret metadata, either in generator output, or in fleet.nix,
+/// where parts are stored along with other secret fields.
+const RESERVED_PART_NAMES: &[&str] = &[
+	"created_at",
+	"expires_at",
+	"marker",
+	"createdAt",
+	"expiresAt",
+	"expire_at",
+	"generationData",
+	"owners",
+];
+
+/// Part is stored as a file of generator output, name should be safe to use as a file name.
+pub fn validate_part_name(name: &str) -> anyhow::Result<()> {
+	anyhow::ensure!(
+		!RESERVED_PART_NAMES.contains(&name),
+		"secret part name {name:?} is reserved for secret metadata"
+	);
+	anyhow::ensure!(
+		!name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0']),
+		"secret part name {name:?} is not a valid file name"
+	);
+	Ok(())
+}
+
+/// Serialize data for logs, with ciphertext replaced by its size.
+///
+/// Output can't be loaded back, use [`crate::host::Config::save`] to persist the data.
+pub fn serialize_redacted(data: &FleetData) -> Result<String, nixlike::Error> {
+	let value = nixlike::redact(nixlike::to_value(data)?, fleet_shared::SECRET_PREFIX);
+	Ok(nixlike::serialize_value_pretty(value))
+}
+
+#[test]
+fn part_names() {
+	assert!(validate_part_name("public").is_ok());
+	assert!(validate_part_name("tls.key").is_ok());
+	for reserved in ["marker", "created_at", "expiresAt", "owners"] {
+		let err = validate_part_name(reserved).unwrap_err();
+		assert!(err.to_string().contains("reserved"), "{err}");
+	}
+	for unsafe_name in ["", ".", "..", "../secret", "a/b"] {
+		let err = validate_part_name(unsafe_name).unwrap_err();
+		assert!(err.to_string().contains("file name"), "{err}");
+	}
+}
+
+#[test]
+fn redacted_secrets() {
+	let secret = encrypt_secret_data(
+		[&age::x25519::Identity::generate().to_public() as &dyn Recipient],
+		b"secret".to_vec(),
+	)
+	.expect("has recipient");
+	let data: FleetData = nixlike::parse_str(&format!(
+		r#"{{
+			version = "0.1.0";
+			hostSecrets.a.key = {{
+				public.raw = "<PLAINTEXT>ssh-ed25519 AAAA";
+				secret.raw = "{secret}";
+			}};
+		}}"#
+	))
+	.expect("fleet data");
+
+	let redacted = serialize_redacted(&data).expect("serialized");
+	assert!(!redacted.contains("<ENCRYPTED>"), "{redacted}");
+	assert!(redacted.contains("<redacted "), "{redacted}");
+	assert!(
+		redacted.contains("<PLAINTEXT>ssh-ed25519 AAAA"),
+		"{redacted}"
+	);
+	assert!(redacted.contains("hostSecrets"), "{redacted}");
+	// Actual data is untouched.
+	assert!(nixlike::serialize(&data)
+		.expect("serialized")
+		.contains("<ENCRYPTED>"));
+}
@@ -1,25 +1,29 @@
 use std::{
 	cell::OnceCell,
-	collections::BTreeSet,
+	collections::{BTreeSet, HashMap},
 	ffi::{OsStr, OsString},
 	fmt::Display,
-	io::Write,
 	ops::Deref,
-	path::PathBuf,
+	path::{Path, PathBuf},
 	str::FromStr,
 	sync::{Arc, Mutex, MutexGuard, OnceLock},
 };
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::{Duration, Utc};
 use fleet_shared::SecretData;
-use nix_eval::{nix_go, nix_go_json, util::assert_warn, NixSession, Value};
+use nix_eval::{
+	nix_go, nix_go_json, util::assert_warn, NixSession, NixSessionPool, OutPaths, Value,
+};
 use openssh::SessionBuilder;
 use serde::de::DeserializeOwned;
-use tempfile::NamedTempFile;
+use tokio::sync::Semaphore;
 
 use crate::{
 	command::MyCommand,
 	fleetdata::{FleetData, FleetSecret, FleetSharedSecret},
+	opts::{fleet_config_field, write_fleet_data, StateFormat},
+	retry::{is_transient_ssh_error, RetryPolicy},
 };
 
 pub struct FleetConfigInternals {
@@ -37,8 +41,44 @@ pub struct FleetConfigInternals {
 	pub nixpkgs: Value,
 
 	pub nix_session: NixSession,
+	/// Pool of the project flake sessions, see [`Config::other_flake_config`]
+	pub nix_pool: NixSessionPool,
+
+	/// Write fleet.nix in canonical form, see [`nixlike::canonicalize`]
+	pub canonical_data: bool,
+	/// Whether project state is stored in fleet.nix or fleet.json
+	pub state_format: StateFormat,
+	/// Overrides detected [`EscalationStrategy`]
+	pub escalation_command: Option<String>,
+	/// Remote command concurrency limits, shared by all [`ConfigHost`] instances of the same host
+	pub host_sessions: HostSessions,
+	/// Retries of transient ssh connection failures
+	pub connect_retry: RetryPolicy,
 }
 
+/// sshd rejects channels over its `MaxSessions` limit, so concurrent remote commands are capped per host.
+pub struct HostSessions {
+	per_host: usize,
+	hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+impl HostSessions {
+	pub fn new(per_host: usize) -> Self {
+		Self {
+			per_host,
+			hosts: Mutex::new(HashMap::new()),
+		}
+	}
+	pub fn limit(&self, host: &str) -> Arc<Semaphore> {
+		let mut hosts = self.hosts.lock().expect("not poisoned");
+		hosts
+			.entry(host.to_owned())
+			.or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+			.clone()
+	}
+}
+
+pub const DEFAULT_SECRETS_IDENTITY_FILE: &str = "/etc/ssh/ssh_host_ed25519_key";
+
 // TODO: Make field not pub
 #[derive(Clone)]
 pub struct Config(pub Arc<FleetConfigInternals>);
@@ -51,11 +91,27 @@ impl Deref for Config {
 	}
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum EscalationStrategy {
 	Sudo,
 	Run0,
 	Su,
+	/// Site-specific wrapper, invoked the same way as sudo: `<wrapper> <command> <args>`
+	Custom(String),
+}
+
+/// Host is unreachable, as opposed to errors of commands executed on the host.
+#[derive(thiserror::Error, Debug)]
+#[error("ssh error while connecting to {host}")]
+pub struct ConnectionError {
+	host: String,
+	#[source]
+	source: openssh::Error,
+}
+impl ConnectionError {
+	pub fn new(host: String, source: openssh::Error) -> Self {
+		Self { host, source }
+	}
 }
 
 pub struct ConfigHost {
@@ -71,9 +127,29 @@ pub struct ConfigHost {
 	pub local: bool,
 	pub session: OnceLock<Arc<openssh::Session>>,
 }
+fn nix_copy(host: &str, path: &Path, substitute_on_destination: bool) -> MyCommand {
+	let mut nix = MyCommand::new(
+		// Not used
+		EscalationStrategy::Su,
+		"nix",
+	);
+	nix.arg("copy");
+	if substitute_on_destination {
+		nix.arg("--substitute-on-destination");
+	}
+	nix.comparg("--to", format!("ssh-ng://{host}")).arg(path);
+	nix
+}
+
 // TODO: Move command helpers away with connectivity refactor
 impl ConfigHost {
 	pub async fn escalation_strategy(&self) -> Result<EscalationStrategy> {
+		if let Some(wrapper) = &self.config.escalation_command {
+			self.find_in_path(wrapper)
+				.await
+				.with_context(|| format!("escalation command {wrapper:?} not found"))?;
+			return Ok(EscalationStrategy::Custom(wrapper.clone()));
+		}
 		// Prefer sudo, as run0 has some gotchas with polkit
 		// and too many repeating prompts.
 		if (self.find_in_path("sudo").await).is_ok() {
@@ -90,15 +166,34 @@ impl ConfigHost {
 		if let Some(session) = &self.session.get() {
 			return Ok((*session).clone());
 		};
-		let session = SessionBuilder::default();
-		let session = session
-			.connect(&self.name)
+		let session = self
+			.config
+			.connect_retry
+			.run(is_transient_ssh_error, || async {
+				SessionBuilder::default().connect(&self.name).await
+			})
 			.await
-			.map_err(|e| anyhow!("ssh error while connecting to {}: {e}", self.name))?;
+			.map_err(|e| ConnectionError::new(self.name.clone(), e))?;
 		let session = Arc::new(session);
 		self.session.set(session.clone()).expect("TOCTOU happened");
 		Ok(session)
 	}
+	/// Difference between host and local clocks, positive if the host clock is ahead.
+	pub async fn clock_skew(&self) -> Result<Duration> {
+		let mut cmd = self.cmd("date").await?;
+		cmd.arg("+%s");
+		let before = Utc::now();
+		let remote: i64 = cmd
+			.run_string()
+			.await?
+			.trim()
+			.parse()
+			.context("unexpected date output")?;
+		let after = Utc::now();
+		// Compensate for the command roundtrip.
+		let local = before + (after - before) / 2;
+		Ok(Duration::seconds(remote - local.timestamp()))
+	}
 	pub async fn mktemp_dir(&self) -> Result<String> {
 		let mut cmd = self.cmd("mktemp").await?;
 		cmd.arg("-d");
@@ -178,14 +273,20 @@ impl ConfigHost {
 			Ok(MyCommand::new(escalation, cmd))
 		} else {
 			let session = self.open_session().await?;
-			Ok(MyCommand::new_on(escalation, cmd, session))
+			Ok(MyCommand::new_on(escalation, cmd, session)
+				.limited(self.config.host_sessions.limit(&self.name)))
 		}
 	}
 
 	pub async fn decrypt(&self, data: SecretData) -> Result<Vec<u8>> {
 		ensure!(data.encrypted, "secret is not encrypted");
+		let identity_file = self.secrets_identity_file().await?;
 		let mut cmd = self.cmd("fleet-install-secrets").await?;
 		cmd.arg("decrypt").eqarg("--secret", data.to_string());
+		// Older fleet-install-secrets versions have no such flag.
+		if identity_file != DEFAULT_SECRETS_IDENTITY_FILE {
+			cmd.eqarg("--identity-file", identity_file);
+		}
 		let encoded = cmd
 			.sudo()
 			.run_string()
@@ -197,8 +298,12 @@ impl ConfigHost {
 	}
 	pub async fn reencrypt(&self, data: SecretData, targets: Vec<String>) -> Result<SecretData> {
 		ensure!(data.encrypted, "secret is not encrypted");
+		let identity_file = self.secrets_identity_file().await?;
 		let mut cmd = self.cmd("fleet-install-secrets").await?;
 		cmd.arg("reencrypt").eqarg("--secret", data.to_string());
+		if identity_file != DEFAULT_SECRETS_IDENTITY_FILE {
+			cmd.eqarg("--identity-file", identity_file);
+		}
 		for target in targets {
 			let key = self.config.key(&target).await?;
 			cmd.eqarg("--targets", key);
@@ -213,21 +318,22 @@ impl ConfigHost {
 		Ok(data)
 	}
 	/// Returns path for futureproofing, as path might change i.e on conversion to CA
-	pub async fn remote_derivation(&self, path: &PathBuf) -> Result<PathBuf> {
+	///
+	/// With `substitute_on_destination`, host downloads whatever it can from its own binary caches,
+	/// otherwise the full closure is uploaded by the deployer.
+	pub async fn remote_derivation(
+		&self,
+		path: &PathBuf,
+		substitute_on_destination: bool,
+	) -> Result<PathBuf> {
 		if self.local {
 			// Path is located locally, thus already trusted.
 			return Ok(path.to_owned());
 		}
-		let mut nix = MyCommand::new(
-			// Not used
-			EscalationStrategy::Su,
-			"nix",
-		);
-		nix.arg("copy")
-			.arg("--substitute-on-destination")
-			.comparg("--to", format!("ssh-ng://{}", self.name))
-			.arg(path);
-		nix.run_nix().await.context("nix copy")?;
+		nix_copy(&self.name, path, substitute_on_destination)
+			.run_nix()
+			.await
+			.context("nix copy")?;
 		Ok(path.to_owned())
 	}
 	pub async fn systemctl_stop(&self, name: &str) -> Result<()> {
@@ -266,6 +372,20 @@ impl ConfigHost {
 
 		Ok(tags)
 	}
+	/// Host private key, used to decrypt secrets
+	pub async fn secrets_identity_file(&self) -> Result<String> {
+		let Some(host_config) = &self.host_config else {
+			return Ok(DEFAULT_SECRETS_IDENTITY_FILE.to_owned());
+		};
+		Ok(nix_go_json!(host_config.secretsIdentityFile))
+	}
+	/// Custom system activation command, see `activationCommand` host option
+	pub async fn activation_command(&self) -> Result<Option<String>> {
+		let Some(host_config) = &self.host_config else {
+			return Ok(None);
+		};
+		Ok(nix_go_json!(host_config.activationCommand))
+	}
 	pub async fn nixos_config(&self) -> Result<Value> {
 		if let Some(v) = self.nixos_config.get() {
 			return Ok(v.clone());
@@ -284,16 +404,9 @@ impl ConfigHost {
 	pub async fn list_configured_secrets(&self) -> Result<Vec<String>> {
 		let nixos = self.nixos_config().await?;
 		let secrets = nix_go!(nixos.secrets);
-		let mut out = Vec::new();
-		for name in secrets.list_fields().await? {
-			let secret = nix_go!(secrets[{ name }]);
-			let is_shared: bool = nix_go_json!(secret.shared);
-			if is_shared {
-				continue;
-			}
-			out.push(name);
-		}
-		Ok(out)
+		let names = secrets.list_fields().await?;
+		let shared: HashMap<String, bool> = secrets.select_all_json(&names, &["shared"]).await?;
+		Ok(names.into_iter().filter(|name| !shared[name]).collect())
 	}
 	pub async fn secret_field(&self, name: &str) -> Result<Value> {
 		let nixos = self.nixos_config().await?;
@@ -375,6 +488,24 @@ impl Config {
 		}
 		Ok(out)
 	}
+	/// System out paths of all hosts, evaluated without building them
+	pub async fn hosts_toplevel_paths(&self) -> Result<OutPaths> {
+		let config = &self.config_field;
+		let hosts = nix_go!(config.hosts);
+		Ok(hosts
+			.attrs_out_paths(&["nixos", "config", "system", "build", "toplevel"])
+			.await?)
+	}
+	/// `fleetConfigurations.default.config` of a different flake ref, i.e to compare configuration
+	/// with its committed version. Current fleet.nix data is passed to it.
+	///
+	/// Evaluation happens in the separate repl, which is stopped once the returned value and all
+	/// values derived from it are dropped.
+	pub async fn other_flake_config(&self, flake: impl Into<OsString>) -> Result<Value> {
+		let pool = self.nix_pool.new_for_flake(flake.into()).await?;
+		let session = pool.get_query().await?;
+		fleet_config_field(session, &self.data, self.state_format).await
+	}
 	// TODO: Replace usages with .host().nixos_config
 	pub async fn system_config(&self, host: &str) -> Result<Value> {
 		let fleet_field = &self.config_field;
@@ -463,18 +594,21 @@ impl Config {
 		self.data.lock().unwrap()
 	}
 	pub fn save(&self) -> Result<()> {
-		let mut tempfile = NamedTempFile::new_in(self.directory.clone()).context("failed to create updated version of fleet.nix in the same directory as original.\nDo you have write access to it? Access only to the fleet.nix won't be enough, the directory is used for atomic overwrite operation.\nIt is not recommended to use fleet by root anyway, move fleet project to your home directory.")?;
-		let data = nixlike::serialize(&self.data() as &FleetData)?;
-		tempfile.write_all(
-			format!(
-				"# This file contains fleet state and shouldn't be edited by hand\n\n{}\n\n# vim: ts=2 et nowrap\n",
-				data
-			)
-			.as_bytes(),
-		)?;
-		let mut fleet_data_path = self.directory.clone();
-		fleet_data_path.push("fleet.nix");
-		tempfile.persist(fleet_data_path)?;
-		Ok(())
+		write_fleet_data(
+			&self.directory,
+			self.state_format,
+			&self.data(),
+			self.canonical_data,
+		)
 	}
 }
+
+#[test]
+fn copy_substitution() {
+	let path = Path::new("/nix/store/aaa-system");
+	let with = format!("{:?}", nix_copy("a", path, true));
+	assert!(with.contains("\"--substitute-on-destination\""), "{with}");
+	let without = format!("{:?}", nix_copy("a", path, false));
+	assert!(!without.contains("substitute"), "{without}");
+	assert!(without.contains("\"ssh-ng://a\""), "{without}");
+}
@@ -31,8 +31,9 @@ impl Config {
 		} else {
 			warn!("Loading key for {}", host);
 			let host = self.host(host).await?;
+			let identity_file = host.secrets_identity_file().await?;
 			let mut cmd = host.cmd("cat").await?;
-			cmd.arg("/etc/ssh/ssh_host_ed25519_key.pub");
+			cmd.arg(format!("{identity_file}.pub"));
 			let key = cmd.run_string().await?;
 			self.update_key(&host.name, key.clone());
 			Ok(key)
@@ -1,5 +1,8 @@
+pub mod age_header;
 pub mod command;
 pub mod fleetdata;
 pub mod host;
 mod keys;
+pub mod migrate;
 pub mod opts;
+pub mod retry;
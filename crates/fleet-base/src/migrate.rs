@@ -0,0 +1,170 @@
+//! Upgrades of fleet.nix data written by older fleet versions.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use fleet_shared::{SecretData, SECRET_PREFIX};
+use serde_json::{Map, Value};
+
+use crate::fleetdata::VERSION;
+
+struct Migration {
+	/// None for data written before fleet.nix was versioned
+	from: Option<&'static str>,
+	to: &'static str,
+	apply: fn(&mut Map<String, Value>) -> Result<()>,
+}
+
+/// Applied in order, every migration upgrades data from the version produced by the previous one.
+const MIGRATIONS: &[Migration] = &[Migration {
+	from: None,
+	to: "0.1.0",
+	apply: |data| {
+		for secret in secrets_mut(data) {
+			if let Some(expires_at) = secret.remove("expire_at") {
+				secret.insert("expiresAt".to_owned(), expires_at);
+			}
+			// Parts were stored as bare strings, public one in plaintext, and secret one as z85.
+			// Parts already stored as `{ raw = ...; }` are kept as is.
+			for name in ["public", "secret"] {
+				let data = match secret.get(name) {
+					None | Some(Value::Object(_)) => continue,
+					Some(Value::String(data)) => data,
+					Some(other) => bail!("unexpected {name} part of the secret: {other}"),
+				};
+				let data = if name == "public" {
+					SecretData {
+						data: data.clone().into_bytes(),
+						encrypted: false,
+					}
+				} else {
+					format!("{SECRET_PREFIX}<Z85-ENCODED>\n{data}")
+						.parse()
+						.map_err(|e| anyhow!("bad secret data: {e}"))?
+				};
+				secret.insert(name.to_owned(), raw_part(data));
+			}
+		}
+		Ok(())
+	},
+}];
+
+fn raw_part(data: SecretData) -> Value {
+	let mut part = Map::new();
+	part.insert("raw".to_owned(), Value::String(data.to_string()));
+	Value::Object(part)
+}
+
+/// Both shared and host secrets.
+fn secrets_mut(data: &mut Map<String, Value>) -> Vec<&mut Map<String, Value>> {
+	let mut out = vec![];
+	for (key, value) in data.iter_mut() {
+		let secret_maps = match key.as_str() {
+			"sharedSecrets" => vec![value],
+			"hostSecrets" => value
+				.as_object_mut()
+				.map(|hosts| hosts.values_mut().collect())
+				.unwrap_or_default(),
+			_ => continue,
+		};
+		for secrets in secret_maps.into_iter().filter_map(Value::as_object_mut) {
+			out.extend(secrets.values_mut().filter_map(Value::as_object_mut));
+		}
+	}
+	out
+}
+
+/// Version of the data, None if it is unversioned.
+pub fn data_version(data: &Value) -> Result<Option<&str>> {
+	let data = data
+		.as_object()
+		.context("fleet.nix should contain an object")?;
+	match data.get("version") {
+		None => Ok(None),
+		Some(Value::String(v)) => Ok(Some(v)),
+		Some(_) => bail!("fleet.nix version should be a string"),
+	}
+}
+
+/// Upgrade data to the current version, returns versions it was migrated through.
+pub fn migrate(data: &mut Value) -> Result<Vec<&'static str>> {
+	let mut applied = vec![];
+	loop {
+		let version = data_version(data)?;
+		if version == Some(VERSION) {
+			return Ok(applied);
+		}
+		let Some(migration) = MIGRATIONS.iter().find(|m| m.from == version) else {
+			bail!(
+				"no migration from fleet.nix version {}, is it written by a newer fleet?",
+				version.unwrap_or("<unversioned>")
+			);
+		};
+		let object = data.as_object_mut().expect("checked by data_version");
+		(migration.apply)(object)
+			.with_context(|| format!("migrating to version {}", migration.to))?;
+		object.insert("version".to_owned(), Value::String(migration.to.to_owned()));
+		ensure!(
+			!applied.contains(&migration.to),
+			"migrations are looping at version {}",
+			migration.to
+		);
+		applied.push(migration.to);
+	}
+}
+
+#[test]
+fn unversioned_data() {
+	use crate::fleetdata::FleetData;
+
+	let encrypted = b"age-encryption.org/v1\n";
+	let mut data: Value = nixlike::parse_str(&format!(
+		r#"{{
+			sharedSecrets.key = {{
+				owners = [ "a" ];
+				public = "ssh-ed25519 AAAA";
+				secret = "{}";
+				expire_at = "2030-01-01T00:00:00Z";
+			}};
+			hostSecrets.a.password.secret = "{}";
+		}}"#,
+		z85::encode(encrypted),
+		z85::encode(encrypted),
+	))
+	.expect("legacy data");
+	assert_eq!(data_version(&data).unwrap(), None);
+	assert_eq!(migrate(&mut data).unwrap(), ["0.1.0"]);
+
+	let data: FleetData = serde_json::from_value(data).expect("current format");
+	let shared = &data.shared_secrets["key"];
+	assert_eq!(shared.secret.parts["public"].raw.data, b"ssh-ed25519 AAAA");
+	assert!(!shared.secret.parts["public"].raw.encrypted);
+	assert_eq!(shared.secret.parts["secret"].raw.data, encrypted);
+	assert!(shared.secret.parts["secret"].raw.encrypted);
+	assert!(shared.secret.expires_at.is_some());
+	assert!(
+		data.host_secrets["a"]["password"].parts["secret"]
+			.raw
+			.encrypted
+	);
+
+	let mut current = serde_json::to_value(&data).expect("serialized");
+	assert!(migrate(&mut current).unwrap().is_empty());
+	let mut future: Value = nixlike::parse_str(r#"{ version = "99.0.0"; }"#).unwrap();
+	assert!(migrate(&mut future).is_err());
+}
+
+#[test]
+fn unversioned_parts() {
+	let mut data: Value = nixlike::parse_str(&format!(
+		r#"{{ hostSecrets.a.password = {{ public.raw = "<PLAINTEXT>kept"; secret = "{}"; }}; }}"#,
+		z85::encode(b"age-encryption.org/v1\n"),
+	))
+	.expect("legacy data");
+	migrate(&mut data).unwrap();
+	assert_eq!(
+		data["hostSecrets"]["a"]["password"]["public"]["raw"],
+		"<PLAINTEXT>kept"
+	);
+	let mut data: Value =
+		nixlike::parse_str(r#"{ sharedSecrets.key.public = [ "unknown" ]; }"#).unwrap();
+	assert!(migrate(&mut data).is_err());
+}
@@ -2,13 +2,19 @@ use std::{
 	collections::BTreeMap,
 	env::current_dir,
 	ffi::OsString,
+	future::Future,
+	io::{Read, Write},
+	path::{Path, PathBuf},
 	str::FromStr,
 	sync::{Arc, Mutex},
+	time::Duration,
 };
 
-use anyhow::Result;
-use clap::Parser;
-use nix_eval::{nix_go, util::assert_warn, NixSessionPool, Value};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use nix_eval::{
+	nix_go, util::assert_warn, NixSession, NixSessionPool, PoolOptions, ResourceLimits, Value,
+};
 use nom::{
 	bytes::complete::take_while1,
 	character::complete::char,
@@ -16,10 +22,12 @@ use nom::{
 	multi::separated_list1,
 	sequence::{preceded, separated_pair},
 };
+use tempfile::NamedTempFile;
 
 use crate::{
 	fleetdata::FleetData,
-	host::{Config, ConfigHost, FleetConfigInternals},
+	host::{Config, ConfigHost, FleetConfigInternals, HostSessions},
+	retry::RetryPolicy,
 };
 
 #[derive(Clone)]
@@ -32,12 +40,17 @@ pub enum HostItem {
 		name: String,
 		attrs: BTreeMap<String, String>,
 	},
+	/// `!host`, excluded even if selected by other items
+	ExcludeHost { name: String },
+	/// `!@tag`
+	ExcludeTag { name: String },
 }
 fn host_item_parser(input: &str) -> Result<HostItem, String> {
 	fn err_to_string(err: nom::Err<nom::error::Error<&str>>) -> String {
 		err.to_string()
 	}
 
+	let (input, is_exclude) = map(opt(char('!')), |c| c.is_some())(input).map_err(err_to_string)?;
 	let (input, is_tag) = map(opt(char('@')), |c| c.is_some())(input).map_err(err_to_string)?;
 	let (input, name) = map(
 		take_while1(|v| v != ',' && v != '?' && v != '@'),
@@ -60,7 +73,16 @@ fn host_item_parser(input: &str) -> Result<HostItem, String> {
 	if !input.is_empty() {
 		return Err(format!("unexpected trailing input: {input:?}"));
 	}
-	Ok(if is_tag {
+	Ok(if is_exclude {
+		if !attrs.is_empty() {
+			return Err("excluded items can't have attributes".to_owned());
+		}
+		if is_tag {
+			HostItem::ExcludeTag { name }
+		} else {
+			HostItem::ExcludeHost { name }
+		}
+	} else if is_tag {
 		HostItem::Tag { name, attrs }
 	} else {
 		HostItem::Host { name, attrs }
@@ -70,10 +92,21 @@ fn host_item_parser(input: &str) -> Result<HostItem, String> {
 // TODO: Rename to HostSelector
 #[derive(Parser, Clone)]
 pub struct FleetOpts {
-	/// All hosts except those would be skipped
+	/// All hosts except those would be skipped.
+	///
+	/// Items prefixed with `!` (`!host`, `!@tag`) are excluded from the selection, if there are
+	/// only excluded items - they are excluded from all hosts.
 	#[clap(long, number_of_values = 1, value_parser = host_item_parser)]
 	pub only: Vec<HostItem>,
 
+	/// Read additional `--only` items from the file, one per line, `-` to read them from stdin.
+	/// List without any items is an error, as it would select every host.
+	#[clap(long, value_name = "PATH")]
+	pub hosts_from: Option<PathBuf>,
+	/// Host names read from `--hosts-from`, checked to exist once config is evaluated
+	#[clap(skip)]
+	hosts_from_names: Vec<String>,
+
 	/// Hosts to skip
 	#[clap(long, number_of_values = 1)]
 	pub skip: Vec<String>,
@@ -87,9 +120,254 @@ pub struct FleetOpts {
 	/// binfmt-declared qemu instead of trying to crosscompile
 	#[clap(long, default_value = env!("NIX_SYSTEM"))]
 	pub local_system: String,
+
+	/// Write fleet.nix in canonical form (sorted keys, normalized values),
+	/// to minimize diffs between fleet versions
+	#[clap(long)]
+	pub canonical_data: bool,
+
+	/// Override flake input for the whole run, i.e. to test configuration against patched nixpkgs,
+	/// passed as `--override-input` to nix
+	#[clap(long, number_of_values = 2, value_names = ["INPUT", "REF"])]
+	pub override_input: Vec<String>,
+
+	/// Privilege escalation wrapper to use on hosts instead of sudo/run0/su, it is called as
+	/// `<wrapper> <command> <args>...`
+	#[clap(long)]
+	pub escalation_command: Option<String>,
+
+	/// Maximum number of concurrently running commands on a single remote host,
+	/// should not exceed `MaxSessions` of host sshd
+	#[clap(long, alias = "concurrency-per-host", default_value = "8", value_parser = clap::value_parser!(u16).range(1..))]
+	pub max_sessions_per_host: u16,
+
+	/// How many times to retry ssh connection on transient failures (refused connection, timeout),
+	/// i.e when the host is still booting
+	#[clap(long, default_value = "3")]
+	pub connect_retries: u32,
+	/// Delay before the first connection retry in seconds, doubled for each next retry
+	#[clap(long, default_value = "1")]
+	pub connect_retry_delay: u64,
+
+	/// Abort evaluation of a single expression after this time (i.e `30s`, `5m`), to fail instead
+	/// of hanging on pathological expressions
+	#[clap(long, env = "FLEET_EVAL_TIMEOUT", value_parser = parse_duration)]
+	pub eval_timeout: Option<Duration>,
+	/// Limit address space of the nix evaluator process (i.e `4G`, `512M`), so that runaway
+	/// evaluation fails instead of consuming all memory of the machine
+	#[clap(long, env = "FLEET_EVAL_MEMORY_MAX", value_parser = parse_size)]
+	pub eval_memory_max: Option<u64>,
+	/// Limit CPU time of the nix evaluator process, consumed by all evaluations during the run
+	#[clap(long, env = "FLEET_EVAL_TIME_MAX", value_parser = parse_duration)]
+	pub eval_time_max: Option<Duration>,
+
+	/// Format of the fleet state file, detected by presence of `fleet.json` by default
+	#[clap(long)]
+	pub state_format: Option<StateFormat>,
+}
+
+/// How fleet state is stored in the project directory.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StateFormat {
+	/// `fleet.nix`, written by nixlike
+	Nix,
+	/// `fleet.json`, for tooling which doesn't understand nix
+	Json,
+}
+impl StateFormat {
+	pub fn file_name(self) -> &'static str {
+		match self {
+			Self::Nix => "fleet.nix",
+			Self::Json => "fleet.json",
+		}
+	}
+	/// Json is only used when the project already has `fleet.json`.
+	pub fn detect(directory: &Path) -> Self {
+		if directory.join(Self::Json.file_name()).exists() {
+			Self::Json
+		} else {
+			Self::Nix
+		}
+	}
+}
+
+/// Duration with `ms`/`s`/`m`/`h` suffix, plain number is seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+	let s = s.trim();
+	let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+	let (value, unit) = s.split_at(split);
+	let value: u64 = value
+		.parse()
+		.map_err(|_| format!("invalid duration: {s:?}"))?;
+	Ok(match unit {
+		"ms" => Duration::from_millis(value),
+		"" | "s" => Duration::from_secs(value),
+		"m" => Duration::from_secs(value * 60),
+		"h" => Duration::from_secs(value * 60 * 60),
+		_ => {
+			return Err(format!(
+				"unknown duration unit {unit:?}, expected ms, s, m or h"
+			))
+		}
+	})
+}
+
+/// Size in bytes with optional `K`/`M`/`G` binary suffix.
+fn parse_size(s: &str) -> Result<u64, String> {
+	let s = s.trim();
+	let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+	let (value, unit) = s.split_at(split);
+	let value: u64 = value.parse().map_err(|_| format!("invalid size: {s:?}"))?;
+	let multiplier: u64 = match unit {
+		"" => 1,
+		"K" => 1 << 10,
+		"M" => 1 << 20,
+		"G" => 1 << 30,
+		_ => return Err(format!("unknown size unit {unit:?}, expected K, M or G")),
+	};
+	value
+		.checked_mul(multiplier)
+		.ok_or_else(|| format!("size is too large: {s:?}"))
+}
+
+/// `fleetConfigurations.default.config` of the flake opened by the session.
+pub(crate) async fn fleet_config_field(
+	nix_session: NixSession,
+	data: &Mutex<FleetData>,
+	format: StateFormat,
+) -> Result<Value> {
+	let fleet_root = match Value::binding(nix_session.clone(), "fleetConfigurations").await {
+		Err(nix_eval::Error::UndefinedVariable(_)) => bail!(missing_fleet_configuration(None)),
+		v => v?,
+	};
+	if !fleet_root.has_field("default").await? {
+		let available = fleet_root.list_fields().await?;
+		bail!(missing_fleet_configuration(Some(&available)));
+	}
+	let fleet_field = match format {
+		StateFormat::Nix => nix_go!(fleet_root.default({ data })),
+		StateFormat::Json => {
+			let json = serde_json::to_string(data)?;
+			let builtins = Value::binding(nix_session, "builtins").await?;
+			let data = nix_go!(builtins.fromJSON({ json }));
+			nix_go!(fleet_root.default(Obj { data }))
+		}
+	};
+	Ok(nix_go!(fleet_field.config))
+}
+
+/// Explains the expected flake structure, `available` is the list of defined fleet configurations.
+fn missing_fleet_configuration(available: Option<&[String]>) -> String {
+	let mut out = match available {
+		None => "flake has no `fleetConfigurations` output".to_owned(),
+		Some([]) => "flake output `fleetConfigurations` is empty".to_owned(),
+		Some(names) => format!(
+			"flake output `fleetConfigurations.default` is missing, defined configurations: {}",
+			names.join(", ")
+		),
+	};
+	out.push_str(
+		"\nfleet expects `fleetConfigurations.default` attribute in flake outputs, \
+		 declared via fleet flake module: `imports = [inputs.fleet.flakeModules.default]; \
+		 fleetConfigurations.default = { ... };`",
+	);
+	out
+}
+
+/// Read fleet state from the fleet project directory, without evaluating anything.
+pub fn read_fleet_data(directory: &Path, format: StateFormat) -> Result<FleetData> {
+	let text = std::fs::read_to_string(directory.join(format.file_name()))?;
+	Ok(match format {
+		StateFormat::Nix => nixlike::parse_str(&text)?,
+		StateFormat::Json => serde_json::from_str(&text)?,
+	})
+}
+
+/// Atomically overwrite fleet state in the fleet project directory.
+pub fn write_fleet_data(
+	directory: &Path,
+	format: StateFormat,
+	data: &FleetData,
+	canonical: bool,
+) -> Result<()> {
+	let mut tempfile = NamedTempFile::new_in(directory).context("failed to create updated version of fleet.nix in the same directory as original.\nDo you have write access to it? Access only to the fleet.nix won't be enough, the directory is used for atomic overwrite operation.\nIt is not recommended to use fleet by root anyway, move fleet project to your home directory.")?;
+	if format == StateFormat::Json {
+		// serde_json::Value keeps keys sorted, output is always canonical.
+		let mut data = serde_json::to_string_pretty(&serde_json::to_value(data)?)?;
+		data.push('\n');
+		tempfile.write_all(data.as_bytes())?;
+		tempfile.persist(directory.join(format.file_name()))?;
+		return Ok(());
+	}
+	let mut data = nixlike::to_value(data)?;
+	if canonical {
+		data = nixlike::canonicalize(data);
+	}
+	// Hand-written notes are kept, unreadable file is overwritten the same way as before.
+	if let Some(existing) = std::fs::read_to_string(directory.join("fleet.nix"))
+		.ok()
+		.and_then(|s| nixlike::parse_str_with_comments(&s).ok())
+	{
+		data.restore_comments(&existing);
+	}
+	let data = nixlike::serialize_value_pretty(data);
+	tempfile.write_all(
+		format!(
+			"# This file contains fleet state and shouldn't be edited by hand\n\n{}\n\n# vim: ts=2 et nowrap\n",
+			data
+		)
+		.as_bytes(),
+	)?;
+	tempfile.persist(directory.join("fleet.nix"))?;
+	Ok(())
+}
+
+/// Items in `--only` format, one per line, empty lines and `#` comments are ignored.
+fn parse_host_items(list: &str) -> Result<Vec<HostItem>> {
+	let mut out = vec![];
+	for (i, line) in list.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let item = host_item_parser(line)
+			.map_err(|e| anyhow!(e))
+			.with_context(|| format!("invalid host selector at line {}: {line:?}", i + 1))?;
+		out.push(item);
+	}
+	Ok(out)
 }
 
 impl FleetOpts {
+	/// Merge items listed in `--hosts-from` into `--only`.
+	pub fn load_hosts_from(&mut self) -> Result<()> {
+		let Some(path) = self.hosts_from.take() else {
+			return Ok(());
+		};
+		let list = if path.as_os_str() == "-" {
+			let mut list = String::new();
+			std::io::stdin()
+				.read_to_string(&mut list)
+				.context("failed to read host list from stdin")?;
+			list
+		} else {
+			std::fs::read_to_string(&path)
+				.with_context(|| format!("failed to read host list from {}", path.display()))?
+		};
+		let items = parse_host_items(&list)?;
+		// Empty --only selects every host, while empty list means there is nothing to select.
+		if items.is_empty() {
+			bail!("host list {} has no hosts", path.display());
+		}
+		for item in &items {
+			if let HostItem::Host { name, .. } | HostItem::ExcludeHost { name } = item {
+				self.hosts_from_names.push(name.clone());
+			}
+		}
+		self.only.extend(items);
+		Ok(())
+	}
+
 	pub async fn filter_skipped(
 		&self,
 		hosts: impl IntoIterator<Item = ConfigHost>,
@@ -104,36 +382,57 @@ impl FleetOpts {
 		Ok(out)
 	}
 	pub async fn should_skip(&self, host: &ConfigHost) -> Result<bool> {
-		if self.skip.iter().any(|h| h as &str == host.name) {
+		self.should_skip_by_name(&host.name, host.tags()).await
+	}
+	/// Host tags are evaluated lazily, only when the host isn't selected/skipped by name.
+	async fn should_skip_by_name(
+		&self,
+		name: &str,
+		tags: impl Future<Output = Result<Vec<String>>>,
+	) -> Result<bool> {
+		if self.skip.iter().any(|h| h == name) {
 			return Ok(true);
 		}
-		if self.only.is_empty() {
-			return Ok(false);
-		}
-		let mut have_group_matches = false;
+		let mut positive = false;
+		let mut explicit = false;
+		let mut include_tags = false;
+		let mut exclude_tags = false;
 		for item in self.only.iter() {
 			match item {
-				HostItem::Host { name, .. } if *name == host.name => {
-					return Ok(false);
+				HostItem::Host { name: only, .. } => {
+					positive = true;
+					explicit |= only == name;
 				}
 				HostItem::Tag { .. } => {
-					have_group_matches = true;
+					positive = true;
+					include_tags = true;
 				}
-				_ => {}
-			}
-		}
-		if have_group_matches {
-			let host_tags = host.tags().await?;
-			for item in self.only.iter() {
-				match item {
-					HostItem::Tag { name, .. } if host_tags.contains(name) => {
-						return Ok(false);
-					}
-					_ => {}
+				HostItem::ExcludeHost { name: excluded } if excluded == name => {
+					return Ok(true);
+				}
+				HostItem::ExcludeHost { .. } => {}
+				HostItem::ExcludeTag { .. } => {
+					exclude_tags = true;
 				}
 			}
 		}
-		Ok(true)
+		let included_by_name = !positive || explicit;
+		if !included_by_name && !include_tags {
+			return Ok(true);
+		}
+		if included_by_name && !exclude_tags {
+			return Ok(false);
+		}
+		let host_tags = tags.await?;
+		let has_tag = |item: &HostItem, exclude: bool| match item {
+			HostItem::Tag { name, .. } if !exclude => host_tags.contains(name),
+			HostItem::ExcludeTag { name } if exclude => host_tags.contains(name),
+			_ => false,
+		};
+		if self.only.iter().any(|i| has_tag(i, true)) {
+			return Ok(true);
+		}
+		Ok(!included_by_name && !self.only.iter().any(|i| has_tag(i, false)))
 	}
 	pub async fn action_attr<T: FromStr>(&self, host: &ConfigHost, attr: &str) -> Result<Option<T>>
 	where
@@ -176,36 +475,94 @@ impl FleetOpts {
 		}
 		Ok(None)
 	}
+	/// Explicit `--state-format`, or the one detected in the project directory.
+	pub fn state_format(&self, directory: &Path) -> StateFormat {
+		self.state_format
+			.unwrap_or_else(|| StateFormat::detect(directory))
+	}
 	pub fn is_local(&self, host: &str) -> bool {
 		self.localhost == host
 	}
 
+	/// Flake input overrides, as nix arguments.
+	fn override_input_args(&self) -> Vec<OsString> {
+		self.override_input
+			.chunks(2)
+			.flat_map(|pair| ["--override-input", &pair[0], &pair[1]])
+			.map(OsString::from)
+			.collect()
+	}
+
 	// TODO: Config should be detached from opts.
 	pub async fn build(&self, nix_args: Vec<OsString>, assert: bool) -> Result<Config> {
+		let pool = self.session_pool(&nix_args).await?;
+		self.build_in(pool, nix_args, assert).await
+	}
+	/// Pool of the project flake sessions, which can be reused by several [`Self::build_in`] calls.
+	pub async fn session_pool(&self, nix_args: &[OsString]) -> Result<NixSessionPool> {
 		let directory = current_dir()?;
+		// Evaluation and builds of evaluated derivations are performed by the session, other
+		// nix invocations only work with store paths, and might not accept flake arguments.
+		let mut session_args = nix_args.to_vec();
+		session_args.extend(self.override_input_args());
 
-		let pool = NixSessionPool::new(
+		Ok(NixSessionPool::new(
 			directory.as_os_str().to_owned(),
-			nix_args.clone(),
+			session_args,
 			self.local_system.clone(),
+			PoolOptions {
+				eval_timeout: self.eval_timeout,
+				limits: ResourceLimits {
+					memory_max: self.eval_memory_max,
+					cpu_time_max: self.eval_time_max,
+				},
+				..Default::default()
+			},
 		)
-		.await?;
-		let nix_session = pool.get().await?;
+		.await?)
+	}
+	/// Evaluate config using sessions of the existing pool.
+	pub async fn build_in(
+		&self,
+		pool: NixSessionPool,
+		nix_args: Vec<OsString>,
+		assert: bool,
+	) -> Result<Config> {
+		let directory = current_dir()?;
+		let nix_session = pool.get_query().await?;
 
 		let builtins_field = Value::binding(nix_session.clone(), "builtins").await?;
 
-		let mut fleet_data_path = directory.clone();
-		fleet_data_path.push("fleet.nix");
-		let bytes = std::fs::read_to_string(fleet_data_path)?;
-		let data: Mutex<FleetData> = nixlike::parse_str(&bytes)?;
+		let state_format = self.state_format(&directory);
+		let data = Mutex::new(read_fleet_data(&directory, state_format)?);
 
-		let fleet_root = Value::binding(nix_session.clone(), "fleetConfigurations").await?;
-		let fleet_field = nix_go!(fleet_root.default({ data }));
+		let config_field = fleet_config_field(nix_session.clone(), &data, state_format).await?;
 
-		let config_field = nix_go!(fleet_field.config);
+		if !self.hosts_from_names.is_empty() {
+			let hosts = nix_go!(config_field.hosts).list_fields().await?;
+			let unknown = self
+				.hosts_from_names
+				.iter()
+				.filter(|name| !hosts.contains(name))
+				.map(String::as_str)
+				.collect::<Vec<_>>();
+			if !unknown.is_empty() {
+				bail!(
+					"hosts listed in --hosts-from are not defined in config: {}",
+					unknown.join(", ")
+				);
+			}
+		}
 
 		if assert {
 			assert_warn("fleet config evaluation", &config_field).await?;
+			// Whole config refers to nixpkgs and host systems, forcing it would evaluate everything,
+			// data is plain, yet its errors would only surface in the middle of the deploy.
+			let data_field = nix_go!(config_field.data);
+			data_field
+				.deep_seq()
+				.await
+				.context("fleet data evaluation")?;
 		}
 
 		let import = nix_go!(builtins_field.import);
@@ -220,6 +577,7 @@ impl FleetOpts {
 
 		Ok(Config(Arc::new(FleetConfigInternals {
 			nix_session,
+			nix_pool: pool,
 			directory,
 			data,
 			local_system: self.local_system.clone(),
@@ -228,6 +586,199 @@ impl FleetOpts {
 			default_pkgs,
 			nixpkgs,
 			localhost: self.localhost.to_owned(),
+			canonical_data: self.canonical_data,
+			state_format,
+			escalation_command: self.escalation_command.clone(),
+			host_sessions: HostSessions::new(self.max_sessions_per_host.into()),
+			connect_retry: RetryPolicy {
+				retries: self.connect_retries,
+				delay: Duration::from_secs(self.connect_retry_delay),
+			},
 		})))
 	}
 }
+
+#[tokio::test]
+async fn skip_by_name() -> Result<()> {
+	let opts = FleetOpts::parse_from([
+		"fleet", "--only", "web01", "--only", "@db", "--skip", "db02",
+	]);
+	let unreachable = async { panic!("tags shouldn't be evaluated") };
+	assert!(!opts.should_skip_by_name("web01", unreachable).await?);
+	let unreachable = async { panic!("tags shouldn't be evaluated") };
+	assert!(opts.should_skip_by_name("db02", unreachable).await?);
+	assert!(
+		!opts
+			.should_skip_by_name("db01", async { Ok(vec!["db".to_owned()]) })
+			.await?
+	);
+	assert!(
+		opts.should_skip_by_name("web02", async { Ok(vec![]) })
+			.await?
+	);
+	Ok(())
+}
+
+#[tokio::test]
+async fn excluded_items() -> Result<()> {
+	assert!(matches!(
+		host_item_parser("!web01"),
+		Ok(HostItem::ExcludeHost { name }) if name == "web01"
+	));
+	assert!(matches!(
+		host_item_parser("!@db"),
+		Ok(HostItem::ExcludeTag { name }) if name == "db"
+	));
+	assert!(host_item_parser("!web01?specialisation=a").is_err());
+
+	let tags = |tags: &[&str]| {
+		let tags = tags.iter().map(|t| t.to_string()).collect();
+		async { Ok(tags) }
+	};
+	// Only exclusions: everything else is selected.
+	let opts = FleetOpts::parse_from(["fleet", "--only", "!web01", "--only", "!@db"]);
+	assert!(opts.should_skip_by_name("web01", tags(&[])).await?);
+	assert!(opts.should_skip_by_name("db01", tags(&["db"])).await?);
+	assert!(!opts.should_skip_by_name("web02", tags(&["web"])).await?);
+
+	// Positive items define the base set, exclusions are subtracted from it.
+	let opts = FleetOpts::parse_from(["fleet", "--only", "@web", "--only", "!web01"]);
+	assert!(opts.should_skip_by_name("web01", tags(&["web"])).await?);
+	assert!(!opts.should_skip_by_name("web02", tags(&["web"])).await?);
+	assert!(opts.should_skip_by_name("db01", tags(&["db"])).await?);
+
+	let opts = FleetOpts::parse_from(["fleet", "--only", "web01", "--only", "!@canary"]);
+	assert!(opts.should_skip_by_name("web01", tags(&["canary"])).await?);
+	Ok(())
+}
+
+#[test]
+fn override_input() {
+	let opts = FleetOpts::parse_from([
+		"fleet",
+		"--override-input",
+		"nixpkgs",
+		"path:/src/nixpkgs",
+		"--override-input",
+		"home-manager",
+		"github:nix-community/home-manager",
+	]);
+	assert_eq!(
+		opts.override_input_args(),
+		[
+			"--override-input",
+			"nixpkgs",
+			"path:/src/nixpkgs",
+			"--override-input",
+			"home-manager",
+			"github:nix-community/home-manager",
+		]
+	);
+	assert!(FleetOpts::try_parse_from(["fleet", "--override-input", "nixpkgs"]).is_err());
+}
+
+#[test]
+fn missing_configuration() {
+	let missing = missing_fleet_configuration(None);
+	assert!(missing.starts_with("flake has no `fleetConfigurations` output\n"));
+	let other = missing_fleet_configuration(Some(&["staging".to_owned(), "prod".to_owned()]));
+	assert!(other.contains("defined configurations: staging, prod"));
+	assert!(other.contains("fleetConfigurations.default = "));
+}
+
+#[test]
+fn eval_timeout() {
+	assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+	assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+	assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+	assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+	assert!(parse_duration("5d").is_err());
+	assert!(parse_duration("s").is_err());
+	let opts = FleetOpts::parse_from(["fleet", "--eval-timeout", "1h"]);
+	assert_eq!(opts.eval_timeout, Some(Duration::from_secs(3600)));
+}
+
+#[test]
+fn eval_limits() {
+	assert_eq!(parse_size("4G"), Ok(4 << 30));
+	assert_eq!(parse_size("512M"), Ok(512 << 20));
+	assert_eq!(parse_size("1000"), Ok(1000));
+	assert!(parse_size("1T").is_err());
+	assert!(parse_size("99999999999999999999G").is_err());
+	let opts =
+		FleetOpts::parse_from(["fleet", "--eval-memory-max", "2G", "--eval-time-max", "10m"]);
+	assert_eq!(opts.eval_memory_max, Some(2 << 30));
+	assert_eq!(opts.eval_time_max, Some(Duration::from_secs(600)));
+}
+
+#[tokio::test]
+async fn hosts_from_file() -> Result<()> {
+	let mut list = NamedTempFile::new()?;
+	writeln!(
+		list,
+		"# computed by change detection\nweb01\n\n@db?deploy_kind=upload\n!db02"
+	)?;
+	let mut opts = FleetOpts::parse_from([
+		"fleet".as_ref(),
+		"--only".as_ref(),
+		"web02".as_ref(),
+		"--hosts-from".as_ref(),
+		list.path().as_os_str(),
+	]);
+	opts.load_hosts_from()?;
+	assert_eq!(opts.only.len(), 4);
+	assert_eq!(opts.hosts_from_names, ["web01", "db02"]);
+	let unreachable = async { panic!("tags shouldn't be evaluated") };
+	assert!(!opts.should_skip_by_name("web01", unreachable).await?);
+	assert!(
+		!opts
+			.should_skip_by_name("db01", async { Ok(vec!["db".to_owned()]) })
+			.await?
+	);
+	let unreachable = async { panic!("tags shouldn't be evaluated") };
+	assert!(opts.should_skip_by_name("db02", unreachable).await?);
+	assert!(matches!(
+		&opts.only[2],
+		HostItem::Tag { attrs, .. } if attrs["deploy_kind"] == "upload"
+	));
+
+	let Err(err) = parse_host_items("web01\n!@db?a=b") else {
+		panic!("excluded items can't have attributes");
+	};
+	assert!(format!("{err:#}").contains("line 2"));
+
+	let mut empty = NamedTempFile::new()?;
+	writeln!(empty, "# no changed hosts\n")?;
+	let mut opts = FleetOpts::parse_from([
+		"fleet".as_ref(),
+		"--hosts-from".as_ref(),
+		empty.path().as_os_str(),
+	]);
+	assert!(opts.load_hosts_from().is_err());
+	Ok(())
+}
+
+#[test]
+fn json_state() -> Result<()> {
+	let dir = tempfile::tempdir()?;
+	assert_eq!(StateFormat::detect(dir.path()), StateFormat::Nix);
+	let data = FleetData::new_project();
+	write_fleet_data(dir.path(), StateFormat::Json, &data, false)?;
+	assert_eq!(StateFormat::detect(dir.path()), StateFormat::Json);
+	let written: serde_json::Value =
+		serde_json::from_str(&std::fs::read_to_string(dir.path().join("fleet.json"))?)?;
+	assert_eq!(written["version"], crate::fleetdata::VERSION);
+	let read = read_fleet_data(dir.path(), StateFormat::Json)?;
+	assert_eq!(read.gc_root_prefix, data.gc_root_prefix);
+
+	// Version is checked the same way as for fleet.nix.
+	std::fs::write(dir.path().join("fleet.json"), r#"{"version": "99.0.0"}"#)?;
+	let Err(err) = read_fleet_data(dir.path(), StateFormat::Json) else {
+		panic!("future version shouldn't be accepted");
+	};
+	assert!(format!("{err:#}").contains("version mismatch"));
+
+	let opts = FleetOpts::parse_from(["fleet", "--state-format", "nix"]);
+	assert_eq!(opts.state_format(dir.path()), StateFormat::Nix);
+	Ok(())
+}
@@ -0,0 +1,154 @@
+//! Retries of transient connection failures.
+
+use std::{future::Future, io::ErrorKind, time::Duration};
+
+use tokio::time::sleep;
+use tracing::warn;
+
+/// Upper bound for a single backoff delay
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+	/// Number of attempts after the first failed one
+	pub retries: u32,
+	/// Delay before the first retry, doubled on every next one
+	pub delay: Duration,
+}
+impl RetryPolicy {
+	fn delay(&self, retry: u32) -> Duration {
+		self.delay
+			.saturating_mul(2u32.saturating_pow(retry))
+			.min(MAX_DELAY)
+	}
+
+	pub async fn run<T, E, F, Fut>(
+		&self,
+		retryable: impl Fn(&E) -> bool,
+		mut attempt: F,
+	) -> Result<T, E>
+	where
+		E: std::fmt::Display,
+		F: FnMut() -> Fut,
+		Fut: Future<Output = Result<T, E>>,
+	{
+		let mut retry = 0;
+		loop {
+			match attempt().await {
+				Ok(v) => return Ok(v),
+				Err(e) if retry < self.retries && retryable(&e) => {
+					let delay = self.delay(retry);
+					retry += 1;
+					warn!("{e}, retrying in {delay:?} ({retry}/{})", self.retries);
+					sleep(delay).await;
+				}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+}
+
+/// Host is booting, or network is temporarily unavailable, as opposed to i.e authentication failure,
+/// which won't be fixed by retrying.
+pub fn is_transient_ssh_error(e: &openssh::Error) -> bool {
+	let openssh::Error::Connect(e) = e else {
+		return false;
+	};
+	if matches!(
+		e.kind(),
+		ErrorKind::ConnectionRefused
+			| ErrorKind::ConnectionReset
+			| ErrorKind::ConnectionAborted
+			| ErrorKind::TimedOut
+			| ErrorKind::HostUnreachable
+			| ErrorKind::NetworkUnreachable
+	) {
+		return true;
+	}
+	// ssh reports errors as text, openssh only has its stderr.
+	let message = e.to_string();
+	[
+		"Connection refused",
+		"Connection timed out",
+		"Connection reset",
+		"Connection closed",
+		"No route to host",
+		"Network is unreachable",
+		"Operation timed out",
+	]
+	.iter()
+	.any(|m| message.contains(m))
+}
+
+#[test]
+fn backoff_schedule() {
+	let policy = RetryPolicy {
+		retries: 10,
+		delay: Duration::from_secs(1),
+	};
+	assert_eq!(policy.delay(0), Duration::from_secs(1));
+	assert_eq!(policy.delay(2), Duration::from_secs(4));
+	assert_eq!(policy.delay(9), MAX_DELAY);
+	assert_eq!(policy.delay(u32::MAX), MAX_DELAY);
+}
+
+#[tokio::test]
+async fn retried_connection() {
+	use std::{cell::Cell, io};
+
+	let policy = RetryPolicy {
+		retries: 3,
+		delay: Duration::from_millis(1),
+	};
+	let connect_error =
+		|kind, message: &str| openssh::Error::Connect(io::Error::new(kind, message));
+
+	// Host comes up after two refused connections.
+	let attempts = Cell::new(0);
+	let connected = policy
+		.run(is_transient_ssh_error, || {
+			attempts.set(attempts.get() + 1);
+			let attempt = attempts.get();
+			async move {
+				if attempt < 3 {
+					Err(connect_error(ErrorKind::ConnectionRefused, "refused"))
+				} else {
+					Ok(attempt)
+				}
+			}
+		})
+		.await;
+	assert_eq!(connected.ok(), Some(3));
+
+	// Authentication failure is not retried.
+	attempts.set(0);
+	let failed = policy
+		.run(is_transient_ssh_error, || {
+			attempts.set(attempts.get() + 1);
+			async {
+				Err::<(), _>(connect_error(
+					ErrorKind::Other,
+					"root@host: Permission denied (publickey).",
+				))
+			}
+		})
+		.await;
+	assert!(failed.is_err());
+	assert_eq!(attempts.get(), 1);
+
+	// Retries are limited.
+	attempts.set(0);
+	let failed = policy
+		.run(is_transient_ssh_error, || {
+			attempts.set(attempts.get() + 1);
+			async {
+				Err::<(), _>(connect_error(
+					ErrorKind::Other,
+					"ssh: connect to host host port 22: No route to host",
+				))
+			}
+		})
+		.await;
+	assert!(failed.is_err());
+	assert_eq!(attempts.get(), 4);
+}
@@ -19,7 +19,8 @@ const Z85_ENCODED_PREFIX: &str = "<Z85-ENCODED>\n";
 const PLAINTEXT_NEWLINE_PREFIX: &str = "<PLAINTEXT-NL>\n";
 const PLAINTEXT_PREFIX: &str = "<PLAINTEXT>";
 
-const SECRET_PREFIX: &str = "<ENCRYPTED>";
+/// Prefix of the serialized encrypted data
+pub const SECRET_PREFIX: &str = "<ENCRYPTED>";
 
 impl<'de> Deserialize<'de> for SecretData {
 	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -51,10 +52,10 @@ impl FromStr for SecretData {
 		};
 		let data = if let Some(unprefixed) = string.strip_prefix(BASE64_ENCODED_PREFIX) {
 			STANDARD_NO_PAD
-				.decode(unprefixed.replace(|v| matches!(v, '\n' | '\t' | ' '), ""))
+				.decode(unprefixed.replace(['\n', '\t', ' '], ""))
 				.map_err(|e| format!("base64-encoded failed: {e}"))?
 		} else if let Some(unprefixed) = string.strip_prefix(Z85_ENCODED_PREFIX) {
-			z85::decode(unprefixed.replace(|v| matches!(v, '\n' | '\t' | ' '), ""))
+			z85::decode(unprefixed.replace(['\n', '\t', ' '], ""))
 				.map_err(|e| format!("z85-encoded failed: {e}"))?
 		} else if let Some(unprefixed) = string.strip_prefix(PLAINTEXT_NEWLINE_PREFIX) {
 			unprefixed.as_bytes().to_owned()
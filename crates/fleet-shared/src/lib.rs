@@ -1,2 +1,2 @@
 mod encoding;
-pub use encoding::SecretData;
+pub use encoding::{SecretData, SECRET_PREFIX};
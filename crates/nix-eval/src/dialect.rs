@@ -0,0 +1,171 @@
+//! Nix repl output is not stable between nix versions, all version-specific knowledge should be kept here.
+
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use tokio::process::Command;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NixVersion {
+	pub major: u32,
+	pub minor: u32,
+	pub patch: u32,
+}
+impl NixVersion {
+	pub(crate) const fn new(major: u32, minor: u32, patch: u32) -> Self {
+		Self {
+			major,
+			minor,
+			patch,
+		}
+	}
+	/// Parse `nix --version` output, e.g `nix (Nix) 2.24.10` or `nix (Lix, like Nix) 2.91.1`
+	pub fn parse(version: &str) -> Option<Self> {
+		let version = version.lines().next()?.split_whitespace().last()?;
+		let mut parts = version.split('.').map(|p| {
+			// Pre-release versions have suffixes, e.g 2.25.0pre20241101_dirty
+			let digits = p.find(|c: char| !c.is_ascii_digit()).unwrap_or(p.len());
+			p[..digits].parse::<u32>().ok()
+		});
+		let major = parts.next()??;
+		let minor = parts.next()??;
+		let patch = parts.next().flatten().unwrap_or(0);
+		Some(Self::new(major, minor, patch))
+	}
+	pub(crate) async fn detect() -> Option<Self> {
+		let output = match Command::new("nix").arg("--version").output().await {
+			Ok(o) if o.status.success() => o,
+			Ok(o) => {
+				warn!(
+					"nix --version failed: {}",
+					String::from_utf8_lossy(&o.stderr)
+				);
+				return None;
+			}
+			Err(e) => {
+				warn!("failed to run nix --version: {e}");
+				return None;
+			}
+		};
+		let output = String::from_utf8_lossy(&output.stdout);
+		let version = Self::parse(&output);
+		if version.is_none() {
+			warn!("unknown nix --version output: {output}");
+		}
+		version
+	}
+}
+impl fmt::Display for NixVersion {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+	}
+}
+
+/// Version-specific repl behavior
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReplDialect {
+	/// Line preceding derivation outputs list in `:b` command output
+	build_header: &'static str,
+	/// `nix build` accepts `<drv>^*` installables, so builds can be performed outside of the repl
+	pub(crate) detached_build: bool,
+}
+impl ReplDialect {
+	const LEGACY: Self = Self {
+		build_header: "this derivation produced the following outputs:",
+		detached_build: false,
+	};
+	const MODERN: Self = Self {
+		build_header: "This derivation produced the following outputs:",
+		detached_build: true,
+	};
+
+	/// Unknown version is assumed to be the latest one.
+	pub(crate) fn for_version(version: Option<NixVersion>) -> Self {
+		match version {
+			Some(v) if v < NixVersion::new(2, 4, 0) => Self::LEGACY,
+			// Output selection syntax was added in 2.13
+			Some(v) if v < NixVersion::new(2, 13, 0) => Self {
+				detached_build: false,
+				..Self::MODERN
+			},
+			_ => Self::MODERN,
+		}
+	}
+
+	/// Parse `:b` output into output name => store path mapping
+	pub(crate) fn parse_build_output(&self, output: &str) -> Option<HashMap<String, PathBuf>> {
+		// Header is preceded by an empty line in every known version
+		let output = output.trim_start_matches('\n');
+		let outputs = output.strip_prefix(self.build_header)?;
+		outputs
+			.split('\n')
+			.filter(|v| !v.trim().is_empty())
+			.map(|v| {
+				let (name, path) = v.split_once(" -> ")?;
+				Some((name.trim().to_owned(), PathBuf::from(path.trim_end())))
+			})
+			.collect()
+	}
+}
+
+#[test]
+fn versions() {
+	assert_eq!(
+		NixVersion::parse("nix (Nix) 2.24.10\n"),
+		Some(NixVersion::new(2, 24, 10))
+	);
+	assert_eq!(
+		NixVersion::parse("nix (Lix, like Nix) 2.91.1\n"),
+		Some(NixVersion::new(2, 91, 1))
+	);
+	assert_eq!(
+		NixVersion::parse("nix (Nix) 2.25.0pre20241101_dirty"),
+		Some(NixVersion::new(2, 25, 0))
+	);
+	assert_eq!(
+		NixVersion::parse("nix (Nix) 2.3"),
+		Some(NixVersion::new(2, 3, 0))
+	);
+	assert_eq!(NixVersion::parse("garbage"), None);
+}
+
+#[test]
+fn build_output() {
+	// Nix 2.3
+	let legacy = ReplDialect::for_version(NixVersion::parse("nix (Nix) 2.3.17"));
+	assert_eq!(
+		legacy.parse_build_output(
+			"this derivation produced the following outputs:\n  out -> /nix/store/aaa-hello-2.12"
+		),
+		Some(HashMap::from([(
+			"out".to_owned(),
+			PathBuf::from("/nix/store/aaa-hello-2.12")
+		)]))
+	);
+	// Nix 2.24
+	let modern = ReplDialect::for_version(NixVersion::parse("nix (Nix) 2.24.10"));
+	assert_eq!(
+		modern.parse_build_output(
+			"\nThis derivation produced the following outputs:\n  dev -> /nix/store/bbb-openssl-dev\n  out -> /nix/store/ccc-openssl\n"
+		),
+		Some(HashMap::from([
+			("dev".to_owned(), PathBuf::from("/nix/store/bbb-openssl-dev")),
+			("out".to_owned(), PathBuf::from("/nix/store/ccc-openssl")),
+		]))
+	);
+	assert_eq!(modern.parse_build_output("error: build failed"), None);
+	assert_eq!(ReplDialect::for_version(None), modern);
+}
+
+#[test]
+fn detached_build_support() {
+	for (version, detached) in [
+		("nix (Nix) 2.3.17", false),
+		("nix (Nix) 2.12.1", false),
+		("nix (Nix) 2.13.0", true),
+		("nix (Lix, like Nix) 2.91.1", true),
+	] {
+		let dialect = ReplDialect::for_version(NixVersion::parse(version));
+		assert_eq!(dialect.detached_build, detached, "{version}");
+	}
+}
@@ -3,16 +3,27 @@
 //!
 //! Current api is awful, little effort was put into this implementation.
 
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
 
-pub use pool::NixSessionPool;
+pub use dialect::NixVersion;
 use pool::NixSessionPoolInner;
+pub use pool::{NixSessionPool, PoolOptions, PoolStats};
 use r2d2::PooledConnection;
-pub use session::{Error, Result};
-use tokio::sync::{mpsc, oneshot};
+pub use session::{
+	parse_diff_closures, ClosureDiffEntry, Error, NixErrorFrame, ResourceLimits, Result,
+};
+use tokio::{
+	sync::{mpsc, oneshot},
+	task::AbortHandle,
+};
 use tracing::instrument;
-pub use value::{Index, Value};
+pub use value::{Diagnostics, Index, OutPaths, Value};
 
+mod dialect;
 mod pool;
 mod session;
 mod value;
@@ -29,14 +40,35 @@ pub mod util;
 // 	nix_raw::libutil_init();
 // }
 
+/// What operations are allowed for the session.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SessionKind {
+	/// Evaluation only session, builds requested for values of this session are performed by
+	/// separate nix process, so that evaluation isn't blocked behind in-progress build.
+	/// Nix older than 2.13 can't build derivation outputs this way, the value is evaluated again
+	/// in a [`Self::Build`] session of the same pool instead.
+	Query,
+	/// Builds are performed in the repl itself, session is locked until the build is finished.
+	Build,
+}
+
 #[derive(Clone)]
-pub struct NixSession(pub(crate) Arc<tokio::sync::Mutex<PooledConnection<NixSessionPoolInner>>>);
+pub struct NixSession(
+	pub(crate) Arc<tokio::sync::Mutex<PooledConnection<NixSessionPoolInner>>>,
+	pub(crate) SessionKind,
+	/// Build batches started in this session, see [`NixSession::abort_builds`]
+	pub(crate) Arc<std::sync::Mutex<Vec<AbortHandle>>>,
+	/// Pool the session was checked out from, builds of [`SessionKind::Query`] values are
+	/// performed by its [`SessionKind::Build`] sessions.
+	pub(crate) NixSessionPool,
+);
 
 struct NixBuildTask(Value, oneshot::Sender<Result<HashMap<String, PathBuf>>>);
 
 #[derive(Clone)]
 pub struct NixBuildBatch {
 	tx: mpsc::UnboundedSender<NixBuildTask>,
+	abort: AbortHandle,
 }
 
 #[instrument(skip(session, values))]
@@ -61,7 +93,8 @@ impl NixBuildBatch {
 	fn new(name: String, session: NixSession) -> Self {
 		let (tx, mut rx) = mpsc::unbounded_channel::<NixBuildTask>();
 
-		tokio::task::spawn(async move {
+		let batches = session.2.clone();
+		let task = tokio::task::spawn(async move {
 			let mut deps = vec![];
 			let mut build_data = vec![];
 			while let Some(task) = rx.recv().await {
@@ -79,31 +112,40 @@ impl NixBuildBatch {
 				}
 				Err(e) => {
 					for NixBuildTask(v, o) in deps {
-						let s = v.to_string_weak().await;
-						let s = match s {
-							Ok(s) => s,
+						// Batch might fail because of the other derivation, outputs that
+						// were built before the failure are still usable.
+						match v.is_built().await {
+							Ok(true) => {
+								let _ = o.send(v.build().await);
+							}
+							Ok(false) => {
+								let _ = o.send(Err(e.clone()));
+							}
 							Err(e) => {
 								let _ = o.send(Err(e));
-								continue;
 							}
-						};
-						if PathBuf::from(s).exists() {
-							let _ = o.send(v.build().await);
-						} else {
-							let _ = o.send(Err(e.clone()));
 						}
 					}
 				}
 			};
 		});
-		Self { tx }
+		let abort = task.abort_handle();
+		let mut batches = batches.lock().expect("not poisoned");
+		batches.retain(|b| !b.is_finished());
+		batches.push(abort.clone());
+		Self { tx, abort }
 	}
 	pub async fn submit(self, task: Value) -> Result<HashMap<String, PathBuf>> {
-		let Self { tx: task_tx } = self;
+		let Self { tx: task_tx, .. } = self;
 		let (tx, rx) = oneshot::channel();
 		let _ = task_tx.send(NixBuildTask(task, tx));
 		drop(task_tx);
-		rx.await.expect("shoudn't be cancelled here")
+		// Sender is dropped with the aborted batch task.
+		rx.await.unwrap_or(Err(Error::Cancelled))
+	}
+	/// Stop the batch build, pending [`Self::submit`] calls return [`Error::Cancelled`].
+	pub fn abort(&self) {
+		self.abort.abort();
 	}
 }
 
@@ -115,8 +157,188 @@ impl NixSession {
 	pub fn new_build_batch(&self, name: String) -> NixBuildBatch {
 		NixBuildBatch::new(name, self.clone())
 	}
+
+	/// Abort all build batches of this session, i.e on interrupt.
+	pub fn abort_builds(&self) {
+		for batch in self.2.lock().expect("not poisoned").drain(..) {
+			batch.abort();
+		}
+	}
+
+	pub fn kind(&self) -> SessionKind {
+		self.1
+	}
+
+	/// Version of nix running the session, None if it wasn't recognized.
+	pub async fn nix_version(&self) -> Option<NixVersion> {
+		self.0.lock().await.nix_version
+	}
+
+	/// Content hash of the built store path, changes only when the path content does.
+	pub async fn path_hash(&self, path: &Path) -> Result<String> {
+		session::query_path_hash(path).await
+	}
+
+	/// Bind value to the top-level variable, so that it can be reused by name in later queries,
+	/// without selecting it again.
+	pub async fn define(&self, name: &str, value: &Value) -> Result<Value> {
+		if !is_valid_binding_name(name) {
+			return Err(Error::InvalidBindingName(name.to_owned()));
+		}
+		self.0
+			.lock()
+			.await
+			.execute_expression_empty(format!("{name} = sess_field_{}", value.session_field_id()))
+			.await?;
+		Value::binding(self.clone(), name).await
+	}
+}
+
+/// Nix identifier, which doesn't collide with session-allocated variables.
+fn is_valid_binding_name(name: &str) -> bool {
+	let mut chars = name.chars();
+	let Some(first) = chars.next() else {
+		return false;
+	};
+	(first.is_ascii_alphabetic() || first == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''))
+		&& !name.starts_with("sess_field_")
+		&& !matches!(
+			name,
+			"if" | "then" | "else" | "assert" | "with" | "let" | "in" | "rec" | "inherit" | "or"
+		)
 }
 
 pub fn init_tokio() {
+	// Every test has its own runtime, sessions are started on the one outliving them all.
+	#[cfg(test)]
+	let _ = pool::TOKIO_RUNTIME.set(test_runtime().handle().clone());
 	let _ = pool::TOKIO_RUNTIME.set(tokio::runtime::Handle::current());
 }
+
+#[cfg(test)]
+fn test_runtime() -> &'static tokio::runtime::Runtime {
+	static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+	RUNTIME.get_or_init(|| {
+		tokio::runtime::Builder::new_multi_thread()
+			.enable_all()
+			.build()
+			.expect("test runtime")
+	})
+}
+
+/// Nix system of the machine running tests.
+#[cfg(test)]
+pub(crate) fn test_system() -> String {
+	format!("{}-linux", std::env::consts::ARCH)
+}
+
+/// Write flake with given `outputs` expression to the directory.
+#[cfg(test)]
+pub(crate) fn write_test_flake(dir: &Path, outputs: &str) {
+	std::fs::write(
+		dir.join("flake.nix"),
+		format!("{{ outputs = _: {outputs}; }}"),
+	)
+	.expect("flake written");
+}
+
+/// Pool of sessions for the flake with given `outputs` expression, tests using it need nix.
+#[cfg(test)]
+pub(crate) async fn test_pool(outputs: &str) -> (NixSessionPool, PathBuf) {
+	test_pool_with(outputs, PoolOptions::default()).await
+}
+
+#[cfg(test)]
+pub(crate) async fn test_pool_with(
+	outputs: &str,
+	options: PoolOptions,
+) -> (NixSessionPool, PathBuf) {
+	init_tokio();
+	let dir = tempfile::tempdir().expect("tempdir").into_path();
+	write_test_flake(&dir, outputs);
+	let pool = NixSessionPool::new(
+		format!("path:{}", dir.display()).into(),
+		vec![
+			"--extra-experimental-features".into(),
+			"nix-command flakes".into(),
+		],
+		test_system(),
+		options,
+	)
+	.await
+	.expect("pool created");
+	(pool, dir)
+}
+
+#[test]
+fn binding_names() {
+	assert!(is_valid_binding_name("lib"));
+	assert!(is_valid_binding_name("host-pkgs'"));
+	assert!(!is_valid_binding_name(""));
+	assert!(!is_valid_binding_name("1lib"));
+	assert!(!is_valid_binding_name("a.b"));
+	assert!(!is_valid_binding_name("let"));
+	assert!(!is_valid_binding_name("sess_field_1"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn defined_binding() -> Result<()> {
+	let (pool, _) = test_pool("{ lib = { double = x: x * 2; }; }").await;
+	let session = pool.get().await?;
+	let lib = Value::binding(session.clone(), "lib").await?;
+	let double = nix_go!(lib.double);
+
+	assert!(matches!(
+		session.define("sess_field_1", &double).await,
+		Err(Error::InvalidBindingName(_))
+	));
+	session.define("double", &double).await?;
+	// Binding is reused by name, without selecting it from `lib` again.
+	for (arg, expected) in [(2, 4), (5, 10)] {
+		let double = Value::binding(session.clone(), "double").await?;
+		let doubled: u32 = nix_go_json!(double({ arg }));
+		assert_eq!(doubled, expected);
+	}
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn defined_binding_commands() -> Result<()> {
+	let dir = tempfile::tempdir().expect("tempdir");
+	let log = dir.path().join("repl.log");
+	// Every command is logged, every query evaluates to 4.
+	let repl = format!(
+		r#"while read -r l; do echo "$l" >> '{}'; case "$l" in
+		"sess_field_"*" = "*|"double = "*) ;;
+		"2 + 2") echo 4;;
+		"builtins.toJSON ("*) echo '"4"';;
+		*) echo "$l";;
+	esac; done"#,
+		log.display()
+	);
+	let pool = NixSessionPool::fake(&repl, None, PoolOptions::default());
+	let session = pool.get().await?;
+	let lib = Value::binding(session.clone(), "lib").await?;
+	let double = nix_go!(lib.double);
+	let commands = || std::fs::read_to_string(&log).expect("log written");
+
+	for name in ["sess_field_1", "let", "a.b"] {
+		assert!(matches!(
+			session.define(name, &double).await,
+			Err(Error::InvalidBindingName(_))
+		));
+	}
+	// Invalid names never reach the repl.
+	assert!(!commands().lines().any(|l| l.starts_with("let =")));
+
+	let defined = session.define("double", &double).await?;
+	let commands = commands();
+	let mut lines = commands.lines();
+	assert!(lines.any(|l| l == format!("double = sess_field_{}", double.session_field_id())));
+	// Returned value is rooted at the defined name.
+	assert!(lines.any(|l| l == format!("sess_field_{} = double", defined.session_field_id())));
+	assert_eq!(defined.as_json::<u32>().await?, 4);
+	Ok(())
+}
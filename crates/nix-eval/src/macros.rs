@@ -1,6 +1,9 @@
+use std::collections::HashMap;
+
+use regex::{Captures, Regex};
 use serde::Serialize;
 
-use crate::{NixSession, Value};
+use crate::{NixSession, Result, Value};
 
 #[derive(Clone)]
 pub struct NixExprBuilder {
@@ -134,6 +137,27 @@ impl NixExprBuilder {
 		}
 		session.expect("expr without fields used")
 	}
+	/// Same expression, with used fields evaluated again in `session`.
+	pub(crate) async fn rebind(&self, session: &NixSession) -> Result<Self> {
+		let mut ids = HashMap::new();
+		let mut used_fields = Vec::with_capacity(self.used_fields.len());
+		for field in &self.used_fields {
+			let rebound = field.rebind(session.clone()).await?;
+			ids.insert(field.session_field_id(), rebound.session_field_id());
+			used_fields.push(rebound);
+		}
+		let field = Regex::new(r"\bsess_field_(\d+)\b").expect("valid regex");
+		let out = field.replace_all(&self.out, |c: &Captures<'_>| {
+			match c[1].parse().ok().and_then(|id: u32| ids.get(&id)) {
+				Some(id) => format!("sess_field_{id}"),
+				None => c[0].to_owned(),
+			}
+		});
+		Ok(Self {
+			out: out.into_owned(),
+			used_fields,
+		})
+	}
 	#[allow(dead_code)]
 	pub fn index_attr(&mut self, s: &str) {
 		let escaped = nixlike::serialize(s).expect("string");
@@ -1,29 +1,181 @@
 use std::{
 	ffi::OsString,
-	sync::{Arc, OnceLock},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, OnceLock,
+	},
+	time::{Duration, Instant},
 };
 
 use r2d2::Pool;
 
-use crate::{session::NixSessionInner, Error, NixSession, Result};
+use crate::{
+	session::{NixSessionInner, ResourceLimits},
+	Error, NixSession, Result, SessionKind,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+	/// Sessions currently checked out
+	pub active: u32,
+	pub idle: u32,
+	/// Total sessions spawned during the pool lifetime
+	pub created: u64,
+	/// Sessions dropped due to exceeding TTL
+	pub recycled: u64,
+}
+
+#[derive(Default)]
+struct PoolCounters {
+	created: AtomicU64,
+	recycled: AtomicU64,
+	/// Incremented on every [`NixSessionPool::reload`]
+	generation: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolOptions {
+	/// Sessions older than `ttl` are recreated on checkout/return, as they may become stale
+	/// after nix daemon restart or flake GC.
+	pub ttl: Option<Duration>,
+	/// Limit for the single expression output, in bytes. Session is discarded when the limit is
+	/// exceeded, as the rest of the output can't be skipped reliably.
+	pub max_output: Option<usize>,
+	/// Limit for the single expression evaluation, session is discarded on timeout, as it is
+	/// still busy evaluating the expression. Builds are not limited.
+	pub eval_timeout: Option<Duration>,
+	pub limits: ResourceLimits,
+}
+impl Default for PoolOptions {
+	fn default() -> Self {
+		Self {
+			ttl: None,
+			max_output: Some(256 * 1024 * 1024),
+			eval_timeout: None,
+			limits: ResourceLimits::default(),
+		}
+	}
+}
+
+/// Arguments the pool was created with, other than the flake.
+#[derive(Clone)]
+struct PoolSettings {
+	nix_args: Vec<OsString>,
+	nix_system: String,
+	options: PoolOptions,
+}
 
-pub struct NixSessionPool(Pool<NixSessionPoolInner>);
+#[derive(Clone)]
+pub struct NixSessionPool(Pool<NixSessionPoolInner>, Arc<PoolCounters>, PoolSettings);
 impl NixSessionPool {
-	pub async fn new(flake: OsString, nix_args: Vec<OsString>, nix_system: String) -> Result<Self> {
+	pub async fn new(
+		flake: OsString,
+		nix_args: Vec<OsString>,
+		nix_system: String,
+		options: PoolOptions,
+	) -> Result<Self> {
+		let settings = PoolSettings {
+			nix_args: nix_args.clone(),
+			nix_system: nix_system.clone(),
+			options,
+		};
+		Self::build(
+			NixSessionPoolInner {
+				flake,
+				nix_args,
+				nix_system,
+				options,
+				counters: Default::default(),
+				#[cfg(test)]
+				fake_repl: None,
+			},
+			settings,
+		)
+	}
+	fn build(inner: NixSessionPoolInner, settings: PoolSettings) -> Result<Self> {
+		let counters = inner.counters.clone();
 		let inner = tokio::task::block_in_place(|| {
 			r2d2::Builder::<NixSessionPoolInner>::new()
 				.min_idle(Some(0))
-				.build(NixSessionPoolInner {
-					flake,
-					nix_args,
-					nix_system,
-				})
+				.build(inner)
 		})?;
-		Ok(Self(inner))
+		Ok(Self(inner, counters, settings))
 	}
-	pub async fn get(&self) -> Result<NixSession> {
+	/// Pool of sessions running shell `script` instead of the nix repl, script should echo the
+	/// commands it doesn't handle, the same way repl prints string and number values.
+	#[cfg(test)]
+	pub(crate) fn fake(
+		script: &str,
+		nix_version: Option<crate::NixVersion>,
+		options: PoolOptions,
+	) -> Self {
+		crate::init_tokio();
+		let settings = PoolSettings {
+			nix_args: vec![],
+			nix_system: "x86_64-linux".to_owned(),
+			options,
+		};
+		Self::build(
+			NixSessionPoolInner {
+				flake: OsString::new(),
+				nix_args: vec![],
+				nix_system: settings.nix_system.clone(),
+				options,
+				counters: Default::default(),
+				fake_repl: Some((script.to_owned(), nix_version)),
+			},
+			settings,
+		)
+		.expect("fake pool created")
+	}
+	/// Pool of sessions for a different flake ref with the same nix arguments and options, i.e to
+	/// compare configuration with its committed version.
+	///
+	/// Pools are independent, repl processes of the new pool are stopped once it is dropped along
+	/// with all of its sessions and values.
+	pub async fn new_for_flake(&self, flake: OsString) -> Result<Self> {
+		let settings = self.2.clone();
+		Self::new(
+			flake,
+			settings.nix_args,
+			settings.nix_system,
+			settings.options,
+		)
+		.await
+	}
+	/// Make sessions see the current state of the flake on disk.
+	///
+	/// Instead of being restarted, sessions are reloaded once they are checked out, so that their
+	/// evaluation caches and fetched inputs are reused. Sessions which are checked out already
+	/// are reloaded on their next checkout.
+	pub fn reload(&self) {
+		self.1.generation.fetch_add(1, Ordering::Relaxed);
+	}
+	pub fn stats(&self) -> PoolStats {
+		let state = self.0.state();
+		PoolStats {
+			active: state.connections - state.idle_connections,
+			idle: state.idle_connections,
+			created: self.1.created.load(Ordering::Relaxed),
+			recycled: self.1.recycled.load(Ordering::Relaxed),
+		}
+	}
+	async fn get_kind(&self, kind: SessionKind) -> Result<NixSession> {
 		let v = tokio::task::block_in_place(|| self.0.get())?;
-		Ok(NixSession(Arc::new(tokio::sync::Mutex::new(v))))
+		Ok(NixSession(
+			Arc::new(tokio::sync::Mutex::new(v)),
+			kind,
+			Default::default(),
+			self.clone(),
+		))
+	}
+	/// Session which performs builds in the repl itself
+	pub async fn get(&self) -> Result<NixSession> {
+		self.get_kind(SessionKind::Build).await
+	}
+	/// Session which never builds in the repl, see [`SessionKind::Query`]
+	pub async fn get_query(&self) -> Result<NixSession> {
+		self.get_kind(SessionKind::Query).await
 	}
 }
 
@@ -31,6 +183,48 @@ pub(crate) struct NixSessionPoolInner {
 	flake: OsString,
 	nix_args: Vec<OsString>,
 	pub(crate) nix_system: String,
+	options: PoolOptions,
+	counters: Arc<PoolCounters>,
+	#[cfg(test)]
+	fake_repl: Option<(String, Option<crate::NixVersion>)>,
+}
+impl NixSessionPoolInner {
+	async fn start_session(&self) -> Result<NixSessionInner> {
+		#[cfg(test)]
+		if let Some((script, nix_version)) = &self.fake_repl {
+			let mut cmd = tokio::process::Command::new("sh");
+			cmd.arg("-c").arg(script);
+			return NixSessionInner::start(
+				cmd,
+				self.nix_system.clone(),
+				vec![],
+				*nix_version,
+				self.options.max_output,
+				self.options.eval_timeout,
+			)
+			.await;
+		}
+		NixSessionInner::new(
+			self.flake.as_os_str(),
+			self.nix_args.iter().map(OsString::as_os_str),
+			self.nix_system.clone(),
+			self.options.max_output,
+			self.options.eval_timeout,
+			self.options.limits,
+		)
+		.await
+	}
+	fn check_expired(&self, conn: &NixSessionInner) -> bool {
+		let expired = is_expired(conn.created_at, self.options.ttl, Instant::now());
+		if expired {
+			self.counters.recycled.fetch_add(1, Ordering::Relaxed);
+		}
+		expired
+	}
+}
+
+fn is_expired(created_at: Instant, ttl: Option<Duration>, now: Instant) -> bool {
+	ttl.is_some_and(|ttl| now.saturating_duration_since(created_at) > ttl)
 }
 
 impl r2d2::ManageConnection for NixSessionPoolInner {
@@ -41,18 +235,29 @@ impl r2d2::ManageConnection for NixSessionPoolInner {
 			.get()
 			.expect("missed tokio runtime init!")
 			.enter();
-		Ok(futures::executor::block_on(NixSessionInner::new(
-			self.flake.as_os_str(),
-			self.nix_args.iter().map(OsString::as_os_str),
-			self.nix_system.clone(),
-		))?)
+		let generation = self.counters.generation.load(Ordering::Relaxed);
+		let mut session = futures::executor::block_on(self.start_session())?;
+		self.counters.created.fetch_add(1, Ordering::Relaxed);
+		session.generation = generation;
+		Ok(session)
 	}
 
 	fn is_valid(&self, conn: &mut Self::Connection) -> std::result::Result<(), Self::Error> {
+		if self.check_expired(conn) {
+			return Err(Error::SessionInit("session exceeded ttl"));
+		}
+		if conn.broken {
+			return Err(Error::Broken);
+		}
 		let _v = TOKIO_RUNTIME
 			.get()
 			.expect("missed tokio runtime init!")
 			.enter();
+		let generation = self.counters.generation.load(Ordering::Relaxed);
+		if conn.generation != generation {
+			futures::executor::block_on(conn.reload())?;
+			conn.generation = generation;
+		}
 		let res = futures::executor::block_on(conn.execute_expression_number("2 + 2"))?;
 		if res != 4 {
 			// just in case, should fail much earlier
@@ -61,8 +266,166 @@ impl r2d2::ManageConnection for NixSessionPoolInner {
 		Ok(())
 	}
 
-	fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
-		false
+	fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+		conn.broken || self.check_expired(conn)
 	}
 }
 pub static TOKIO_RUNTIME: OnceLock<tokio::runtime::Handle> = OnceLock::new();
+
+#[test]
+fn session_ttl() {
+	let created_at = Instant::now();
+	let ttl = Some(Duration::from_secs(60));
+	assert!(!is_expired(
+		created_at,
+		None,
+		created_at + Duration::from_secs(3600)
+	));
+	assert!(!is_expired(
+		created_at,
+		ttl,
+		created_at + Duration::from_secs(30)
+	));
+	assert!(is_expired(
+		created_at,
+		ttl,
+		created_at + Duration::from_secs(61)
+	));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn recreated_session() -> Result<()> {
+	// Every query evaluates to the pid of the repl process.
+	let repl = r#"while read -r l; do case "$l" in
+		"sess_field_"*" = "*) ;;
+		"2 + 2") echo 4;;
+		"builtins.toJSON ("*) echo "\"$$\"";;
+		*) echo "$l";;
+	esac; done"#;
+	let options = PoolOptions {
+		ttl: Some(Duration::from_millis(300)),
+		..Default::default()
+	};
+	let pool = NixSessionPool::fake(repl, None, options);
+	let repl_pid = |pool: NixSessionPool| async move {
+		let session = pool.get().await?;
+		crate::Value::binding(session, "pid")
+			.await?
+			.as_json::<u32>()
+			.await
+	};
+	let first = repl_pid(pool.clone()).await?;
+	// Session is idle, yet still fresh.
+	assert_eq!(repl_pid(pool.clone()).await?, first);
+	assert_eq!((pool.stats().created, pool.stats().recycled), (1, 0));
+
+	tokio::time::sleep(Duration::from_millis(600)).await;
+	assert_ne!(repl_pid(pool.clone()).await?, first);
+	let stats = pool.stats();
+	assert_eq!(stats.created, 2);
+	assert!(stats.recycled >= 1);
+	assert_eq!(stats.active + stats.idle, 1);
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn expired_session() -> Result<()> {
+	let options = PoolOptions {
+		ttl: Some(Duration::from_millis(500)),
+		..Default::default()
+	};
+	let (pool, _) = crate::test_pool_with("{ answer = 42; }", options).await;
+	let answer = |pool: NixSessionPool| async move {
+		let session = pool.get().await?;
+		crate::Value::binding(session, "answer")
+			.await?
+			.as_json::<u32>()
+			.await
+	};
+	assert_eq!(answer(pool.clone()).await?, 42);
+	// Session is idle, yet still fresh.
+	assert_eq!(answer(pool.clone()).await?, 42);
+	assert_eq!((pool.stats().created, pool.stats().recycled), (1, 0));
+
+	tokio::time::sleep(Duration::from_secs(1)).await;
+	assert_eq!(answer(pool.clone()).await?, 42);
+	let stats = pool.stats();
+	assert_eq!(stats.created, 2);
+	assert!(stats.recycled >= 1);
+	assert_eq!(stats.active + stats.idle, 1);
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn other_flake() {
+	let options = PoolOptions {
+		eval_timeout: Some(Duration::from_secs(5)),
+		..Default::default()
+	};
+	let pool = NixSessionPool::new(
+		"/src/current".into(),
+		vec!["--impure".into()],
+		"x86_64-linux".to_owned(),
+		options,
+	)
+	.await
+	.unwrap();
+	let other = pool
+		.new_for_flake("git+file:///src?ref=v1".into())
+		.await
+		.unwrap();
+	let (current, other) = (&pool.2, &other.2);
+	assert_eq!(other.nix_args, current.nix_args);
+	assert_eq!(other.nix_system, current.nix_system);
+	assert_eq!(other.options.eval_timeout, Some(Duration::from_secs(5)));
+	// Sessions are created lazily, and aren't shared between pools.
+	assert_eq!(pool.stats().created + pool.stats().idle as u64, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn independent_flakes() -> Result<()> {
+	let (pool, _) = crate::test_pool("{ answer = 1; }").await;
+	let dir = tempfile::tempdir().expect("tempdir");
+	crate::write_test_flake(dir.path(), "{ answer = 2; }");
+	let other = pool
+		.new_for_flake(format!("path:{}", dir.path().display()).into())
+		.await?;
+	let answer = |pool: NixSessionPool| async move {
+		let session = pool.get_query().await?;
+		crate::Value::binding(session, "answer")
+			.await?
+			.as_json::<u32>()
+			.await
+	};
+	assert_eq!(answer(pool.clone()).await?, 1);
+	assert_eq!(answer(other.clone()).await?, 2);
+	// Other flake is changed, yet only its own pool sees that.
+	crate::write_test_flake(dir.path(), "{ answer = 3; }");
+	other.reload();
+	assert_eq!(answer(other.clone()).await?, 3);
+	assert_eq!(answer(pool.clone()).await?, 1);
+	assert_eq!((pool.stats().created, other.stats().created), (1, 1));
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn reloaded_flake() -> Result<()> {
+	let (pool, dir) = crate::test_pool("{ answer = 1; }").await;
+	let answer = |pool: NixSessionPool| async move {
+		let session = pool.get().await?;
+		crate::Value::binding(session, "answer")
+			.await?
+			.as_json::<u32>()
+			.await
+	};
+	assert_eq!(answer(pool.clone()).await?, 1);
+	crate::write_test_flake(&dir, "{ answer = 2; }");
+	pool.reload();
+	assert_eq!(answer(pool.clone()).await?, 2);
+	// Session was reloaded, not replaced.
+	assert_eq!(pool.stats().created, 1);
+	Ok(())
+}
@@ -1,25 +1,44 @@
-use std::{ffi::OsStr, num::ParseIntError, process::Stdio, sync::Arc};
+use std::{
+	collections::{BTreeMap, HashMap},
+	ffi::{OsStr, OsString},
+	fmt,
+	num::ParseIntError,
+	path::{Path, PathBuf},
+	process::Stdio,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
 use better_command::{ClonableHandler, Handler, NixHandler, NoopHandler};
 use futures::StreamExt;
 use itertools::Itertools as _;
-use serde::{de::DeserializeOwned, Deserialize};
+use rand::{
+	distributions::{Alphanumeric, DistString},
+	thread_rng,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{
 	io::AsyncWriteExt,
-	process::{ChildStderr, ChildStdin, ChildStdout, Command},
+	process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
 	select,
 	sync::{mpsc, oneshot, Mutex},
 };
 use tokio_util::codec::{FramedRead, LinesCodec};
 use tracing::{debug, error, warn, Level};
 
+use crate::dialect::{NixVersion, ReplDialect};
+
 #[derive(Error, Debug, Clone)]
 pub enum Error {
 	#[error("failed to create nix repl session: {0}")]
 	SessionInit(&'static str),
-	#[error("unexpected end of output, nix crashed?")]
-	MissingDelimiter,
+	/// All values of the session are lost with the repl process.
+	#[error("nix repl process has exited (killed by OOM killer, or crashed?), values of this session can't be used anymore")]
+	SessionDied,
+	/// Same as [`Self::SessionDied`], for sessions started with [`ResourceLimits`].
+	#[error("nix repl process has exited, it has likely exceeded the resource limit ({0}), raise the limit or narrow the evaluation")]
+	SessionCrashed(ResourceLimits),
 
 	#[error("expression did'nt produce any output")]
 	ExpectedOutput,
@@ -34,6 +53,13 @@ pub enum Error {
 
 	#[error("output: {0}")]
 	Json(Arc<serde_json::Error>),
+	#[error("{error} at {attribute}, nix value is {got}")]
+	TypeMismatch {
+		attribute: String,
+		/// `builtins.typeOf` of the value
+		got: String,
+		error: String,
+	},
 	// int outputs are too specific, and should not be used,
 	// thus error is ok to be not informative.
 	#[error("int output: {0}")]
@@ -47,8 +73,112 @@ pub enum Error {
 	#[error("at {0}: {1}")]
 	InContext(String, Box<Self>),
 
+	/// Joined error message, and its frames if nix reported them in structured form.
 	#[error("error: {0}")]
-	NixError(String),
+	NixError(String, Vec<NixErrorFrame>),
+
+	#[error("{attribute} is not callable, expected lambda or attrset with __functor, got {got}")]
+	NotCallable { attribute: String, got: String },
+	#[error("invalid binding name: {0:?}")]
+	InvalidBindingName(String),
+	#[error("{attribute} is called with value of a different session")]
+	ForeignArgument { attribute: String },
+	#[error("{0} can't be built in query session, use build session instead")]
+	BuildInQuerySession(String),
+	#[error("{attribute} can't be evaluated again in other session, it isn't selected from top-level binding")]
+	NotReplayable { attribute: String },
+
+	#[error("expression output exceeds {0} bytes, try to narrow the query")]
+	OutputTooLarge(usize),
+	#[error("session output is out of sync after previous error")]
+	Broken,
+	#[error("evaluation timed out after {0:?}")]
+	Timeout(Duration),
+	#[error("build was cancelled")]
+	Cancelled,
+	#[error("undefined variable: {0}")]
+	UndefinedVariable(String),
+	#[error(
+		"infinite recursion while evaluating {attribute}, it might depend on itself:\n{error}"
+	)]
+	InfiniteRecursion { attribute: String, error: String },
+}
+impl Error {
+	/// Nix reports undefined variables while parsing expression, this error is not attributed to any value.
+	pub(crate) fn undefined_variable(self, name: &str) -> Self {
+		match &self {
+			Self::NixError(e, _) if is_undefined_variable(e, name) => {
+				Self::UndefinedVariable(name.to_owned())
+			}
+			_ => self,
+		}
+	}
+	/// Structured nix error frames, the first one is the error itself, followed by its trace.
+	pub fn frames(&self) -> &[NixErrorFrame] {
+		match self {
+			Self::NixError(_, frames) => frames,
+			Self::InContext(_, e) => e.frames(),
+			_ => &[],
+		}
+	}
+}
+
+/// Single entry of the nix error trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NixErrorFrame {
+	pub message: String,
+	pub file: Option<String>,
+	pub line: Option<u32>,
+	pub column: Option<u32>,
+}
+impl NixErrorFrame {
+	/// Parsed from the internal-json error message or its trace entry, fields are read leniently,
+	/// as their format differs between nix versions.
+	fn from_json(value: &serde_json::Value) -> Option<Self> {
+		let message = value.get("raw_msg")?.as_str()?;
+		let position = |name: &str| {
+			value
+				.get(name)
+				.and_then(serde_json::Value::as_u64)
+				.and_then(|v| u32::try_from(v).ok())
+		};
+		Some(Self {
+			message: strip_ansi(message).trim().to_owned(),
+			file: value
+				.get("file")
+				.and_then(serde_json::Value::as_str)
+				.map(str::to_owned),
+			line: position("line"),
+			column: position("column"),
+		})
+	}
+}
+impl fmt::Display for NixErrorFrame {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.message)?;
+		if let Some(file) = &self.file {
+			write!(f, "\n  at {file}")?;
+			if let (Some(line), Some(column)) = (self.line, self.column) {
+				write!(f, ":{line}:{column}")?;
+			}
+		}
+		Ok(())
+	}
+}
+/// Variable name might be highlighted, message is matched without quotes.
+fn is_undefined_variable(error: &str, name: &str) -> bool {
+	error.lines().any(|line| {
+		line.strip_prefix("undefined variable '")
+			.is_some_and(|rest| rest.contains(name))
+	})
+}
+/// Stack overflow is reported instead of infinite recursion when the cycle isn't detected directly.
+fn is_infinite_recursion(error: &str) -> bool {
+	error.lines().any(|line| {
+		line.contains("infinite recursion encountered")
+			|| line.starts_with("stack overflow")
+			|| line.contains("max-call-depth exceeded")
+	})
 }
 impl From<r2d2::Error> for Error {
 	fn from(value: r2d2::Error) -> Self {
@@ -67,7 +197,15 @@ impl From<serde_json::Error> for Error {
 }
 impl Error {
 	pub(crate) fn context(self, context: String) -> Self {
-		Self::InContext(context, Box::new(self))
+		match self {
+			Self::NixError(error, _) if is_infinite_recursion(&error) => Self::InfiniteRecursion {
+				attribute: context,
+				error,
+			},
+			// Innermost attribute is the most specific.
+			e @ Self::InfiniteRecursion { .. } => e,
+			e => Self::InContext(context, Box::new(e)),
+		}
 	}
 }
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -87,27 +225,34 @@ impl OutputHandler {
 		let (tx, rx) = mpsc::channel(20);
 		let (mut cancelled, _cancel_handle) = oneshot::channel();
 		tokio::spawn(async move {
+			let mut err_closed = false;
 			loop {
 				select! {
 					// We should receive errors earlier than synchronization
 					biased;
-					e = err.next() => {
-						let Some(Ok(e)) = e else {
-							if e.is_some() {
-								error!("bad repl stderr: {e:?}");
+					e = err.next(), if !err_closed => {
+						match e {
+							Some(Ok(e)) => {
+								let _ = tx.send(OutputLine::Err(e)).await;
 							}
-							continue;
-						};
-						let _ = tx.send(OutputLine::Err(e)).await;
+							Some(Err(e)) => error!("bad repl stderr: {e:?}"),
+							None => err_closed = true,
+						}
 					}
 					o = out.next() => {
-						let Some(Ok(o)) = o else {
-							if o.is_some() {
-								error!("bad repl stdout: {o:?}");
+						match o {
+							Some(Ok(o)) => {
+								let _ = tx.send(OutputLine::Out(o)).await;
+							}
+							Some(Err(e)) => error!("bad repl stdout: {e:?}"),
+							// Repl has exited, leftover errors might explain why.
+							None => {
+								while let Some(Ok(e)) = err.next().await {
+									let _ = tx.send(OutputLine::Err(e)).await;
+								}
+								break;
 							}
-							continue;
-						};
-						let _ = tx.send(OutputLine::Out(o)).await;
+						}
 					}
 					// Reader doesn't care about stdout, as this is cancelled.
 					// Error still might be useful, to process leftover span closures?
@@ -124,15 +269,73 @@ impl OutputHandler {
 	}
 }
 
+/// Accumulates expression output lines, bounded to avoid OOM on accidentally huge values.
+struct OutputBuffer {
+	out: String,
+	limit: Option<usize>,
+}
+impl OutputBuffer {
+	fn new(limit: Option<usize>) -> Self {
+		Self {
+			out: String::new(),
+			limit,
+		}
+	}
+	fn push_line(&mut self, line: &str) -> Result<()> {
+		if !self.out.is_empty() {
+			self.out.push('\n');
+		}
+		self.out.push_str(line);
+		match self.limit {
+			Some(limit) if self.out.len() > limit => Err(Error::OutputTooLarge(limit)),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Remove terminal color sequences, nix highlights parts of its messages.
+fn strip_ansi(msg: &str) -> String {
+	let mut out = String::with_capacity(msg.len());
+	let mut chars = msg.chars();
+	while let Some(c) = chars.next() {
+		if c == '\u{1b}' {
+			// CSI sequence is terminated by a letter.
+			for c in chars.by_ref() {
+				if c.is_ascii_alphabetic() {
+					break;
+				}
+			}
+			continue;
+		}
+		out.push(c);
+	}
+	out
+}
+
+/// Messages emitted by the evaluated code itself, using `builtins.trace` and `builtins.warn`.
+fn diagnostic_message(level: u32, msg: &str) -> Option<String> {
+	let msg = strip_ansi(msg);
+	let is_diagnostic = match level {
+		0 => msg.starts_with("trace: "),
+		1 => msg.starts_with("evaluation warning: "),
+		_ => false,
+	};
+	is_diagnostic.then(|| msg.trim_end().to_owned())
+}
+
 #[must_use]
 struct ErrorCollector<'i, H> {
 	collected: Vec<String>,
+	frames: Vec<NixErrorFrame>,
+	diagnostics: Vec<String>,
 	inner: &'i mut H,
 }
 impl<'i, H> ErrorCollector<'i, H> {
 	fn new(inner: &'i mut H) -> Self {
 		Self {
 			collected: vec![],
+			frames: vec![],
+			diagnostics: vec![],
 			inner,
 		}
 	}
@@ -147,17 +350,41 @@ impl<H> ErrorCollector<'_, H> {
 			action: String,
 			level: u32,
 			msg: String,
+			#[serde(default)]
+			raw_msg: Option<serde_json::Value>,
+			#[serde(default)]
+			trace: Vec<serde_json::Value>,
+			#[serde(flatten)]
+			position: serde_json::Map<String, serde_json::Value>,
 		}
 		let Ok(act) = serde_json::from_str::<ErrorAction>(msg) else {
 			return false;
 		};
-		if act.action != "msg" || act.level != 0 {
+		if act.action != "msg" {
+			return false;
+		}
+		if let Some(diagnostic) = diagnostic_message(act.level, &act.msg) {
+			self.diagnostics.push(diagnostic);
+			return true;
+		}
+		if act.level != 0 {
 			return false;
 		}
+		if let Some(raw_msg) = act.raw_msg {
+			let mut error = act.position;
+			error.insert("raw_msg".to_owned(), raw_msg);
+			self.frames
+				.extend(NixErrorFrame::from_json(&serde_json::Value::Object(error)));
+			self.frames
+				.extend(act.trace.iter().filter_map(NixErrorFrame::from_json));
+		}
 		self.collected.push(act.msg);
 		true
 	}
 	fn finish(self) -> Result<()> {
+		for line in &self.diagnostics {
+			warn!("{line}");
+		}
 		// fn dedent(s: String) -> String {
 		// 	s.split('\n').filter(|s| !s.trim().is_empty()).map(|v| v.)
 		// }
@@ -175,12 +402,13 @@ impl<H> ErrorCollector<'_, H> {
 					})
 					.join("\n")
 					.to_string(),
+				self.frames,
 			));
 		}
 		Ok(())
 	}
 	fn flush(self) {
-		for line in self.collected {
+		for line in self.diagnostics.into_iter().chain(self.collected) {
 			warn!("{line}");
 		}
 	}
@@ -194,11 +422,162 @@ impl<H: Handler> Handler for ErrorCollector<'_, H> {
 	}
 }
 
+#[derive(Deserialize)]
+struct BuiltDerivation {
+	outputs: HashMap<String, PathBuf>,
+}
+fn parse_build_json(output: &[u8]) -> Result<HashMap<String, PathBuf>> {
+	let built: Vec<BuiltDerivation> = serde_json::from_slice(output)?;
+	built
+		.into_iter()
+		.next()
+		.map(|b| b.outputs)
+		.ok_or(Error::ExpectedOutput)
+}
+
+/// Build derivation by separate nix process, so that the repl session is not locked for the build duration.
+pub(crate) async fn build_detached(
+	drv_path: &str,
+	nix_args: &[OsString],
+) -> Result<HashMap<String, PathBuf>> {
+	let mut cmd = Command::new("nix");
+	cmd.arg("build")
+		.args(nix_args)
+		.arg("--no-link")
+		.arg("--json")
+		.arg("--log-format")
+		.arg("internal-json")
+		.arg(format!("{drv_path}^*"));
+	cmd.stdin(Stdio::null());
+	cmd.stdout(Stdio::piped());
+	cmd.stderr(Stdio::piped());
+	// Build is stopped if it is cancelled.
+	cmd.kill_on_drop(true);
+	let mut child = cmd.spawn()?;
+
+	let mut nix_handler = NixHandler::default();
+	let mut collected = ErrorCollector::new(&mut nix_handler);
+	let mut err = FramedRead::new(child.stderr.take().expect("piped"), LinesCodec::new());
+	while let Some(line) = err.next().await {
+		match line {
+			Ok(line) => collected.handle_line(&line),
+			Err(e) => error!("bad nix build stderr: {e}"),
+		}
+	}
+	let output = child.wait_with_output().await?;
+	if !output.status.success() {
+		collected.finish()?;
+		return Err(Error::NixError(
+			format!("nix build exited with {}", output.status),
+			vec![],
+		));
+	}
+	collected.flush();
+	parse_build_json(&output.stdout)
+}
+
+/// Package changed between two closures, as reported by `nix store diff-closures`.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ClosureDiffEntry {
+	#[serde(skip_deserializing)]
+	pub name: String,
+	/// Versions in the older closure, empty if the package was added
+	#[serde(default)]
+	pub versions_before: Vec<String>,
+	/// Versions in the newer closure, empty if the package was removed
+	#[serde(default)]
+	pub versions_after: Vec<String>,
+	/// Size difference in bytes
+	#[serde(default)]
+	pub size_delta: i64,
+}
+/// Parse `nix store diff-closures --json` output, entries are sorted by package name.
+///
+/// Command itself is not executed here, as closures are usually compared on the remote host.
+pub fn parse_diff_closures(output: &[u8]) -> Result<Vec<ClosureDiffEntry>> {
+	#[derive(Deserialize)]
+	struct DiffClosures {
+		packages: BTreeMap<String, ClosureDiffEntry>,
+	}
+	let diff: DiffClosures = serde_json::from_slice(output)?;
+	Ok(diff
+		.packages
+		.into_iter()
+		.map(|(name, entry)| ClosureDiffEntry { name, ..entry })
+		.collect())
+}
+/// Content hash of the store path, as registered in the nix database.
+pub(crate) async fn query_path_hash(path: &Path) -> Result<String> {
+	let output = Command::new("nix-store")
+		.arg("--query")
+		.arg("--hash")
+		.arg(path)
+		.stdin(Stdio::null())
+		.output()
+		.await?;
+	if !output.status.success() {
+		return Err(Error::NixError(
+			format!(
+				"failed to query hash of {path:?}: {}",
+				String::from_utf8_lossy(&output.stderr).trim()
+			),
+			vec![],
+		));
+	}
+	parse_path_hash(&output.stdout)
+}
+fn parse_path_hash(output: &[u8]) -> Result<String> {
+	let hash = std::str::from_utf8(output)
+		.map_err(|_| Error::InvalidType)?
+		.trim();
+	if !hash.starts_with("sha256:") || hash.contains(char::is_whitespace) {
+		return Err(Error::InvalidType);
+	}
+	Ok(hash.to_owned())
+}
+
+/// Whether all the paths are registered in the local store.
+pub(crate) async fn query_valid_paths(paths: &[String]) -> Result<bool> {
+	if paths.is_empty() {
+		return Ok(true);
+	}
+	let output = Command::new("nix-store")
+		.arg("--check-validity")
+		.arg("--print-invalid")
+		.args(paths)
+		.stdin(Stdio::null())
+		.output()
+		.await?;
+	if !output.status.success() {
+		return Err(Error::NixError(
+			format!(
+				"failed to check validity of {paths:?}: {}",
+				String::from_utf8_lossy(&output.stderr).trim()
+			),
+			vec![],
+		));
+	}
+	Ok(parse_invalid_paths(&output.stdout).is_empty())
+}
+fn parse_invalid_paths(output: &[u8]) -> Vec<&str> {
+	std::str::from_utf8(output)
+		.unwrap_or_default()
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty())
+		.collect()
+}
+
 pub struct NixSessionInner {
+	/// Expression, evaluating to [`Self::full_delimiter`]
+	delimiter: String,
 	full_delimiter: String,
 	nix_handler: ClonableHandler<NixHandler>,
 	out: OutputHandler,
 	stdin: ChildStdin,
+	/// Killed on drop, evaluation might still be running when the session is discarded.
+	child: Child,
 	string_wrapping: (String, String),
 	number_wrapping: (String, String),
 
@@ -208,10 +587,87 @@ pub struct NixSessionInner {
 	pub(crate) free_list: Vec<u32>,
 
 	pub nix_system: String,
+	pub(crate) nix_args: Vec<OsString>,
+	pub nix_version: Option<NixVersion>,
+	pub(crate) dialect: ReplDialect,
+	pub(crate) created_at: Instant,
+	/// Pool generation the flake was (re)loaded at, see [`crate::NixSessionPool::reload`]
+	pub(crate) generation: u64,
+	max_output: Option<usize>,
+	eval_timeout: Option<Duration>,
+	/// Output of the failed command wasn't consumed, session can't be used anymore.
+	pub(crate) broken: bool,
+	/// Repl process has exited, implies `broken`.
+	died: bool,
+	limits: ResourceLimits,
+	/// Traces and warnings emitted by the last evaluated expression
+	pub(crate) last_diagnostics: Vec<String>,
 }
 
-/// Discover inter-message repl delimiter
-const REPL_DELIMITER: &str = "\"FLEET_MAGIC_REPL_DELIMITER\"";
+/// Limits of the repl process, applied with `setrlimit`, process is killed once it exceeds them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+	/// Address space of the process, in bytes
+	pub memory_max: Option<u64>,
+	/// CPU time consumed by the process during its whole lifetime, not only by a single
+	/// expression, see [`crate::PoolOptions::eval_timeout`] for that.
+	pub cpu_time_max: Option<Duration>,
+}
+impl ResourceLimits {
+	fn is_limited(&self) -> bool {
+		self.memory_max.is_some() || self.cpu_time_max.is_some()
+	}
+	fn apply(self, cmd: &mut Command) {
+		if !self.is_limited() {
+			return;
+		}
+		let limits = [
+			(libc::RLIMIT_AS, self.memory_max),
+			(
+				libc::RLIMIT_CPU,
+				self.cpu_time_max.map(|t| t.as_secs().max(1)),
+			),
+		];
+		// SAFETY: setrlimit is async-signal-safe, and nothing is allocated in the child.
+		unsafe {
+			cmd.pre_exec(move || {
+				for (resource, limit) in limits {
+					let Some(limit) = limit else {
+						continue;
+					};
+					let limit = libc::rlimit {
+						rlim_cur: limit as libc::rlim_t,
+						rlim_max: limit as libc::rlim_t,
+					};
+					if libc::setrlimit(resource, &limit) != 0 {
+						return Err(std::io::Error::last_os_error());
+					}
+				}
+				Ok(())
+			});
+		}
+	}
+}
+impl fmt::Display for ResourceLimits {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut limits = vec![];
+		if let Some(memory) = self.memory_max {
+			limits.push(format!("memory {} MiB", memory / 1024 / 1024));
+		}
+		if let Some(time) = self.cpu_time_max {
+			limits.push(format!("cpu time {time:?}"));
+		}
+		write!(f, "{}", limits.join(", "))
+	}
+}
+
+/// Discover inter-message repl delimiter.
+///
+/// It is unique per session, so that evaluated values can't be mistaken for the delimiter.
+fn repl_delimiter() -> String {
+	let nonce = Alphanumeric.sample_string(&mut thread_rng(), 24);
+	format!("\"FLEET_REPL_DELIMITER_{nonce}\"")
+}
 /// Discover formatting around strings
 const TRAIN_STRING: &str = "\"TRAIN_STRING\"";
 /// Discover formatting around numbers
@@ -225,26 +681,58 @@ impl NixSessionInner {
 		flake: &OsStr,
 		extra_args: impl IntoIterator<Item = &OsStr>,
 		nix_system: String,
+		max_output: Option<usize>,
+		eval_timeout: Option<Duration>,
+		limits: ResourceLimits,
 	) -> Result<Self> {
+		let nix_version = NixVersion::detect().await;
+		if let Some(version) = nix_version {
+			debug!("detected nix {version}");
+		}
 		let mut cmd = Command::new("nix");
 		cmd.arg("repl")
 			.args(["--option", "pure-eval", "true"])
 			.arg(flake)
 			.arg("--log-format")
 			.arg("internal-json");
-		for arg in extra_args {
-			cmd.arg(arg);
-		}
+		let nix_args = extra_args
+			.into_iter()
+			.map(OsStr::to_owned)
+			.collect::<Vec<_>>();
+		cmd.args(&nix_args);
+		limits.apply(&mut cmd);
+		let mut session = Self::start(
+			cmd,
+			nix_system,
+			nix_args,
+			nix_version,
+			max_output,
+			eval_timeout,
+		)
+		.await?;
+		session.limits = limits;
+		Ok(session)
+	}
+	pub(crate) async fn start(
+		mut cmd: Command,
+		nix_system: String,
+		nix_args: Vec<OsString>,
+		nix_version: Option<NixVersion>,
+		max_output: Option<usize>,
+		eval_timeout: Option<Duration>,
+	) -> Result<Self> {
 		cmd.stdin(Stdio::piped());
 		cmd.stdout(Stdio::piped());
 		cmd.stderr(Stdio::piped());
-		let cmd = cmd.spawn()?;
-		let stdout = cmd.stdout.unwrap();
-		let stderr = cmd.stderr.unwrap();
+		cmd.kill_on_drop(true);
+		let mut child = cmd.spawn()?;
+		let stdout = child.stdout.take().unwrap();
+		let stderr = child.stderr.take().unwrap();
 		let mut out = OutputHandler::new(stdout, stderr);
-		let mut stdin = cmd.stdin.unwrap();
+		let mut stdin = child.stdin.take().unwrap();
+		let delimiter = repl_delimiter();
 		// Standard repl hello doesn't work with internal-json logger
-		stdin.write_all(REPL_DELIMITER.as_bytes()).await?;
+		stdin.write_all(delimiter.as_bytes()).await?;
 		stdin.write_all(b"\n").await?;
 		stdin.flush().await?;
 		let nix_handler = NixHandler::default();
@@ -259,7 +747,7 @@ impl NixSessionInner {
 					continue;
 				}
 			};
-			if line.contains(REPL_DELIMITER) {
+			if line.contains(&delimiter) {
 				debug!("discovered repl delimiter with added colors: {line}");
 				full_delimiter = Some(line.to_owned());
 				break;
@@ -272,10 +760,12 @@ impl NixSessionInner {
 			return Err(Error::SessionInit("failed to discover delimiter"));
 		};
 		let mut res = Self {
+			delimiter,
 			full_delimiter,
 			nix_handler: ClonableHandler::new(nix_handler),
 			out,
 			stdin,
+			child,
 			string_wrapping: Default::default(),
 			number_wrapping: Default::default(),
 
@@ -285,6 +775,17 @@ impl NixSessionInner {
 			free_list: vec![],
 
 			nix_system,
+			nix_args,
+			nix_version,
+			dialect: ReplDialect::for_version(nix_version),
+			created_at: Instant::now(),
+			generation: 0,
+			max_output,
+			eval_timeout,
+			broken: false,
+			died: false,
+			limits: ResourceLimits::default(),
+			last_diagnostics: vec![],
 		};
 		res.train().await?;
 		Ok(res)
@@ -311,7 +812,7 @@ impl NixSessionInner {
 		Ok(())
 	}
 	async fn send_command(&mut self, cmd: impl AsRef<[u8]>) -> Result<()> {
-		if tracing::enabled!(Level::DEBUG) && cmd.as_ref() != REPL_DELIMITER.as_bytes() {
+		if tracing::enabled!(Level::DEBUG) && cmd.as_ref() != self.delimiter.as_bytes() {
 			let cmd_str = String::from_utf8_lossy(cmd.as_ref());
 			tracing::debug!("{cmd_str}");
 		};
@@ -320,7 +821,7 @@ impl NixSessionInner {
 		Ok(())
 	}
 	async fn read_until_delimiter(&mut self, err_handler: &mut dyn Handler) -> Result<String> {
-		let mut out = String::new();
+		let mut out = OutputBuffer::new(self.max_output);
 		while let Some(line) = self.out.next().await {
 			let line = match line {
 				OutputLine::Out(out) => out,
@@ -330,14 +831,15 @@ impl NixSessionInner {
 				}
 			};
 			if line == self.full_delimiter {
-				return Ok(out);
+				return Ok(out.out);
 			}
-			if !out.is_empty() {
-				out.push('\n');
+			if let Err(e) = out.push_line(&line) {
+				// Rest of the output is left unread, next command would receive it instead of its own.
+				self.broken = true;
+				return Err(e);
 			}
-			out.push_str(&line);
 		}
-		Err(Error::MissingDelimiter)
+		Err(Error::SessionDied)
 	}
 	pub(crate) async fn execute_expression_number(
 		&mut self,
@@ -380,7 +882,8 @@ impl NixSessionInner {
 	) -> Result<String> {
 		let mut nix_handler = self.nix_handler.clone();
 		let mut collected = ErrorCollector::new(&mut nix_handler);
-		let res = self.execute_expression_raw(expr, &mut collected).await?;
+		let res = self.execute_expression_timed(expr, &mut collected).await?;
+		self.last_diagnostics = collected.diagnostics.clone();
 		if res.is_empty() {
 			collected.finish()?;
 			return Err(Error::ExpectedOutput);
@@ -395,16 +898,42 @@ impl NixSessionInner {
 		};
 		Ok(res.to_owned())
 	}
-	async fn execute_expression_empty(&mut self, expr: impl AsRef<[u8]>) -> Result<()> {
+	/// Reload the flake from disk, keeping evaluator caches warm.
+	///
+	/// Variables bound in the session are lost, thus no values of the session should be alive.
+	pub(crate) async fn reload(&mut self) -> Result<()> {
 		let mut nix_handler = self.nix_handler.clone();
 		let mut collected = ErrorCollector::new(&mut nix_handler);
-		let v = self.execute_expression_raw(expr, &mut collected).await?;
+		// Output only tells how many variables were loaded.
+		self.execute_expression_timed(":r", &mut collected).await?;
+		collected.finish()
+	}
+	pub(crate) async fn execute_expression_empty(&mut self, expr: impl AsRef<[u8]>) -> Result<()> {
+		let mut nix_handler = self.nix_handler.clone();
+		let mut collected = ErrorCollector::new(&mut nix_handler);
+		let v = self.execute_expression_timed(expr, &mut collected).await?;
+		self.last_diagnostics = collected.diagnostics.clone();
 		collected.finish()?;
 		if !v.is_empty() {
 			return Err(Error::UnexpectedOutput);
 		}
 		Ok(())
 	}
+	/// Builds are not limited by the timeout, only evaluation is.
+	async fn execute_expression_timed(
+		&mut self,
+		expr: impl AsRef<[u8]>,
+		err_handler: &mut dyn Handler,
+	) -> Result<String> {
+		let Some(timeout) = self.eval_timeout else {
+			return self.execute_expression_raw(expr, err_handler).await;
+		};
+		match tokio::time::timeout(timeout, self.execute_expression_raw(expr, err_handler)).await {
+			Ok(res) => res,
+			// Interrupted command has marked the session as broken.
+			Err(_) => Err(Error::Timeout(timeout)),
+		}
+	}
 	pub(crate) async fn execute_expression_raw(
 		&mut self,
 		expr: impl AsRef<[u8]>,
@@ -413,11 +942,47 @@ impl NixSessionInner {
 		// Prevent two commands from being executed in parallel, messing with each other.
 		let _lock = self.executing_command.clone();
 		let _guard = _lock.lock().await;
+		if self.died {
+			return Err(self.died_error());
+		}
+		if self.broken {
+			return Err(Error::Broken);
+		}
+		// Reset once the output is consumed, if the command is interrupted (timed out, or the
+		// build is cancelled), its output would be received by the next command.
+		self.broken = true;
 
-		self.send_command(expr).await?;
-		// It will be echoed
-		self.send_command(REPL_DELIMITER).await?;
-		self.read_until_delimiter(err_handler).await
+		let out = async {
+			self.send_command(expr).await?;
+			// It will be echoed
+			let delimiter = self.delimiter.clone();
+			self.send_command(delimiter).await?;
+			self.read_until_delimiter(err_handler).await
+		}
+		.await;
+		match out {
+			Ok(out) => {
+				self.broken = false;
+				Ok(out)
+			}
+			Err(Error::SessionDied) => {
+				self.died = true;
+				Err(self.died_error())
+			}
+			// Write to the exited process fails with broken pipe.
+			Err(Error::Io(_)) if matches!(self.child.try_wait(), Ok(Some(_))) => {
+				self.died = true;
+				Err(self.died_error())
+			}
+			Err(e) => Err(e),
+		}
+	}
+	fn died_error(&self) -> Error {
+		if self.limits.is_limited() {
+			Error::SessionCrashed(self.limits)
+		} else {
+			Error::SessionDied
+		}
 	}
 	pub(crate) async fn execute_assign(&mut self, expr: impl AsRef<str>) -> Result<u32> {
 		let id = self.allocate_id();
@@ -444,3 +1009,346 @@ impl NixSessionInner {
 	// 	Ok(())
 	// }
 }
+
+#[test]
+fn closure_diff() {
+	// nix store diff-closures --json output, as of nix 2.28
+	let output = br#"{"packages":{"hello":{"sizeDelta":0,"versionsAfter":["2.12"],"versionsBefore":[]},"firefox":{"sizeDelta":1049088,"versionsAfter":["126.0"],"versionsBefore":["125.0"]},"libfoo":{"sizeDelta":-20480,"versionsAfter":[],"versionsBefore":["1.0","1.1"]}}}"#;
+	assert_eq!(
+		parse_diff_closures(output).unwrap(),
+		[
+			ClosureDiffEntry {
+				name: "firefox".to_owned(),
+				versions_before: vec!["125.0".to_owned()],
+				versions_after: vec!["126.0".to_owned()],
+				size_delta: 1049088,
+			},
+			ClosureDiffEntry {
+				name: "hello".to_owned(),
+				versions_before: vec![],
+				versions_after: vec!["2.12".to_owned()],
+				size_delta: 0,
+			},
+			ClosureDiffEntry {
+				name: "libfoo".to_owned(),
+				versions_before: vec!["1.0".to_owned(), "1.1".to_owned()],
+				versions_after: vec![],
+				size_delta: -20480,
+			},
+		]
+	);
+	assert!(parse_diff_closures(b"firefox: 125.0 \xe2\x86\x92 126.0").is_err());
+}
+
+#[test]
+fn detached_build_output() {
+	// nix build --json output, as of nix 2.24
+	let output = br#"[{"drvPath":"/nix/store/aaa-openssl.drv","outputs":{"dev":"/nix/store/bbb-openssl-dev","out":"/nix/store/ccc-openssl"},"startTime":0,"stopTime":0}]"#;
+	assert_eq!(
+		parse_build_json(output).unwrap(),
+		HashMap::from([
+			(
+				"dev".to_owned(),
+				PathBuf::from("/nix/store/bbb-openssl-dev")
+			),
+			("out".to_owned(), PathBuf::from("/nix/store/ccc-openssl")),
+		])
+	);
+	assert!(matches!(
+		parse_build_json(b"[]"),
+		Err(Error::ExpectedOutput)
+	));
+}
+
+#[test]
+fn path_hashes() {
+	let a =
+		parse_path_hash(b"sha256:1jgq6qhqzjv2xrw0xcyk9hwn3dqkm2m7zlnyjb0nyl2s6yi1hzsp\n").unwrap();
+	let b =
+		parse_path_hash(b"sha256:0c2bxr5l2zyb6wa5dsyhcxdrjfhm3ydzz0i8pghr9ncj6vrvbrw4\n").unwrap();
+	assert_eq!(
+		a,
+		"sha256:1jgq6qhqzjv2xrw0xcyk9hwn3dqkm2m7zlnyjb0nyl2s6yi1hzsp"
+	);
+	assert_ne!(a, b);
+	assert!(parse_path_hash(b"error: path is not valid\n").is_err());
+	assert!(parse_path_hash(b"").is_err());
+}
+
+#[test]
+fn invalid_paths() {
+	let built = "/nix/store/1jgq6qhqzjv2xrw0xcyk9hwn3dqkm2m7-hello-2.12";
+	let unbuilt = "/nix/store/0c2bxr5l2zyb6wa5dsyhcxdrjfhm3ydz-hello-2.12";
+	// Only invalid paths are printed, built paths are missing from the output.
+	assert_eq!(
+		parse_invalid_paths(format!("{unbuilt}\n").as_bytes()),
+		[unbuilt]
+	);
+	assert!(parse_invalid_paths(b"").is_empty());
+	assert!(parse_invalid_paths(b"\n").is_empty());
+	let output = format!("{unbuilt}\n{built}\n");
+	assert_eq!(parse_invalid_paths(output.as_bytes()), [unbuilt, built]);
+}
+
+#[test]
+fn output_limit() {
+	let mut out = OutputBuffer::new(Some(8));
+	out.push_line("1234").unwrap();
+	out.push_line("567").unwrap();
+	assert_eq!(out.out, "1234\n567");
+	assert!(matches!(out.push_line("8"), Err(Error::OutputTooLarge(8))));
+
+	let mut unlimited = OutputBuffer::new(None);
+	unlimited.push_line(&"x".repeat(1 << 20)).unwrap();
+}
+
+#[test]
+fn undefined_variable() {
+	let plain = "undefined variable 'fleetConfigurations'\n\n       at «string»:1:14:";
+	assert!(is_undefined_variable(plain, "fleetConfigurations"));
+	let highlighted = "undefined variable '\u{1b}[35;1mfleetConfigurations\u{1b}[0m'";
+	assert!(is_undefined_variable(highlighted, "fleetConfigurations"));
+	assert!(!is_undefined_variable(
+		"attribute 'default' missing",
+		"fleetConfigurations"
+	));
+	assert!(matches!(
+		Error::NixError(plain.to_owned(), vec![]).undefined_variable("fleetConfigurations"),
+		Error::UndefinedVariable(_)
+	));
+}
+
+#[test]
+fn infinite_recursion() {
+	for error in [
+		"infinite recursion encountered\n\n       at «string»:1:14:",
+		"stack overflow (possible infinite recursion)",
+		"stack overflow; max-call-depth exceeded\n       at «string»:1:1:",
+	] {
+		match Error::NixError(error.to_owned(), vec![]).context("config.a".to_owned()) {
+			Error::InfiniteRecursion { attribute, .. } => assert_eq!(attribute, "config.a"),
+			e => panic!("unexpected error: {e}"),
+		}
+	}
+	let recursion = Error::NixError("infinite recursion encountered".to_owned(), vec![])
+		.context("config.a.b".to_owned())
+		.context("config.a".to_owned());
+	assert!(
+		matches!(recursion, Error::InfiniteRecursion { attribute, .. } if attribute == "config.a.b")
+	);
+	assert!(matches!(
+		Error::NixError("attribute 'recursion' missing".to_owned(), vec![])
+			.context("config".to_owned()),
+		Error::InContext(..)
+	));
+}
+
+#[test]
+fn collected_diagnostics() {
+	let mut handler = NoopHandler;
+	let mut collected = ErrorCollector::new(&mut handler);
+	for line in [
+		r#"@nix {"action":"msg","level":0,"msg":"\u001b[1mtrace: \u001b[0mevaluating a"}"#,
+		r#"@nix {"action":"msg","level":1,"msg":"\u001b[1;35mevaluation warning:\u001b[0m b is deprecated"}"#,
+		r#"@nix {"action":"msg","level":1,"msg":"\u001b[35;1mwarning:\u001b[0m Git tree is dirty"}"#,
+		r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m attribute 'c' missing"}"#,
+	] {
+		collected.handle_line(line);
+	}
+	assert_eq!(
+		collected.diagnostics,
+		["trace: evaluating a", "evaluation warning: b is deprecated"]
+	);
+	// Traces are not reported as part of the error.
+	let Err(Error::NixError(e, _)) = collected.finish() else {
+		panic!("error expected");
+	};
+	assert_eq!(e, "attribute 'c' missing");
+}
+
+#[tokio::test]
+async fn delimiter_collision() {
+	// `cat` echoes every expression back, the same way repl prints the string value.
+	let mut session = NixSessionInner::start(
+		Command::new("cat"),
+		"x86_64-linux".to_owned(),
+		vec![],
+		None,
+		None,
+		None,
+	)
+	.await
+	.expect("session started");
+	let old_delimiter = "\"FLEET_MAGIC_REPL_DELIMITER\"";
+	for expr in [old_delimiter, "\"value\""] {
+		let out = session
+			.execute_expression_raw(expr, &mut NoopHandler)
+			.await
+			.expect("executed");
+		assert_eq!(out, expr);
+	}
+	assert_ne!(repl_delimiter(), repl_delimiter());
+}
+
+#[tokio::test]
+async fn evaluation_timeout() {
+	// Echoes expressions like `cat`, but never finishes evaluating the `hang` one.
+	let mut cmd = Command::new("sh");
+	cmd.arg("-c").arg(
+		r#"while read -r l; do case "$l" in hang) exec sleep 100;; *) echo "$l";; esac; done"#,
+	);
+	let mut session = NixSessionInner::start(
+		cmd,
+		"x86_64-linux".to_owned(),
+		vec![],
+		None,
+		None,
+		Some(Duration::from_millis(200)),
+	)
+	.await
+	.expect("session started");
+	assert_eq!(
+		session
+			.execute_expression_timed("\"value\"", &mut NoopHandler)
+			.await
+			.expect("executed"),
+		"\"value\""
+	);
+	assert!(matches!(
+		session
+			.execute_expression_timed("hang", &mut NoopHandler)
+			.await,
+		Err(Error::Timeout(_))
+	));
+	// Session is replaced by the pool.
+	assert!(session.broken);
+	assert!(matches!(
+		session
+			.execute_expression_timed("\"value\"", &mut NoopHandler)
+			.await,
+		Err(Error::Broken)
+	));
+}
+
+#[test]
+fn error_frames() {
+	let mut handler = NoopHandler;
+	let mut collected = ErrorCollector::new(&mut handler);
+	collected.handle_line(
+		r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m\n       … while evaluating\n\n       error: host is broken","raw_msg":"host is \u001b[35;1mbroken\u001b[0m","file":"/src/hosts.nix","line":12,"column":5,"trace":[{"raw_msg":"while evaluating the attribute 'ips'","file":"/src/flake.nix","line":3,"column":7},{"raw_msg":"while calling anonymous lambda","file":null,"line":null,"column":null}]}"#,
+	);
+	let Err(e) = collected.finish() else {
+		panic!("error expected");
+	};
+	let e = e.context("config.hosts.web".to_owned());
+	let frames = e.frames();
+	assert_eq!(
+		frames[0],
+		NixErrorFrame {
+			message: "host is broken".to_owned(),
+			file: Some("/src/hosts.nix".to_owned()),
+			line: Some(12),
+			column: Some(5),
+		}
+	);
+	assert_eq!(
+		frames[1].to_string(),
+		"while evaluating the attribute 'ips'\n  at /src/flake.nix:3:7"
+	);
+	assert_eq!(frames[2].to_string(), "while calling anonymous lambda");
+	assert!(e.to_string().contains("error: host is broken"));
+}
+
+#[tokio::test]
+async fn interrupted_command() {
+	let mut cmd = Command::new("sh");
+	cmd.arg("-c").arg(
+		r#"while read -r l; do case "$l" in hang) exec sleep 100;; *) echo "$l";; esac; done"#,
+	);
+	let mut session =
+		NixSessionInner::start(cmd, "x86_64-linux".to_owned(), vec![], None, None, None)
+			.await
+			.expect("session started");
+	session
+		.execute_expression_raw("\"value\"", &mut NoopHandler)
+		.await
+		.expect("executed");
+	assert!(!session.broken);
+	// Command future is dropped, i.e its build was cancelled.
+	let interrupted = tokio::time::timeout(
+		Duration::from_millis(200),
+		session.execute_expression_raw("hang", &mut NoopHandler),
+	)
+	.await;
+	assert!(interrupted.is_err());
+	assert!(session.broken);
+}
+
+#[tokio::test]
+async fn died_session() {
+	let mut cmd = Command::new("sh");
+	cmd.arg("-c")
+		.arg(r#"while read -r l; do case "$l" in die) exit 1;; *) echo "$l";; esac; done"#);
+	let mut session =
+		NixSessionInner::start(cmd, "x86_64-linux".to_owned(), vec![], None, None, None)
+			.await
+			.expect("session started");
+	assert!(matches!(
+		session
+			.execute_expression_raw("die", &mut NoopHandler)
+			.await,
+		Err(Error::SessionDied)
+	));
+	assert!(session.broken);
+	// Reported the same way for the later commands, instead of generic out of sync error.
+	assert!(matches!(
+		session
+			.execute_expression_raw("\"value\"", &mut NoopHandler)
+			.await,
+		Err(Error::SessionDied)
+	));
+}
+
+#[tokio::test]
+async fn resource_limits() {
+	let mut cmd = Command::new("sh");
+	cmd.arg("-c").arg(
+		r#"while read -r l; do case "$l" in burn) while :; do :; done;; *) echo "$l";; esac; done"#,
+	);
+	let limits = ResourceLimits {
+		memory_max: None,
+		cpu_time_max: Some(Duration::from_secs(1)),
+	};
+	limits.apply(&mut cmd);
+	let mut session =
+		NixSessionInner::start(cmd, "x86_64-linux".to_owned(), vec![], None, None, None)
+			.await
+			.expect("session started");
+	session.limits = limits;
+	let err = tokio::time::timeout(
+		Duration::from_secs(30),
+		session.execute_expression_raw("burn", &mut NoopHandler),
+	)
+	.await
+	.expect("process is killed by the limit")
+	.unwrap_err();
+	assert!(matches!(err, Error::SessionCrashed(l) if l == limits));
+	assert!(err.to_string().contains("(cpu time 1s)"), "{err}");
+}
+
+#[tokio::test]
+async fn reloaded_session() {
+	// Flake fails to load on the second reload.
+	let mut cmd = Command::new("sh");
+	cmd.arg("-c").arg(
+		r#"n=0; while read -r l; do case "$l" in :r) n=$((n+1)); if [ $n -gt 1 ]; then echo '@nix {"action":"msg","level":0,"msg":"error: syntax error, unexpected end of file"}' >&2; else echo "Added 1 variables."; fi;; *) echo "$l";; esac; done"#,
+	);
+	let mut session =
+		NixSessionInner::start(cmd, "x86_64-linux".to_owned(), vec![], None, None, None)
+			.await
+			.expect("session started");
+	session.reload().await.expect("reloaded");
+	assert!(!session.broken);
+	let err = session.reload().await.unwrap_err();
+	assert!(err.to_string().contains("syntax error"), "{err}");
+}
@@ -1,9 +1,15 @@
 use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc};
 
 use better_command::NixHandler;
+use futures::future::BoxFuture;
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::{macros::NixExprBuilder, nix_go, Error, NixBuildBatch, NixSession, Result};
+use crate::{
+	macros::NixExprBuilder,
+	nix_go, nix_go_json,
+	session::{build_detached, query_valid_paths},
+	Error, NixBuildBatch, NixSession, Result, SessionKind,
+};
 
 #[derive(Clone)]
 pub enum Index {
@@ -80,10 +86,95 @@ impl fmt::Display for PathDisplay<'_> {
 		Ok(())
 	}
 }
+pub struct OutPaths {
+	pub paths: HashMap<String, PathBuf>,
+	/// Attributes, for which evaluation has failed
+	pub failed: Vec<String>,
+}
+impl OutPaths {
+	fn from_evaluated(evaluated: HashMap<String, Option<PathBuf>>) -> Self {
+		let mut paths = HashMap::new();
+		let mut failed = vec![];
+		for (name, path) in evaluated {
+			match path {
+				Some(path) => {
+					paths.insert(name, path);
+				}
+				None => failed.push(name),
+			}
+		}
+		failed.sort();
+		Self { paths, failed }
+	}
+}
+fn out_paths_query(field: &str, path: &[&str]) -> String {
+	let mut select = "v".to_owned();
+	for attr in path {
+		select.push('.');
+		select.push_str(&nixlike::escape_string(attr));
+	}
+	format!("builtins.mapAttrs (_: v: let r = builtins.tryEval {select}.outPath; in if r.success then r.value else null) {field}")
+}
+
+fn select_all_query(field: &str, names: &[impl AsRef<str>], sub_path: &[&str]) -> String {
+	let mut select = format!("{field}.${{n}}");
+	for attr in sub_path {
+		select.push('.');
+		select.push_str(&nixlike::escape_string(attr));
+	}
+	let mut attrs = String::new();
+	for name in names {
+		attrs.push_str(&nixlike::escape_string(name.as_ref()));
+		attrs.push_str(" = null; ");
+	}
+	format!("builtins.mapAttrs (n: _: {select}) {{ {attrs}}}")
+}
+
+fn type_mismatch(attribute: String, got: String, error: &serde_json::Error) -> Error {
+	Error::TypeMismatch {
+		attribute,
+		got,
+		error: error.to_string(),
+	}
+}
+
+fn deep_seq_query(field: &str) -> String {
+	format!("builtins.deepSeq {field} null")
+}
+
+fn check_callable(attribute: String, got: String, has_functor: bool) -> Result<()> {
+	if got == "lambda" || has_functor {
+		return Ok(());
+	}
+	Err(Error::NotCallable { attribute, got })
+}
+
+/// Attrsets with `__functor` are called the same way as lambdas.
+fn functor_call_query(f: &str, arg: &str) -> String {
+	format!("((if builtins.isAttrs {f} then {f}.__functor {f} else {f}) {arg})")
+}
+
+/// Messages emitted by the config code while evaluating the value.
+#[derive(Clone, Debug)]
+pub struct Diagnostics {
+	pub attribute: String,
+	pub messages: Vec<String>,
+}
+impl fmt::Display for Diagnostics {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "while evaluating {}, config emitted:", self.attribute)?;
+		for message in &self.messages {
+			write!(f, "\n{message}")?;
+		}
+		Ok(())
+	}
+}
+
 struct ValueInner {
 	full_path: Vec<Index>,
 	session: NixSession,
 	value: u32,
+	last_diagnostics: std::sync::Mutex<Vec<String>>,
 }
 #[derive(Clone)]
 pub struct Value(Arc<ValueInner>);
@@ -94,6 +185,7 @@ impl Value {
 			full_path: vec![],
 			session,
 			value: vid,
+			last_diagnostics: Default::default(),
 		})))
 	}
 	/// Get a top-level binding.
@@ -101,11 +193,18 @@ impl Value {
 	/// In flake repl session, every output is exposed as top-level binding.
 	pub async fn binding(session: NixSession, query: &str) -> Result<Self> {
 		// TODO: Verify that query is a valid variable name
-		let vid = session.0.lock().await.execute_assign(query).await?;
+		let vid = session
+			.0
+			.lock()
+			.await
+			.execute_assign(query)
+			.await
+			.map_err(|e| e.undefined_variable(query))?;
 		Ok(Self(Arc::new(ValueInner {
 			full_path: vec![Index::Var(query.to_owned())],
 			session,
 			value: vid,
+			last_diagnostics: Default::default(),
 		})))
 	}
 	pub async fn select(&self, name: impl IntoIterator<Item = Index>) -> Result<Self> {
@@ -171,59 +270,115 @@ impl Value {
 			full_path,
 			session: self.0.session.clone(),
 			value: vid,
+			last_diagnostics: Default::default(),
 		})))
 	}
 	pub async fn as_json<V: DeserializeOwned>(&self) -> Result<V> {
+		self.typed_as_json().await
+	}
+	/// Deserialize value, on mismatch the error mentions the nix type of the value.
+	pub async fn typed_as_json<V: DeserializeOwned>(&self) -> Result<V> {
 		let query = self.sess_field_name();
-		self.0
-			.session
-			.0
-			.lock()
-			.await
-			.execute_expression_to_json(&query)
-			.await
-			.map_err(|e| e.context(self.attribute()))
+		let json: serde_json::Value = self.query_json(&query).await?;
+		match serde_json::from_value(json) {
+			Ok(v) => Ok(v),
+			Err(e) => Err(type_mismatch(self.attribute(), self.type_of().await?, &e)),
+		}
 	}
 	#[allow(dead_code)]
 	pub async fn has_field(&self, name: &str) -> Result<bool> {
 		let key = nixlike::escape_string(name);
 		let query = format!("{} ? {key}", self.sess_field_name());
-		self.0
-			.session
-			.0
-			.lock()
-			.await
-			.execute_expression_to_json(&query)
-			.await
-			.map_err(|e| e.context(self.attribute()))
+		self.query_json(&query).await
 	}
 	pub async fn list_fields(&self) -> Result<Vec<String>> {
 		let query = format!("builtins.attrNames {}", self.sess_field_name());
-		self.0
-			.session
-			.0
-			.lock()
-			.await
-			.execute_expression_to_json(&query)
-			.await
-			.map_err(|e| e.context(self.attribute()))
+		self.query_json(&query).await
+	}
+	/// Force evaluation of the whole value, to report errors hidden by laziness up front.
+	///
+	/// Value should not refer to anything infinite or huge, e.g nixpkgs package set.
+	pub async fn deep_seq(&self) -> Result<()> {
+		let query = deep_seq_query(&self.sess_field_name());
+		self.query_json(&query).await
 	}
 	pub async fn type_of(&self) -> Result<String> {
 		let query = format!("builtins.typeOf {}", self.sess_field_name());
-		self.0
+		self.query_json(&query).await
+	}
+	/// Value at `sub_path` of every listed attribute, evaluated in a single query.
+	pub async fn select_all_json<V: DeserializeOwned>(
+		&self,
+		names: &[impl AsRef<str>],
+		sub_path: &[&str],
+	) -> Result<HashMap<String, V>> {
+		if names.is_empty() {
+			return Ok(HashMap::new());
+		}
+		let query = select_all_query(&self.sess_field_name(), names, sub_path);
+		self.query_json(&query).await
+	}
+	/// Same as [`Self::call_functor`], but checks that the value is callable first.
+	pub async fn call(&self, arg: Value) -> Result<Value> {
+		let got = self.type_of().await?;
+		let has_functor = got == "set" && self.has_field("__functor").await?;
+		check_callable(self.attribute(), got, has_functor)?;
+		self.call_functor(&arg).await
+	}
+	/// Call this value, which is either a lambda or an attrset with `__functor`, with `arg`.
+	///
+	/// `arg` should be from the same session, [`Error::ForeignArgument`] is returned otherwise.
+	pub async fn call_functor(&self, arg: &Value) -> Result<Self> {
+		if !NixSession::ptr_eq(&self.0.session, &arg.0.session) {
+			return Err(Error::ForeignArgument {
+				attribute: self.attribute(),
+			});
+		}
+		let query = functor_call_query(&self.sess_field_name(), &arg.sess_field_name());
+		let mut full_path = self.0.full_path.clone();
+		// Functors are called natively by nix, so the call is replayed as a plain application.
+		full_path.push(Index::ExprApply(NixExprBuilder::value(arg.clone())));
+		let vid = self
+			.0
 			.session
 			.0
 			.lock()
 			.await
-			.execute_expression_to_json(&query)
+			.execute_assign(&query)
 			.await
-			.map_err(|e| e.context(self.attribute()))
+			.map_err(|e| e.context(self.attribute()))?;
+		Ok(Self(Arc::new(ValueInner {
+			full_path,
+			session: self.0.session.clone(),
+			value: vid,
+			last_diagnostics: Default::default(),
+		})))
 	}
 	#[allow(dead_code)]
 	pub async fn import(&self) -> Result<Self> {
 		let import = Self::new(self.0.session.clone(), "import").await?;
 		Ok(nix_go!(self | import))
 	}
+	/// Evaluate expression referring to this value, its traces are kept as [`Self::last_diagnostics`].
+	async fn query_json<V: DeserializeOwned>(&self, query: &str) -> Result<V> {
+		let mut session = self.0.session.0.lock().await;
+		let result = session.execute_expression_to_json(query).await;
+		*self.0.last_diagnostics.lock().expect("not poisoned") =
+			std::mem::take(&mut session.last_diagnostics);
+		result.map_err(|e| e.context(self.attribute()))
+	}
+	/// Traces and warnings emitted during the last evaluation of this value.
+	pub fn last_diagnostics(&self) -> Diagnostics {
+		Diagnostics {
+			attribute: self.attribute(),
+			messages: self
+				.0
+				.last_diagnostics
+				.lock()
+				.expect("not poisoned")
+				.clone(),
+		}
+	}
 	fn sess_field_name(&self) -> String {
 		format!("sess_field_{}", self.0.value)
 	}
@@ -238,13 +393,31 @@ impl Value {
 		}
 	}
 	pub async fn build(&self) -> Result<HashMap<String, PathBuf>> {
+		if self.0.session.kind() == SessionKind::Query {
+			// Older nix has no syntax to build all outputs of the derivation, repl of the build
+			// session is used instead, so that the query session isn't locked for the build time.
+			if !self.0.session.0.lock().await.dialect.detached_build {
+				let session = self.0.session.3.get().await?;
+				return self.rebind(session).await?.build_in_repl().await;
+			}
+			let drv_path: String = nix_go_json!(self.drvPath);
+			let nix_args = self.0.session.0.lock().await.nix_args.clone();
+			return build_detached(&drv_path, &nix_args)
+				.await
+				.map_err(|e| Error::BuildFailed {
+					attribute: self.attribute(),
+					error: e.to_string(),
+				});
+		}
+		self.build_in_repl().await
+	}
+	async fn build_in_repl(&self) -> Result<HashMap<String, PathBuf>> {
+		if self.0.session.kind() == SessionKind::Query {
+			return Err(Error::BuildInQuerySession(self.attribute()));
+		}
 		let query = format!(":b {}", self.sess_field_name());
-		let vid = self
-			.0
-			.session
-			.0
-			.lock()
-			.await
+		let mut session = self.0.session.0.lock().await;
+		let vid = session
 			.execute_expression_raw(&query, &mut NixHandler::default())
 			.await?;
 		if vid.is_empty() {
@@ -253,20 +426,33 @@ impl Value {
 				error: "build produced no output".to_owned(),
 			});
 		}
-		let Some(vid) = vid.strip_prefix("This derivation produced the following outputs:\n")
-		else {
-			return Err(Error::BuildFailed {
+		session
+			.dialect
+			.parse_build_output(&vid)
+			.ok_or_else(|| Error::BuildFailed {
 				attribute: self.attribute(),
 				error: format!("failed to parse output: {vid}"),
-			});
-		};
-		let outputs = vid
-			.split('\n')
-			.filter(|v| !v.is_empty())
-			.map(|v| v.split_once(" -> ").expect("unexpected build output"))
-			.map(|(a, b)| (a.trim_start().to_owned(), PathBuf::from(b)))
-			.collect();
-		Ok(outputs)
+			})
+	}
+	/// Whether every output of the derivation is present in the local store, nothing is built.
+	pub async fn is_built(&self) -> Result<bool> {
+		query_valid_paths(&self.output_paths().await?).await
+	}
+	/// Store paths of every output of the derivation, `out` if it doesn't list its outputs.
+	async fn output_paths(&self) -> Result<Vec<String>> {
+		let v = self.sess_field_name();
+		let query = format!("map (o: {v}.${{o}}.outPath) ({v}.outputs or [\"out\"])");
+		self.query_json(&query).await
+	}
+	/// Out paths of derivations, selected by `path` from every attribute of this attrset, evaluated
+	/// in a single query without building anything.
+	///
+	/// Only errors catchable by `builtins.tryEval` (`throw`, `assert`) are reported per attribute,
+	/// other evaluation errors fail the whole query.
+	pub async fn attrs_out_paths(&self, path: &[&str]) -> Result<OutPaths> {
+		let query = out_paths_query(&self.sess_field_name(), path);
+		let paths: HashMap<String, Option<PathBuf>> = self.query_json(&query).await?;
+		Ok(OutPaths::from_evaluated(paths))
 	}
 	/// Weakly convert string-like types (derivation/path/string) to string
 	pub async fn to_string_weak(&self) -> Result<String> {
@@ -282,6 +468,33 @@ impl Value {
 		Ok(vid)
 	}
 
+	/// Evaluate the same value in other session, by selecting its path again.
+	pub(crate) fn rebind(&self, session: NixSession) -> BoxFuture<'_, Result<Self>> {
+		Box::pin(async move {
+			let Some((Index::Var(root), path)) = self.0.full_path.split_first() else {
+				return Err(Error::NotReplayable {
+					attribute: self.attribute(),
+				});
+			};
+			let root = Value::binding(session.clone(), root).await?;
+			if path.is_empty() {
+				return Ok(root);
+			}
+			let mut rebound = Vec::with_capacity(path.len());
+			for index in path {
+				rebound.push(match index {
+					Index::Var(_) => unreachable!("var item may only be first"),
+					Index::String(_) | Index::Apply(_) => index.clone(),
+					Index::Expr(e) => Index::Expr(e.rebind(&session).await?),
+					Index::ExprApply(e) => Index::ExprApply(e.rebind(&session).await?),
+					Index::Pipe(e) => Index::Pipe(e.rebind(&session).await?),
+					Index::Merge(e) => Index::Merge(e.rebind(&session).await?),
+				});
+			}
+			root.select(rebound).await
+		})
+	}
+
 	fn attribute(&self) -> String {
 		PathDisplay(&self.0.full_path).to_string()
 	}
@@ -302,3 +515,332 @@ impl Drop for ValueInner {
 		// Leaked
 	}
 }
+
+#[test]
+fn deep_seq() {
+	assert_eq!(
+		deep_seq_query("sess_field_1"),
+		"builtins.deepSeq sess_field_1 null"
+	);
+	// Query result is `null`.
+	serde_json::from_str::<()>("null").expect("unit is deserialized from null");
+}
+
+#[test]
+fn callable() {
+	assert!(check_callable("f".to_owned(), "lambda".to_owned(), false).is_ok());
+	assert!(check_callable("f".to_owned(), "set".to_owned(), true).is_ok());
+	let err = check_callable("config.f".to_owned(), "set".to_owned(), false).unwrap_err();
+	assert_eq!(
+		err.to_string(),
+		"config.f is not callable, expected lambda or attrset with __functor, got set"
+	);
+	assert!(check_callable("f".to_owned(), "int".to_owned(), false).is_err());
+}
+
+#[test]
+fn functor_call() {
+	let query = functor_call_query("sess_field_1", "sess_field_2");
+	assert_eq!(query, "((if builtins.isAttrs sess_field_1 then sess_field_1.__functor sess_field_1 else sess_field_1) sess_field_2)");
+}
+
+#[test]
+fn mismatched_type() {
+	let error = serde_json::from_value::<Vec<String>>(serde_json::json!("10.0.0.1")).unwrap_err();
+	let error = type_mismatch(
+		"config.hosts.web.ips".to_owned(),
+		"string".to_owned(),
+		&error,
+	);
+	assert_eq!(
+		error.to_string(),
+		"invalid type: string \"10.0.0.1\", expected a sequence at config.hosts.web.ips, nix value is string"
+	);
+}
+
+#[test]
+fn select_all() {
+	assert_eq!(
+		select_all_query("sess_field_1", &["a", "b.c"], &["expectedOwners"]),
+		"builtins.mapAttrs (n: _: sess_field_1.${n}.\"expectedOwners\") { \"a\" = null; \"b.c\" = null; }"
+	);
+	assert_eq!(
+		select_all_query("sess_field_1", &["a"], &[]),
+		"builtins.mapAttrs (n: _: sess_field_1.${n}) { \"a\" = null; }"
+	);
+}
+
+#[test]
+fn out_paths() {
+	assert_eq!(
+		out_paths_query("sess_field_1", &["system", "build"]),
+		"builtins.mapAttrs (_: v: let r = builtins.tryEval v.\"system\".\"build\".outPath; in if r.success then r.value else null) sess_field_1"
+	);
+	let evaluated: HashMap<String, Option<PathBuf>> =
+		serde_json::from_str(r#"{"a": "/nix/store/aaa-system", "b": null}"#).unwrap();
+	let out = OutPaths::from_evaluated(evaluated);
+	assert_eq!(
+		out.paths,
+		HashMap::from([("a".to_owned(), PathBuf::from("/nix/store/aaa-system"))])
+	);
+	assert_eq!(out.failed, ["b"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn query_during_build() -> Result<()> {
+	use std::time::{Duration, Instant};
+
+	use crate::{NixSessionPool, NixVersion, PoolOptions};
+
+	// Nix without detached builds, `:b` takes 2 seconds, every query evaluates to 5.
+	let repl = r#"while read -r l; do case "$l" in
+		"sess_field_"*" = "*) ;;
+		"2 + 2") echo 4;;
+		":b "*) sleep 2; printf '\nThis derivation produced the following outputs:\n  out -> /nix/store/slow\n';;
+		"builtins.toJSON ("*) echo '"5"';;
+		*) echo "$l";;
+	esac; done"#;
+	let pool = NixSessionPool::fake(
+		repl,
+		Some(NixVersion::new(2, 12, 0)),
+		PoolOptions::default(),
+	);
+	let session = pool.get_query().await?;
+	let slow = Value::binding(session.clone(), "slow").await?;
+	let nonce = "nonce";
+	let slow = nix_go!(slow({ nonce }));
+	// Query session has no build to run `:b` for.
+	assert!(matches!(
+		slow.build_in_repl().await,
+		Err(Error::BuildInQuerySession(_))
+	));
+	let build = tokio::spawn({
+		let slow = slow.clone();
+		async move { slow.build().await }
+	});
+	tokio::time::sleep(Duration::from_millis(500)).await;
+
+	let started = Instant::now();
+	let length: u32 = slow.as_json().await?;
+	assert_eq!(length, 5);
+	assert!(started.elapsed() < Duration::from_secs(1));
+	assert!(!build.is_finished(), "query shouldn't wait for the build");
+
+	let outputs = build.await.expect("build task")?;
+	assert_eq!(outputs["out"], PathBuf::from("/nix/store/slow"));
+	// Build was performed by the build session of the same pool.
+	assert_eq!(pool.stats().created, 2);
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn foreign_argument() -> Result<()> {
+	use crate::{NixSessionPool, PoolOptions};
+
+	let repl = r#"while read -r l; do case "$l" in
+		"sess_field_"*" = "*) ;;
+		"2 + 2") echo 4;;
+		*) echo "$l";;
+	esac; done"#;
+	let pool = NixSessionPool::fake(repl, None, PoolOptions::default());
+	let (a, b) = (pool.get().await?, pool.get().await?);
+	let f = Value::binding(a.clone(), "f").await?;
+	let arg = Value::binding(b, "arg").await?;
+	assert!(matches!(
+		f.call_functor(&arg).await,
+		Err(Error::ForeignArgument { .. })
+	));
+	let arg = Value::binding(a, "arg").await?;
+	f.call_functor(&arg).await?;
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn forced_failure() -> Result<()> {
+	let (pool, _) =
+		crate::test_pool(r#"{ lazy = { ok = 1; bad.nested = throw "lazy landmine"; }; }"#).await;
+	let lazy = Value::binding(pool.get().await?, "lazy").await?;
+	// Lazy evaluation doesn't notice the failing attribute...
+	let ok: u32 = nix_go_json!(lazy.ok);
+	assert_eq!(ok, 1);
+	assert!(lazy.has_field("bad").await?);
+	// ...until everything is forced.
+	let err = lazy.deep_seq().await.expect_err("forced value fails");
+	assert!(err.to_string().contains("lazy landmine"), "{err}");
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn evaluation_diagnostics() -> Result<()> {
+	use crate::{NixSessionPool, PoolOptions};
+
+	// Values below are bound to `sess_field_0` .. `sess_field_2`, only the first evaluation of
+	// `sess_field_1` is traced.
+	let repl = r#"traced=0; while read -r l; do case "$l" in
+		"sess_field_"*" = "*) ;;
+		"2 + 2") echo 4;;
+		"builtins.toJSON (sess_field_1)")
+			if [ $traced = 0 ]; then
+				traced=1
+				printf '%s\n' '@nix {"action":"msg","level":0,"msg":"\u001b[1mtrace: \u001b[0mcomputing answer"}' >&2
+				printf '%s\n' '@nix {"action":"msg","level":1,"msg":"\u001b[1;35mevaluation warning:\u001b[0m answer is deprecated"}' >&2
+			fi
+			echo '"42"';;
+		"builtins.toJSON ("*) echo '"0"';;
+		*) echo "$l";;
+	esac; done"#;
+	let pool = NixSessionPool::fake(repl, None, PoolOptions::default());
+	let traced = Value::binding(pool.get().await?, "traced").await?;
+	let (answer, other) = (nix_go!(traced.answer), nix_go!(traced.other));
+	assert!(answer.last_diagnostics().messages.is_empty());
+	assert_eq!(answer.as_json::<u32>().await?, 42);
+	let diagnostics = answer.last_diagnostics();
+	assert_eq!(diagnostics.attribute, "traced.answer");
+	assert_eq!(
+		diagnostics.messages,
+		[
+			"trace: computing answer",
+			"evaluation warning: answer is deprecated"
+		]
+	);
+	// Diagnostics belong to the evaluated value only.
+	assert_eq!(other.as_json::<u32>().await?, 0);
+	assert!(other.last_diagnostics().messages.is_empty());
+	assert_eq!(answer.last_diagnostics().messages.len(), 2);
+	// And are replaced by the next evaluation of the value.
+	assert_eq!(answer.as_json::<u32>().await?, 42);
+	assert!(answer.last_diagnostics().messages.is_empty());
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn traced_value() -> Result<()> {
+	let (pool, _) =
+		crate::test_pool(r#"{ traced = { answer = builtins.trace "computing answer" 42; }; }"#)
+			.await;
+	let traced = Value::binding(pool.get().await?, "traced").await?;
+	let answer = nix_go!(traced.answer);
+	assert!(answer.last_diagnostics().messages.is_empty());
+	assert_eq!(answer.as_json::<u32>().await?, 42);
+	let diagnostics = answer.last_diagnostics();
+	assert_eq!(diagnostics.attribute, answer.attribute());
+	assert!(
+		diagnostics
+			.messages
+			.iter()
+			.any(|m| m.contains("computing answer")),
+		"{diagnostics}"
+	);
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn recursion_errors() -> Result<()> {
+	use crate::{NixSessionPool, PoolOptions};
+
+	// Values below are bound to `sess_field_0` .. `sess_field_3`.
+	let repl = r#"while read -r l; do case "$l" in
+		"sess_field_"*" = "*) ;;
+		"2 + 2") echo 4;;
+		"builtins.toJSON (sess_field_1)") printf '%s\n' '@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m\n       … while evaluating the attribute '"'"'a'"'"'\n\n       error: infinite recursion encountered"}' >&2;;
+		"builtins.toJSON (sess_field_2)") echo '@nix {"action":"msg","level":0,"msg":"error: stack overflow; max-call-depth exceeded"}' >&2;;
+		"builtins.toJSON (sess_field_3)") echo '@nix {"action":"msg","level":0,"msg":"error: attribute '"'"'missing'"'"' missing"}' >&2;;
+		*) echo "$l";;
+	esac; done"#;
+	let pool = NixSessionPool::fake(repl, None, PoolOptions::default());
+	let config = Value::binding(pool.get().await?, "config").await?;
+	let (a, deep, missing) = (
+		nix_go!(config.a),
+		nix_go!(config.deep),
+		nix_go!(config.missing),
+	);
+	for recursive in [&a, &deep] {
+		match recursive.as_json::<u32>().await {
+			Err(Error::InfiniteRecursion { attribute, .. }) => {
+				assert_eq!(attribute, recursive.attribute())
+			}
+			other => panic!("expected infinite recursion, got {other:?}"),
+		}
+	}
+	assert!(matches!(
+		missing.as_json::<u32>().await,
+		Err(Error::InContext(attribute, _)) if attribute == "config.missing"
+	));
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn recursive_value() -> Result<()> {
+	let (pool, _) = crate::test_pool("{ config = rec { a = b + 1; b = a; }; }").await;
+	let config = Value::binding(pool.get().await?, "config").await?;
+	let a = nix_go!(config.a);
+	match a.as_json::<u32>().await {
+		Err(Error::InfiniteRecursion { attribute, .. }) => assert_eq!(attribute, a.attribute()),
+		other => panic!("expected infinite recursion, got {other:?}"),
+	}
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs nix"]
+async fn built_derivation() -> Result<()> {
+	let (pool, _) = crate::test_pool(&format!(
+		r#"{{ greeting = nonce: derivation {{
+			name = "greeting";
+			system = "{}";
+			builder = "/bin/sh";
+			args = [ "-c" "echo $nonce > $out" ];
+			inherit nonce;
+		}}; }}"#,
+		crate::test_system()
+	))
+	.await;
+	let greeting = Value::binding(pool.get().await?, "greeting").await?;
+	// Unique derivation, so that it is not built by the previous run.
+	let nonce = format!("{:?}", std::time::SystemTime::now());
+	let greeting = nix_go!(greeting({ nonce }));
+	assert!(!greeting.is_built().await?);
+	let outputs = greeting.build().await?;
+	assert!(outputs["out"].exists());
+	assert!(greeting.is_built().await?);
+	Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn output_paths() -> Result<()> {
+	use crate::{NixSessionPool, PoolOptions};
+
+	// Values below are bound to `sess_field_0`, `sess_field_1` and `sess_field_2`.
+	let repl = r#"while read -r l; do case "$l" in
+		"sess_field_"*" = "*) ;;
+		"2 + 2") echo 4;;
+		"builtins.toJSON (map (o: sess_field_0."*) echo '"[\"/nix/store/a-hello\",\"/nix/store/b-hello-man\"]"';;
+		"builtins.toJSON (map (o: sess_field_1."*) echo '"[]"';;
+		"builtins.toJSON (map (o: sess_field_2."*) echo '@nix {"action":"msg","level":0,"msg":"error: attribute '"'"'outPath'"'"' missing"}' >&2;;
+		*) echo "$l";;
+	esac; done"#;
+	let pool = NixSessionPool::fake(repl, None, PoolOptions::default());
+	let session = pool.get().await?;
+	let (hello, empty, broken) = (
+		Value::binding(session.clone(), "hello").await?,
+		Value::binding(session.clone(), "empty").await?,
+		Value::binding(session, "broken").await?,
+	);
+	assert_eq!(
+		hello.output_paths().await?,
+		["/nix/store/a-hello", "/nix/store/b-hello-man"]
+	);
+	// Nothing to check in the store.
+	assert!(empty.is_built().await?);
+	match broken.is_built().await {
+		Err(Error::InContext(attribute, e)) => {
+			assert_eq!(attribute, "broken");
+			assert!(e.to_string().contains("'outPath' missing"), "{e}");
+		}
+		other => panic!("expected evaluation error, got {other:?}"),
+	}
+	Ok(())
+}
@@ -0,0 +1,65 @@
+//! Canonical form of the value, used to keep serialized output stable across versions of serializer
+//! and the code producing the value.
+//!
+//! Rules:
+//! - Object keys are sorted in byte order, recursively. Nix attribute sets are unordered anyway.
+//! - Negative zero float is written as `0.0`, nix considers them equal.
+//! - Floats stay floats even when integral, because nix distinguishes `1` and `1.0`.
+//! - Strings are kept verbatim, their contents are data (and may be hashed/encrypted), and the
+//!   representation (`''` block for strings ending with a newline, quoted otherwise) is already
+//!   picked deterministically by the writer.
+//! - Array order is significant, and is kept as-is.
+//! - Comments are kept attached to their values.
+
+use linked_hash_map::LinkedHashMap;
+
+use crate::Value;
+
+pub fn canonicalize(value: Value) -> Value {
+	match value {
+		Value::Float(f) if f == 0.0 => Value::Float(0.0),
+		Value::Array(a) => Value::Array(a.into_iter().map(canonicalize).collect()),
+		// Locations point to the source, which is not kept in sync with the canonical value.
+		Value::Located { value, .. } => canonicalize(*value),
+		Value::Commented { comments, value } => Value::Commented {
+			comments,
+			value: Box::new(canonicalize(*value)),
+		},
+		Value::Object(o) => {
+			let mut entries = o.into_iter().collect::<Vec<_>>();
+			entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+			Value::Object(
+				entries
+					.into_iter()
+					.map(|(k, v)| (k, canonicalize(v)))
+					.collect::<LinkedHashMap<_, _>>(),
+			)
+		}
+		v => v,
+	}
+}
+
+#[test]
+fn idempotent() {
+	let input = r#"{
+		z = { b = -0.0; a = [ { y = 1; x = 2; } "s\n" ]; };
+		a.c = 1.0;
+		a.b = "hello";
+		m = null;
+	}"#;
+	let parse = || crate::nixlike::root(input, &Default::default()).expect("parse");
+
+	let once = canonicalize(parse());
+	assert_eq!(once, canonicalize(canonicalize(parse())));
+	let located = crate::ParseOptions {
+		track_locations: true,
+		..Default::default()
+	};
+	let located = crate::nixlike::root(input, &located).expect("parse");
+	assert_eq!(canonicalize(located), once);
+	#[cfg(feature = "format")]
+	assert_eq!(
+		crate::serialize_value_pretty(once),
+		"{\n  a = {\n    b = \"hello\";\n    c = 1.0;\n  };\n  m = null;\n  z = {\n    a = [\n      {\n        x = 2;\n        y = 1;\n      }\n      ''\n        s\n      ''\n    ];\n    b = 0.0;\n  };\n}\n"
+	);
+}
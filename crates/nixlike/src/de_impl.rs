@@ -2,7 +2,7 @@ use std::convert::{TryFrom, TryInto};
 
 use linked_hash_map::LinkedHashMap;
 use serde::{
-	de::{self, MapAccess, SeqAccess},
+	de::{self, EnumAccess, MapAccess, SeqAccess, VariantAccess},
 	Deserializer,
 };
 
@@ -40,7 +40,7 @@ impl<'de> MapAccess<'de> for ObjectAccess {
 	where
 		V: de::DeserializeSeed<'de>,
 	{
-		seed.deserialize(self.value.take().unwrap())
+		deserialize_located(self.value.take().unwrap(), |v| seed.deserialize(v))
 	}
 }
 
@@ -63,13 +63,96 @@ impl<'de> SeqAccess<'de> for ArrayAccess {
 		T: de::DeserializeSeed<'de>,
 	{
 		if let Some(v) = self.iter.next() {
-			Ok(Some(seed.deserialize(v)?))
+			Ok(Some(deserialize_located(v, |v| seed.deserialize(v))?))
 		} else {
 			Ok(None)
 		}
 	}
 }
 
+/// Externally tagged enum, unit variants are written as strings, and the rest as
+/// `{ Variant = value; }` objects.
+struct VariantValue {
+	variant: String,
+	value: Option<Value>,
+}
+
+impl<'de> EnumAccess<'de> for VariantValue {
+	type Error = Error;
+	type Variant = Self;
+
+	fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error>
+	where
+		V: de::DeserializeSeed<'de>,
+	{
+		let variant = seed.deserialize(Value::String(self.variant.clone()))?;
+		Ok((variant, self))
+	}
+}
+
+impl<'de> VariantAccess<'de> for VariantValue {
+	type Error = Error;
+
+	fn unit_variant(self) -> Result<(), Self::Error> {
+		match self.value {
+			None => Ok(()),
+			Some(v) => deserialize_located(v, Value::parse_null),
+		}
+	}
+
+	fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+	where
+		T: de::DeserializeSeed<'de>,
+	{
+		deserialize_located(self.value.ok_or(Error::Expected("variant value"))?, |v| {
+			seed.deserialize(v)
+		})
+	}
+
+	fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+	where
+		V: de::Visitor<'de>,
+	{
+		deserialize_located(self.value.ok_or(Error::Expected("variant array"))?, |v| {
+			v.deserialize_seq(visitor)
+		})
+	}
+
+	fn struct_variant<V>(
+		self,
+		_fields: &'static [&'static str],
+		visitor: V,
+	) -> Result<V::Value, Self::Error>
+	where
+		V: de::Visitor<'de>,
+	{
+		deserialize_located(self.value.ok_or(Error::Expected("variant object"))?, |v| {
+			v.deserialize_map(visitor)
+		})
+	}
+}
+
+/// Deserialize value without comments, attaching its location to the error.
+pub(crate) fn deserialize_located<T>(
+	value: Value,
+	f: impl FnOnce(Value) -> Result<T, Error>,
+) -> Result<T, Error> {
+	match value {
+		Value::Commented { value, .. } => deserialize_located(*value, f),
+		Value::Located { offset, value } => {
+			deserialize_located(*value, f).map_err(|e| match e {
+				// Innermost value is the most precise location.
+				e @ Error::AtOffset { .. } => e,
+				e => Error::AtOffset {
+					offset,
+					error: Box::new(e),
+				},
+			})
+		}
+		v => f(v),
+	}
+}
+
 impl Value {
 	fn parse_int<T: TryFrom<i64>>(&self) -> Result<T, Error> {
 		match self {
@@ -77,6 +160,25 @@ impl Value {
 			_ => Err(Error::Expected("integer")),
 		}
 	}
+	/// Negative values are reported separately, as the rest of out of range errors are only
+	/// possible for types narrower than i64.
+	fn parse_uint<T: TryFrom<u64>>(&self) -> Result<T, Error> {
+		match self {
+			Value::Number(n) => {
+				let n = u64::try_from(*n).map_err(|_| Error::NegativeUnsigned(*n))?;
+				n.try_into().map_err(|_| Error::BadNumber)
+			}
+			_ => Err(Error::Expected("integer")),
+		}
+	}
+	fn parse_float(&self) -> Result<f64, Error> {
+		match self {
+			Value::Float(f) => Ok(*f),
+			// Integer literals are commonly written in place of floats.
+			Value::Number(n) => Ok(*n as f64),
+			_ => Err(Error::Expected("float")),
+		}
+	}
 	fn parse_boolean(self) -> Result<bool, Error> {
 		match self {
 			Value::Boolean(b) => Ok(b),
@@ -85,7 +187,7 @@ impl Value {
 	}
 	pub fn parse_string(&self) -> Result<&str, Error> {
 		match self {
-			Value::String(s) => Ok(s),
+			Value::String(s) | Value::Path(s) => Ok(s),
 			_ => Err(Error::Expected("string")),
 		}
 	}
@@ -101,6 +203,24 @@ impl Value {
 			_ => Err(Error::Expected("array")),
 		}
 	}
+	/// Tuples are stored as arrays, serde would report missing elements, but would ignore extra ones.
+	fn parse_tuple(self, len: usize) -> Result<Vec<Value>, Error> {
+		let items = self.parse_array()?;
+		if items.len() != len {
+			return Err(Error::Custom(format!(
+				"expected tuple of {len} elements, got array of {}",
+				items.len()
+			)));
+		}
+		Ok(items)
+	}
+	/// Bytes are stored as an array of numbers, see `serialize_bytes`.
+	fn parse_bytes(self) -> Result<Vec<u8>, Error> {
+		self.parse_array()?
+			.into_iter()
+			.map(|v| deserialize_located(v, |v| v.parse_uint()))
+			.collect()
+	}
 	fn parse_object(self) -> Result<LinkedHashMap<String, Value>, Error> {
 		match self {
 			Value::Object(s) => Ok(s),
@@ -132,12 +252,16 @@ impl<'de> Deserializer<'de> for Value {
 		V: serde::de::Visitor<'de>,
 	{
 		match self {
-			Value::Number(f) => visitor.visit_i64(f),
-			Value::String(s) => visitor.visit_str(&s),
+			Value::Number(n) => visitor.visit_i64(n),
+			Value::Float(f) => visitor.visit_f64(f),
+			Value::String(s) | Value::Path(s) => visitor.visit_str(&s),
 			Value::Boolean(b) => visitor.visit_bool(b),
 			Value::Object(o) => visitor.visit_map(ObjectAccess::new(o)),
 			Value::Array(a) => visitor.visit_seq(ArrayAccess::new(a)),
 			Value::Null => visitor.visit_none(),
+			v @ (Value::Commented { .. } | Value::Located { .. }) => {
+				deserialize_located(v, |v| v.deserialize_any(visitor))
+			}
 		}
 	}
 
@@ -180,42 +304,56 @@ impl<'de> Deserializer<'de> for Value {
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		visitor.visit_u8(self.parse_int()?)
+		visitor.visit_u8(self.parse_uint()?)
 	}
 
 	fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		visitor.visit_u16(self.parse_int()?)
+		visitor.visit_u16(self.parse_uint()?)
 	}
 
 	fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		visitor.visit_u32(self.parse_int()?)
+		visitor.visit_u32(self.parse_uint()?)
 	}
 
 	fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		visitor.visit_u64(self.parse_int()?)
+		visitor.visit_u64(self.parse_uint()?)
+	}
+
+	fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+	where
+		V: serde::de::Visitor<'de>,
+	{
+		visitor.visit_i128(self.parse_int()?)
 	}
 
-	fn deserialize_f32<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+	fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		todo!()
+		visitor.visit_u128(self.parse_uint()?)
 	}
 
-	fn deserialize_f64<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+	fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		todo!()
+		visitor.visit_f32(self.parse_float()? as f32)
+	}
+
+	fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+	where
+		V: serde::de::Visitor<'de>,
+	{
+		visitor.visit_f64(self.parse_float()?)
 	}
 
 	fn deserialize_char<V>(self, visitor: V) -> Result<V::Value, Self::Error>
@@ -239,18 +377,18 @@ impl<'de> Deserializer<'de> for Value {
 		visitor.visit_string(self.parse_string()?.to_owned())
 	}
 
-	fn deserialize_bytes<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+	fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		todo!()
+		visitor.visit_bytes(&self.parse_bytes()?)
 	}
 
-	fn deserialize_byte_buf<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+	fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		todo!()
+		visitor.visit_byte_buf(self.parse_bytes()?)
 	}
 
 	fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
@@ -300,23 +438,23 @@ impl<'de> Deserializer<'de> for Value {
 		visitor.visit_seq(self.parse_array().map(ArrayAccess::new)?)
 	}
 
-	fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+	fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		self.deserialize_seq(visitor)
+		visitor.visit_seq(self.parse_tuple(len).map(ArrayAccess::new)?)
 	}
 
 	fn deserialize_tuple_struct<V>(
 		self,
 		_name: &'static str,
-		_len: usize,
+		len: usize,
 		visitor: V,
 	) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		self.deserialize_seq(visitor)
+		self.deserialize_tuple(len, visitor)
 	}
 
 	fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Self::Error>
@@ -342,12 +480,27 @@ impl<'de> Deserializer<'de> for Value {
 		self,
 		_name: &'static str,
 		_variants: &'static [&'static str],
-		_visitor: V,
+		visitor: V,
 	) -> Result<V::Value, Self::Error>
 	where
 		V: serde::de::Visitor<'de>,
 	{
-		todo!()
+		match self {
+			Value::String(variant) => visitor.visit_enum(VariantValue {
+				variant,
+				value: None,
+			}),
+			Value::Object(o) if o.len() == 1 => {
+				let (variant, value) = o.into_iter().next().expect("single entry");
+				visitor.visit_enum(VariantValue {
+					variant,
+					value: Some(value),
+				})
+			}
+			_ => Err(Error::Expected(
+				"enum variant name, or an object with a single variant attribute",
+			)),
+		}
 	}
 
 	fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value, Self::Error>
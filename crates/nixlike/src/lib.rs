@@ -5,41 +5,167 @@
 //! expressions and expect it to work, only basic primitives are supported, and there is no
 //! variables/recursive records, interpolation, e.t.c.
 
+use std::{borrow::Cow, num::IntErrorKind};
+
 use linked_hash_map::LinkedHashMap;
 use peg::str::LineCol;
 use se_impl::MySerialize;
 use serde::{Deserialize, Serialize};
 
+mod canonical;
 mod de_impl;
+mod redact;
 mod se_impl;
 mod to_string;
 
+pub use canonical::canonicalize;
+pub use redact::redact;
 pub use to_string::escape_string;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
 	#[error("bad number")]
 	BadNumber,
+	#[error("expected unsigned integer, got negative {0}")]
+	NegativeUnsigned(i64),
 	#[error("expected {0}")]
 	Expected(&'static str),
-	#[error("parse error")]
-	ParseError(#[from] peg::error::ParseError<LineCol>),
+	#[error("parse error at {location}\n{1}", location = .0.location)]
+	ParseError(peg::error::ParseError<LineCol>, String),
+	#[error("parse error at {0}: interpolation is not allowed in fleet.nix; escape as \\${{ or use a literal\n{1}")]
+	Interpolation(LineCol, String),
+	#[error("parse error at {0}: `inherit` is not supported in fleet.nix, inherited values can't be resolved without evaluation; write the attribute explicitly\n{1}")]
+	Inherit(LineCol, String),
+	#[error("parse error at {0}: attribute access (`a.b or default`) is not supported in fleet.nix, it can't be resolved without evaluation; write the value explicitly\n{1}")]
+	AttributeAccess(LineCol, String),
+	#[error("parse error at {0}: integer is out of range, nix integers are 64-bit signed\n{1}")]
+	IntegerOverflow(LineCol, String),
 	#[error("{0}")]
 	Custom(String),
+	/// Deserialization error of the value at the byte offset in the source, see
+	/// [`ParseOptions::track_locations`].
+	#[error("{error} at byte {offset}")]
+	AtOffset { offset: usize, error: Box<Error> },
+	#[error("{error} at line {}, col {}\n{snippet}", .location.line, .location.column)]
+	At {
+		location: LineCol,
+		error: Box<Error>,
+		snippet: String,
+	},
 	#[error("io: {0}")]
 	Io(#[from] std::io::Error),
 	#[error("fmt: {0}")]
 	Fmt(#[from] std::fmt::Error),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Value {
+	/// Nix integers are 64-bit signed, wider values can't be represented: out of range literals
+	/// are rejected by the parser, and `u64`/`u128` are only deserialized from non-negative values.
 	Number(i64),
+	/// Nix distinguishes `1` and `1.0`, thus floats are stored separately from integers.
+	Float(f64),
 	String(String),
+	/// Path literal (`./a`, `../a`, `/a`, `~/a`), stored as written. It is deserialized as a string,
+	/// but written back unquoted, so that nix still sees it as a path.
+	Path(String),
 	Boolean(bool),
 	Object(LinkedHashMap<String, Value>),
 	Array(Vec<Value>),
 	Null,
+	/// Value with its byte offset in the source, produced by parser when
+	/// [`ParseOptions::track_locations`] is set, and only used to locate deserialization errors.
+	Located {
+		offset: usize,
+		value: Box<Value>,
+	},
+	/// Value preceded by comments, produced by parser for array elements and object entries
+	/// when [`ParseOptions::preserve_comments`] is set, so that they can be written back.
+	/// Comment text doesn't include leading `#`.
+	Commented {
+		comments: Vec<String>,
+		value: Box<Value>,
+	},
+}
+impl Value {
+	fn commented(comments: Vec<&str>, value: Value) -> Self {
+		if comments.is_empty() {
+			return value;
+		}
+		Self::Commented {
+			comments: comments
+				.into_iter()
+				.map(|c| c.trim_end().to_owned())
+				.collect(),
+			value: Box::new(value),
+		}
+	}
+	/// Strip comments attached to the value
+	pub fn into_uncommented(self) -> Self {
+		match self {
+			Self::Commented { value, .. } => value.into_uncommented(),
+			v => v,
+		}
+	}
+	/// Value without the parser-attached [`Value::Located`] and [`Value::Commented`] wrappers.
+	fn unwrapped_mut(&mut self) -> &mut Self {
+		match self {
+			Self::Located { value, .. } | Self::Commented { value, .. } => value.unwrapped_mut(),
+			v => v,
+		}
+	}
+	fn uncommented(&self) -> &Self {
+		match self {
+			Self::Commented { value, .. } => value.uncommented(),
+			v => v,
+		}
+	}
+	/// Attach comments of the `source` object entries to the same entries of this value.
+	///
+	/// Array elements have no identity, thus their comments are not restored.
+	pub fn restore_comments(&mut self, source: &Value) {
+		match (self, source.uncommented()) {
+			(Self::Commented { value, .. }, source) => value.restore_comments(source),
+			(Self::Object(target), Self::Object(source)) => {
+				for (key, value) in target.iter_mut() {
+					let Some(source) = source.get(key) else {
+						continue;
+					};
+					value.restore_comments(source);
+					if let (Self::Commented { comments, .. }, false) =
+						(source, matches!(value, Self::Commented { .. }))
+					{
+						let uncommented = std::mem::replace(value, Self::Null);
+						*value = Self::Commented {
+							comments: comments.clone(),
+							value: Box::new(uncommented),
+						};
+					}
+				}
+			}
+			_ => {}
+		}
+	}
+	/// Recursively merge `overlay` objects into this value.
+	///
+	/// Existing keys keep their position, even when their value is replaced, new keys are appended
+	/// in the overlay order. Non-object values (including arrays) are replaced by the overlay.
+	pub fn merge(&mut self, overlay: Value) {
+		match (self, overlay) {
+			(Self::Commented { value, .. }, overlay) => value.merge(overlay),
+			(Self::Object(base), Self::Object(overlay)) => {
+				for (key, value) in overlay {
+					// LinkedHashMap::insert moves existing key to the end.
+					if let Some(existing) = base.get_mut(&key) {
+						existing.merge(value);
+					} else {
+						base.insert(key, value);
+					}
+				}
+			}
+			(base, overlay) => *base = overlay,
+		}
+	}
 }
 
 fn count_spaces(l: &str) -> usize {
@@ -83,7 +209,7 @@ fn process_multiline(lines: Vec<&str>) -> String {
 		// ''' is hard escape
 		for (i, part) in dedent(line, dedent_by).split("'''").enumerate() {
 			if i != 0 {
-				out.push_str(r#"""""#);
+				out.push_str("''");
 			}
 			// This is the only replacements done by nixlike writer, no need to support more.
 			out.push_str(&part.replace("''${", "${").replace("''\\t", "\t"));
@@ -94,9 +220,21 @@ fn process_multiline(lines: Vec<&str>) -> String {
 }
 
 peg::parser! {
-pub grammar nixlike() for str {
+pub grammar nixlike(options: &ParseOptions) for str {
+	// Same as nix float literal, decimal point is required, exponent is optional (`2e3` is not
+	// a float in nix). Nix has no unary plus, negative floats are written with unary minus.
+	rule float() -> f64
+		= quiet! { v:$("-"? (['0'..='9']+ "." ['0'..='9']* / "." ['0'..='9']+) (['e' | 'E'] ['+' | '-']? ['0'..='9']+)?) {?
+			v.parse().map_err(|_| "<float>")
+		} } / expected!("<float>")
+	// Overflow is reported outside of quiet!, so that it is distinguished from the syntax error.
 	rule number() -> i64
-		= quiet! { v:$(['0'..='9' | '+' | '-']+) {? v.parse().map_err(|_| "<number>")} } / expected!("<number>")
+		= v:quiet! { $(['0'..='9' | '+' | '-']+) } {?
+			v.parse().map_err(|e: std::num::ParseIntError| match e.kind() {
+				IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => "<integer overflow>",
+				_ => "<number>",
+			})
+		} / expected!("<number>")
 	rule string_char() -> &'input str
 		= "\\\"" { "\"" }
 		/ "\\\\" { "\\" }
@@ -106,16 +244,32 @@ pub grammar nixlike() for str {
 		/ "\\$" { "$" }
 		/ c:$([_]) { c }
 	rule string() -> String = singleline_string() / multiline_string();
+	// fleet.nix is static data, unescaped interpolation is an error, see [`Error::Interpolation`].
+	rule interpolation()
+		= &"${" {? Err("<interpolation>") }
+	// Length is checked before the string is collected, error is reported at the string start.
+	rule limit_length(len: usize)
+		= "" {? if len > options.max_string_length { Err("<string too long>") } else { Ok(()) } }
+	rule singleline_chars() -> &'input str
+		= $((!"\"" !"${" string_char())*)
 	rule singleline_string() -> String
-		= quiet! { "\"" v:(!"\"" c:string_char() {c})* "\"" { v.into_iter().collect() } } / expected!("<string>")
+		= quiet! { "\"" } raw:&singleline_chars() limit_length(raw.len())
+		v:(!"\"" !"${" c:string_char() {c})* (interpolation() / "\"") { v.into_iter().collect() } / expected!("<string>")
+	rule multiline_lines() -> &'input str
+		= $(("'''" / "''${" / "''\\t" / !"''" !"${" [_])*)
 	pub rule multiline_string() -> String
 		= "''"
 		// First line may also contain text, and whitespace for it is counted, but if it is empty - then it is'nt counted as full line...
 		// This logic is complicated, see `parse_multiline` test.
-		lines:$(("'''" / !"''" [_])*) "''"
+		raw:&multiline_lines() limit_length(raw.len())
+		lines:multiline_lines() (interpolation() / "''")
 		{
 			process_multiline(lines.split('\n').collect())
 		}
+	rule path_segment()
+		= ['a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' | '+']+
+	rule path() -> String
+		= quiet! { p:$(("./" / "../" / "~/" / "/") path_segment() ++ "/") { p.to_owned() } } / expected!("<path>")
 	rule boolean() -> bool
 		= quiet! { "true" {true}
 		/ "false" {false} } / expected!("<boolean>")
@@ -124,15 +278,33 @@ pub grammar nixlike() for str {
 			s:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-']+) { s.to_owned() }
 			/ "\"" s:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.']+) "\"" { s.to_owned() }
 		} / expected!("<identifier>")
+	// Both `inherit a b;` and `inherit (source) a b;` are recognized, only to be rejected with
+	// a clear error, see [`Error::Inherit`].
+	rule inherit()
+		= "inherit" !['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-'] _
+			("(" _ (indent()++(_ "." _) / value()) _ ")" _)?
+			indent()++_ _ ";" {? Err("<inherit>") }
+	rule access_ident()
+		= ['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '\'']*
+	// `a.b` and `a.b or default` are recognized at value position, only to be rejected with a clear
+	// error, see [`Error::AttributeAccess`].
+	rule attribute_access()
+		// First character is consumed for the rule to be non-empty, error is reported after it.
+		= &(access_ident() ++ (_ "." _) (_ "or" !['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-'] _ value())?)
+		[_] {? Err("<attribute access>") }
+	// Comments are attached to the innermost value of `a.b.c = v;` entries.
 	rule object() -> LinkedHashMap<String, Value>
-		= "{" _
-			e:(k:indent()++(_ "." _) _ "=" _ v:value() _ ";" _ {(k, v)})*
-		"}" {?
+		= "{"
+			e:(c:comments() e:(inherit() {unreachable!()} / k:indent()++(_ "." _) _ "=" _ v:value() _ ";" {(k, v)}) {
+				let (k, v) = e;
+				(k, if options.preserve_comments { Value::commented(c, v) } else { v })
+			})*
+		comments() "}" {?
 			let mut out = LinkedHashMap::new();
 			for (k, v) in e {
 				let mut map = &mut out;
 				for v in k.iter().take(k.len() - 1) {
-					map = match map.entry(v.clone()).or_insert_with(|| Value::Object(Default::default())) {
+					map = match map.entry(v.clone()).or_insert_with(|| Value::Object(Default::default())).unwrapped_mut() {
 						Value::Object(v) => v,
 						_ => return Err("expected object"),
 					}
@@ -147,42 +319,249 @@ pub grammar nixlike() for str {
 			Ok(out)
 		}
 
+	// Trailing comments (after the last element) are not preserved.
 	rule array() -> Vec<Value>
-		= "[" _ v:value()**_ _ "]" {v}
+		= "[" v:(c:comments() v:value() {
+			if options.preserve_comments { Value::commented(c, v) } else { v }
+		})* comments() "]" {v}
 
 	rule value() -> Value
+		= offset:position!() v:unlocated_value() {
+			if options.track_locations {
+				Value::Located { offset, value: Box::new(v) }
+			} else {
+				v
+			}
+		}
+	rule unlocated_value() -> Value
 		= o:object() { Value::Object(o) }
 		/ a:array() { Value::Array(a) }
 		/ s:string() { Value::String(s) }
+		/ p:path() { Value::Path(p) }
 		/ "null" { Value::Null }
 		/ b:boolean() { Value::Boolean(b) }
+		/ f:float() { Value::Float(f) }
 		/ n:number() { Value::Number(n) }
+		/ attribute_access() { unreachable!() }
 
 	pub rule root() -> Value
 		= _ v:value() _ { v }
 
+	rule ws()
+		= quiet!{ [' ' | '\t' | '\r' | '\n']+ }
+	rule comment() -> &'input str
+		= "#" c:$((!['\n'] [_])*) ("\n" / ![_]) { c }
+	rule comments() -> Vec<&'input str>
+		= ws()? c:(c:comment() ws()? { c })* { c }
+
 	rule _()
-		= ( quiet!{ [' ' | '\t' | '\n']+ }
-		/ "#" (!['\n'] [_])* "\n" )*
+		= ( ws() / comment() )*
 }
 }
 
+/// Files edited on windows might have CRLF line endings and BOM, nixlike output never contains
+/// raw CR characters, so it is safe to normalize them before parsing.
+fn normalize_input(s: &str) -> Cow<'_, str> {
+	let s = s.strip_prefix('\u{feff}').unwrap_or(s);
+	if s.contains("\r\n") {
+		Cow::Owned(s.replace("\r\n", "\n"))
+	} else {
+		Cow::Borrowed(s)
+	}
+}
+
+/// Render the offending source line with the caret under the error location, rustc-style.
+pub fn error_snippet(source: &str, error: &peg::error::ParseError<LineCol>) -> String {
+	snippet(
+		source,
+		&error.location,
+		&format!("expected {}", error.expected),
+	)
+}
+
+fn snippet(source: &str, location: &LineCol, message: &str) -> String {
+	let LineCol { line, column, .. } = *location;
+	let text = source.lines().nth(line - 1).unwrap_or_default();
+	// Tabs are kept, so that the caret is aligned the same way as the source line.
+	let pad: String = text
+		.chars()
+		.take(column - 1)
+		.map(|c| if c == '\t' { '\t' } else { ' ' })
+		.collect();
+	let gutter = " ".repeat(line.to_string().len());
+	format!("{gutter} |\n{line} | {text}\n{gutter} | {pad}^ {message}")
+}
+
+/// Strings are read from the untrusted (flake-provided) data, so their size is bounded.
+pub const DEFAULT_MAX_STRING_LENGTH: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ParseOptions {
+	/// Maximum length of the string literal in source, in bytes
+	pub max_string_length: usize,
+	/// Keep comments preceding object entries and array elements as [`Value::Commented`]
+	pub preserve_comments: bool,
+	/// Wrap parsed values in [`Value::Located`], so that deserialization errors point to the
+	/// source, enabled by [`parse_str`]
+	pub track_locations: bool,
+}
+impl Default for ParseOptions {
+	fn default() -> Self {
+		Self {
+			max_string_length: DEFAULT_MAX_STRING_LENGTH,
+			preserve_comments: false,
+			track_locations: false,
+		}
+	}
+}
+
 pub fn parse_str<'de, D: Deserialize<'de>>(s: &str) -> Result<D, Error> {
-	let value = nixlike::root(s)?;
-	D::deserialize(value)
+	parse_str_with(
+		s,
+		&ParseOptions {
+			track_locations: true,
+			..Default::default()
+		},
+	)
+}
+
+pub fn parse_str_with<'de, D: Deserialize<'de>>(
+	s: &str,
+	options: &ParseOptions,
+) -> Result<D, Error> {
+	let s = normalize_input(s);
+	let value = parse_normalized(&s, options)?;
+	de_impl::deserialize_located(value, D::deserialize).map_err(|e| match e {
+		Error::AtOffset { offset, error } => {
+			let location = peg::Parse::position_repr(&*s, offset);
+			let snippet = snippet(&s, &location, &error.to_string());
+			Error::At {
+				location,
+				error,
+				snippet,
+			}
+		}
+		e => e,
+	})
+}
+
+/// Parse document keeping comments of object entries and array elements, so that it can be
+/// modified and written back without losing them.
+pub fn parse_str_with_comments(s: &str) -> Result<Value, Error> {
+	parse_normalized(
+		&normalize_input(s),
+		&ParseOptions {
+			preserve_comments: true,
+			..Default::default()
+		},
+	)
+}
+
+fn parse_normalized(s: &str, options: &ParseOptions) -> Result<Value, Error> {
+	let value = nixlike::root(s, options).map_err(|e| {
+		if e.expected.tokens().any(|t| t == "<string too long>") {
+			let limit = options.max_string_length;
+			let snippet = snippet(s, &e.location, "string too long");
+			return Error::Custom(format!(
+				"string too long at {}, limit is {limit} bytes\n{snippet}",
+				e.location
+			));
+		}
+		if e.expected.tokens().any(|t| t == "<interpolation>") {
+			let snippet = snippet(s, &e.location, "interpolation");
+			return Error::Interpolation(e.location, snippet);
+		}
+		if e.expected.tokens().any(|t| t == "<inherit>") {
+			// Error is reported at the end of the statement.
+			let start = s[..e.location.offset].rfind("inherit").unwrap_or(0);
+			let location = peg::Parse::position_repr(s, start);
+			let snippet = snippet(s, &location, "inherit");
+			return Error::Inherit(location, snippet);
+		}
+		if e.expected.tokens().any(|t| t == "<integer overflow>") {
+			// Error is reported after the literal.
+			let start = s[..e.location.offset]
+				.trim_end_matches(|c: char| c.is_ascii_digit() || c == '+' || c == '-')
+				.len();
+			let location = peg::Parse::position_repr(s, start);
+			let snippet = snippet(s, &location, "integer overflow");
+			return Error::IntegerOverflow(location, snippet);
+		}
+		if e.expected.tokens().any(|t| t == "<attribute access>") {
+			let location = peg::Parse::position_repr(s, e.location.offset - 1);
+			let snippet = snippet(s, &location, "attribute access");
+			return Error::AttributeAccess(location, snippet);
+		}
+		let snippet = error_snippet(s, &e);
+		Error::ParseError(e, snippet)
+	})?;
+	Ok(value)
 }
 
 pub fn parse_value<'de, D: Deserialize<'de>>(value: Value) -> Result<D, Error> {
 	D::deserialize(value)
 }
 
+/// Post-processing of serialized nix code.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Format {
+	/// Formatted with alejandra, same as `Compact` when `format` feature is disabled.
+	#[default]
+	Pretty,
+	/// Built-in emitter output, valid, but not nicely indented.
+	Compact,
+}
+
+pub fn serialize_value(value: Value, format: Format) -> String {
+	to_string::write_nix(&value, format)
+}
+
 pub fn serialize_value_pretty(value: Value) -> String {
-	to_string::write_nix(&value)
+	serialize_value(value, Format::Pretty)
 }
 
 pub fn serialize<S: Serialize>(value: S) -> Result<String, Error> {
-	let value: Value = value.serialize(MySerialize)?;
-	Ok(serialize_value_pretty(value))
+	Ok(serialize_value_pretty(to_value(value)?))
+}
+
+pub fn to_value<S: Serialize>(value: S) -> Result<Value, Error> {
+	value.serialize(MySerialize)
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerializeOptions {
+	/// Write `None`/null attributes of structs and maps as absent keys, array elements are kept.
+	pub omit_null_in_objects: bool,
+}
+
+pub fn serialize_with<S: Serialize>(value: S, options: &SerializeOptions) -> Result<String, Error> {
+	Ok(serialize_value_pretty(to_value_with(value, options)?))
+}
+
+pub fn to_value_with<S: Serialize>(value: S, options: &SerializeOptions) -> Result<Value, Error> {
+	let value = to_value(value)?;
+	Ok(if options.omit_null_in_objects {
+		omit_null_in_objects(value)
+	} else {
+		value
+	})
+}
+
+fn omit_null_in_objects(value: Value) -> Value {
+	match value {
+		Value::Array(a) => Value::Array(a.into_iter().map(omit_null_in_objects).collect()),
+		Value::Commented { comments, value } => Value::Commented {
+			comments,
+			value: Box::new(omit_null_in_objects(*value)),
+		},
+		Value::Object(o) => Value::Object(
+			o.into_iter()
+				.filter(|(_, v)| !matches!(v, Value::Null))
+				.map(|(k, v)| (k, omit_null_in_objects(v)))
+				.collect(),
+		),
+		v => v,
+	}
 }
 
 pub fn format_identifier(i: &str) -> String {
@@ -195,6 +574,7 @@ pub fn format_identifier(i: &str) -> String {
 fn test() {
 	assert_eq!(serialize("Hello\nworld").unwrap(), "\"Hello\\nworld\"\n");
 }
+#[cfg(feature = "format")]
 pub fn format_nix(value: &String) -> String {
 	let (_, out) = alejandra::format::in_memory("".to_owned(), value.to_owned());
 	out
@@ -203,14 +583,764 @@ pub fn format_nix(value: &String) -> String {
 #[test]
 fn parse_multiline() {
 	// First line is ignored, unless there is a significant characters.
-	assert_eq!(nixlike::multiline_string("''\n''").expect("parse"), "");
+	assert_eq!(
+		nixlike::multiline_string("''\n''", &ParseOptions::default()).expect("parse"),
+		""
+	);
 	// Rest of the lines are processed normally.
-	assert_eq!(nixlike::multiline_string("''\n\n''").expect("parse"), "\n");
+	assert_eq!(
+		nixlike::multiline_string("''\n\n''", &ParseOptions::default()).expect("parse"),
+		"\n"
+	);
 	// Example with significant character on first line.
-	assert_eq!(nixlike::multiline_string("''t\n''").expect("parse"), "t\n");
+	assert_eq!(
+		nixlike::multiline_string("''t\n''", &ParseOptions::default()).expect("parse"),
+		"t\n"
+	);
 	// There might be nothing in multiline string block.
-	assert_eq!(nixlike::multiline_string("''''").expect("parse"), "");
+	assert_eq!(
+		nixlike::multiline_string("''''", &ParseOptions::default()).expect("parse"),
+		""
+	);
 	// And there also might just be spaces, they are removed due to dedent, and output is empty because
 	// first line was also ignored due to missing significant characters.
-	assert_eq!(nixlike::multiline_string("''    ''").expect("parse"), "");
+	assert_eq!(
+		nixlike::multiline_string("''    ''", &ParseOptions::default()).expect("parse"),
+		""
+	);
+}
+
+#[test]
+fn multiline_roundtrip() {
+	for value in [
+		"line\n",
+		"a\nb\nc",
+		"first\n  indented\nlast",
+		"\nleading\nnewline\n",
+		"with ''quotes'' and ${interpolation}\n\tand tab\n",
+		"trailing spaces  \n  \nend",
+		"\n\n",
+		// Common indentation can't be written in the block.
+		"  a\n  b\n",
+		"a\r\nb\nc",
+		"single\nnewline",
+	] {
+		let written = serialize(value).unwrap();
+		assert_eq!(parse_str::<String>(&written).unwrap(), value, "{written}");
+		let compact = serialize_value(to_value(value).unwrap(), Format::Compact);
+		assert_eq!(parse_str::<String>(&compact).unwrap(), value, "{compact}");
+	}
+	// Exact layout is produced by alejandra.
+	#[cfg(feature = "format")]
+	assert_eq!(serialize("a\nb\nc").unwrap(), "''\n  a\n  b\n  c''\n");
+	assert_eq!(
+		serialize("single\nnewline").unwrap(),
+		"\"single\\nnewline\"\n"
+	);
+	assert_eq!(serialize("  a\n  b\n").unwrap(), "\"  a\\n  b\\n\"\n");
+}
+
+#[test]
+fn float_integer_distinction() {
+	let float = || nixlike::root("{ x = 1.0; }", &ParseOptions::default()).expect("parse");
+	let int = || nixlike::root("{ x = 1; }", &ParseOptions::default()).expect("parse");
+	#[cfg(feature = "format")]
+	{
+		assert_eq!(serialize_value_pretty(float()), "{\n  x = 1.0;\n}\n");
+		assert_eq!(serialize_value_pretty(int()), "{\n  x = 1;\n}\n");
+	}
+	assert_eq!(serialize_value(float(), Format::Compact), "{x = 1.0;\n}\n");
+	assert_eq!(serialize_value(int(), Format::Compact), "{x = 1;\n}\n");
+
+	assert_eq!(serialize(1.0f64).unwrap(), "1.0\n");
+	assert_eq!(serialize(1i64).unwrap(), "1\n");
+
+	assert_eq!(parse_str::<f64>("1.0").unwrap(), 1.0);
+	assert_eq!(parse_str::<f64>("1").unwrap(), 1.0);
+	assert_eq!(parse_str::<i64>("1").unwrap(), 1);
+	assert!(parse_str::<i64>("1.0").is_err());
+}
+
+#[test]
+fn crlf_input() {
+	let input = "\u{feff}# comment\r\n{\r\n  a = ''\r\n    line\r\n  '';\r\n  b = \"c\\rd\";\r\n}\r\n# trailing";
+	let value = nixlike::root(&normalize_input(input), &ParseOptions::default()).expect("parse");
+	let out = serialize_value_pretty(value);
+	assert!(!out.contains('\r'));
+	assert!(out.ends_with('\n'));
+	#[cfg(feature = "format")]
+	assert_eq!(out, "{\n  a = ''\n    line\n  '';\n  b = \"c\\rd\";\n}\n");
+
+	// Raw CR is never written, even in multiline strings.
+	assert_eq!(serialize("a\rb\n").unwrap(), "\"a\\rb\\n\"\n");
+}
+
+#[test]
+fn array_comments() {
+	let options = ParseOptions {
+		preserve_comments: true,
+		..Default::default()
+	};
+	let value = || nixlike::root("[ # a\n 1 # b\n 2 ]", &options).expect("parse");
+	#[cfg(feature = "format")]
+	assert_eq!(
+		serialize_value_pretty(value()),
+		"[\n  # a\n  1\n  # b\n  2\n]\n"
+	);
+	assert_eq!(
+		serialize_value(value(), Format::Compact),
+		"[# a\n1\n# b\n2\n]\n"
+	);
+	// Comments are transparent for deserialization.
+	assert_eq!(
+		parse_str::<Vec<i64>>("[ # a\n 1 # b\n 2 ]").unwrap(),
+		vec![1, 2]
+	);
+	assert_eq!(
+		nixlike::root("[ # a\n 1 ]", &ParseOptions::default()).unwrap(),
+		Value::Array(vec![Value::Number(1)])
+	);
+}
+
+#[test]
+fn object_comments() {
+	let input = "{\n  # a\n  a = 1;\n  # c\n  b.c = [ 2 ];\n  d = { e = 3; }; # trailing\n}";
+	let value = parse_str_with_comments(input).expect("parse");
+	assert_eq!(
+		serialize_value(parse_str_with_comments(input).unwrap(), Format::Compact),
+		"{# a\na = 1;\n# c\nb.c = [2\n];\nd.e = 3;\n}\n"
+	);
+	// Comments are transparent for deserialization.
+	#[derive(Deserialize, PartialEq, Debug)]
+	struct B {
+		c: Vec<i64>,
+	}
+	#[derive(Deserialize, PartialEq, Debug)]
+	struct Doc {
+		a: i64,
+		b: B,
+	}
+	assert_eq!(
+		parse_value::<Doc>(value).unwrap(),
+		Doc {
+			a: 1,
+			b: B { c: vec![2] }
+		}
+	);
+	// Not preserved by default.
+	let value = nixlike::root(input, &ParseOptions::default()).expect("parse");
+	assert!(!serialize_value(value, Format::Compact).contains('#'));
+
+	// Updated document keeps comments of the entries which are still present.
+	let mut updated =
+		nixlike::root("{ b.c = [ 4 ]; a = 5; f = 6; }", &ParseOptions::default()).expect("parse");
+	updated.restore_comments(&parse_str_with_comments(input).unwrap());
+	assert_eq!(
+		serialize_value(updated, Format::Compact),
+		"{# c\nb.c = [4\n];\n# a\na = 5;\nf = 6;\n}\n"
+	);
+}
+
+#[test]
+fn path_literals() {
+	let input =
+		"{ src = ./relative; abs = /etc/bar; up = ../x/y.nix; home = ~/x; s = \"./quoted\"; }";
+	let value = || nixlike::root(input, &ParseOptions::default()).expect("parse");
+	assert_eq!(
+		serialize_value(value(), Format::Compact),
+		"{src = ./relative;\nabs = /etc/bar;\nup = ../x/y.nix;\nhome = ~/x;\ns = \"./quoted\";\n}\n"
+	);
+
+	#[derive(Deserialize)]
+	struct Paths {
+		src: std::path::PathBuf,
+		up: String,
+	}
+	let paths: Paths = parse_value(value()).unwrap();
+	assert_eq!(paths.src, std::path::Path::new("./relative"));
+	assert_eq!(paths.up, "../x/y.nix");
+	let json: serde_json::Value = parse_value(value()).unwrap();
+	assert_eq!(json["abs"], "/etc/bar");
+
+	for invalid in ["./", "/etc/", "./a//b"] {
+		assert!(
+			parse_str::<serde_json::Value>(invalid).is_err(),
+			"{invalid}"
+		);
+	}
+}
+
+#[test]
+fn deserialization_error_location() {
+	#[derive(Deserialize, Debug)]
+	#[allow(dead_code)]
+	struct Port {
+		port: u16,
+	}
+	#[derive(Deserialize, Debug)]
+	#[allow(dead_code)]
+	struct Doc {
+		hosts: std::collections::BTreeMap<String, Port>,
+	}
+	let err =
+		parse_str::<Doc>("{\n  hosts.a.port = 22;\n  hosts.b = {\n    port = 70000;\n  };\n}")
+			.unwrap_err();
+	let Error::At { location, .. } = &err else {
+		panic!("unexpected error: {err}");
+	};
+	assert_eq!((location.line, location.column), (4, 12));
+	let message = err.to_string();
+	let lines = message.lines().collect::<Vec<_>>();
+	assert_eq!(lines[0], "bad number at line 4, col 12");
+	assert_eq!(lines[2], "4 |     port = 70000;");
+
+	// Serde errors are reported at the containing value.
+	let err = parse_str::<Doc>("{\n  hosts.a = { };\n}").unwrap_err();
+	assert!(err
+		.to_string()
+		.starts_with("missing field `port` at line 2, col 13"));
+	// Values parsed without locations are not wrapped.
+	let value = nixlike::root("[ 1 ]", &ParseOptions::default()).unwrap();
+	assert_eq!(value, Value::Array(vec![Value::Number(1)]));
+}
+
+#[test]
+fn wide_integers() {
+	assert_eq!(serialize(-5i128).unwrap(), "-5\n");
+	assert_eq!(
+		serialize(u128::from(u64::MAX >> 1)).unwrap(),
+		"9223372036854775807\n"
+	);
+	assert_eq!(parse_str::<i128>("-5").unwrap(), -5);
+	assert_eq!(
+		parse_str::<u128>("9223372036854775807").unwrap(),
+		(u64::MAX >> 1).into()
+	);
+
+	for err in [
+		serialize(i128::MIN).unwrap_err(),
+		serialize(u128::MAX).unwrap_err(),
+	] {
+		assert!(
+			err.to_string()
+				.starts_with("Nix does not support 128-bit integers"),
+			"{err}"
+		);
+	}
+	assert!(matches!(
+		parse_str::<u128>("-1").unwrap_err(),
+		Error::At { error, .. } if matches!(*error, Error::NegativeUnsigned(-1))
+	));
+}
+
+#[test]
+fn byte_arrays() {
+	#[derive(Serialize, Deserialize, PartialEq, Debug)]
+	struct Blob {
+		#[serde(with = "serde_bytes")]
+		data: Vec<u8>,
+	}
+	let blob = Blob {
+		data: vec![0, 1, 255],
+	};
+	let value = || to_value(&blob).unwrap();
+	assert_eq!(
+		serialize_value(value(), Format::Compact),
+		"{data = [0\n1\n255\n];\n}\n"
+	);
+	assert_eq!(parse_value::<Blob>(value()).unwrap(), blob);
+	assert_eq!(
+		parse_str::<Blob>("{ data = [ ]; }").unwrap(),
+		Blob { data: vec![] }
+	);
+
+	let err = parse_str::<Blob>("{ data = [ 1 256 ]; }").unwrap_err();
+	assert!(
+		matches!(&err, Error::At { error, .. } if matches!(**error, Error::BadNumber)),
+		"{err}"
+	);
+	assert!(parse_str::<Blob>("{ data = [ -1 ]; }").is_err());
+	assert!(parse_str::<Blob>("{ data = \"abc\"; }").is_err());
+}
+
+#[test]
+fn struct_field_order() {
+	#[derive(Serialize)]
+	struct Inner {
+		y: u32,
+		b: u32,
+	}
+	#[derive(Serialize)]
+	enum Variant {
+		V { n: u32, c: u32 },
+	}
+	#[derive(Serialize)]
+	struct Doc {
+		z: u32,
+		a: Inner,
+		m: Variant,
+	}
+	// Fields are written in declaration order, not sorted.
+	let doc = Doc {
+		z: 1,
+		a: Inner { y: 2, b: 3 },
+		m: Variant::V { n: 4, c: 5 },
+	};
+	assert_eq!(
+		serialize_value(to_value(&doc).unwrap(), Format::Compact),
+		"{z = 1;\na = {y = 2;\nb = 3;\n};\nm.V = {n = 4;\nc = 5;\n};\n}\n"
+	);
+	// Sorting is opt-in.
+	assert_eq!(
+		serialize_value(canonicalize(to_value(&doc).unwrap()), Format::Compact),
+		"{a = {b = 3;\ny = 2;\n};\nm.V = {c = 5;\nn = 4;\n};\nz = 1;\n}\n"
+	);
+}
+
+#[test]
+fn parse_error_snippet() {
+	let input = "{\n\ta = 1;\n\tb = ;\n}\n";
+	let err = parse_str::<std::collections::BTreeMap<String, u32>>(input).unwrap_err();
+	let Error::ParseError(e, snippet) = &err else {
+		panic!("unexpected error: {err}");
+	};
+	assert_eq!((e.location.line, e.location.column), (3, 6));
+	let lines = snippet.lines().collect::<Vec<_>>();
+	assert_eq!(lines[..2], ["  |", "3 | \tb = ;"]);
+	assert!(lines[2].starts_with("  | \t    ^ expected one of"));
+}
+
+#[test]
+fn compact_output() {
+	let input = "{ a = [ 1 \"b\" ]; c.d = ''\n  e\n''; f = { }; }";
+	let value = nixlike::root(input, &ParseOptions::default()).expect("parse");
+	let out = serialize_value(
+		nixlike::root(input, &ParseOptions::default()).expect("parse"),
+		Format::Compact,
+	);
+	assert_eq!(out, "{a = [1\n\"b\"\n];\nc.d = ''\ne\n'';\nf = { };\n}\n");
+	assert_eq!(
+		nixlike::root(&out, &ParseOptions::default()).expect("reparse"),
+		value
+	);
+}
+
+#[test]
+fn json_value_interop() {
+	use serde_json::json;
+
+	let input = r#"{
+		a = { b = [ # one
+			1 2.5 "s" ]; c = null; };
+		d = true;
+		e = -3;
+		f = [ { } [ ] ];
+	}"#;
+	let expected = json!({
+		"a": { "b": [1, 2.5, "s"], "c": null },
+		"d": true,
+		"e": -3,
+		"f": [{}, []],
+	});
+	let parsed: serde_json::Value = parse_str(input).unwrap();
+	assert_eq!(parsed, expected);
+	// Integer and float representations are kept.
+	assert!(parsed["a"]["b"][0].is_i64());
+	assert!(parsed["a"]["b"][1].is_f64());
+
+	let serialized = serialize(&expected).unwrap();
+	assert_eq!(
+		parse_str::<serde_json::Value>(&serialized).unwrap(),
+		expected
+	);
+	// Nix integers are signed 64 bit.
+	assert!(matches!(serialize(json!(u64::MAX)), Err(Error::BadNumber)));
+}
+
+#[test]
+fn interpolation() {
+	for (input, at) in [
+		("{\n  a = \"x${b}\";\n}", (2, 9)),
+		("{\n  a = ''\n    x ${b}\n  '';\n}", (3, 7)),
+	] {
+		match parse_str::<std::collections::BTreeMap<String, String>>(input) {
+			Err(Error::Interpolation(location, _)) => {
+				assert_eq!((location.line, location.column), at, "{input}")
+			}
+			Err(e) => panic!("unexpected error: {e}"),
+			Ok(_) => panic!("interpolation should not be accepted: {input}"),
+		}
+	}
+
+	// Escaped forms are accepted, and serializer output roundtrips.
+	assert_eq!(parse_str::<String>(r#""a\${b}""#).unwrap(), "a${b}");
+	assert_eq!(parse_str::<String>("''\n  a''${b}\n''").unwrap(), "a${b}\n");
+	for value in ["a${b}", "a${b}\n", "a\t${b}\n"] {
+		let serialized = serialize(value).unwrap();
+		assert_eq!(
+			parse_str::<String>(&serialized).unwrap(),
+			value,
+			"{serialized}"
+		);
+	}
+}
+
+#[test]
+fn inherit() {
+	for (input, at) in [
+		("{\n  inherit a b;\n}", (2, 3)),
+		("{\n  a = 1;\n  inherit (pkgs.lib) b;\n}", (3, 3)),
+		("{ x = { inherit (\"src\") c; }; }", (1, 9)),
+	] {
+		match parse_str::<serde_json::Value>(input) {
+			Err(Error::Inherit(location, _)) => {
+				assert_eq!((location.line, location.column), at, "{input}")
+			}
+			Err(e) => panic!("unexpected error: {e}"),
+			Ok(_) => panic!("inherit should not be accepted: {input}"),
+		}
+	}
+	// Attribute may still be named `inherit`-something.
+	assert!(parse_str::<serde_json::Value>("{ inherited = 1; }").is_ok());
+}
+
+#[test]
+fn attribute_access() {
+	for (input, at) in [
+		("{\n  x = foo.bar or 5;\n}", (2, 7)),
+		("{ x = foo or \"default\"; }", (1, 7)),
+		("[ 1 pkgs.hello ]", (1, 5)),
+		("{ x = { y = a.b.c or { z = 1; }; }; }", (1, 13)),
+	] {
+		match parse_str::<serde_json::Value>(input) {
+			Err(Error::AttributeAccess(location, _)) => {
+				assert_eq!((location.line, location.column), at, "{input}")
+			}
+			Err(e) => panic!("unexpected error: {e}"),
+			Ok(_) => panic!("attribute access should not be accepted: {input}"),
+		}
+	}
+	// Literals are not mistaken for attribute access.
+	assert_eq!(
+		parse_str::<serde_json::Value>("{ a = null; b = true; c = \"or\"; }").unwrap(),
+		serde_json::json!({"a": null, "b": true, "c": "or"})
+	);
+}
+
+#[test]
+fn display_value() {
+	let source = r#"{
+		a.b = [ 1 2.5 "x" ./path null true ];
+		# comment
+		c = "multi\nline\nstring\n";
+		"d.e" = { };
+		f = [ ];
+	}"#;
+	let value = nixlike::root(source, &ParseOptions::default()).unwrap();
+	let displayed = value.to_string();
+	assert!(!displayed.ends_with('\n'));
+	assert_eq!(
+		nixlike::root(&displayed, &ParseOptions::default()).unwrap(),
+		value
+	);
+	assert_eq!(Value::Number(-1).to_string(), "-1");
+	assert_eq!(Value::Array(vec![]).to_string(), "[ ]");
+}
+
+#[test]
+fn integer_limits() {
+	assert_eq!(parse_str::<i64>("-9223372036854775808").unwrap(), i64::MIN);
+	for (input, at) in [
+		("9223372036854775808", (1, 1)),
+		("{\n  a = -99999999999999999999;\n}", (2, 7)),
+	] {
+		match parse_str::<serde_json::Value>(input) {
+			Err(Error::IntegerOverflow(location, _)) => {
+				assert_eq!((location.line, location.column), at, "{input}")
+			}
+			Err(e) => panic!("unexpected error: {e}"),
+			Ok(_) => panic!("overflow should not be accepted: {input}"),
+		}
+	}
+	// Not an overflow, just a bad literal.
+	assert!(matches!(
+		parse_str::<serde_json::Value>("1-2"),
+		Err(Error::ParseError(..))
+	));
+
+	let err = parse_str::<u64>("-3").unwrap_err();
+	assert!(
+		matches!(&err, Error::At { error, .. } if matches!(**error, Error::NegativeUnsigned(-3))),
+		"{err}"
+	);
+	assert!(err
+		.to_string()
+		.starts_with("expected unsigned integer, got negative -3"));
+	assert!(matches!(
+		parse_str::<u8>("-1").unwrap_err(),
+		Error::At { error, .. } if matches!(*error, Error::NegativeUnsigned(-1))
+	));
+	assert_eq!(
+		parse_str::<u64>("9223372036854775807").unwrap(),
+		i64::MAX as u64
+	);
+}
+
+#[test]
+fn string_length_limit() {
+	let options = ParseOptions {
+		max_string_length: 8,
+		..Default::default()
+	};
+	assert_eq!(
+		parse_str_with::<String>("\"12345678\"", &options).unwrap(),
+		"12345678"
+	);
+	for input in [
+		"{\n  a = \"123456789\";\n}",
+		"{\n  a = ''\n    123456789\n  '';\n}",
+	] {
+		match parse_str_with::<serde_json::Value>(input, &options) {
+			Err(Error::Custom(e)) => {
+				assert!(e.starts_with("string too long at 2:"), "{e}")
+			}
+			Err(e) => panic!("unexpected error: {e}"),
+			Ok(_) => panic!("long string should not be accepted: {input}"),
+		}
+	}
+}
+
+#[test]
+fn located_errors() {
+	let input = "{\n  a = \"x\";\n}";
+	let Err(e) = parse_str::<std::collections::HashMap<String, u32>>(input) else {
+		panic!("string is not a number");
+	};
+	assert!(
+		matches!(e, Error::At { ref location, .. } if location.line == 2),
+		"{e}"
+	);
+	// Locations are only tracked when asked to.
+	let Err(e) =
+		parse_str_with::<std::collections::HashMap<String, u32>>(input, &ParseOptions::default())
+	else {
+		panic!("string is not a number");
+	};
+	assert!(
+		!matches!(e, Error::At { .. } | Error::AtOffset { .. }),
+		"{e}"
+	);
+}
+
+#[test]
+fn merged_located_paths() {
+	// Values are wrapped in `Value::Located` by `parse_str`, this shouldn't break path merging.
+	let value: serde_json::Value = parse_str("{ a = { x = 1; }; a.y = 2; }").unwrap();
+	assert_eq!(value, serde_json::json!({ "a": { "x": 1, "y": 2 } }));
+	assert!(parse_str::<serde_json::Value>("{ a = 1; a.y = 2; }").is_err());
+}
+
+#[test]
+fn merged_commented_paths() {
+	let value = parse_str_with_comments("{\n  # note\n  a = { x = 1; };\n  a.y = 2;\n}").unwrap();
+	let Value::Object(mut root) = value else {
+		panic!("object expected");
+	};
+	let Some(Value::Commented { comments, value }) = root.remove("a") else {
+		panic!("comment should be kept");
+	};
+	assert_eq!(comments, [" note"]);
+	let value: serde_json::Value = parse_value(*value).unwrap();
+	assert_eq!(value, serde_json::json!({ "x": 1, "y": 2 }));
+}
+
+#[test]
+fn tuple_length() {
+	#[derive(Deserialize, Debug, PartialEq)]
+	struct Pair(u32, String);
+
+	assert_eq!(
+		parse_str::<(u32, String)>("[ 1 \"a\" ]").unwrap(),
+		(1, "a".to_owned())
+	);
+	assert_eq!(
+		parse_str::<Pair>("[ 1 \"a\" ]").unwrap(),
+		Pair(1, "a".to_owned())
+	);
+	for (input, actual) in [("[ 1 ]", 1), ("[ 1 \"a\" 2 ]", 3)] {
+		let err = parse_str::<(u32, String)>(input).unwrap_err();
+		assert!(
+			err.to_string().starts_with(&format!(
+				"expected tuple of 2 elements, got array of {actual}"
+			)),
+			"{err}"
+		);
+		assert!(parse_str::<Pair>(input).is_err(), "{input}");
+	}
+}
+
+#[test]
+fn empty_containers() {
+	for (input, expected) in [
+		("{ }", "{ }\n"),
+		("[ ]", "[ ]\n"),
+		// Empty object is not a single-key object, it should not be written as a path.
+		("{ a = { }; }", "{a = { };\n}\n"),
+		("{ a = [ ]; }", "{a = [ ];\n}\n"),
+		("{ a.b = { }; }", "{a.b = { };\n}\n"),
+		(
+			"{ a = { b = { }; c = [ ]; }; }",
+			"{a = {b = { };\nc = [ ];\n};\n}\n",
+		),
+		("[ { } [ ] [ { } ] ]", "[{ }\n[ ]\n[{ }\n]\n]\n"),
+	] {
+		let value = || nixlike::root(input, &ParseOptions::default()).expect("parse");
+		let out = serialize_value(value(), Format::Compact);
+		assert_eq!(out, expected, "{input}");
+		assert_eq!(
+			nixlike::root(&out, &ParseOptions::default()).expect("reparse"),
+			value(),
+			"{out}"
+		);
+		#[cfg(feature = "format")]
+		assert_eq!(
+			nixlike::root(&serialize_value_pretty(value()), &ParseOptions::default())
+				.expect("reparse"),
+			value()
+		);
+	}
+}
+
+#[test]
+fn omitted_nulls() {
+	use std::collections::BTreeMap;
+
+	#[derive(serde::Serialize)]
+	struct Item {
+		name: &'static str,
+		description: Option<&'static str>,
+		nested: Option<Box<Item>>,
+	}
+	let item = |name, nested| Item {
+		name,
+		description: None,
+		nested,
+	};
+	let value = (
+		item("a", Some(Box::new(item("b", None)))),
+		BTreeMap::from([("x", None), ("y", Some(1))]),
+		vec![None, Some(item("c", None))],
+	);
+	let options = SerializeOptions {
+		omit_null_in_objects: true,
+	};
+	assert_eq!(
+		serialize_value(to_value_with(&value, &options).unwrap(), Format::Compact),
+		"[{name = \"a\";\nnested.name = \"b\";\n}\n{y = 1;\n}\n[null\n{name = \"c\";\n}\n]\n]\n"
+	);
+	// Nulls are kept by default.
+	let value = to_value(&value).unwrap();
+	assert_eq!(
+		serialize_value(value, Format::Compact),
+		"[{name = \"a\";\ndescription = null;\nnested = {name = \"b\";\ndescription = null;\nnested = null;\n};\n}\n{x = null;\ny = 1;\n}\n[null\n{name = \"c\";\ndescription = null;\nnested = null;\n}\n]\n]\n"
+	);
+}
+
+#[test]
+fn ordered_merge() {
+	let parse = |s| nixlike::root(s, &ParseOptions::default()).expect("parse");
+	let mut base = parse("{ z = 1; a = { y = 1; b = 2; }; m = [ 1 ]; }");
+	base.merge(parse(
+		"{ n = 1; a = { c = 3; y = 4; }; z = { x = 1; }; m = [ 2 ]; b = null; }",
+	));
+	assert_eq!(
+		serialize_value(base, Format::Compact),
+		"{z.x = 1;\na = {y = 4;\nb = 2;\nc = 3;\n};\nm = [2\n];\nn = 1;\nb = null;\n}\n"
+	);
+
+	// Non-object base is replaced.
+	let mut base = parse("[ 1 ]");
+	base.merge(parse("{ a = 1; }"));
+	assert_eq!(base, parse("{ a = 1; }"));
+}
+
+#[test]
+fn float_literals() {
+	for (input, expected) in [
+		("1.5", 1.5),
+		("-0.3", -0.3),
+		("2.0e3", 2000.0),
+		(".5", 0.5),
+		("1.", 1.0),
+	] {
+		assert_eq!(parse_str::<f64>(input).unwrap(), expected, "{input}");
+	}
+	for input in ["2e3", "+1.5"] {
+		assert!(parse_str::<f64>(input).is_err(), "{input}");
+	}
+	assert_eq!(serialize(0.1f64).unwrap(), "0.1\n");
+	assert_eq!(serialize(0.1f32).unwrap(), "0.1\n");
+	assert_eq!(serialize(-0.3f32).unwrap(), "-0.3\n");
+	assert_eq!(serialize(1e20f64).unwrap(), "1.0e20\n");
+	assert_eq!(
+		parse_str::<f64>(&serialize(1e20f64).unwrap()).unwrap(),
+		1e20
+	);
+	assert!(matches!(serialize(f32::NAN), Err(Error::BadNumber)));
+	// Integers are still parsed as integers.
+	assert_eq!(parse_str::<i64>("-3").unwrap(), -3);
+}
+
+#[test]
+fn enum_variants() {
+	#[derive(serde::Serialize, Deserialize, PartialEq, Debug)]
+	enum Kind {
+		Unit,
+		Newtype(u32),
+		Tuple(u32, String),
+		Struct { a: u32, b: Option<String> },
+	}
+	for kind in [
+		Kind::Unit,
+		Kind::Newtype(1),
+		Kind::Tuple(2, "t".to_owned()),
+		Kind::Struct {
+			a: 3,
+			b: Some("s".to_owned()),
+		},
+	] {
+		let serialized = serialize(&kind).unwrap();
+		assert_eq!(
+			parse_str::<Kind>(&serialized).unwrap(),
+			kind,
+			"{serialized}"
+		);
+	}
+	assert_eq!(parse_str::<Kind>("\"Unit\"").unwrap(), Kind::Unit);
+	assert_eq!(
+		parse_str::<Kind>("{ Struct.a = 1; }").unwrap(),
+		Kind::Struct { a: 1, b: None }
+	);
+	assert!(parse_str::<Kind>("\"Missing\"").is_err());
+	assert!(parse_str::<Kind>("{ Unit = null; Newtype = 1; }").is_err());
+	assert!(parse_str::<Kind>("{ Newtype = \"1\"; }").is_err());
+}
+
+#[test]
+fn float_fields() {
+	#[derive(Deserialize, PartialEq, Debug)]
+	struct Limits {
+		x: f64,
+		y: f32,
+	}
+	assert_eq!(
+		parse_str::<Limits>("{ x = 5; y = 5.5; }").unwrap(),
+		Limits { x: 5.0, y: 5.5 }
+	);
+	assert_eq!(
+		parse_str::<Limits>("{ x = 5.5; y = -2; }").unwrap(),
+		Limits { x: 5.5, y: -2.0 }
+	);
+	assert!(parse_str::<Limits>("{ x = \"5\"; y = 1; }").is_err());
+	assert!(parse_str::<f64>("true").is_err());
 }
@@ -0,0 +1,34 @@
+//! Value with sensitive strings replaced, for logging and debugging.
+//!
+//! Redacted value can't be loaded back, it should never be written to the data file.
+
+use crate::Value;
+
+/// Replace strings starting with `prefix` with the placeholder, which only keeps their length.
+pub fn redact(value: Value, prefix: &str) -> Value {
+	match value {
+		Value::String(s) if s.starts_with(prefix) => {
+			Value::String(format!("<redacted {} bytes>", s.len()))
+		}
+		Value::Array(a) => Value::Array(a.into_iter().map(|v| redact(v, prefix)).collect()),
+		Value::Commented { comments, value } => Value::Commented {
+			comments,
+			value: Box::new(redact(*value, prefix)),
+		},
+		Value::Object(o) => {
+			Value::Object(o.into_iter().map(|(k, v)| (k, redact(v, prefix))).collect())
+		}
+		v => v,
+	}
+}
+
+#[test]
+fn redacted_strings() {
+	let input = r#"{ a.secret = "<S>abcd"; a.public = "abcd"; list = [ "<S>" 1 ]; }"#;
+	let value = crate::nixlike::root(input, &Default::default()).expect("parse");
+	let expected = r#"{ a.secret = "<redacted 7 bytes>"; a.public = "abcd"; list = [ "<redacted 3 bytes>" 1 ]; }"#;
+	assert_eq!(
+		redact(value, "<S>"),
+		crate::nixlike::root(expected, &Default::default()).expect("parse")
+	);
+}
@@ -20,6 +20,12 @@ impl ser::Error for Error {
 	}
 }
 
+fn wide_integer(v: impl std::fmt::Display) -> Error {
+	Error::Custom(format!(
+		"Nix does not support 128-bit integers, {v} is out of i64 range"
+	))
+}
+
 pub struct MySerializeSeq(Vec<Value>);
 
 impl SerializeSeq for MySerializeSeq {
@@ -27,9 +33,9 @@ impl SerializeSeq for MySerializeSeq {
 
 	type Error = Error;
 
-	fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+	fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		self.0.push(value.serialize(MySerialize)?);
 		Ok(())
@@ -44,9 +50,9 @@ impl SerializeTuple for MySerializeSeq {
 
 	type Error = Error;
 
-	fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+	fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		self.0.push(value.serialize(MySerialize)?);
 		Ok(())
@@ -61,9 +67,9 @@ impl SerializeTupleStruct for MySerializeSeq {
 
 	type Error = Error;
 
-	fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+	fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		self.0.push(value.serialize(MySerialize)?);
 		Ok(())
@@ -81,9 +87,9 @@ impl SerializeTupleVariant for MySerializeSeqVariant {
 
 	type Error = Error;
 
-	fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+	fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		self.1.serialize_field(value)
 	}
@@ -104,9 +110,9 @@ impl SerializeMap for MySerializeMap {
 
 	type Error = Error;
 
-	fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
+	fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		let _ = self
 			.1
@@ -114,9 +120,9 @@ impl SerializeMap for MySerializeMap {
 		Ok(())
 	}
 
-	fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+	fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		self.0
 			.insert(self.1.take().unwrap(), value.serialize(MySerialize)?);
@@ -135,9 +141,9 @@ impl SerializeStruct for MySerializeStruct {
 
 	type Error = Error;
 
-	fn serialize_field<T: ?Sized>(&mut self, key: &str, value: &T) -> Result<(), Self::Error>
+	fn serialize_field<T>(&mut self, key: &str, value: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		self.0.insert(key.to_owned(), value.serialize(MySerialize)?);
 		Ok(())
@@ -155,13 +161,9 @@ impl SerializeStructVariant for MySerializeStructVariant {
 
 	type Error = Error;
 
-	fn serialize_field<T: ?Sized>(
-		&mut self,
-		key: &'static str,
-		value: &T,
-	) -> Result<(), Self::Error>
+	fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		self.1.insert(key.to_owned(), value.serialize(MySerialize)?);
 		Ok(())
@@ -231,12 +233,31 @@ impl Serializer for MySerialize {
 		Ok(Value::Number(v.try_into().map_err(|_| Error::BadNumber)?))
 	}
 
-	fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
-		todo!()
+	fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+		Ok(Value::Number(v.try_into().map_err(|_| wide_integer(v))?))
+	}
+
+	fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+		Ok(Value::Number(v.try_into().map_err(|_| wide_integer(v))?))
+	}
+
+	fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+		// `as f64` keeps the binary value, so 0.1f32 would be written as 0.10000000149011612,
+		// shortest f32 representation is used instead.
+		let v = if v.is_finite() {
+			v.to_string().parse().expect("float display is parseable")
+		} else {
+			v as f64
+		};
+		self.serialize_f64(v)
 	}
 
-	fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
-		todo!()
+	fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+		// There is no literal for NaN/infinity in nix.
+		if !v.is_finite() {
+			return Err(Error::BadNumber);
+		}
+		Ok(Value::Float(v))
 	}
 
 	fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
@@ -247,17 +268,20 @@ impl Serializer for MySerialize {
 		Ok(Value::String(v.to_owned()))
 	}
 
-	fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok, Self::Error> {
-		todo!()
+	/// Nix strings can't contain arbitrary bytes, thus bytes are written as an array of numbers.
+	fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+		Ok(Value::Array(
+			v.iter().map(|&b| Value::Number(b.into())).collect(),
+		))
 	}
 
 	fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
 		Ok(Value::Null)
 	}
 
-	fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
+	fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		value.serialize(self)
 	}
@@ -279,18 +303,18 @@ impl Serializer for MySerialize {
 		Ok(Value::String(variant.to_string()))
 	}
 
-	fn serialize_newtype_struct<T: ?Sized>(
+	fn serialize_newtype_struct<T>(
 		self,
 		_name: &'static str,
 		value: &T,
 	) -> Result<Self::Ok, Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		value.serialize(self)
 	}
 
-	fn serialize_newtype_variant<T: ?Sized>(
+	fn serialize_newtype_variant<T>(
 		self,
 		_name: &'static str,
 		_variant_index: u32,
@@ -298,7 +322,7 @@ impl Serializer for MySerialize {
 		value: &T,
 	) -> Result<Self::Ok, Self::Error>
 	where
-		T: serde::Serialize,
+		T: ?Sized + serde::Serialize,
 	{
 		Ok(Value::Object(
 			vec![(variant.to_string(), value.serialize(self)?)]
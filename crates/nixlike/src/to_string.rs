@@ -1,4 +1,6 @@
-use crate::Value;
+use std::fmt;
+
+use crate::{Format, Value};
 
 pub fn write_identifier(k: &str, out: &mut String) {
 	if k.contains(['.', '\'', '\"', '\\', '\n', '\t', '\r', '$']) {
@@ -8,21 +10,40 @@ pub fn write_identifier(k: &str, out: &mut String) {
 	}
 }
 
-fn write_nix_obj_key_buf(k: &str, v: &Value, out: &mut String) {
-	write_identifier(k, out);
-	match v {
-		Value::Object(o) if o.len() == 1 => {
-			let (k, v) = o.iter().next().unwrap();
+fn write_comments(comments: &[String], out: &mut String) {
+	for comment in comments {
+		out.push('#');
+		out.push_str(comment);
+		out.push('\n');
+	}
+}
 
-			out.push('.');
-			write_nix_obj_key_buf(k, v, out);
+fn write_nix_obj_key_buf(k: &str, mut v: &Value, out: &mut String) {
+	// `a.b = v;` shorthand, empty objects are kept as `a = { };`
+	let mut path = vec![k];
+	while let Value::Object(o) = v {
+		if o.len() != 1 {
+			break;
 		}
-		v => {
-			out.push_str(" = ");
-			write_nix_buf(v, out);
-			out.push(';');
+		let (k, inner) = o.iter().next().unwrap();
+		path.push(k);
+		v = inner;
+	}
+	// Parser attaches comments to the innermost value of the shorthand, so they are written above
+	// the whole entry, and the commented value itself is written in full.
+	if let Value::Commented { comments, value } = v {
+		write_comments(comments, out);
+		v = value;
+	}
+	for (i, k) in path.into_iter().enumerate() {
+		if i != 0 {
+			out.push('.');
 		}
+		write_identifier(k, out);
 	}
+	out.push_str(" = ");
+	write_nix_buf(v, out);
+	out.push(';');
 }
 
 pub fn escape_string(str: &str) -> String {
@@ -37,8 +58,25 @@ pub fn escape_string(str: &str) -> String {
 	)
 }
 
+/// Whether the string can be written as `''` block, which is read back unchanged.
+fn is_multiline_writable(str: &str) -> bool {
+	// Multiline strings can't contain escaped CR, and raw CR would be lost on CRLF normalization.
+	if str.contains('\r') {
+		return false;
+	}
+	// Common indentation of lines is removed on read, and there is no way to escape a space
+	// without interpolation. Whitespace-only lines are also reindented by formatter.
+	let lines = || str.split('\n');
+	if lines().any(|l| !l.is_empty() && l.chars().all(|c| c == ' ')) {
+		return false;
+	}
+	let mut significant = lines().filter(|l| !l.is_empty());
+	significant.clone().next().is_none() || significant.any(|l| !l.starts_with(' '))
+}
+
 pub fn write_nix_str(str: &str, out: &mut String) {
-	if str.ends_with('\n') {
+	let multiline = str.ends_with('\n') || str.matches('\n').count() >= 2;
+	if multiline && is_multiline_writable(str) {
 		out.push_str("''");
 		for ele in str.split('\n') {
 			out.push('\n');
@@ -55,19 +93,43 @@ pub fn write_nix_str(str: &str, out: &mut String) {
 					.replace('\t', "''\\t"),
 			);
 		}
-		// Final newline is assumed due to str.ends_with condition
+		// Final newline is kept as an empty last line.
 		out.push_str("''");
 	} else {
 		out.push_str(&escape_string(str))
 	}
 }
 
+/// Nix float literal requires either a decimal point or an exponent, and nix lexer doesn't accept
+/// exponent without a decimal point, so `1e20` is written as `1.0e20`.
+pub fn write_float(f: f64, out: &mut String) {
+	// Debug formatting produces the shortest representation which roundtrips to the same value.
+	let v = format!("{f:?}");
+	if v.contains('.') {
+		out.push_str(&v);
+	} else if let Some((mantissa, exponent)) = v.split_once('e') {
+		out.push_str(mantissa);
+		out.push_str(".0e");
+		out.push_str(exponent);
+	} else {
+		out.push_str(&v);
+		out.push_str(".0");
+	}
+}
+
 fn write_nix_buf(value: &Value, out: &mut String) {
 	match value {
 		Value::Null => out.push_str("null"),
 		Value::Boolean(v) => out.push_str(if *v { "true" } else { "false" }),
 		Value::Number(n) => out.push_str(&format!("{}", n)),
+		Value::Float(f) => write_float(*f, out),
+		Value::Located { value, .. } => write_nix_buf(value, out),
+		Value::Commented { comments, value } => {
+			write_comments(comments, out);
+			write_nix_buf(value, out);
+		}
 		Value::String(s) => write_nix_str(s, out),
+		Value::Path(p) => out.push_str(p),
 		Value::Array(a) => {
 			if a.is_empty() {
 				out.push_str("[ ]");
@@ -95,9 +157,26 @@ fn write_nix_buf(value: &Value, out: &mut String) {
 	};
 }
 
-pub fn write_nix(value: &Value) -> String {
+/// Compact nix, same as [`Format::Compact`] without the trailing newline.
+impl fmt::Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut out = String::new();
+		write_nix_buf(self, &mut out);
+		f.write_str(&out)
+	}
+}
+
+pub fn write_nix(value: &Value, format: Format) -> String {
 	let mut out = String::new();
 	write_nix_buf(value, &mut out);
-	let (_, out) = alejandra::format::in_memory("".to_owned(), out);
+	#[cfg(feature = "format")]
+	if format == Format::Pretty {
+		out = alejandra::format::in_memory("".to_owned(), out).1;
+	}
+	#[cfg(not(feature = "format"))]
+	let _ = format;
+	if !out.ends_with('\n') {
+		out.push('\n');
+	}
 	out
 }
@@ -7,7 +7,7 @@
   inherit (fleetLib.modules) mkFleetGeneratorDefault;
   inherit (fleetLib.types) mkHostsType mkDataType;
   inherit (lib.options) mkOption;
-  inherit (lib.types) str listOf attrsOf submodule;
+  inherit (lib.types) str listOf attrsOf submodule nullOr;
   inherit (lib.attrsets) mapAttrsToList mapAttrs;
   inherit (lib.lists) flatten groupBy;
 in {
@@ -54,6 +54,18 @@ in {
             description = "Host tag. In CLI, you can refer to all hosts having this tag using @tag syntax.";
             type = listOf str;
           };
+          secretsIdentityFile = mkOption {
+            description = "Host private key, used to decrypt secrets. Public key is expected to be located at the same path with .pub suffix.";
+            type = str;
+            default = "/etc/ssh/ssh_host_ed25519_key";
+            example = "/persist/etc/ssh/ssh_host_ed25519_key";
+          };
+          activationCommand = mkOption {
+            description = "Command used to activate the deployed system instead of bin/switch-to-configuration, relative to the system closure unless absolute. It is called with the deploy action (switch/test/boot) as an argument.";
+            type = nullOr str;
+            default = null;
+            example = "bin/activate";
+          };
           network = mkOption {
             type = submodule {
               options = {
@@ -74,6 +86,7 @@ in {
         };
         config = {
           nixos.networking.hostName = mkFleetGeneratorDefault config._module.args.name;
+          nixos.secretsIdentityFile = config.secretsIdentityFile;
           tags = ["all"];
         };
         _file = ./meta.nix;
@@ -49,6 +49,29 @@
         stablePath = "/run/secrets/${secretName}/${partName}";
       };
     });
+  extraFileType = secretName:
+    submodule ({config, ...}: let
+      fileName = config._module.args.name;
+    in {
+      options = {
+        content = mkOption {
+          type = str;
+          description = "File content, stored in nix store, thus it should not contain any secret data";
+        };
+        mode = mkOption {
+          type = str;
+          description = "File mode";
+          default = "0444";
+        };
+        path = mkOption {
+          type = str;
+          description = "Path to the installed file";
+        };
+      };
+      config = {
+        path = "/run/secrets/${secretName}/${fileName}";
+      };
+    });
   secretType = submodule ({config, ...}: let
     secretName = config._module.args.name;
   in {
@@ -85,14 +108,23 @@
         description = "Data that gets embedded into secret part";
         default = null;
       };
+      extraFiles = mkOption {
+        type = attrsOf (extraFileType secretName);
+        description = "Non-secret companion files (config templates, CA bundles), installed together with the secret parts, owned by the secret owner";
+        default = {};
+      };
     };
   });
   processPart = part: {
     inherit (part) raw path stablePath;
   };
+  processExtraFile = file: {
+    inherit (file) content mode path;
+  };
   processSecret = secret:
     {
       inherit (secret) group mode owner;
+      extraFiles = mapAttrs (_: processExtraFile) secret.extraFiles;
     }
     // (mapAttrs (_: processPart) (removeAttrs secret [
       "shared"
@@ -101,6 +133,7 @@
       "group"
       "owner"
       "expectedGenerationData"
+      "extraFiles"
     ]));
   secretsFile = pkgs.writeTextFile {
     name = "secrets.json";
@@ -116,6 +149,11 @@ in {
       default = {};
       description = "Host-local secrets";
     };
+    secretsIdentityFile = mkOption {
+      type = str;
+      default = "/etc/ssh/ssh_host_ed25519_key";
+      description = "Host private key, used to decrypt secrets";
+    };
   };
   config = {
     environment.systemPackages = [pkgs.fleet-install-secrets];
@@ -136,7 +174,7 @@ in {
       serviceConfig = {
         Type = "oneshot";
         RemainAfterExit = true;
-        ExecStart = "${pkgs.fleet-install-secrets}/bin/fleet-install-secrets install ${secretsFile}";
+        ExecStart = "${pkgs.fleet-install-secrets}/bin/fleet-install-secrets install --identity-file ${config.secretsIdentityFile} ${secretsFile}";
       };
     };
     system.activationScripts.decryptSecrets =
@@ -155,7 +193,7 @@ in {
           ++ (optional (config.system.activationScripts ? "persist-files") "persist-files")
         ) ''
           1>&2 echo "setting up secrets"
-          ${pkgs.fleet-install-secrets}/bin/fleet-install-secrets install ${secretsFile}
+          ${pkgs.fleet-install-secrets}/bin/fleet-install-secrets install --identity-file ${config.secretsIdentityFile} ${secretsFile}
         ''
       );
   };
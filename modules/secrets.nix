@@ -99,6 +99,13 @@ in {
                 exit 1
               fi
 
+              for reserved in created_at marker; do
+                if test -e "$out/$reserved"; then
+                  echo "impure generator script produced part named $reserved, this name is reserved for secret metadata"
+                  exit 1
+                fi
+              done
+
               echo -n $created_at > $out/created_at
               echo -n SUCCESS > $out/marker
             '')